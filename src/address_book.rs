@@ -0,0 +1,508 @@
+use crate::messages::addr::TimedAddress;
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How many addresses are remembered. Past it a newcomer replaces the one
+/// least worth dialling.
+pub const MAX_ADDRESSES: usize = 4_096;
+
+/// Dials in a row an address may fail before it is forgotten.
+pub const MAX_FAILURES: u32 = 5;
+
+/// How long after dialling an address it may be dialled again, whatever
+/// came of it.
+pub const RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// The address book's file, in the network's part of the data directory.
+pub const ADDRESS_BOOK_FILE: &str = "peers.txt";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AddressEntry {
+    /// Unix time we, or whoever told us of it, last heard from a node there.
+    pub last_seen: u32,
+    /// Dials that have failed since one last succeeded.
+    pub failures: u32,
+}
+
+/// Addresses nodes have been heard of on: the ones we dial when we want more
+/// peers, and what a `getaddr` is answered from.
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    entries: HashMap<SocketAddr, AddressEntry>,
+    /// When each was last dialled. Not persisted: a restarted node may try
+    /// everything at once.
+    attempted: HashMap<SocketAddr, Instant>,
+    /// Addresses a dial to which reached this node. Never learned or dialled
+    /// again this run; a restart may listen somewhere else.
+    ourselves: HashSet<SocketAddr>,
+    /// Changed since it was last written out.
+    changed: bool,
+}
+
+impl AddressBook {
+    /// Records that a node was heard of on `address` at `last_seen`, which is
+    /// never taken to be later than `now`. Whether it was news.
+    pub fn learn(&mut self, address: SocketAddr, last_seen: u32, now: u32) -> bool {
+        if !dialable(address) || self.ourselves.contains(&address) {
+            return false;
+        }
+        let last_seen = last_seen.min(now);
+
+        if let Some(entry) = self.entries.get_mut(&address) {
+            if last_seen > entry.last_seen {
+                entry.last_seen = last_seen;
+                self.changed = true;
+            }
+            return false;
+        }
+
+        if self.entries.len() >= MAX_ADDRESSES {
+            self.evict();
+        }
+        self.entries.insert(
+            address,
+            AddressEntry {
+                last_seen,
+                failures: 0,
+            },
+        );
+        self.changed = true;
+        true
+    }
+
+    /// A dial to `address` reached a completed handshake.
+    pub fn connected(&mut self, address: SocketAddr, now: u32) {
+        self.entries.insert(
+            address,
+            AddressEntry {
+                last_seen: now,
+                failures: 0,
+            },
+        );
+        self.changed = true;
+    }
+
+    pub fn attempted(&mut self, address: SocketAddr, at: Instant) {
+        self.attempted.insert(address, at);
+    }
+
+    /// `address` reaches this node, not a peer.
+    pub fn mark_ourselves(&mut self, address: SocketAddr) {
+        self.ourselves.insert(address);
+        if self.entries.remove(&address).is_some() {
+            self.changed = true;
+        }
+    }
+
+    pub fn ourselves(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.ourselves.iter()
+    }
+
+    /// A dial to `address` did not connect. Enough of those in a row and it
+    /// is forgotten.
+    pub fn failed(&mut self, address: SocketAddr) {
+        let Some(entry) = self.entries.get_mut(&address) else {
+            return;
+        };
+
+        entry.failures += 1;
+        if entry.failures >= MAX_FAILURES {
+            self.entries.remove(&address);
+        }
+        self.changed = true;
+    }
+
+    /// Up to `count` addresses worth dialling, best first: fewest failures,
+    /// then most recently seen. Nothing in `exclude`, and nothing dialled
+    /// within `RETRY_AFTER` of `now`.
+    pub fn candidates(
+        &self,
+        exclude: &HashSet<SocketAddr>,
+        count: usize,
+        now: Instant,
+    ) -> Vec<SocketAddr> {
+        let mut candidates: Vec<(&SocketAddr, &AddressEntry)> =
+            self.worth_dialling(exclude, now).collect();
+
+        candidates.sort_by_key(|(address, entry)| {
+            (
+                entry.failures,
+                std::cmp::Reverse(entry.last_seen),
+                **address,
+            )
+        });
+        candidates
+            .into_iter()
+            .take(count)
+            .map(|(address, _)| *address)
+            .collect()
+    }
+
+    /// The address worth a feeler: the one heard from longest ago, which the
+    /// book knows least about. Nothing in `exclude`, and nothing dialled
+    /// within `RETRY_AFTER` of `now`.
+    pub fn stalest(&self, exclude: &HashSet<SocketAddr>, now: Instant) -> Option<SocketAddr> {
+        self.worth_dialling(exclude, now)
+            .min_by_key(|(address, entry)| (entry.last_seen, **address))
+            .map(|(address, _)| *address)
+    }
+
+    fn worth_dialling<'a>(
+        &'a self,
+        exclude: &'a HashSet<SocketAddr>,
+        now: Instant,
+    ) -> impl Iterator<Item = (&'a SocketAddr, &'a AddressEntry)> {
+        self.entries
+            .iter()
+            .filter(|(address, _)| !exclude.contains(*address))
+            .filter(move |(address, _)| {
+                self.attempted
+                    .get(*address)
+                    .is_none_or(|at| now.saturating_duration_since(*at) >= RETRY_AFTER)
+            })
+    }
+
+    /// Up to `count` addresses to tell a peer of, most recently seen first.
+    pub fn sample(&self, count: usize) -> Vec<TimedAddress> {
+        let mut known: Vec<TimedAddress> = self
+            .entries
+            .iter()
+            .map(|(address, entry)| TimedAddress {
+                last_seen: entry.last_seen,
+                address: *address,
+            })
+            .collect();
+
+        known.sort_by_key(|timed| (std::cmp::Reverse(timed.last_seen), timed.address));
+        known.truncate(count);
+        known
+    }
+
+    pub fn get(&self, address: &SocketAddr) -> Option<&AddressEntry> {
+        self.entries.get(address)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Whether it changed since this was last asked, and so needs writing out.
+    pub fn take_changes(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
+    }
+
+    /// One line per address: the address, when it was last seen, and how many
+    /// dials to it have failed since one succeeded.
+    pub fn to_text(&self) -> String {
+        let mut lines: Vec<String> = self
+            .entries
+            .iter()
+            .map(|(address, entry)| format!("{address} {} {}", entry.last_seen, entry.failures))
+            .collect();
+
+        lines.sort();
+        lines.iter().map(|line| format!("{line}\n")).collect()
+    }
+
+    /// Held to `MAX_ADDRESSES` as `learn` holds it, for a file written by
+    /// hand or under a larger cap: past it, whichever entry is least worth
+    /// dialling goes, and the book is marked changed so the file follows.
+    pub fn from_text(text: &str) -> Result<AddressBook> {
+        let mut book = AddressBook::default();
+
+        for (number, line) in text.lines().enumerate() {
+            let entry = parse_line(line).with_context(|| format!("line {}", number + 1))?;
+            book.entries.extend(entry);
+            if book.entries.len() > MAX_ADDRESSES {
+                book.evict();
+                book.changed = true;
+            }
+        }
+
+        Ok(book)
+    }
+
+    /// A missing file is an empty book: a node's first run has not written
+    /// one yet.
+    pub fn load(path: &Path) -> Result<AddressBook> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(AddressBook::default()),
+            Err(e) => return Err(e).with_context(|| format!("could not read {}", path.display())),
+        };
+
+        AddressBook::from_text(&text)
+            .with_context(|| format!("{} could not be understood", path.display()))
+    }
+
+    /// The entry least worth dialling: most failures, then least recently
+    /// seen.
+    fn evict(&mut self) {
+        let worst = self
+            .entries
+            .iter()
+            .max_by_key(|(address, entry)| {
+                (
+                    entry.failures,
+                    std::cmp::Reverse(entry.last_seen),
+                    **address,
+                )
+            })
+            .map(|(address, _)| *address);
+
+        if let Some(worst) = worst {
+            self.entries.remove(&worst);
+            self.attempted.remove(&worst);
+        }
+    }
+}
+
+/// Somewhere a node could be listening. A peer advertising an unspecified
+/// address or port zero has not said where.
+fn dialable(address: SocketAddr) -> bool {
+    !address.ip().is_unspecified() && address.port() != 0
+}
+
+fn parse_line(line: &str) -> Result<Option<(SocketAddr, AddressEntry)>> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [address, last_seen, failures] = fields[..] else {
+        return Err(anyhow!("expected an address, a time and a failure count"));
+    };
+
+    Ok(Some((
+        address.parse().context("not an address")?,
+        AddressEntry {
+            last_seen: last_seen.parse().context("not a time")?,
+            failures: failures.parse().context("not a failure count")?,
+        },
+    )))
+}
+
+/// Written beside the file and renamed over it, so a node killed mid-write
+/// leaves the previous book rather than half of this one.
+pub fn save(path: &Path, text: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("could not create {}", dir.display()))?;
+    }
+
+    let partial = path.with_extension("tmp");
+    fs::write(&partial, text).with_context(|| format!("could not write {}", partial.display()))?;
+    fs::rename(&partial, path).with_context(|| format!("could not replace {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{port}").parse().unwrap()
+    }
+
+    #[test]
+    fn a_learned_address_is_a_candidate_until_it_is_dialled() {
+        let mut book = AddressBook::default();
+        let now = Instant::now();
+        book.learn(address(1), 100, 200);
+
+        assert_eq!(vec![address(1)], book.candidates(&HashSet::new(), 8, now));
+
+        book.attempted(address(1), now);
+
+        assert!(book.candidates(&HashSet::new(), 8, now).is_empty());
+        assert_eq!(
+            vec![address(1)],
+            book.candidates(&HashSet::new(), 8, now + RETRY_AFTER)
+        );
+    }
+
+    #[test]
+    fn candidates_skip_what_is_excluded_and_come_best_first() {
+        let mut book = AddressBook::default();
+        book.learn(address(1), 100, 500);
+        book.learn(address(2), 300, 500);
+        book.learn(address(3), 200, 500);
+        book.learn(address(4), 400, 500);
+        book.failed(address(2));
+
+        let candidates = book.candidates(&HashSet::from([address(4)]), 8, Instant::now());
+
+        assert_eq!(vec![address(3), address(1), address(2)], candidates);
+    }
+
+    #[test]
+    fn a_feeler_tests_the_address_heard_from_longest_ago() {
+        let mut book = AddressBook::default();
+        book.learn(address(1), 300, 500);
+        book.learn(address(2), 100, 500);
+        book.learn(address(3), 200, 500);
+        let now = Instant::now();
+        book.attempted(address(2), now);
+
+        assert_eq!(Some(address(3)), book.stalest(&HashSet::new(), now));
+        assert_eq!(
+            Some(address(1)),
+            book.stalest(&HashSet::from([address(3)]), now)
+        );
+        assert_eq!(
+            Some(address(2)),
+            book.stalest(&HashSet::new(), now + RETRY_AFTER)
+        );
+    }
+
+    #[test]
+    fn a_time_from_the_future_is_taken_as_now() {
+        let mut book = AddressBook::default();
+
+        book.learn(address(1), u32::MAX, 500);
+
+        assert_eq!(500, book.get(&address(1)).unwrap().last_seen);
+    }
+
+    #[test]
+    fn an_address_nobody_could_dial_is_not_learned() {
+        let mut book = AddressBook::default();
+
+        assert!(!book.learn("0.0.0.0:34352".parse().unwrap(), 1, 1));
+        assert!(!book.learn(address(0), 1, 1));
+        assert_eq!(0, book.len());
+    }
+
+    #[test]
+    fn an_address_that_reaches_ourselves_is_forgotten_and_never_learned_again() {
+        let mut book = AddressBook::default();
+        book.learn(address(1), 1, 1);
+
+        book.mark_ourselves(address(1));
+
+        assert!(book.get(&address(1)).is_none());
+        assert!(!book.learn(address(1), 2, 2));
+        assert!(book
+            .candidates(&HashSet::new(), 8, Instant::now())
+            .is_empty());
+    }
+
+    #[test]
+    fn an_address_that_keeps_failing_is_forgotten_and_one_success_forgives_it() {
+        let mut book = AddressBook::default();
+        book.learn(address(1), 1, 1);
+        book.learn(address(2), 1, 1);
+
+        for _ in 0..MAX_FAILURES - 1 {
+            book.failed(address(1));
+            book.failed(address(2));
+        }
+        book.connected(address(2), 9);
+        book.failed(address(1));
+        book.failed(address(2));
+
+        assert!(book.get(&address(1)).is_none());
+        assert_eq!(
+            Some(&AddressEntry {
+                last_seen: 9,
+                failures: 1
+            }),
+            book.get(&address(2))
+        );
+    }
+
+    #[test]
+    fn a_full_book_makes_room_by_forgetting_the_least_promising() {
+        let mut book = AddressBook::default();
+        for port in 1..=MAX_ADDRESSES as u16 {
+            book.learn(address(port), 100 + port as u32, 10_000);
+        }
+        book.failed(address(50));
+
+        assert!(book.learn(address(60_000), 1, 10_000));
+
+        assert_eq!(MAX_ADDRESSES, book.len());
+        assert!(book.get(&address(50)).is_none());
+        assert!(book.get(&address(60_000)).is_some());
+    }
+
+    #[test]
+    fn a_sample_is_the_most_recently_seen() {
+        let mut book = AddressBook::default();
+        book.learn(address(1), 100, 500);
+        book.learn(address(2), 300, 500);
+        book.learn(address(3), 200, 500);
+
+        let sample: Vec<SocketAddr> = book.sample(2).iter().map(|timed| timed.address).collect();
+
+        assert_eq!(vec![address(2), address(3)], sample);
+    }
+
+    #[test]
+    fn the_book_survives_a_round_trip_through_its_file() {
+        let mut book = AddressBook::default();
+        book.learn(address(1), 100, 500);
+        book.learn("[::1]:8333".parse().unwrap(), 300, 500);
+        book.failed(address(1));
+        let path = std::env::temp_dir()
+            .join(format!("avicoin-address-book-{}", std::process::id()))
+            .join(ADDRESS_BOOK_FILE);
+
+        save(&path, &book.to_text()).unwrap();
+        let loaded = AddressBook::load(&path).unwrap();
+
+        assert_eq!(book.to_text(), loaded.to_text());
+        assert_eq!(1, loaded.get(&address(1)).unwrap().failures);
+        fs::remove_dir_all(path.parent().unwrap()).ok();
+    }
+
+    #[test]
+    fn a_missing_file_is_an_empty_book() {
+        let absent = std::env::temp_dir().join("avicoin-no-such-address-book.txt");
+
+        assert_eq!(0, AddressBook::load(&absent).unwrap().len());
+    }
+
+    #[test]
+    fn a_file_past_the_cap_loads_only_the_addresses_most_worth_dialling() {
+        let text: String = (1..=MAX_ADDRESSES as u16 + 1)
+            .map(|port| {
+                let failures = if port == 7 { 3 } else { 0 };
+                format!("{} 100 {failures}\n", address(port))
+            })
+            .collect();
+
+        let mut book = AddressBook::from_text(&text).unwrap();
+
+        assert_eq!(MAX_ADDRESSES, book.len());
+        assert_eq!(None, book.get(&address(7)), "the one that kept failing");
+        assert!(book.take_changes(), "the file is to be rewritten to match");
+    }
+
+    #[test]
+    fn a_line_that_does_not_parse_is_reported_with_its_number() {
+        let error = AddressBook::from_text("127.0.0.1:1 100 0\n127.0.0.1:2 yesterday 0\n")
+            .expect_err("not a time");
+
+        assert!(format!("{error:#}").contains("line 2"), "got: {error:#}");
+    }
+
+    #[test]
+    fn only_a_change_needs_writing_out() {
+        let mut book = AddressBook::default();
+        book.learn(address(1), 100, 500);
+
+        assert!(book.take_changes());
+        assert!(!book.take_changes());
+
+        book.learn(address(1), 50, 500);
+        assert!(!book.take_changes(), "an older sighting changes nothing");
+    }
+}
@@ -1,8 +1,8 @@
 use crate::byte_reader::ByteReader;
+use crate::difficulty::Target;
 use crate::transaction::Transaction;
 use crate::util::{get_compact_int, get_hash};
 use anyhow::{anyhow, Context, Result};
-use primitive_types::U256;
 
 fn merkle_root(leaves: &[[u8; 32]]) -> Option<[u8; 32]> {
     if leaves.is_empty() {
@@ -28,6 +28,50 @@ fn merkle_root(leaves: &[[u8; 32]]) -> Option<[u8; 32]> {
     Some(level[0])
 }
 
+pub const BLOCK_HEADER_LENGTH: usize = 80;
+
+/// The 80 bytes a block's hash covers, without the transactions: what
+/// headers-first sync downloads, validates and chooses among before any body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub version: i32,
+    pub previous_block_hash: [u8; 32],
+    pub merkle_root_hash: [u8; 32],
+    pub time: u32,
+    pub n_bits: u32,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    pub fn hash(&self) -> [u8; 32] {
+        get_hash(&self.get_raw_format())
+    }
+
+    pub fn get_raw_format(&self) -> [u8; BLOCK_HEADER_LENGTH] {
+        let mut raw_format = [0; BLOCK_HEADER_LENGTH];
+
+        raw_format[0..4].copy_from_slice(&self.version.to_le_bytes());
+        raw_format[4..36].copy_from_slice(&self.previous_block_hash);
+        raw_format[36..68].copy_from_slice(&self.merkle_root_hash);
+        raw_format[68..72].copy_from_slice(&self.time.to_le_bytes());
+        raw_format[72..76].copy_from_slice(&self.n_bits.to_le_bytes());
+        raw_format[76..80].copy_from_slice(&self.nonce.to_le_bytes());
+
+        raw_format
+    }
+
+    pub fn parse_raw(reader: &mut ByteReader) -> Result<BlockHeader> {
+        Ok(BlockHeader {
+            version: reader.read_i32()?,
+            previous_block_hash: reader.read_array::<32>()?,
+            merkle_root_hash: reader.read_array::<32>()?,
+            time: reader.read_u32()?,
+            n_bits: reader.read_u32()?,
+            nonce: reader.read_u32()?,
+        })
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Block {
     pub version: i32,
@@ -67,13 +111,12 @@ impl Block {
 
         self.prepare_for_mining()?;
 
-        let n_bits = self.get_target_256();
+        let target = Target::from_compact(self.n_bits)?;
 
         for nonce in 0..u32::MAX {
             self.mine_array[76..80].copy_from_slice(&nonce.to_le_bytes());
             let hash = get_hash(self.mine_array.as_slice());
-            let hash256 = U256::from_little_endian(&hash);
-            if hash256 < n_bits {
+            if target.is_met_by(&hash) {
                 self.nonce = nonce;
                 self.hash = Some(hash);
                 return Ok(true);
@@ -83,6 +126,32 @@ impl Block {
         Ok(false)
     }
 
+    /// Fills in the merkle root and hash for the nonce already set, without
+    /// searching. This is how a block whose nonce was committed elsewhere —
+    /// genesis — is rebuilt, and why it can be rebuilt on every startup.
+    pub fn seal(&mut self) -> Result<[u8; 32]> {
+        self.merkle_root_hash = Some(self.get_merkle_root_hash()?);
+        self.prepare_for_mining()?;
+
+        let hash = get_hash(self.mine_array.as_slice());
+        self.hash = Some(hash);
+        Ok(hash)
+    }
+
+    /// The header this block's transactions commit to. The merkle root is
+    /// recomputed, not copied from the wire, so a body that does not match the
+    /// root it arrived with yields a header with a different hash.
+    pub fn header(&self) -> Result<BlockHeader> {
+        Ok(BlockHeader {
+            version: self.version,
+            previous_block_hash: self.previous_block_hash,
+            merkle_root_hash: self.get_merkle_root_hash()?,
+            time: self.time,
+            n_bits: self.n_bits,
+            nonce: self.nonce,
+        })
+    }
+
     fn prepare_for_mining(&mut self) -> Result<()> {
         self.mine_array[0..4].copy_from_slice(&self.version.to_le_bytes());
 
@@ -103,21 +172,24 @@ impl Block {
         Ok(())
     }
 
-    fn get_target_256(&self) -> U256 {
-        let target: u32 = self.n_bits;
-        let exponent = target >> 24;
-        let mantissa = target & 0x007FFFFF;
-
-        let target = U256::from(mantissa);
-        target << (exponent * 8)
-    }
-
     fn get_merkle_root_hash(&self) -> Result<[u8; 32]> {
         let leaves: Vec<[u8; 32]> = self.transactions.iter().map(|tx| tx.get_tx_id()).collect();
 
         merkle_root(&leaves).context("a block needs a transaction to have a merkle root")
     }
 
+    /// What the block weighs serialized; unlike `get_raw_format`, known before
+    /// it is mined.
+    pub fn size(&self) -> usize {
+        BLOCK_HEADER_LENGTH
+            + get_compact_int(self.transactions.len() as u64).len()
+            + self
+                .transactions
+                .iter()
+                .map(|tx| tx.get_raw_format().len())
+                .sum::<usize>()
+    }
+
     pub fn get_raw_format(&self) -> Result<Vec<u8>> {
         if self.hash.is_none() {
             return Err(anyhow!(
@@ -147,11 +219,15 @@ impl Block {
         let nonce = reader.read_u32()?;
         let tx_count = reader.read_compact()?;
 
-        let mut transactions = Vec::with_capacity(tx_count as usize);
+        let mut transactions = Vec::with_capacity(reader.capacity_for(tx_count));
         for _ in 0..tx_count {
             transactions.push(Transaction::parse_raw(&mut reader)?);
         }
 
+        if reader.remaining() != 0 {
+            return Err(anyhow!("block has {} bytes to spare", reader.remaining()));
+        }
+
         let block = Self {
             version,
             previous_block_hash,
@@ -173,9 +249,12 @@ mod tests {
     use super::*;
     use crate::transaction::{Outpoint, TxIn, TxOut};
     use hex::{decode, encode};
-    use primitive_types::U256;
     use rstest::rstest;
 
+    /// Met by every other hash, so a test that mines does not grind for
+    /// minutes. Bitcoin's 0x1d00ffff would take billions of attempts.
+    const TRIVIAL_N_BITS: u32 = 0x207fffff;
+
     // Hashes are little-endian internally, so a txid copied from an explorer
     // has to be reversed to be used here.
     fn leaf(displayed: &str) -> [u8; 32] {
@@ -304,6 +383,7 @@ mod tests {
     #[case(4usize)]
     fn mines_generates_correct_hash(#[case] number_of_transactions: usize) {
         let mut block = get_block(number_of_transactions);
+        block.n_bits = TRIVIAL_N_BITS;
 
         // Asserts only that a nonce was found; any root satisfies that.
         assert!(block.mine().unwrap());
@@ -317,14 +397,13 @@ mod tests {
 
         let hash = get_hash(block.mine_array.as_slice());
 
-        let hash = U256::from_little_endian(&hash);
-        let target = block.get_target_256();
+        let target = Target::from_compact(block.n_bits).unwrap();
 
-        assert!(hash < target, "Hash should be lesser than target");
+        assert!(target.is_met_by(&hash), "Hash should meet the target");
 
         assert_eq!(
             "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
-            encode(hash.to_big_endian()),
+            displayed(hash),
             "Block hash is wrong"
         )
     }
@@ -391,9 +470,71 @@ mod tests {
             "n_bits part does not match"
         );
     }
+    #[test]
+    fn a_mined_blocks_header_hashes_to_the_blocks_hash() {
+        let mut block = get_block(3);
+        block.n_bits = TRIVIAL_N_BITS;
+        assert!(block.mine().unwrap());
+
+        let header = block.header().unwrap();
+
+        assert_eq!(block.hash.unwrap(), header.hash());
+        assert_eq!(block.mine_array, header.get_raw_format());
+    }
+
+    #[test]
+    fn a_header_survives_a_round_trip() {
+        let mut block = get_block(2);
+        block.n_bits = TRIVIAL_N_BITS;
+        assert!(block.mine().unwrap());
+        let header = block.header().unwrap();
+
+        let raw = header.get_raw_format();
+        let parsed = BlockHeader::parse_raw(&mut ByteReader::new(&raw)).unwrap();
+
+        assert_eq!(header, parsed);
+    }
+
+    #[test]
+    fn a_header_commits_to_the_transactions_not_to_the_root_it_arrived_with() {
+        let mut block = get_block(2);
+        let honest = block.header().unwrap();
+        block.merkle_root_hash = Some([9; 32]);
+
+        assert_eq!(
+            honest,
+            block.header().unwrap(),
+            "a claimed root is recomputed, never trusted"
+        );
+    }
+
+    #[test]
+    fn a_block_claiming_more_transactions_than_it_carries_is_refused() {
+        let mut block = get_block(1);
+        block.n_bits = TRIVIAL_N_BITS;
+        assert!(block.mine().unwrap());
+        let mut raw = block.get_raw_format().unwrap();
+        raw.truncate(BLOCK_HEADER_LENGTH);
+        raw.extend(get_compact_int(u64::MAX));
+
+        Block::parse_raw(raw).expect_err("a count is a claim, not an allocation");
+    }
+
+    #[test]
+    fn a_block_with_bytes_to_spare_is_refused() {
+        let mut block = get_block(1);
+        block.n_bits = TRIVIAL_N_BITS;
+        assert!(block.mine().unwrap());
+        let mut raw = block.get_raw_format().unwrap();
+        raw.push(0);
+
+        Block::parse_raw(raw).expect_err("trailing bytes");
+    }
+
     #[test]
     fn test_serialization_and_deserialization() {
         let mut original_block = get_block(3);
+        original_block.n_bits = TRIVIAL_N_BITS;
 
         assert!(original_block.mine().unwrap());
 
@@ -0,0 +1,1116 @@
+use crate::block::{Block, BlockHeader};
+use crate::difficulty::{next_target, Target, RETARGET_WINDOW};
+use crate::mempool::check_transaction;
+use crate::params::{subsidy, Params};
+use crate::util::{display_hash, unix_time};
+use crate::utxo::UtxoSet;
+use anyhow::{anyhow, Context, Result};
+use primitive_types::U256;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// How many blocks back median-time-past looks (ADR-0009).
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// How far ahead of our own clock a header may be timestamped (ADR-0009).
+pub const MAX_FUTURE_DRIFT: u32 = 5 * 60;
+
+/// The most headers one `headers` message carries, and so one answer serves.
+pub const MAX_HEADERS: usize = 2000;
+
+/// The most a serialized block may weigh, and so the most a `block` or a `tx`
+/// message may carry.
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
+/// Locator entries that step back one block before the steps start doubling.
+const LOCATOR_SINGLE_STEPS: usize = 10;
+
+/// A header refused for what may be our fault rather than its sender's: a
+/// parent we have not heard of yet, or a time past our clock, which may be
+/// the one that is wrong (ADR-0009). Told apart from the rest so its sender
+/// is not charged for it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PrematureHeader {
+    UnknownParent { hash: [u8; 32], parent: [u8; 32] },
+    FromTheFuture { hash: [u8; 32], time: u32, now: u32 },
+}
+
+impl fmt::Display for PrematureHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrematureHeader::UnknownParent { hash, parent } => write!(
+                f,
+                "header {} builds on {}, which we do not know",
+                display_hash(hash),
+                display_hash(parent)
+            ),
+            PrematureHeader::FromTheFuture { hash, time, now } => write!(
+                f,
+                "header {} is timestamped {time}, more than {MAX_FUTURE_DRIFT}s past our \
+                 clock's {now}; if our clock is wrong, every new block will be refused until \
+                 it is fixed",
+                display_hash(hash)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PrematureHeader {}
+
+#[derive(Debug)]
+struct HeaderEntry {
+    header: BlockHeader,
+    height: u32,
+    /// The work of this header and every ancestor: what picks the best chain.
+    chain_work: U256,
+}
+
+/// Every valid header heard of, and the active chain of bodies, genesis first.
+///
+/// Headers are validated and chosen among before any body is downloaded, so a
+/// peer feeding us a long but easy chain costs 80 bytes a header, not a block.
+/// Bodies are in memory only until ADR-0013's storage lands, and on a single
+/// branch: side chains of bodies and reorgs (ADR-0012) are not built.
+#[derive(Debug)]
+pub struct Blockchain {
+    blocks: Vec<Block>,
+    headers: HashMap<[u8; 32], HeaderEntry>,
+    /// The most-work header chain, by height: what sync extends and serves.
+    best_headers: Vec<[u8; 32]>,
+    /// What the connected bodies left unspent.
+    utxos: UtxoSet,
+    /// Blocks whose body would not connect: never indexed again, nor
+    /// anything built directly on them.
+    invalid: HashSet<[u8; 32]>,
+}
+
+impl Blockchain {
+    /// `genesis` must be sealed, which every `Params` genesis is.
+    pub fn new(genesis: Block) -> Blockchain {
+        let hash = genesis.hash.expect("a chain starts from a sealed genesis");
+        let header = genesis.header().expect("a sealed genesis has a header");
+        let chain_work = Target::from_compact(header.n_bits)
+            .expect("genesis meets its own target")
+            .work();
+        // By the same path as any other coinbase (ADR-0007).
+        let mut utxos = UtxoSet::default();
+        utxos.apply(&genesis.transactions, 0);
+
+        Blockchain {
+            blocks: vec![genesis],
+            headers: HashMap::from([(
+                hash,
+                HeaderEntry {
+                    header,
+                    height: 0,
+                    chain_work,
+                },
+            )]),
+            best_headers: vec![hash],
+            utxos,
+            invalid: HashSet::new(),
+        }
+    }
+
+    pub fn height(&self) -> u32 {
+        (self.blocks.len() - 1) as u32
+    }
+
+    pub fn utxos(&self) -> &UtxoSet {
+        &self.utxos
+    }
+
+    pub fn tip(&self) -> &Block {
+        self.blocks
+            .last()
+            .expect("a chain always holds its genesis")
+    }
+
+    pub fn tip_hash(&self) -> [u8; 32] {
+        self.tip().hash.expect("only sealed blocks are connected")
+    }
+
+    /// The height of the most-work header chain, which may be ahead of the
+    /// bodies connected so far.
+    pub fn best_header_height(&self) -> u32 {
+        (self.best_headers.len() - 1) as u32
+    }
+
+    pub fn best_header_hash(&self) -> [u8; 32] {
+        *self
+            .best_headers
+            .last()
+            .expect("the best chain always holds genesis")
+    }
+
+    /// The height of any header we have indexed, on the best chain or not.
+    pub fn height_of(&self, hash: &[u8; 32]) -> Option<u32> {
+        self.headers.get(hash).map(|entry| entry.height)
+    }
+
+    /// A connected block by hash: what a peer's `getdata` can be served from.
+    pub fn block(&self, hash: &[u8; 32]) -> Option<&Block> {
+        let block = self.blocks.get(self.height_of(hash)? as usize)?;
+        (block.hash.as_ref() == Some(hash)).then_some(block)
+    }
+
+    /// The next `limit` blocks of the best header chain past the connected
+    /// tip, with their heights: the bodies still to be downloaded. Empty when
+    /// the best headers fork below the tip, since reaching them means
+    /// disconnecting blocks, which is ADR-0012's work.
+    pub fn missing_bodies(&self, limit: usize) -> Vec<([u8; 32], u32)> {
+        let next = self.blocks.len();
+        if self.best_headers.get(next - 1) != Some(&self.tip_hash()) {
+            return Vec::new();
+        }
+
+        self.best_headers
+            .iter()
+            .enumerate()
+            .skip(next)
+            .take(limit)
+            .map(|(height, hash)| (*hash, height as u32))
+            .collect()
+    }
+
+    /// The median timestamp of the last `MEDIAN_TIME_SPAN` blocks, or of all
+    /// of them while the chain is shorter. A new block's time must exceed it.
+    pub fn median_time_past(&self) -> u32 {
+        median_time(&self.ancestors(&self.tip_hash(), MEDIAN_TIME_SPAN))
+    }
+
+    /// The n_bits the block after the tip must carry.
+    pub fn next_n_bits(&self, params: &Params) -> Result<u32> {
+        required_n_bits(
+            &self.ancestors(&self.tip_hash(), RETARGET_WINDOW + 1),
+            params,
+        )
+    }
+
+    /// Up to `count` headers ending at `hash`, oldest first.
+    fn ancestors(&self, hash: &[u8; 32], count: usize) -> Vec<BlockHeader> {
+        let mut ancestors = Vec::with_capacity(count);
+        let mut cursor = self.headers.get(hash);
+
+        while let Some(entry) = cursor.filter(|_| ancestors.len() < count) {
+            ancestors.push(entry.header);
+            cursor = match entry.height {
+                0 => None,
+                _ => self.headers.get(&entry.header.previous_block_hash),
+            };
+        }
+
+        ancestors.reverse();
+        ancestors
+    }
+
+    /// Validates `header` against its ancestors and indexes it, returning
+    /// whether it was new. Its parent must already be known: headers arrive in
+    /// order, so an orphan is a peer on another chain or a peer lying.
+    ///
+    /// Nothing about the sender is trusted, only the work: if the header ends
+    /// a chain with more of it than the best so far, that chain becomes best.
+    pub fn accept_header(
+        &mut self,
+        header: BlockHeader,
+        params: &Params,
+        now: u32,
+    ) -> Result<bool> {
+        let hash = header.hash();
+        if self.headers.contains_key(&hash) {
+            return Ok(false);
+        }
+        if self.invalid.contains(&hash) || self.invalid.contains(&header.previous_block_hash) {
+            return Err(anyhow!(
+                "header {} is of, or builds on, a block found invalid",
+                display_hash(&hash)
+            ));
+        }
+
+        let Some(parent) = self.headers.get(&header.previous_block_hash) else {
+            return Err(PrematureHeader::UnknownParent {
+                hash,
+                parent: header.previous_block_hash,
+            }
+            .into());
+        };
+        let (height, parent_work) = (parent.height + 1, parent.chain_work);
+        let ancestors = self.ancestors(&header.previous_block_hash, RETARGET_WINDOW + 1);
+
+        let expected = required_n_bits(&ancestors, params)?;
+        if header.n_bits != expected {
+            return Err(anyhow!(
+                "header {} claims n_bits {:#010x}, expected {expected:#010x}",
+                display_hash(&hash),
+                header.n_bits
+            ));
+        }
+
+        let target = Target::from_compact(header.n_bits)?;
+        if !target.is_met_by(&hash) {
+            return Err(anyhow!(
+                "header {} does not meet its target",
+                display_hash(&hash)
+            ));
+        }
+
+        let median = median_time(&ancestors[ancestors.len().saturating_sub(MEDIAN_TIME_SPAN)..]);
+        if header.time <= median {
+            return Err(anyhow!(
+                "header {} is timestamped {}, not after the median time past {median}",
+                display_hash(&hash),
+                header.time
+            ));
+        }
+
+        // Loud, because it is as likely our clock as their header: a node
+        // whose clock runs slow refuses every block its peers mine.
+        if header.time > now.saturating_add(MAX_FUTURE_DRIFT) {
+            return Err(PrematureHeader::FromTheFuture {
+                hash,
+                time: header.time,
+                now,
+            }
+            .into());
+        }
+
+        let chain_work = parent_work.saturating_add(target.work());
+        self.headers.insert(
+            hash,
+            HeaderEntry {
+                header,
+                height,
+                chain_work,
+            },
+        );
+
+        // Strictly more: on a tie the chain heard of first stays best, so two
+        // peers racing equal chains cannot flip us back and forth.
+        if chain_work > self.headers[&self.best_header_hash()].chain_work {
+            self.adopt_best(hash);
+        }
+
+        Ok(true)
+    }
+
+    /// Forgets the header of a body that would not connect, and every header
+    /// built on it, so it is neither asked for again nor built on. The best
+    /// header chain falls back to the most work left. The hashes forgotten;
+    /// none for a block already connected or never heard of.
+    pub fn invalidate(&mut self, hash: &[u8; 32]) -> Vec<[u8; 32]> {
+        let Some(height) = self.headers.get(hash).map(|entry| entry.height) else {
+            return Vec::new();
+        };
+        if height <= self.height() {
+            return Vec::new();
+        }
+
+        let mut above: Vec<_> = self
+            .headers
+            .iter()
+            .filter(|(_, entry)| entry.height > height)
+            .map(|(hash, entry)| (entry.height, *hash, entry.header.previous_block_hash))
+            .collect();
+        above.sort();
+        let mut forgotten = vec![*hash];
+        for (_, hash, parent) in above {
+            if forgotten.contains(&parent) {
+                forgotten.push(hash);
+            }
+        }
+        for hash in &forgotten {
+            self.headers.remove(hash);
+        }
+        self.invalid.insert(*hash);
+
+        // On a tie, what is left of the best chain stays best.
+        self.best_headers.truncate(height as usize);
+        let best = self
+            .headers
+            .iter()
+            .max_by_key(|(hash, entry)| {
+                let on_best = self.best_headers.get(entry.height as usize) == Some(hash);
+                (entry.chain_work, on_best)
+            })
+            .map(|(hash, _)| *hash)
+            .expect("genesis is never forgotten");
+        self.adopt_best(best);
+
+        forgotten
+    }
+
+    /// Repoints `best_headers` at the chain ending in `hash`, from where it
+    /// forks off the current one.
+    fn adopt_best(&mut self, hash: [u8; 32]) {
+        let mut path = Vec::new();
+        let mut cursor = hash;
+
+        loop {
+            let entry = &self.headers[&cursor];
+            if self.best_headers.get(entry.height as usize) == Some(&cursor) {
+                self.best_headers.truncate(entry.height as usize + 1);
+                break;
+            }
+            path.push(cursor);
+            cursor = entry.header.previous_block_hash;
+        }
+
+        self.best_headers.extend(path.into_iter().rev());
+    }
+
+    /// Hashes from the best header back to genesis: the newest ten one by one,
+    /// then doubling the step. A peer finds the newest one it shares with us
+    /// in few entries, however far apart our chains have grown.
+    pub fn locator(&self) -> Vec<[u8; 32]> {
+        let mut locator = Vec::new();
+        let mut height = self.best_headers.len() - 1;
+        let mut step = 1;
+
+        loop {
+            locator.push(self.best_headers[height]);
+            if height == 0 {
+                return locator;
+            }
+            if locator.len() >= LOCATOR_SINGLE_STEPS {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+    }
+
+    /// The best chain's headers after the first `locator` entry on it, up to
+    /// `stop` or `MAX_HEADERS`. A locator sharing nothing with us starts after
+    /// genesis, which every peer on the network shares.
+    pub fn headers_after(&self, locator: &[[u8; 32]], stop: &[u8; 32]) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| {
+                let entry = self.headers.get(hash)?;
+                let on_best = self.best_headers.get(entry.height as usize) == Some(hash);
+                on_best.then_some(entry.height as usize + 1)
+            })
+            .unwrap_or(1);
+
+        let mut headers = Vec::new();
+        for hash in self.best_headers.iter().skip(start).take(MAX_HEADERS) {
+            headers.push(self.headers[hash].header);
+            if hash == stop {
+                break;
+            }
+        }
+
+        headers
+    }
+
+    /// Validates `block` against the tip and appends it. The hash is always
+    /// recomputed, so a hash the block arrived claiming is never trusted.
+    pub fn connect(&mut self, mut block: Block, params: &Params) -> Result<[u8; 32]> {
+        let hash = block.seal()?;
+        let height = self.height() + 1;
+
+        if block.previous_block_hash != self.tip_hash() {
+            return Err(anyhow!(
+                "block {} does not build on the tip {}",
+                display_hash(&hash),
+                display_hash(&self.tip_hash())
+            ));
+        }
+
+        check_body(&block, &hash)?;
+        let fees = self
+            .utxos
+            .check_spends(&block.transactions, height, params.coinbase_maturity)
+            .with_context(|| format!("block {} spends badly", display_hash(&hash)))?;
+        let claimed = coinbase_claim(&block, &hash)?;
+        if claimed > subsidy(height).saturating_add(fees) {
+            return Err(anyhow!(
+                "block {} coinbase claims {claimed}, more than the subsidy {} and {fees} in fees",
+                display_hash(&hash),
+                subsidy(height)
+            ));
+        }
+
+        // Last, so a body that fails its own checks leaves no header behind.
+        // Already known is fine: headers-first sync indexes it before the body.
+        self.accept_header(block.header()?, params, unix_time())?;
+
+        self.utxos.apply(&block.transactions, height);
+        self.blocks.push(block);
+        Ok(hash)
+    }
+}
+
+/// What a body must satisfy beyond its header. Checkable the moment it
+/// arrives, ahead of the blocks below it.
+pub fn check_body(block: &Block, hash: &[u8; 32]) -> Result<()> {
+    if block.size() > MAX_BLOCK_SIZE {
+        return Err(anyhow!(
+            "block {} weighs {} bytes, more than {MAX_BLOCK_SIZE}",
+            display_hash(hash),
+            block.size()
+        ));
+    }
+
+    let Some((coinbase, rest)) = block.transactions.split_first() else {
+        return Err(anyhow!("block {} has no transactions", display_hash(hash)));
+    };
+    if !coinbase.is_coinbase() || rest.iter().any(|tx| tx.is_coinbase()) {
+        return Err(anyhow!(
+            "block {} must have exactly one coinbase, and first",
+            display_hash(hash)
+        ));
+    }
+    for transaction in rest {
+        check_transaction(transaction).with_context(|| {
+            format!(
+                "block {} holds invalid transaction {}",
+                display_hash(hash),
+                display_hash(&transaction.get_tx_id())
+            )
+        })?;
+    }
+
+    // Only whether it adds up: what it may claim past the subsidy is fees,
+    // known once the outputs the block spends are, and `connect` holds it to
+    // both.
+    coinbase_claim(block, hash)?;
+
+    Ok(())
+}
+
+/// What `block`'s coinbase pays out in all.
+fn coinbase_claim(block: &Block, hash: &[u8; 32]) -> Result<u64> {
+    block.transactions[0]
+        .outputs
+        .iter()
+        .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+        .ok_or_else(|| anyhow!("block {} coinbase overflows", display_hash(hash)))
+}
+
+/// The median of `headers`' timestamps; `headers` is never empty, since every
+/// chain holds its genesis.
+fn median_time(headers: &[BlockHeader]) -> u32 {
+    let mut times: Vec<u32> = headers.iter().map(|header| header.time).collect();
+    times.sort_unstable();
+
+    times[times.len() / 2]
+}
+
+/// The n_bits the child of `ancestors` (oldest first, ending at the parent)
+/// must carry.
+fn required_n_bits(ancestors: &[BlockHeader], params: &Params) -> Result<u32> {
+    if !params.retargets {
+        return Ok(params.starting_n_bits);
+    }
+
+    let limit = Target::from_compact(params.starting_n_bits)?;
+    Ok(next_target(ancestors, limit)?.to_compact())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::Network;
+    use crate::transaction::{Outpoint, Transaction, TxIn, TxOut};
+    use rstest::rstest;
+
+    fn regtest() -> (Blockchain, Params) {
+        let params = Params::of(Network::Regtest).unwrap();
+        (Blockchain::new(params.genesis.clone()), params)
+    }
+
+    fn next_block(chain: &Blockchain, params: &Params, claim: u64) -> Block {
+        next_block_holding(chain, params, claim, Vec::new())
+    }
+
+    fn next_block_holding(
+        chain: &Blockchain,
+        params: &Params,
+        claim: u64,
+        transactions: Vec<Transaction>,
+    ) -> Block {
+        let height = chain.height() + 1;
+        let coinbase = Transaction::coinbase(
+            format!("height {height}"),
+            vec![TxOut {
+                value: claim,
+                destiny_pub_key: "miner".to_string(),
+            }],
+        );
+        let mut block = Block::new(
+            1,
+            chain.tip_hash(),
+            chain.median_time_past() + 1,
+            params.starting_n_bits,
+            std::iter::once(coinbase).chain(transactions).collect(),
+        );
+        assert!(block.mine().unwrap());
+        block
+    }
+
+    /// Output `v_out` of `spent`'s coinbase, paid on to someone.
+    fn spend_of(spent: &Block, v_out: u32, value: u64) -> Transaction {
+        Transaction {
+            version: 1,
+            inputs: vec![TxIn {
+                previous_output: Outpoint {
+                    tx_id: spent.transactions[0].get_tx_id(),
+                    v_out,
+                },
+                signature: "signed".to_string(),
+                sequence: u32::MAX,
+            }],
+            outputs: vec![TxOut {
+                value,
+                destiny_pub_key: "someone".to_string(),
+            }],
+            lock_time: 0,
+        }
+    }
+
+    /// A header on `parent` carrying what `chain` requires of it, mined.
+    fn child(chain: &Blockchain, params: &Params, parent: &BlockHeader, time: u32) -> BlockHeader {
+        let ancestors = chain.ancestors(&parent.hash(), RETARGET_WINDOW + 1);
+        let mut header = BlockHeader {
+            version: 1,
+            previous_block_hash: parent.hash(),
+            merkle_root_hash: [0; 32],
+            time,
+            n_bits: required_n_bits(&ancestors, params).unwrap(),
+            nonce: 0,
+        };
+        let target = Target::from_compact(header.n_bits).unwrap();
+        while !target.is_met_by(&header.hash()) {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    /// `count` headers on `parent`, a second apart, each accepted.
+    fn extend(
+        chain: &mut Blockchain,
+        params: &Params,
+        parent: BlockHeader,
+        count: usize,
+    ) -> Vec<BlockHeader> {
+        let mut headers = vec![parent];
+        for _ in 0..count {
+            let last = *headers.last().unwrap();
+            let next = child(chain, params, &last, last.time + 1);
+            chain.accept_header(next, params, next.time).unwrap();
+            headers.push(next);
+        }
+        headers.split_off(1)
+    }
+
+    fn genesis_header(params: &Params) -> BlockHeader {
+        params.genesis.header().unwrap()
+    }
+
+    #[test]
+    fn a_chain_starts_at_its_genesis() {
+        let (chain, params) = regtest();
+
+        assert_eq!(0, chain.height());
+        assert_eq!(params.genesis.hash.unwrap(), chain.tip_hash());
+    }
+
+    #[test]
+    fn a_valid_block_extends_the_tip() {
+        let (mut chain, params) = regtest();
+        let block = next_block(&chain, &params, subsidy(1));
+
+        let hash = chain.connect(block, &params).unwrap();
+
+        assert_eq!(1, chain.height());
+        assert_eq!(hash, chain.tip_hash());
+    }
+
+    #[test]
+    fn a_block_not_building_on_the_tip_is_refused() {
+        let (mut chain, params) = regtest();
+        let mut block = next_block(&chain, &params, subsidy(1));
+        block.previous_block_hash = [7; 32];
+        assert!(block.mine().unwrap());
+
+        let error = chain.connect(block, &params).expect_err("an orphan");
+
+        assert!(format!("{error:#}").contains("tip"), "got: {error:#}");
+        assert_eq!(0, chain.height());
+    }
+
+    #[test]
+    fn a_block_that_misses_its_target_is_refused() {
+        let (mut chain, params) = regtest();
+        let mut block = next_block(&chain, &params, subsidy(1));
+
+        // Regtest's target is met by half of all hashes, so a nonce that
+        // misses it is found quickly.
+        let target = Target::from_compact(block.n_bits).unwrap();
+        while target.is_met_by(&block.seal().unwrap()) {
+            block.nonce += 1;
+        }
+
+        let error = chain.connect(block, &params).expect_err("no proof of work");
+
+        assert!(format!("{error:#}").contains("target"), "got: {error:#}");
+    }
+
+    #[test]
+    fn a_coinbase_claiming_more_than_the_subsidy_is_refused() {
+        let (mut chain, params) = regtest();
+        let block = next_block(&chain, &params, subsidy(1) + 1);
+
+        let error = chain
+            .connect(block, &params)
+            .expect_err("minted from nothing");
+
+        assert!(format!("{error:#}").contains("subsidy"), "got: {error:#}");
+    }
+
+    #[test]
+    fn a_block_heavier_than_the_limit_is_refused() {
+        let (mut chain, params) = regtest();
+        let mut block = next_block(&chain, &params, subsidy(1));
+        block.transactions[0].inputs[0].signature = "x".repeat(MAX_BLOCK_SIZE);
+        assert!(block.mine().unwrap());
+        assert_eq!(block.get_raw_format().unwrap().len(), block.size());
+
+        let error = chain.connect(block, &params).expect_err("too heavy");
+
+        assert!(format!("{error:#}").contains("weighs"), "got: {error:#}");
+        assert_eq!(0, chain.height());
+    }
+
+    #[test]
+    fn a_block_spending_genesis_allocations_moves_them_in_the_utxo_set() {
+        let (mut chain, params) = regtest();
+        let spend = spend_of(&params.genesis, 0, 5);
+        let block = next_block_holding(&chain, &params, subsidy(1), vec![spend.clone()]);
+
+        chain.connect(block, &params).unwrap();
+
+        let spent = &spend.inputs[0].previous_output;
+        assert!(chain.utxos().get(spent).is_none());
+        let paid = Outpoint {
+            tx_id: spend.get_tx_id(),
+            v_out: 0,
+        };
+        assert_eq!(
+            Some(5),
+            chain.utxos().get(&paid).map(|coin| coin.output.value)
+        );
+    }
+
+    #[rstest]
+    #[case::output_not_there(3, 5, "not there to spend")]
+    #[case::more_than_it_holds(0, 100_000_000_001, "more than")]
+    fn a_block_spending_badly_is_refused(
+        #[case] v_out: u32,
+        #[case] value: u64,
+        #[case] reason: &str,
+    ) {
+        let (mut chain, params) = regtest();
+        let spend = spend_of(&params.genesis, v_out, value);
+        let block = next_block_holding(&chain, &params, subsidy(1), vec![spend]);
+
+        let error = chain.connect(block, &params).expect_err("spends badly");
+
+        assert!(format!("{error:#}").contains(reason), "got: {error:#}");
+        assert_eq!(0, chain.height());
+    }
+
+    #[test]
+    fn genesis_allocations_wait_for_maturity_like_any_coinbase() {
+        let (mut chain, mut params) = regtest();
+        params.coinbase_maturity = 2;
+        let spend = spend_of(&params.genesis, 0, 5);
+        let early = next_block_holding(&chain, &params, subsidy(1), vec![spend.clone()]);
+
+        let error = chain.connect(early, &params).expect_err("mature at 2");
+        assert!(format!("{error:#}").contains("mature"), "got: {error:#}");
+
+        chain
+            .connect(next_block(&chain, &params, subsidy(1)), &params)
+            .unwrap();
+        let mature = next_block_holding(&chain, &params, subsidy(2), vec![spend]);
+        chain.connect(mature, &params).unwrap();
+    }
+
+    #[test]
+    fn a_block_holding_a_transaction_that_can_never_be_valid_is_refused() {
+        let (chain, params) = regtest();
+        let mut twice = spend_of(&params.genesis, 0, 5);
+        twice.inputs.push(twice.inputs[0].clone());
+        let mut block = next_block_holding(&chain, &params, subsidy(1), vec![twice]);
+        let hash = block.seal().unwrap();
+
+        let error = check_body(&block, &hash).expect_err("spends one output twice");
+
+        assert!(format!("{error:#}").contains("twice"), "got: {error:#}");
+    }
+
+    #[rstest]
+    #[case::subsidy_and_fees(0, true)]
+    #[case::one_atom_more(1, false)]
+    fn a_coinbase_may_claim_the_fees_its_block_leaves(#[case] extra: u64, #[case] legal: bool) {
+        let (mut chain, params) = regtest();
+        let funding = params.genesis.transactions[0].outputs[0].value;
+        let spend = spend_of(&params.genesis, 0, 5);
+        let claim = subsidy(1) + (funding - 5) + extra;
+        let block = next_block_holding(&chain, &params, claim, vec![spend]);
+
+        let connected = chain.connect(block, &params);
+
+        assert_eq!(legal, connected.is_ok(), "got: {connected:?}");
+    }
+
+    #[test]
+    fn claiming_less_than_the_subsidy_is_legal() {
+        let (mut chain, params) = regtest();
+        let block = next_block(&chain, &params, 1);
+
+        chain.connect(block, &params).unwrap();
+    }
+
+    #[test]
+    fn a_block_timestamped_at_the_median_time_past_is_refused() {
+        let (mut chain, params) = regtest();
+        let mut block = next_block(&chain, &params, subsidy(1));
+        block.time = chain.median_time_past();
+        assert!(block.mine().unwrap());
+
+        let error = chain
+            .connect(block, &params)
+            .expect_err("not after the median");
+
+        assert!(format!("{error:#}").contains("median"), "got: {error:#}");
+    }
+
+    #[test]
+    fn the_median_time_past_looks_only_at_the_last_eleven_blocks() {
+        let (mut chain, params) = regtest();
+        for _ in 0..MEDIAN_TIME_SPAN + 4 {
+            let block = next_block(&chain, &params, 0);
+            chain.connect(block, &params).unwrap();
+        }
+
+        let newest: Vec<u32> = chain.blocks[chain.blocks.len() - MEDIAN_TIME_SPAN..]
+            .iter()
+            .map(|b| b.time)
+            .collect();
+
+        assert_eq!(newest[MEDIAN_TIME_SPAN / 2], chain.median_time_past());
+    }
+
+    #[test]
+    fn a_header_on_the_best_one_extends_the_best_chain_without_a_body() {
+        let (mut chain, params) = regtest();
+
+        let headers = extend(&mut chain, &params, genesis_header(&params), 3);
+
+        assert_eq!(3, chain.best_header_height());
+        assert_eq!(headers[2].hash(), chain.best_header_hash());
+        assert_eq!(0, chain.height(), "bodies are connected separately");
+    }
+
+    #[test]
+    fn an_invalidated_header_takes_its_descendants_with_it_and_is_never_indexed_again() {
+        let (mut chain, params) = regtest();
+        let genesis = genesis_header(&params);
+        let best = extend(&mut chain, &params, genesis, 3);
+        let fork_start = child(&chain, &params, &genesis, genesis.time + 5);
+        chain
+            .accept_header(fork_start, &params, fork_start.time)
+            .unwrap();
+        let fork = extend(&mut chain, &params, fork_start, 1);
+
+        let forgotten = chain.invalidate(&best[1].hash());
+
+        assert_eq!(vec![best[1].hash(), best[2].hash()], forgotten);
+        assert_eq!(
+            fork[0].hash(),
+            chain.best_header_hash(),
+            "the most work left"
+        );
+        for header in [best[1], best[2]] {
+            let error = chain
+                .accept_header(header, &params, header.time)
+                .expect_err("found invalid");
+            assert!(format!("{error:#}").contains("invalid"), "got: {error:#}");
+        }
+    }
+
+    #[test]
+    fn a_connected_block_is_not_invalidated() {
+        let (mut chain, params) = regtest();
+        let hash = chain
+            .connect(next_block(&chain, &params, subsidy(1)), &params)
+            .unwrap();
+
+        assert!(chain.invalidate(&hash).is_empty());
+        assert_eq!(hash, chain.best_header_hash());
+    }
+
+    #[test]
+    fn a_header_heard_twice_is_known_the_second_time() {
+        let (mut chain, params) = regtest();
+        let header = child(
+            &chain,
+            &params,
+            &genesis_header(&params),
+            params.genesis.time + 1,
+        );
+
+        assert!(chain.accept_header(header, &params, header.time).unwrap());
+        assert!(!chain.accept_header(header, &params, header.time).unwrap());
+    }
+
+    #[test]
+    fn a_header_on_an_unknown_parent_is_refused() {
+        let (mut chain, params) = regtest();
+        let mut orphan = genesis_header(&params);
+        orphan.previous_block_hash = [7; 32];
+        orphan.time += 1;
+
+        let error = chain
+            .accept_header(orphan, &params, orphan.time)
+            .expect_err("an orphan");
+
+        assert!(
+            format!("{error:#}").contains("do not know"),
+            "got: {error:#}"
+        );
+        assert!(matches!(
+            error.downcast_ref::<PrematureHeader>(),
+            Some(PrematureHeader::UnknownParent { .. })
+        ));
+    }
+
+    #[test]
+    fn a_header_carrying_other_than_the_required_n_bits_is_refused() {
+        let (mut chain, params) = regtest();
+        let mut header = child(
+            &chain,
+            &params,
+            &genesis_header(&params),
+            params.genesis.time + 1,
+        );
+        // Easier than required: with no check, a cheap chain could claim any.
+        header.n_bits = 0x2100ffff;
+
+        let error = chain
+            .accept_header(header, &params, header.time)
+            .expect_err("the wrong difficulty");
+
+        assert!(format!("{error:#}").contains("n_bits"), "got: {error:#}");
+        assert!(
+            error.downcast_ref::<PrematureHeader>().is_none(),
+            "the sender's fault"
+        );
+    }
+
+    #[test]
+    fn a_header_too_far_ahead_of_our_clock_is_refused_loudly() {
+        let (mut chain, params) = regtest();
+        let now = params.genesis.time + 1;
+        let header = child(
+            &chain,
+            &params,
+            &genesis_header(&params),
+            now + MAX_FUTURE_DRIFT + 1,
+        );
+
+        let error = chain
+            .accept_header(header, &params, now)
+            .expect_err("from the future");
+
+        assert!(format!("{error:#}").contains("clock"), "got: {error:#}");
+        assert!(matches!(
+            error.downcast_ref::<PrematureHeader>(),
+            Some(PrematureHeader::FromTheFuture { .. })
+        ));
+        assert!(!chain.headers.contains_key(&header.hash()));
+    }
+
+    #[test]
+    fn a_header_at_the_edge_of_the_future_drift_is_accepted() {
+        let (mut chain, params) = regtest();
+        let now = params.genesis.time + 1;
+        let header = child(
+            &chain,
+            &params,
+            &genesis_header(&params),
+            now + MAX_FUTURE_DRIFT,
+        );
+
+        chain.accept_header(header, &params, now).unwrap();
+    }
+
+    #[test]
+    fn a_branch_with_more_work_becomes_best_and_an_equal_one_does_not() {
+        let (mut chain, params) = regtest();
+        let genesis = genesis_header(&params);
+        let first = extend(&mut chain, &params, genesis, 2);
+
+        // A different time makes a different branch of the same length.
+        let rival = child(&chain, &params, &genesis, genesis.time + 2);
+        chain.accept_header(rival, &params, rival.time).unwrap();
+        let rival = [vec![rival], extend(&mut chain, &params, rival, 1)].concat();
+        assert_eq!(
+            first[1].hash(),
+            chain.best_header_hash(),
+            "a tie keeps the first"
+        );
+
+        extend(&mut chain, &params, rival[1], 1);
+
+        assert_eq!(3, chain.best_header_height());
+        assert_eq!(
+            rival[0].hash(),
+            chain.best_headers[1],
+            "the old branch is gone from best"
+        );
+    }
+
+    #[test]
+    fn on_a_retargeting_network_quick_blocks_raise_the_required_difficulty() {
+        let (mut chain, mut params) = regtest();
+        params.retargets = true;
+
+        let headers = extend(&mut chain, &params, genesis_header(&params), 3);
+
+        let targets: Vec<Target> = headers
+            .iter()
+            .map(|header| Target::from_compact(header.n_bits).unwrap())
+            .collect();
+        assert!(
+            targets.windows(2).all(|pair| pair[1] < pair[0]),
+            "a block a second is thirty times too fast, got {targets:?}"
+        );
+    }
+
+    #[test]
+    fn a_locator_runs_from_the_best_header_back_to_genesis_in_doubling_steps() {
+        let (mut chain, params) = regtest();
+        extend(&mut chain, &params, genesis_header(&params), 100);
+
+        let locator = chain.locator();
+
+        let heights: Vec<u32> = locator
+            .iter()
+            .map(|hash| chain.headers[hash].height)
+            .collect();
+        assert_eq!(
+            vec![100, 99, 98, 97, 96, 95, 94, 93, 92, 91, 89, 85, 77, 61, 29, 0],
+            heights
+        );
+    }
+
+    #[test]
+    fn headers_after_a_shared_locator_entry_continue_from_it() {
+        let (mut chain, params) = regtest();
+        let headers = extend(&mut chain, &params, genesis_header(&params), 5);
+
+        let after = chain.headers_after(&[[9; 32], headers[1].hash()], &[0; 32]);
+
+        assert_eq!(headers[2..], after[..]);
+    }
+
+    #[test]
+    fn headers_after_stop_at_the_stop_hash() {
+        let (mut chain, params) = regtest();
+        let headers = extend(&mut chain, &params, genesis_header(&params), 5);
+
+        let after = chain.headers_after(&[params.genesis.hash.unwrap()], &headers[2].hash());
+
+        assert_eq!(headers[..3], after[..]);
+    }
+
+    #[test]
+    fn headers_after_a_locator_we_share_nothing_with_start_after_genesis_and_are_capped() {
+        let (mut chain, params) = regtest();
+        let headers = extend(
+            &mut chain,
+            &params,
+            genesis_header(&params),
+            MAX_HEADERS + 1,
+        );
+
+        let after = chain.headers_after(&[[9; 32]], &[0; 32]);
+
+        assert_eq!(MAX_HEADERS, after.len());
+        assert_eq!(headers[0], after[0]);
+    }
+
+    #[test]
+    fn a_connected_block_indexes_its_header_too() {
+        let (mut chain, params) = regtest();
+        let block = next_block(&chain, &params, subsidy(1));
+
+        let hash = chain.connect(block, &params).unwrap();
+
+        assert_eq!(hash, chain.best_header_hash());
+    }
+
+    #[test]
+    fn a_body_whose_header_arrived_first_still_connects() {
+        let (mut chain, params) = regtest();
+        let block = next_block(&chain, &params, subsidy(1));
+        chain
+            .accept_header(block.header().unwrap(), &params, block.time)
+            .unwrap();
+
+        chain.connect(block, &params).unwrap();
+
+        assert_eq!(1, chain.height());
+    }
+
+    #[test]
+    fn the_bodies_missing_are_the_best_headers_past_the_tip() {
+        let (mut chain, params) = regtest();
+        let block = next_block(&chain, &params, subsidy(1));
+        let connected = chain.connect(block, &params).unwrap();
+        let tip = chain.headers[&connected].header;
+        let headers = extend(&mut chain, &params, tip, 5);
+
+        let missing = chain.missing_bodies(3);
+
+        assert_eq!(
+            vec![
+                (headers[0].hash(), 2),
+                (headers[1].hash(), 3),
+                (headers[2].hash(), 4)
+            ],
+            missing
+        );
+    }
+
+    #[test]
+    fn no_bodies_are_missing_when_the_best_headers_fork_below_the_tip() {
+        let (mut chain, params) = regtest();
+        let genesis = genesis_header(&params);
+        let block = next_block(&chain, &params, subsidy(1));
+        chain.connect(block, &params).unwrap();
+
+        // A heavier branch off genesis: reaching it needs a reorg of bodies.
+        let rival = child(&chain, &params, &genesis, genesis.time + 7);
+        chain.accept_header(rival, &params, rival.time).unwrap();
+        extend(&mut chain, &params, rival, 2);
+
+        assert!(chain.missing_bodies(10).is_empty());
+    }
+
+    #[test]
+    fn only_a_connected_block_is_served_by_hash() {
+        let (mut chain, params) = regtest();
+        let block = next_block(&chain, &params, subsidy(1));
+        let hash = chain.connect(block, &params).unwrap();
+        let tip = chain.headers[&hash].header;
+        let header_only = extend(&mut chain, &params, tip, 1)[0];
+
+        assert_eq!(Some(hash), chain.block(&hash).and_then(|block| block.hash));
+        assert!(chain.block(&header_only.hash()).is_none());
+        assert!(chain.block(&[3; 32]).is_none());
+    }
+}
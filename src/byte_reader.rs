@@ -65,6 +65,19 @@ impl<'a> ByteReader<'a> {
             .ok_or_else(|| anyhow!("EOF: Not sufficient bytes to read vec of {} bytes", size))
     }
 
+    /// Bytes not yet read: a fixed-layout payload with any left over is
+    /// malformed, not padded.
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    /// What to reserve for `count` items a payload claims to hold. Each takes
+    /// at least a byte, so more than the bytes left is a lie, and trusting it
+    /// would let a peer make us allocate gigabytes with one compact size.
+    pub fn capacity_for(&self, count: u64) -> usize {
+        usize::try_from(count).map_or(self.remaining(), |count| count.min(self.remaining()))
+    }
+
     pub fn read_compact(&mut self) -> Result<u64> {
         match self.read_byte()? {
             0xfd => Ok(self.read_u16()? as u64),
@@ -312,4 +325,19 @@ mod tests {
             "the cursor must not have moved"
         );
     }
+
+    #[rstest]
+    #[case::honest(3, 3)]
+    #[case::more_than_is_left(1_000, 7)]
+    #[case::absurd(u64::MAX, 7)]
+    fn a_claimed_count_reserves_no_more_than_the_bytes_left(
+        #[case] claimed: u64,
+        #[case] reserved: usize,
+    ) {
+        let bytes = [0u8; 8];
+        let mut reader = ByteReader::new(&bytes);
+        reader.read_byte().unwrap();
+
+        assert_eq!(reserved, reader.capacity_for(claimed));
+    }
 }
@@ -0,0 +1,251 @@
+use crate::block::{Block, BlockHeader};
+use crate::mempool::Mempool;
+use crate::messages::compact_block::{CompactBlock, ShortId};
+use crate::transaction::Transaction;
+use crate::util::display_hash;
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+
+/// A compact block being rebuilt: its header, and a slot per transaction,
+/// filled from what it sent whole and what the mempool held under its short
+/// ids. The rest are asked for with a `getblocktxn`.
+#[derive(Debug)]
+pub struct PartialBlock {
+    header: BlockHeader,
+    slots: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    /// A short id two transactions answer to, in the block or in the mempool,
+    /// fills nothing: guessing would rebuild a block that fails its merkle
+    /// root, and cost a whole block to recover from.
+    pub fn new(compact: &CompactBlock, mempool: &Mempool) -> PartialBlock {
+        let mut slots: Vec<Option<Transaction>> = vec![None; compact.transaction_count()];
+        for prefilled in &compact.prefilled {
+            slots[prefilled.index as usize] = Some(prefilled.transaction.clone());
+        }
+
+        let mut by_short_id: HashMap<ShortId, usize> = HashMap::new();
+        let mut ambiguous: HashSet<ShortId> = HashSet::new();
+        let empty = slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(index, _)| index);
+        for (short_id, index) in compact.short_ids.iter().zip(empty) {
+            if by_short_id.insert(*short_id, index).is_some() {
+                ambiguous.insert(*short_id);
+            }
+        }
+
+        let mut matched: HashMap<ShortId, &Transaction> = HashMap::new();
+        for (txid, transaction) in mempool.iter() {
+            let short_id = compact.short_id(txid);
+            if !by_short_id.contains_key(&short_id) {
+                continue;
+            }
+            if matched.insert(short_id, transaction).is_some() {
+                ambiguous.insert(short_id);
+            }
+        }
+
+        for (short_id, transaction) in matched {
+            if !ambiguous.contains(&short_id) {
+                slots[by_short_id[&short_id]] = Some(transaction.clone());
+            }
+        }
+
+        PartialBlock {
+            header: compact.header,
+            slots,
+        }
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        self.header.hash()
+    }
+
+    /// The indexes still empty, ascending: what a `getblocktxn` asks for.
+    pub fn missing(&self) -> Vec<u32> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+
+    /// Fills the empty slots, in order, with a `blocktxn`'s transactions.
+    pub fn fill(&mut self, transactions: Vec<Transaction>) -> Result<()> {
+        let missing = self.missing();
+        if transactions.len() != missing.len() {
+            return Err(anyhow!(
+                "block {} lacks {} transactions, and {} were sent",
+                display_hash(&self.hash()),
+                missing.len(),
+                transactions.len()
+            ));
+        }
+
+        for (index, transaction) in missing.into_iter().zip(transactions) {
+            self.slots[index as usize] = Some(transaction);
+        }
+        Ok(())
+    }
+
+    /// The block, if every slot is filled and with what its header commits
+    /// to. A mempool transaction that shared a short id with the one really
+    /// in the block shows up here, as a merkle root that does not match.
+    pub fn into_block(self) -> Result<Block> {
+        let expected = self.hash();
+        let transactions = self
+            .slots
+            .into_iter()
+            .collect::<Option<Vec<Transaction>>>()
+            .ok_or_else(|| anyhow!("block {} is not complete", display_hash(&expected)))?;
+
+        let mut block = Block::new(
+            self.header.version,
+            self.header.previous_block_hash,
+            self.header.time,
+            self.header.n_bits,
+            transactions,
+        );
+        block.nonce = self.header.nonce;
+        if block.seal()? != expected {
+            return Err(anyhow!(
+                "block {} rebuilt from short ids does not match its header",
+                display_hash(&expected)
+            ));
+        }
+
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Outpoint, TxIn, TxOut};
+    use crate::utxo::UtxoSet;
+
+    /// A confirmed coinbase with an output of 10 for each tag to spend.
+    fn funding() -> Transaction {
+        Transaction::coinbase(
+            "height 0".to_string(),
+            vec![
+                TxOut {
+                    value: 10,
+                    destiny_pub_key: "payee".to_string(),
+                };
+                4
+            ],
+        )
+    }
+
+    fn a_spend(tag: u8) -> Transaction {
+        Transaction {
+            version: 1,
+            inputs: vec![TxIn {
+                previous_output: Outpoint {
+                    tx_id: funding().get_tx_id(),
+                    v_out: tag.into(),
+                },
+                signature: "signed".to_string(),
+                sequence: u32::MAX,
+            }],
+            outputs: vec![TxOut {
+                value: 10,
+                destiny_pub_key: "payee".to_string(),
+            }],
+            lock_time: 0,
+        }
+    }
+
+    fn a_block_spending(tags: &[u8]) -> Block {
+        let coinbase = Transaction::coinbase(
+            "height 1".to_string(),
+            vec![TxOut {
+                value: 50,
+                destiny_pub_key: "miner".to_string(),
+            }],
+        );
+        let transactions = std::iter::once(coinbase)
+            .chain(tags.iter().map(|tag| a_spend(*tag)))
+            .collect();
+        let mut block = Block::new(1, [0; 32], 1, 0x207fffff, transactions);
+        block.seal().unwrap();
+        block
+    }
+
+    fn a_mempool_of(tags: &[u8]) -> Mempool {
+        let mut utxos = UtxoSet::default();
+        utxos.apply(&[funding()], 0);
+        let mut mempool = Mempool::default();
+        for tag in tags {
+            mempool.admit(a_spend(*tag), &utxos, 1).unwrap();
+        }
+        mempool
+    }
+
+    #[test]
+    fn a_block_whose_transactions_are_all_pooled_is_rebuilt_whole() {
+        let block = a_block_spending(&[1, 2]);
+        let compact = CompactBlock::new(&block, 7).unwrap();
+
+        let partial = PartialBlock::new(&compact, &a_mempool_of(&[1, 2, 3]));
+
+        assert!(partial.missing().is_empty());
+        assert_eq!(block.hash, partial.into_block().unwrap().hash);
+    }
+
+    #[test]
+    fn what_the_mempool_lacks_is_missing_until_filled() {
+        let block = a_block_spending(&[1, 2, 3]);
+        let compact = CompactBlock::new(&block, 7).unwrap();
+
+        let mut partial = PartialBlock::new(&compact, &a_mempool_of(&[2]));
+
+        assert_eq!(vec![1, 3], partial.missing());
+        partial.fill(vec![a_spend(1), a_spend(3)]).unwrap();
+        assert_eq!(block.hash, partial.into_block().unwrap().hash);
+    }
+
+    #[test]
+    fn a_blocktxn_of_the_wrong_length_is_refused() {
+        let compact = CompactBlock::new(&a_block_spending(&[1, 2]), 7).unwrap();
+        let mut partial = PartialBlock::new(&compact, &Mempool::default());
+
+        let error = partial.fill(vec![a_spend(1)]).expect_err("one short");
+
+        assert!(format!("{error:#}").contains("lacks 2"), "got: {error:#}");
+    }
+
+    #[test]
+    fn a_short_id_two_slots_answer_to_fills_neither() {
+        let block = a_block_spending(&[1, 1]);
+        let compact = CompactBlock::new(&block, 7).unwrap();
+
+        let partial = PartialBlock::new(&compact, &a_mempool_of(&[1]));
+
+        assert_eq!(vec![1, 2], partial.missing());
+    }
+
+    #[test]
+    fn a_wrong_transaction_under_a_short_id_fails_to_rebuild() {
+        let block = a_block_spending(&[1]);
+        let mut compact = CompactBlock::new(&block, 7).unwrap();
+        compact.short_ids = vec![compact.short_id(&a_spend(2).get_tx_id())];
+
+        let partial = PartialBlock::new(&compact, &a_mempool_of(&[2]));
+
+        assert!(partial.missing().is_empty());
+        let error = partial
+            .into_block()
+            .expect_err("not the block's transaction");
+        assert!(
+            format!("{error:#}").contains("does not match its header"),
+            "got: {error:#}"
+        );
+    }
+}
@@ -1,22 +1,42 @@
+use crate::node::{MAX_INBOUND, MAX_OUTBOUND};
+use crate::params::Network;
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use serde::Deserialize;
 use std::fs;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 const CONFIG_FILE: &str = "config.toml";
 const DEFAULT_HOST_ADDRESS: &str = "127.0.0.1:34352";
+const DEFAULT_DATA_DIR: &str = "data";
 
 #[derive(Debug)]
 pub struct Config {
+    pub network: Network,
     pub host_address: SocketAddr,
     pub addresses_to_connect: Vec<SocketAddr>,
+    /// Where the node keeps what outlives a run, one subdirectory per network.
+    pub data_dir: PathBuf,
+    /// How many peers may dial us, and how many we dial, at once.
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+}
+
+impl Config {
+    /// This network's part of the data directory.
+    pub fn network_dir(&self) -> PathBuf {
+        self.data_dir.join(self.network.to_string())
+    }
 }
 
 #[derive(Debug, Default, Deserialize)]
 #[serde(deny_unknown_fields)]
 struct FileConfig {
+    #[serde(default)]
+    network: Option<Network>,
+    #[serde(default)]
+    data_dir: Option<PathBuf>,
     #[serde(default)]
     server: FileServerConfig,
 }
@@ -28,11 +48,51 @@ struct FileServerConfig {
     host_address: Option<String>,
     #[serde(default)]
     addresses_to_connect: Option<Vec<String>>,
+    #[serde(default)]
+    max_inbound: Option<usize>,
+    #[serde(default)]
+    max_outbound: Option<usize>,
+}
+
+/// What the binary was asked to do: run a node, or one of its tools.
+#[derive(Debug)]
+pub enum Invocation {
+    Node(Config),
+    Tool(Tool),
+}
+
+#[derive(Debug, PartialEq, Eq, Subcommand)]
+pub enum Tool {
+    /// Re-mine a network's genesis nonce after its allocation changes
+    Genesis {
+        /// The network to regenerate; every network when omitted
+        #[arg(value_enum)]
+        network: Option<Network>,
+
+        /// Only verify the committed genesis, failing if it is stale
+        #[arg(long)]
+        check: bool,
+
+        /// Directory holding the allocation files
+        #[arg(long, default_value = "params")]
+        dir: PathBuf,
+    },
 }
 
 #[derive(Debug, Default, Parser)]
 #[command(name = "avicoin", about = "A Bitcoin-like cryptocurrency node")]
 struct Args {
+    #[command(subcommand)]
+    tool: Option<Tool>,
+
+    /// Which network's parameters and genesis block to run with
+    #[arg(long, value_enum)]
+    network: Option<Network>,
+
+    /// Directory the node keeps its state in, e.g. the addresses it has learned
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+
     /// Address this node listens on, e.g. 127.0.0.1:34352
     #[arg(long)]
     host_address: Option<String>,
@@ -40,14 +100,35 @@ struct Args {
     /// Peer address to connect to; repeat the flag for several peers
     #[arg(long)]
     addresses_to_connect: Vec<String>,
+
+    /// Most peers that may be connected after dialling us
+    #[arg(long)]
+    max_inbound: Option<usize>,
+
+    /// Most peers we may be connected to after dialling them
+    #[arg(long)]
+    max_outbound: Option<usize>,
 }
 
-pub fn get_config() -> Result<Config> {
-    resolve(read_file_config(CONFIG_FILE.as_ref())?, Args::parse())
+pub fn get_invocation() -> Result<Invocation> {
+    let mut args = Args::parse();
+
+    // A tool does not run a node, so a broken config.toml must not stop it.
+    if let Some(tool) = args.tool.take() {
+        return Ok(Invocation::Tool(tool));
+    }
+
+    resolve(read_file_config(CONFIG_FILE.as_ref())?, args).map(Invocation::Node)
 }
 
 fn resolve(file: Option<FileConfig>, args: Args) -> Result<Config> {
-    let file = file.unwrap_or_default().server;
+    let file = file.unwrap_or_default();
+    let network = args.network.or(file.network).unwrap_or_default();
+    let data_dir = args
+        .data_dir
+        .or(file.data_dir)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_DIR));
+    let file = file.server;
 
     let host_address = args
         .host_address
@@ -60,12 +141,19 @@ fn resolve(file: Option<FileConfig>, args: Args) -> Result<Config> {
         file.addresses_to_connect.unwrap_or_default()
     };
 
+    let max_inbound = args.max_inbound.or(file.max_inbound).unwrap_or(MAX_INBOUND);
+    let max_outbound = args.max_outbound.or(file.max_outbound).unwrap_or(MAX_OUTBOUND);
+
     Ok(Config {
+        network,
         host_address: parse_address(&host_address, "host_address")?,
         addresses_to_connect: addresses_to_connect
             .iter()
             .map(|a| parse_address(a, "addresses_to_connect"))
             .collect::<Result<Vec<_>>>()?,
+        data_dir,
+        max_inbound,
+        max_outbound,
     })
 }
 
@@ -94,8 +182,13 @@ mod tests {
 
     fn args(host: Option<&str>, peers: &[&str]) -> Args {
         Args {
+            tool: None,
+            network: None,
+            data_dir: None,
             host_address: host.map(String::from),
             addresses_to_connect: peers.iter().map(|s| s.to_string()).collect(),
+            max_inbound: None,
+            max_outbound: None,
         }
     }
 
@@ -115,6 +208,7 @@ mod tests {
     fn defaults_apply_when_nothing_else_is_supplied() {
         let config = resolve(None, Args::default()).unwrap();
 
+        assert_eq!(Network::Main, config.network);
         assert_eq!(addr(DEFAULT_HOST_ADDRESS), config.host_address);
         assert!(config.addresses_to_connect.is_empty());
     }
@@ -162,6 +256,100 @@ mod tests {
         assert_eq!(vec![addr("127.0.0.1:9001")], config.addresses_to_connect);
     }
 
+    #[rstest]
+    #[case::file_alone(file("network = \"test\""), None, Network::Test)]
+    #[case::flag_alone(None, Some(Network::Regtest), Network::Regtest)]
+    #[case::flag_over_file(file("network = \"test\""), Some(Network::Main), Network::Main)]
+    fn the_network_is_chosen_by_flag_then_file_then_mainnet(
+        #[case] file: Option<FileConfig>,
+        #[case] flag: Option<Network>,
+        #[case] expected: Network,
+    ) {
+        let args = Args {
+            network: flag,
+            ..Args::default()
+        };
+
+        assert_eq!(expected, resolve(file, args).unwrap().network);
+    }
+
+    #[rstest]
+    #[case::default(None, None, "data")]
+    #[case::file_alone(file("data_dir = \"state\""), None, "state")]
+    #[case::flag_over_file(file("data_dir = \"state\""), Some("elsewhere"), "elsewhere")]
+    fn the_data_directory_is_chosen_by_flag_then_file_then_default(
+        #[case] file: Option<FileConfig>,
+        #[case] flag: Option<&str>,
+        #[case] expected: &str,
+    ) {
+        let args = Args {
+            data_dir: flag.map(PathBuf::from),
+            ..Args::default()
+        };
+
+        assert_eq!(
+            PathBuf::from(expected),
+            resolve(file, args).unwrap().data_dir
+        );
+    }
+
+    #[rstest]
+    #[case::default(None, None, (MAX_INBOUND, MAX_OUTBOUND))]
+    #[case::file_alone(file("[server]\nmax_inbound = 4\nmax_outbound = 2"), None, (4, 2))]
+    #[case::flag_over_file(file("[server]\nmax_inbound = 4"), Some((6, 3)), (6, 3))]
+    fn the_connection_caps_are_chosen_by_flag_then_file_then_default(
+        #[case] file: Option<FileConfig>,
+        #[case] flags: Option<(usize, usize)>,
+        #[case] expected: (usize, usize),
+    ) {
+        let args = Args {
+            max_inbound: flags.map(|(inbound, _)| inbound),
+            max_outbound: flags.map(|(_, outbound)| outbound),
+            ..Args::default()
+        };
+
+        let config = resolve(file, args).unwrap();
+        assert_eq!(expected, (config.max_inbound, config.max_outbound));
+    }
+
+    #[test]
+    fn each_network_keeps_its_own_part_of_the_data_directory() {
+        let args = Args {
+            network: Some(Network::Regtest),
+            data_dir: Some(PathBuf::from("state")),
+            ..Args::default()
+        };
+
+        assert_eq!(
+            PathBuf::from("state").join("regtest"),
+            resolve(None, args).unwrap().network_dir()
+        );
+    }
+
+    #[test]
+    fn an_unknown_network_in_the_file_is_rejected() {
+        let parsed: std::result::Result<FileConfig, _> = toml::from_str("network = \"mainnet\"");
+
+        assert!(
+            parsed.is_err(),
+            "a misspelled network must not fall back to main"
+        );
+    }
+
+    #[test]
+    fn the_genesis_tool_checks_every_network_in_params_by_default() {
+        let args = Args::try_parse_from(["avicoin", "genesis", "--check"]).unwrap();
+
+        assert_eq!(
+            Some(Tool::Genesis {
+                network: None,
+                check: true,
+                dir: PathBuf::from("params"),
+            }),
+            args.tool
+        );
+    }
+
     #[test]
     fn several_peers_are_all_kept() {
         let config = resolve(
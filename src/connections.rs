@@ -0,0 +1,431 @@
+use crate::address_book::{save, ADDRESS_BOOK_FILE};
+use crate::node::{record, Node, SharedNode};
+use crate::protocol::{connect, feel};
+use rand::RngExt;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many dialled connections the node keeps up when it knows enough
+/// addresses to.
+pub const TARGET_OUTBOUND: usize = 8;
+
+/// The wait after a configured peer's first failed attempt, and after it
+/// disconnects. Each further failure doubles it, up to `MAX_BACKOFF`.
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(64);
+
+/// How often, once every outbound slot is taken, one address from the book
+/// is tested with a feeler.
+pub const FEELER_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+const TICK: Duration = Duration::from_secs(1);
+
+/// Where one configured peer stands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Retry {
+    /// Dials since one last reached a completed handshake.
+    pub attempts: u32,
+    pub next_attempt: Instant,
+    /// Whether the last dial reached a completed handshake and has not been
+    /// seen to end since.
+    pub connected: bool,
+}
+
+/// The peers the node was told to connect to, which unlike anything in the
+/// address book it never gives up on: a node that restarts, or was not up
+/// yet, is dialled again until it answers.
+#[derive(Debug, Default)]
+pub struct ConfiguredPeers {
+    peers: HashMap<SocketAddr, Retry>,
+}
+
+impl ConfiguredPeers {
+    /// Every one of them due at once.
+    pub fn new(addresses: &[SocketAddr], now: Instant) -> Self {
+        ConfiguredPeers {
+            peers: addresses
+                .iter()
+                .map(|address| {
+                    let retry = Retry {
+                        attempts: 0,
+                        next_attempt: now,
+                        connected: false,
+                    };
+                    (*address, retry)
+                })
+                .collect(),
+        }
+    }
+
+    /// Those to dial now, each counted as attempted and given its next
+    /// attempt. A connection seen to have ended since the last call waits
+    /// `INITIAL_BACKOFF` first, so peers that lose the same node do not all
+    /// dial it back at the same instant. `jitter` is drawn from `[0, 1)`.
+    pub fn due(
+        &mut self,
+        connected: &HashSet<SocketAddr>,
+        now: Instant,
+        mut jitter: impl FnMut() -> f64,
+    ) -> Vec<SocketAddr> {
+        let mut due = Vec::new();
+
+        for (address, retry) in self.peers.iter_mut() {
+            if connected.contains(address) {
+                continue;
+            }
+
+            if retry.connected {
+                retry.connected = false;
+                retry.next_attempt = now + jittered(INITIAL_BACKOFF, jitter());
+            } else if retry.next_attempt <= now {
+                retry.attempts += 1;
+                retry.next_attempt = now + jittered(backoff(retry.attempts), jitter());
+                due.push(*address);
+            }
+        }
+
+        due.sort();
+        due
+    }
+
+    /// A dial to `address` reached a completed handshake: the next failure
+    /// starts the backoff over.
+    pub fn connected(&mut self, address: SocketAddr) {
+        if let Some(retry) = self.peers.get_mut(&address) {
+            retry.attempts = 0;
+            retry.connected = true;
+        }
+    }
+
+    pub fn get(&self, address: &SocketAddr) -> Option<&Retry> {
+        self.peers.get(address)
+    }
+
+    pub fn addresses(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.peers.keys()
+    }
+
+    /// One line per configured peer, in address order.
+    pub fn report(&self, now: Instant) -> Vec<String> {
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        peers.sort_by_key(|(address, _)| **address);
+
+        peers
+            .into_iter()
+            .map(|(address, retry)| {
+                if retry.connected {
+                    format!("{address} connected")
+                } else {
+                    format!(
+                        "{address} after {} attempts; next in {}s",
+                        retry.attempts,
+                        retry.next_attempt.saturating_duration_since(now).as_secs()
+                    )
+                }
+            })
+            .collect()
+    }
+}
+
+/// `INITIAL_BACKOFF` doubled for each attempt after the first, at most
+/// `MAX_BACKOFF`.
+fn backoff(attempts: u32) -> Duration {
+    let doublings = attempts.saturating_sub(1).min(16);
+    (INITIAL_BACKOFF * 2u32.pow(doublings)).min(MAX_BACKOFF)
+}
+
+/// Somewhere in the upper half of `delay`: never much sooner than asked, and
+/// never in step with another node waiting out the same delay.
+fn jittered(delay: Duration, jitter: f64) -> Duration {
+    delay.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+/// The connection manager: keeps the configured peers connected, tops the
+/// node's dialled connections up to `TARGET_OUTBOUND` from the address book,
+/// tests the book with a feeler every `FEELER_INTERVAL`, and writes it out
+/// whenever it has changed.
+pub fn run(node: SharedNode) {
+    let mut next_feeler = Instant::now() + FEELER_INTERVAL;
+    loop {
+        let now = Instant::now();
+        tick(&node, now);
+        if now >= next_feeler {
+            send_feeler(&node, now);
+            next_feeler = now + FEELER_INTERVAL;
+        }
+        thread::sleep(TICK);
+    }
+}
+
+/// How many dialled connections `node` keeps up: `TARGET_OUTBOUND`, unless
+/// it is configured to allow fewer.
+fn outbound_target(node: &Node) -> usize {
+    TARGET_OUTBOUND.min(node.config.max_outbound)
+}
+
+/// While outbound slots are free, dialling the book tests it already; once
+/// they are full, this is what keeps finding out which addresses still answer.
+fn send_feeler(node: &SharedNode, now: Instant) {
+    let address = {
+        let mut locked = node.lock().expect("node lock poisoned");
+        let locked = &mut *locked;
+        if locked.peers.dialled_count() < outbound_target(locked) {
+            return;
+        }
+
+        let mut excluded = locked.peers.addresses();
+        excluded.insert(locked.config.host_address);
+        excluded.extend(locked.addresses.ourselves());
+        excluded.extend(locked.configured.addresses());
+        let Some(address) = locked
+            .addresses
+            .stalest(&excluded, now)
+            .filter(|address| !locked.bans.is_banned(address.ip(), now))
+        else {
+            return;
+        };
+        locked.addresses.attempted(address, now);
+        address
+    };
+
+    if let Err(e) = feel(address, Arc::clone(node)) {
+        node.lock()
+            .expect("node lock poisoned")
+            .addresses
+            .failed(address);
+        record(
+            node,
+            format!("Feeler could not connect to {address}: {e:#}"),
+        );
+    }
+}
+
+fn tick(node: &SharedNode, now: Instant) {
+    let (configured, learned, changed) = {
+        let mut locked = node.lock().expect("node lock poisoned");
+        let locked = &mut *locked;
+
+        // Not dialled: whatever we are connected to, and whatever turned out
+        // to be this node, even if it was configured.
+        let mut excluded = locked.peers.addresses();
+        excluded.insert(locked.config.host_address);
+        excluded.extend(locked.addresses.ourselves());
+        let mut rng = rand::rng();
+        let mut configured = locked
+            .configured
+            .due(&excluded, now, || rng.random::<f64>());
+        // A banned peer keeps its schedule, so it is tried once its ban ends.
+        configured.retain(|address| !locked.bans.is_banned(address.ip(), now));
+
+        // Configured peers keep their own schedule, whatever the book says.
+        excluded.extend(locked.configured.addresses());
+        let wanted = outbound_target(locked)
+            .saturating_sub(locked.peers.dialled_count())
+            .saturating_sub(configured.len());
+        let mut learned = locked.addresses.candidates(&excluded, wanted, now);
+        learned.retain(|address| !locked.bans.is_banned(address.ip(), now));
+        for address in &learned {
+            locked.addresses.attempted(*address, now);
+        }
+
+        let changed = locked.addresses.take_changes().then(|| {
+            (
+                locked.config.network_dir().join(ADDRESS_BOOK_FILE),
+                locked.addresses.to_text(),
+            )
+        });
+        (configured, learned, changed)
+    };
+
+    if let Some((path, text)) = changed {
+        if let Err(e) = save(&path, &text) {
+            record(node, format!("Could not save the address book: {e:#}"));
+        }
+    }
+
+    for address in configured {
+        if let Err(e) = connect(address, Arc::clone(node)) {
+            let retry = node
+                .lock()
+                .expect("node lock poisoned")
+                .configured
+                .get(&address)
+                .copied();
+            let retrying = retry.map_or_else(String::new, |retry| {
+                format!(
+                    "; attempt {}, next in {:?}",
+                    retry.attempts,
+                    retry.next_attempt.saturating_duration_since(now)
+                )
+            });
+            record(
+                node,
+                format!("Could not connect to {address}: {e:#}{retrying}"),
+            );
+        }
+    }
+
+    for address in learned {
+        if let Err(e) = connect(address, Arc::clone(node)) {
+            node.lock()
+                .expect("node lock poisoned")
+                .addresses
+                .failed(address);
+            record(node, format!("Could not connect to {address}: {e:#}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::node::Origin;
+    use crate::outbound::{queue, OUTBOUND_BUDGET};
+    use crate::params::{Network, Params};
+    use crate::util::unix_time;
+    use rstest::rstest;
+    use std::net::TcpListener;
+
+    fn address(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{port}").parse().unwrap()
+    }
+
+    fn no_jitter() -> f64 {
+        1.0
+    }
+
+    #[test]
+    fn every_configured_peer_is_due_at_first() {
+        let now = Instant::now();
+        let mut peers = ConfiguredPeers::new(&[address(2), address(1)], now);
+
+        assert_eq!(
+            vec![address(1), address(2)],
+            peers.due(&HashSet::new(), now, no_jitter)
+        );
+    }
+
+    #[test]
+    fn each_failed_attempt_doubles_the_wait_up_to_the_cap() {
+        let mut now = Instant::now();
+        let mut peers = ConfiguredPeers::new(&[address(1)], now);
+        let mut waits = Vec::new();
+
+        for _ in 0..10 {
+            assert_eq!(vec![address(1)], peers.due(&HashSet::new(), now, no_jitter));
+            let next = peers.get(&address(1)).unwrap().next_attempt;
+            waits.push((next - now).as_secs());
+
+            assert!(
+                peers
+                    .due(&HashSet::new(), next - Duration::from_millis(1), no_jitter)
+                    .is_empty(),
+                "not due before its wait is up"
+            );
+            now = next;
+        }
+
+        assert_eq!(vec![1, 2, 4, 8, 16, 32, 64, 64, 64, 64], waits);
+    }
+
+    #[test]
+    fn jitter_shortens_a_wait_by_at_most_half() {
+        assert_eq!(
+            Duration::from_secs(4),
+            jittered(Duration::from_secs(8), 0.0)
+        );
+        assert_eq!(
+            Duration::from_secs(6),
+            jittered(Duration::from_secs(8), 0.5)
+        );
+        assert_eq!(
+            Duration::from_secs(8),
+            jittered(Duration::from_secs(8), 1.0)
+        );
+    }
+
+    #[test]
+    fn a_connected_peer_is_not_dialled_and_one_that_drops_is_dialled_again_after_a_pause() {
+        let now = Instant::now();
+        let mut peers = ConfiguredPeers::new(&[address(1)], now);
+        peers.due(&HashSet::new(), now, no_jitter);
+        peers.due(&HashSet::new(), now + Duration::from_secs(1), no_jitter);
+        peers.connected(address(1));
+
+        let later = now + Duration::from_secs(600);
+        assert!(peers
+            .due(&HashSet::from([address(1)]), later, no_jitter)
+            .is_empty());
+
+        assert!(
+            peers.due(&HashSet::new(), later, no_jitter).is_empty(),
+            "a peer just lost is given a moment"
+        );
+        let retry = *peers.get(&address(1)).unwrap();
+        assert_eq!(0, retry.attempts, "its backoff started over");
+        assert_eq!(
+            vec![address(1)],
+            peers.due(&HashSet::new(), retry.next_attempt, no_jitter)
+        );
+    }
+
+    #[test]
+    fn the_report_says_where_each_peer_stands() {
+        let now = Instant::now();
+        let mut peers = ConfiguredPeers::new(&[address(1), address(2)], now);
+        peers.due(&HashSet::new(), now, no_jitter);
+        peers.connected(address(2));
+
+        assert_eq!(
+            vec![
+                "127.0.0.1:1 after 1 attempts; next in 1s".to_string(),
+                "127.0.0.1:2 connected".to_string(),
+            ],
+            peers.report(now)
+        );
+    }
+
+    #[rstest]
+    #[case::every_slot_taken(1, 1)]
+    #[case::slots_to_spare(crate::node::MAX_OUTBOUND, 0)]
+    fn a_feeler_goes_out_once_the_configured_outbound_slots_are_full(
+        #[case] max_outbound: usize,
+        #[case] feelers: u32,
+    ) {
+        let node = Node::shared(
+            Config {
+                network: Network::Regtest,
+                host_address: "127.0.0.1:34352".parse().unwrap(),
+                addresses_to_connect: Vec::new(),
+                data_dir: "data".into(),
+                max_inbound: crate::node::MAX_INBOUND,
+                max_outbound,
+            },
+            Params::of(Network::Regtest).unwrap(),
+        );
+        // Nothing listens there once the listener is gone, so a feeler fails
+        // at once and is counted.
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (outbound, _queued) = queue(OUTBOUND_BUDGET);
+        {
+            let mut locked = node.lock().unwrap();
+            locked
+                .peers
+                .register(address(1), Origin::Dialled, outbound)
+                .unwrap();
+            locked.addresses.learn(closed, unix_time(), unix_time());
+        }
+
+        send_feeler(&node, Instant::now());
+
+        let locked = node.lock().unwrap();
+        assert_eq!(feelers, locked.addresses.get(&closed).unwrap().failures);
+    }
+}
@@ -0,0 +1,114 @@
+use crate::mining::generate;
+use crate::node::{record, SharedNode};
+use crate::util::display_hash;
+use anyhow::{anyhow, Context, Result};
+use std::io::BufRead;
+use std::time::Instant;
+
+/// Commands typed on stdin, one per line: the scripting surface until the
+/// HTTP API exists. Answers go to the log, so they reach stdout with
+/// everything else the node says.
+pub fn run(node: SharedNode, input: impl BufRead) {
+    for line in input.lines() {
+        // A closed or unreadable stdin ends the console, never the node.
+        let Ok(line) = line else { return };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match execute(&node, &line) {
+            Ok(answer) => record(&node, answer),
+            Err(e) => record(&node, format!("{:?} failed: {e:#}", line.trim())),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Generate {
+        count: u32,
+        to: String,
+    },
+    /// Where each configured peer stands: connected, or when it is next dialled.
+    Connections,
+}
+
+fn parse(line: &str) -> Result<Command> {
+    match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["generate", count, "to", to] => Ok(Command::Generate {
+            count: count
+                .parse()
+                .with_context(|| format!("{count:?} is not a block count"))?,
+            to: to.to_string(),
+        }),
+        ["generate", ..] => Err(anyhow!("usage: generate <count> to <public key>")),
+        ["connections"] => Ok(Command::Connections),
+        _ => Err(anyhow!(
+            "unknown command; try: generate <count> to <public key>, or connections"
+        )),
+    }
+}
+
+fn execute(node: &SharedNode, line: &str) -> Result<String> {
+    match parse(line)? {
+        Command::Generate { count, to } => {
+            let generated = generate(node, count, &to)?;
+            let height = node.lock().expect("node lock poisoned").chain.height();
+
+            Ok(match generated.last() {
+                Some(tip) => format!(
+                    "Generated {} blocks; tip {} at height {height}",
+                    generated.len(),
+                    display_hash(tip)
+                ),
+                None => format!("Generated nothing; height is still {height}"),
+            })
+        }
+        Command::Connections => {
+            let report = node
+                .lock()
+                .expect("node lock poisoned")
+                .configured
+                .report(Instant::now());
+
+            Ok(if report.is_empty() {
+                "No peers configured".to_string()
+            } else {
+                format!("Configured peers:\n{}", report.join("\n"))
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[test]
+    fn generate_names_a_count_and_a_destination() {
+        assert_eq!(
+            Command::Generate {
+                count: 101,
+                to: "02ab".to_string()
+            },
+            parse("  generate 101 to 02ab ").unwrap()
+        );
+    }
+
+    #[test]
+    fn connections_takes_no_arguments() {
+        assert_eq!(Command::Connections, parse(" connections ").unwrap());
+        parse("connections all").expect_err("nothing to choose between");
+    }
+
+    #[rstest]
+    #[case::no_destination("generate 5")]
+    #[case::negative_count("generate -1 to 02ab")]
+    #[case::missing_to("generate 5 02ab")]
+    #[case::unknown("mine 5 to 02ab")]
+    fn a_malformed_command_is_refused(#[case] line: &str) {
+        parse(line).expect_err("a typo must not mine anything");
+    }
+}
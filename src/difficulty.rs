@@ -0,0 +1,462 @@
+use crate::block::BlockHeader;
+use anyhow::{anyhow, Result};
+use primitive_types::{U256, U512};
+
+const SIGN_BIT: u32 = 0x0080_0000;
+const MANTISSA_MASK: u32 = 0x007f_ffff;
+
+/// Seconds between blocks that retargeting steers towards (ADR-0006).
+pub const TARGET_SPACING: u32 = 30;
+
+/// How many of the most recent block intervals a retarget averages over.
+pub const RETARGET_WINDOW: usize = 60;
+
+/// The most one block may move the target from its parent's, either way.
+/// Adapting to a 1000x change in hashrate then takes at least ten blocks, and
+/// in practice tens: the window's average lags behind the clamp.
+pub const RETARGET_CLAMP: u32 = 2;
+
+/// The longest one block may count as taking, however far apart its
+/// timestamp claims it is from its parent's.
+const MAX_SOLVE_TIME: u32 = 6 * TARGET_SPACING;
+
+/// The target the block after `ancestors` must meet. `ancestors` ends at the
+/// parent, oldest first; only the last `RETARGET_WINDOW` intervals are read.
+///
+/// Every block retargets (ADR-0009). The window's hashrate is estimated as its
+/// average work over its recency-weighted solve time, and the next target is
+/// the one that hashrate meets in `TARGET_SPACING`. The result is clamped
+/// against the parent's target and is never easier than `limit`, the
+/// network's starting difficulty.
+pub fn next_target(ancestors: &[BlockHeader], limit: Target) -> Result<Target> {
+    if ancestors.len() < 2 {
+        return Ok(limit);
+    }
+
+    let window = &ancestors[ancestors.len().saturating_sub(RETARGET_WINDOW + 1)..];
+    let intervals = window.len() as u64 - 1;
+    let parent = Target::from_compact(window[window.len() - 1].n_bits)?;
+
+    // Work, not targets: an average of targets is dominated by the easiest
+    // in the window and lags a rise in hashrate by the window's full length.
+    // Sixty works can pass 2^256, so the arithmetic is in 512 bits.
+    let mut work = U512::zero();
+    for header in &window[1..] {
+        work += U512::from(Target::from_compact(header.n_bits)?.work());
+    }
+
+    // Weighted by recency, so the newest blocks dominate. Each solve time is
+    // clamped: timestamps only have to beat a median, so one can appear to take
+    // no time or less than none, or a lying one can claim hours.
+    let mut weighted_time = 0u64;
+    for (weight, pair) in window.windows(2).enumerate() {
+        let solve_time = pair[1]
+            .time
+            .saturating_sub(pair[0].time)
+            .clamp(1, MAX_SOLVE_TIME);
+        weighted_time += (weight as u64 + 1) * u64::from(solve_time);
+    }
+    let weights = intervals * (intervals + 1) / 2;
+
+    // work / intervals is the average; weighted_time / weights the average
+    // solve time. Scaled to TARGET_SPACING, with the divisions last.
+    let next_work = (work * U512::from(weights) * U512::from(TARGET_SPACING)
+        / (U512::from(intervals) * U512::from(weighted_time)))
+    .max(U512::one());
+
+    let parent = U512::from(parent.0);
+    let next = (U512::from(U256::MAX) / next_work)
+        .clamp(
+            parent / U512::from(RETARGET_CLAMP),
+            parent * U512::from(RETARGET_CLAMP),
+        )
+        .min(U512::from(limit.0))
+        .max(U512::one());
+
+    let next = Target(U256::try_from(next).expect("clamped below a U256 limit"));
+
+    // Through the compact encoding and back, so the result is exactly the
+    // target that a header carrying it will be checked against.
+    Target::from_compact(next.to_compact())
+}
+
+/// The 256-bit proof-of-work threshold a header's hash must not exceed.
+///
+/// Only reachable through `from_compact`, so a `Target` is never negative,
+/// never zero and never wider than 256 bits: those are refused at the boundary
+/// rather than checked wherever a target is used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Target(U256);
+
+impl Target {
+    /// Decodes `n_bits`: a one-byte exponent counting the target's width in
+    /// bytes, then a three-byte mantissa holding its most significant bytes.
+    /// The mantissa's own width is why the shift is `exponent - 3` bytes and
+    /// not `exponent`.
+    pub fn from_compact(n_bits: u32) -> Result<Target> {
+        let exponent = n_bits >> 24;
+        let mantissa = n_bits & MANTISSA_MASK;
+
+        // The encoding is signed, a legacy of the bignum library it was
+        // designed around. A negative target is meaningless, not merely hard.
+        if mantissa != 0 && n_bits & SIGN_BIT != 0 {
+            return Err(anyhow!("n_bits {n_bits:#010x} encodes a negative target"));
+        }
+
+        // Judged on the encoding, before shifting: U256's shift would drop the
+        // high bytes and hand back a smaller target than the one claimed.
+        let overflows = mantissa != 0
+            && (exponent > 34
+                || (mantissa > 0xff && exponent > 33)
+                || (mantissa > 0xffff && exponent > 32));
+        if overflows {
+            return Err(anyhow!("n_bits {n_bits:#010x} overflows 256 bits"));
+        }
+
+        let target = if exponent <= 3 {
+            U256::from(mantissa >> (8 * (3 - exponent)))
+        } else {
+            U256::from(mantissa) << (8 * (exponent - 3))
+        };
+
+        if target.is_zero() {
+            return Err(anyhow!("n_bits {n_bits:#010x} encodes a zero target"));
+        }
+
+        Ok(Target(target))
+    }
+
+    /// The shortest encoding of this target. Precision below the mantissa's
+    /// three bytes is lost, so a round trip is exact only for targets that
+    /// came from a compact encoding in the first place.
+    pub fn to_compact(self) -> u32 {
+        let mut size = self.0.bits().div_ceil(8) as u32;
+
+        let mut mantissa = if size <= 3 {
+            self.0.low_u32() << (8 * (3 - size))
+        } else {
+            (self.0 >> (8 * (size - 3))).low_u32()
+        };
+
+        // A mantissa with its top bit set would read back as negative, so it
+        // gives up its lowest byte to a wider exponent instead.
+        if mantissa & SIGN_BIT != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+
+        size << 24 | mantissa
+    }
+
+    /// Whether a header hash, as HASH256 returns it, meets this target.
+    pub fn is_met_by(self, hash: &[u8; 32]) -> bool {
+        U256::from_little_endian(hash) <= self.0
+    }
+
+    /// The expected number of hashes needed to meet this target:
+    /// `2^256 / (target + 1)`. Cumulative work is the sum of this over a
+    /// branch, which is what makes it the chain-selection rule and height not.
+    pub fn work(self) -> U256 {
+        // 2^256 does not fit in a U256. Since `!target` is `2^256 - 1 - target`,
+        // `!target / (target + 1) + 1` is the same quotient without it.
+        match self.0.checked_add(U256::one()) {
+            Some(divisor) => !self.0 / divisor + U256::one(),
+            None => U256::one(),
+        }
+    }
+
+    pub fn as_u256(self) -> U256 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::get_hash;
+    use hex::decode;
+    use rstest::rstest;
+
+    fn target(displayed: &str) -> U256 {
+        U256::from_big_endian(&decode(displayed).unwrap())
+    }
+
+    #[rstest]
+    #[case::bitcoin_genesis(
+        0x1d00ffff,
+        "00000000ffff0000000000000000000000000000000000000000000000000000"
+    )]
+    #[case::bitcoin_block_100000(
+        0x1b04864c,
+        "000000000004864c000000000000000000000000000000000000000000000000"
+    )]
+    #[case::bitcoin_block_800000(
+        0x17023c7e,
+        "000000000000000000023c7e0000000000000000000000000000000000000000"
+    )]
+    #[case::bitcoin_regtest(
+        0x207fffff,
+        "7fffff0000000000000000000000000000000000000000000000000000000000"
+    )]
+    fn a_real_n_bits_decodes_to_its_published_target(#[case] n_bits: u32, #[case] displayed: &str) {
+        assert_eq!(
+            target(displayed),
+            Target::from_compact(n_bits).unwrap().as_u256(),
+            "the mantissa is shifted by exponent - 3 bytes, not by exponent"
+        );
+    }
+
+    #[rstest]
+    #[case::exponent_one(0x01123456, 0x12)]
+    #[case::exponent_two(0x02123456, 0x1234)]
+    #[case::exponent_three(0x03123456, 0x123456)]
+    #[case::exponent_four(0x04123456, 0x12345600)]
+    #[case::leading_zero_mantissa(0x05009234, 0x92340000)]
+    fn a_small_exponent_shifts_the_mantissa_right_rather_than_left(
+        #[case] n_bits: u32,
+        #[case] expected: u64,
+    ) {
+        assert_eq!(
+            U256::from(expected),
+            Target::from_compact(n_bits).unwrap().as_u256()
+        );
+    }
+
+    #[rstest]
+    #[case::sign_bit(0x04923456)]
+    #[case::sign_bit_with_a_small_exponent(0x01fedcba)]
+    fn a_negative_target_is_refused(#[case] n_bits: u32) {
+        let error = Target::from_compact(n_bits).expect_err("a target cannot be negative");
+
+        assert!(format!("{error:#}").contains("negative"), "got: {error:#}");
+    }
+
+    #[rstest]
+    #[case::exponent_past_34(0x23000001)]
+    #[case::two_bytes_past_33(0x22000100)]
+    #[case::three_bytes_past_32(0x21010000)]
+    #[case::largest_exponent(0xff123456)]
+    fn a_target_wider_than_256_bits_is_refused(#[case] n_bits: u32) {
+        let error = Target::from_compact(n_bits)
+            .expect_err("an overflowing target must not be silently truncated");
+
+        assert!(format!("{error:#}").contains("overflows"), "got: {error:#}");
+    }
+
+    #[test]
+    fn the_widest_legal_encoding_is_not_mistaken_for_an_overflow() {
+        assert_eq!(
+            U256::from(0xffu8) << 248,
+            Target::from_compact(0x220000ff).unwrap().as_u256()
+        );
+    }
+
+    #[rstest]
+    #[case::zero_mantissa(0x1d000000)]
+    #[case::shifted_out(0x01003456)]
+    #[case::sign_bit_on_nothing(0x04800000)]
+    fn a_target_no_hash_can_meet_is_refused(#[case] n_bits: u32) {
+        Target::from_compact(n_bits).expect_err("a zero target accepts nothing");
+    }
+
+    #[rstest]
+    #[case(0x1d00ffff)]
+    #[case(0x1b04864c)]
+    #[case(0x17023c7e)]
+    #[case(0x207fffff)]
+    #[case(0x05009234)]
+    #[case(0x03123456)]
+    fn a_canonical_encoding_survives_a_round_trip(#[case] n_bits: u32) {
+        assert_eq!(n_bits, Target::from_compact(n_bits).unwrap().to_compact());
+    }
+
+    #[test]
+    fn a_mantissa_that_would_read_as_negative_moves_to_a_wider_exponent() {
+        let target = Target::from_compact(0x04008000).unwrap();
+
+        assert_eq!(U256::from(0x80_0000u64), target.as_u256());
+        assert_eq!(
+            0x04008000,
+            target.to_compact(),
+            "0x03800000 is the same digits with the sign bit set"
+        );
+    }
+
+    #[rstest]
+    #[case::bitcoin_genesis(0x1d00ffff, 0x1_0001_0001)]
+    #[case::bitcoin_block_100000(0x1b04864c, 62_209_952_899_966)]
+    #[case::bitcoin_regtest(0x207fffff, 2)]
+    fn work_is_two_to_the_256_over_the_target_plus_one(#[case] n_bits: u32, #[case] expected: u64) {
+        // Bitcoin reports chainwork 0x100010001 at its genesis block, and 2 at
+        // regtest's: a single block's work is the whole chain's there.
+        assert_eq!(
+            U256::from(expected),
+            Target::from_compact(n_bits).unwrap().work()
+        );
+    }
+
+    #[test]
+    fn a_harder_target_is_more_work() {
+        let easy = Target::from_compact(0x1d00ffff).unwrap();
+        let hard = Target::from_compact(0x1b04864c).unwrap();
+
+        assert!(hard < easy);
+        assert!(hard.work() > easy.work());
+    }
+
+    #[test]
+    fn a_real_header_meets_the_target_its_own_n_bits_encode() {
+        // Bitcoin block 800000. n_bits is bytes 72..76 of the header.
+        let header = decode("0060e42a66e55d1755f14ef39f83dd779f6f113e57d5d8ccf17601000000000000000000482556db9b7955df11d5663e377ed7e55eba6da43ce17cc741702f17cf33448323f894697e3c02174d19e6b3").unwrap();
+        let n_bits = u32::from_le_bytes(header[72..76].try_into().unwrap());
+        let hash = get_hash(&header);
+
+        assert_eq!(0x17023c7e, n_bits);
+        assert!(Target::from_compact(n_bits).unwrap().is_met_by(&hash));
+
+        let one_byte_harder = Target::from_compact(n_bits - 0x0100_0000).unwrap();
+        assert!(
+            !one_byte_harder.is_met_by(&hash),
+            "the header should not also meet a target 256 times smaller"
+        );
+    }
+
+    const STARTING: u32 = 0x1e0fffff;
+
+    /// `count` headers `spacing` seconds apart, all carrying `n_bits`.
+    fn spaced(count: usize, spacing: u32, n_bits: u32) -> Vec<BlockHeader> {
+        (0..count)
+            .map(|i| BlockHeader {
+                version: 1,
+                previous_block_hash: [0; 32],
+                merkle_root_hash: [0; 32],
+                time: 1_785_369_600 + i as u32 * spacing,
+                n_bits,
+                nonce: 0,
+            })
+            .collect()
+    }
+
+    fn limit() -> Target {
+        Target::from_compact(STARTING).unwrap()
+    }
+
+    /// What `value` becomes once a header has carried it as `n_bits`.
+    fn encoded(value: U256) -> Target {
+        Target::from_compact(Target(value).to_compact()).unwrap()
+    }
+
+    #[rstest]
+    #[case::genesis_alone(1)]
+    #[case::nothing(0)]
+    fn with_no_interval_to_measure_the_target_is_the_limit(#[case] count: usize) {
+        let ancestors = spaced(count, TARGET_SPACING, STARTING);
+
+        assert_eq!(limit(), next_target(&ancestors, limit()).unwrap());
+    }
+
+    #[test]
+    fn blocks_on_schedule_keep_the_target() {
+        let harder = 0x1d0fffff;
+        let ancestors = spaced(RETARGET_WINDOW + 1, TARGET_SPACING, harder);
+
+        assert_eq!(
+            harder,
+            next_target(&ancestors, limit()).unwrap().to_compact()
+        );
+    }
+
+    #[test]
+    fn blocks_twice_as_fast_halve_the_target() {
+        let ancestors = spaced(RETARGET_WINDOW + 1, TARGET_SPACING / 2, STARTING);
+
+        let next = next_target(&ancestors, limit()).unwrap();
+
+        assert_eq!(encoded(limit().as_u256() / 2), next);
+    }
+
+    #[test]
+    fn the_target_never_becomes_easier_than_the_limit() {
+        let ancestors = spaced(RETARGET_WINDOW + 1, TARGET_SPACING * 10, STARTING);
+
+        assert_eq!(limit(), next_target(&ancestors, limit()).unwrap());
+    }
+
+    #[test]
+    fn one_block_cannot_ease_the_target_more_than_the_clamp() {
+        let hard = 0x1c0fffff;
+        let ancestors = spaced(RETARGET_WINDOW + 1, TARGET_SPACING * 1000, hard);
+
+        let next = next_target(&ancestors, limit()).unwrap();
+
+        assert_eq!(
+            encoded(Target::from_compact(hard).unwrap().as_u256() * RETARGET_CLAMP),
+            next,
+            "hashrate leaving is followed at once, but at most by the clamp"
+        );
+    }
+
+    #[test]
+    fn timestamps_that_stand_still_cannot_make_the_target_harder_than_the_clamp() {
+        let mut ancestors = spaced(RETARGET_WINDOW + 1, TARGET_SPACING, STARTING);
+        let start = ancestors[0].time;
+        for (i, header) in ancestors.iter_mut().enumerate() {
+            // Alternately equal and backwards: legal, as long as each beats a median.
+            header.time = start - (i as u32 % 2);
+        }
+
+        let next = next_target(&ancestors, limit()).unwrap();
+
+        assert_eq!(encoded(limit().as_u256() / RETARGET_CLAMP), next);
+    }
+
+    #[test]
+    fn only_the_window_is_read() {
+        let recent = spaced(RETARGET_WINDOW + 1, TARGET_SPACING, 0x1d0fffff);
+        let mut long_history = spaced(500, 1, 0x1c00ffff);
+        long_history.extend(recent.iter().copied());
+
+        assert_eq!(
+            next_target(&recent, limit()).unwrap(),
+            next_target(&long_history, limit()).unwrap()
+        );
+    }
+
+    #[test]
+    fn a_thousandfold_hashrate_rise_is_absorbed_in_tens_of_blocks() {
+        let mut chain = spaced(RETARGET_WINDOW + 1, TARGET_SPACING, STARTING);
+        let hashes_per_second = limit().work() * 1000 / TARGET_SPACING;
+
+        let mut blocks = 0;
+        loop {
+            let target = next_target(&chain, limit()).unwrap();
+            let seconds = (target.work() / hashes_per_second).low_u32();
+            if seconds * 2 >= TARGET_SPACING {
+                break;
+            }
+
+            let mut next = *chain.last().unwrap();
+            next.time += seconds;
+            next.n_bits = target.to_compact();
+            chain.push(next);
+            blocks += 1;
+        }
+
+        assert!(
+            (10..100).contains(&blocks),
+            "took {blocks} blocks to reach half the target spacing"
+        );
+    }
+
+    #[test]
+    fn a_hash_equal_to_the_target_meets_it() {
+        let target = Target::from_compact(0x03123456).unwrap();
+        let mut hash = [0u8; 32];
+        hash[..3].copy_from_slice(&[0x56, 0x34, 0x12]);
+
+        assert!(target.is_met_by(&hash), "the bound is inclusive");
+
+        hash[0] += 1;
+        assert!(!target.is_met_by(&hash));
+    }
+}
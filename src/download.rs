@@ -0,0 +1,443 @@
+use crate::block::Block;
+use crate::compact::PartialBlock;
+use crate::messages::get_data::GetData;
+use crate::messages::inventory::Inventory;
+use crate::messages::message::Message;
+use crate::node::{record, Node, PeerId, SharedNode};
+use crate::outbound::Sent;
+use crate::util::display_hash;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How far past the connected tip bodies are requested. Blocks arrive out of
+/// order from several peers, so much of the window may be held at once.
+pub const DOWNLOAD_WINDOW: usize = 1024;
+
+/// The most that blocks held ahead of the tip may weigh, in bytes. Past it,
+/// only the lowest missing block is asked for: a full window of full blocks
+/// would be a gigabyte.
+pub const MAX_ARRIVED_SIZE: usize = 32 * 1024 * 1024;
+
+/// Requests outstanding to one peer at a time: enough to keep a fast peer
+/// busy, few enough that one slow peer holds back little of the window.
+pub const MAX_IN_FLIGHT_PER_PEER: usize = 16;
+
+/// The lowest missing block asked for this long ago and not delivered means
+/// its peer is stalling the window, and its requests go to someone else.
+pub const BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TICK: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct InFlight {
+    peer: PeerId,
+    since: Instant,
+}
+
+/// A block delivered ahead of the tip, and who sent it: the one to blame if
+/// it will not connect once its turn comes.
+#[derive(Debug)]
+struct Arrived {
+    block: Block,
+    from: PeerId,
+}
+
+/// What `connect_arrived` did.
+#[derive(Debug, Default)]
+pub struct Connected {
+    /// How many connected, and the tip they left, if any did.
+    pub tip: Option<(usize, [u8; 32], u32)>,
+    /// An arrived block that would not connect: who sent it, and why. Its
+    /// header, and every one built on it, are forgotten.
+    pub refused: Option<(PeerId, anyhow::Error)>,
+}
+
+/// Block bodies requested and not yet connected. Every peer that has shown us
+/// a header is a source, and each is given what the others have not been.
+#[derive(Debug, Default)]
+pub struct Downloads {
+    in_flight: HashMap<[u8; 32], InFlight>,
+    /// Delivered ahead of a block still missing below them.
+    arrived: HashMap<[u8; 32], Arrived>,
+    /// What `arrived` weighs, in bytes.
+    arrived_size: usize,
+    /// Compact blocks waiting on a `blocktxn`. Each is also in flight, from
+    /// whoever announced it.
+    partial: HashMap<[u8; 32], PartialBlock>,
+}
+
+impl Downloads {
+    /// Hands each of `wanted` that is neither in flight nor arrived to the
+    /// least-loaded of `sources` whose best header is at least as high. With
+    /// `MAX_ARRIVED_SIZE` held, only the first of `wanted` is: nothing else
+    /// would let what is held connect.
+    pub fn assign(
+        &mut self,
+        wanted: &[([u8; 32], u32)],
+        sources: &[(PeerId, u32)],
+        now: Instant,
+    ) -> Vec<(PeerId, Vec<[u8; 32]>)> {
+        let wanted = if self.arrived_size >= MAX_ARRIVED_SIZE {
+            &wanted[..wanted.len().min(1)]
+        } else {
+            wanted
+        };
+        let mut load: HashMap<PeerId, usize> = HashMap::new();
+        for request in self.in_flight.values() {
+            *load.entry(request.peer).or_default() += 1;
+        }
+
+        let mut assigned: HashMap<PeerId, Vec<[u8; 32]>> = HashMap::new();
+        for (hash, height) in wanted {
+            if self.in_flight.contains_key(hash) || self.arrived.contains_key(hash) {
+                continue;
+            }
+
+            let Some(peer) = sources
+                .iter()
+                .filter(|(peer, best)| {
+                    best >= height
+                        && load.get(peer).copied().unwrap_or_default() < MAX_IN_FLIGHT_PER_PEER
+                })
+                .min_by_key(|(peer, _)| load.get(peer).copied().unwrap_or_default())
+                .map(|(peer, _)| *peer)
+            else {
+                continue;
+            };
+
+            *load.entry(peer).or_default() += 1;
+            self.in_flight.insert(*hash, InFlight { peer, since: now });
+            assigned.entry(peer).or_default().push(*hash);
+        }
+
+        assigned.into_iter().collect()
+    }
+
+    /// Takes `hash` as in flight from `peer`, who announced it as a compact
+    /// block: the body is theirs to complete, and asked of no one else. False
+    /// if it is already someone's, or here.
+    pub fn claim(&mut self, hash: [u8; 32], peer: PeerId, now: Instant) -> bool {
+        if self.in_flight.contains_key(&hash) || self.arrived.contains_key(&hash) {
+            return false;
+        }
+        self.in_flight.insert(hash, InFlight { peer, since: now });
+        true
+    }
+
+    /// Holds a claimed compact block until its missing transactions arrive.
+    pub fn await_transactions(&mut self, partial: PartialBlock) {
+        self.partial.insert(partial.hash(), partial);
+    }
+
+    /// The compact block `peer` owes us transactions for, if they do.
+    pub fn take_partial(&mut self, hash: &[u8; 32], peer: PeerId) -> Option<PartialBlock> {
+        if self.requested_from(hash) != Some(peer) {
+            return None;
+        }
+        self.partial.remove(hash)
+    }
+
+    /// Who `hash` was asked of, if anyone: a block from anyone else was not
+    /// requested, and is not let into the window.
+    pub fn requested_from(&self, hash: &[u8; 32]) -> Option<PeerId> {
+        self.in_flight.get(hash).map(|request| request.peer)
+    }
+
+    /// Holds a block requested of `from` until everything below it has
+    /// connected.
+    pub fn deliver(&mut self, hash: [u8; 32], block: Block, from: PeerId) {
+        self.in_flight.remove(&hash);
+        self.partial.remove(&hash);
+        self.arrived_size += block.size();
+        if let Some(replaced) = self.arrived.insert(hash, Arrived { block, from }) {
+            self.arrived_size -= replaced.block.size();
+        }
+    }
+
+    /// Forgets one request, if it was `peer`'s: they have said they cannot
+    /// serve it.
+    pub fn cancel(&mut self, hash: &[u8; 32], peer: PeerId) -> bool {
+        if self.requested_from(hash) != Some(peer) {
+            return false;
+        }
+        self.in_flight.remove(hash);
+        self.partial.remove(hash);
+        true
+    }
+
+    /// An arrived block, and who sent it.
+    pub fn take(&mut self, hash: &[u8; 32]) -> Option<(Block, PeerId)> {
+        let arrived = self.arrived.remove(hash)?;
+        self.arrived_size -= arrived.block.size();
+        Some((arrived.block, arrived.from))
+    }
+
+    /// Drops whatever is requested or held of `hashes`: blocks no longer
+    /// wanted, whoever was to send them.
+    pub fn forget(&mut self, hashes: &[[u8; 32]]) {
+        for hash in hashes {
+            self.in_flight.remove(hash);
+            self.partial.remove(hash);
+            self.take(hash);
+        }
+    }
+
+    /// Returns `peer`'s requests to the pool, for the next `assign` to hand
+    /// to someone else. How many there were.
+    pub fn release(&mut self, peer: PeerId) -> usize {
+        let before = self.in_flight.len();
+        self.in_flight.retain(|_, request| request.peer != peer);
+        self.partial
+            .retain(|hash, _| self.in_flight.contains_key(hash));
+        before - self.in_flight.len()
+    }
+
+    /// The peer asked for `lowest`, the block everything held is waiting on,
+    /// if they were asked more than `BLOCK_TIMEOUT` ago. A peer slow with
+    /// anything higher holds up no one, and is left to it.
+    pub fn stalled(&self, lowest: &[u8; 32], now: Instant) -> Option<PeerId> {
+        self.in_flight
+            .get(lowest)
+            .filter(|request| now.saturating_duration_since(request.since) >= BLOCK_TIMEOUT)
+            .map(|request| request.peer)
+    }
+}
+
+/// Asks sources for whatever of the window is unassigned. Called with the
+/// node locked: after headers arrive, after a block does, and on every tick.
+pub fn request_blocks(node: &mut Node, now: Instant) -> Result<()> {
+    let wanted = node.chain.missing_bodies(DOWNLOAD_WINDOW);
+    if wanted.is_empty() {
+        return Ok(());
+    }
+
+    let sources = node.peers.sources();
+    for (peer, hashes) in node.downloads.assign(&wanted, &sources, now) {
+        let request = GetData {
+            inventory: hashes.into_iter().map(Inventory::Block).collect(),
+        };
+        let bytes = Message::new(node.params.magic, request)?.get_raw_format()?;
+
+        // A peer too backed up to take the request is dropped by `send_to`,
+        // and what was meant for it goes back to the pool; so does what was
+        // never sent, whatever the reason.
+        if node.peers.send_to(peer, bytes) != Sent::Queued {
+            node.downloads.release(peer);
+        }
+    }
+
+    Ok(())
+}
+
+/// Connects every arrived block that now follows the tip, in order. One that
+/// will not connect stops it: its header is forgotten with everything built
+/// on it, so it is not asked for again, and its sender is returned to be
+/// charged — not whichever peer's delivery happened to reach it.
+pub fn connect_arrived(node: &mut Node) -> Connected {
+    let mut connected = 0;
+    let mut refused = None;
+
+    while let Some(&(next, _)) = node.chain.missing_bodies(1).first() {
+        let Some((block, from)) = node.downloads.take(&next) else {
+            break;
+        };
+        let confirmed = block.transactions.clone();
+        if let Err(e) = node.chain.connect(block, &node.params) {
+            let forgotten = node.chain.invalidate(&next);
+            node.downloads.forget(&forgotten);
+            refused = Some((from, e));
+            break;
+        }
+        node.mempool.remove_confirmed(&confirmed);
+        connected += 1;
+    }
+
+    Connected {
+        tip: (connected > 0).then(|| (connected, node.chain.tip_hash(), node.chain.height())),
+        refused,
+    }
+}
+
+/// The scheduler's own thread: reassigns what a stalling peer is sitting on,
+/// and drops them. Without it a peer that never answers would hold the
+/// lowest missing block, and with it the whole window, forever.
+pub fn run(node: SharedNode) {
+    loop {
+        thread::sleep(TICK);
+        tick(&node, Instant::now());
+    }
+}
+
+fn tick(node: &SharedNode, now: Instant) {
+    let (dropped, requested) = {
+        let mut locked = node.lock().expect("node lock poisoned");
+        let locked = &mut *locked;
+        let lowest = locked
+            .chain
+            .missing_bodies(1)
+            .first()
+            .map(|(hash, _)| *hash);
+        let dropped = lowest.and_then(|hash| {
+            let peer = locked.downloads.stalled(&hash, now)?;
+            locked.downloads.release(peer);
+            locked
+                .peers
+                .remove(peer)
+                .map(|handle| (handle.address, hash))
+        });
+
+        (dropped, request_blocks(locked, now))
+    };
+
+    if let Some((address, hash)) = dropped {
+        record(
+            node,
+            format!(
+                "Dropping {address}: block {} not delivered within {BLOCK_TIMEOUT:?}",
+                display_hash(&hash)
+            ),
+        );
+    }
+    if let Err(e) = requested {
+        record(node, format!("Could not request blocks: {e:#}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wanted(count: u8) -> Vec<([u8; 32], u32)> {
+        (1..=count).map(|i| ([i; 32], u32::from(i))).collect()
+    }
+
+    fn assigned_to(assigned: &[(PeerId, Vec<[u8; 32]>)], peer: PeerId) -> Vec<[u8; 32]> {
+        assigned
+            .iter()
+            .find(|(id, _)| *id == peer)
+            .map(|(_, hashes)| hashes.clone())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn the_window_is_spread_across_every_source() {
+        let mut downloads = Downloads::default();
+
+        let assigned = downloads.assign(&wanted(8), &[(1, 100), (2, 100)], Instant::now());
+
+        assert_eq!(4, assigned_to(&assigned, 1).len());
+        assert_eq!(4, assigned_to(&assigned, 2).len());
+    }
+
+    #[test]
+    fn no_source_is_given_more_than_its_share_in_flight() {
+        let mut downloads = Downloads::default();
+
+        let assigned = downloads.assign(&wanted(40), &[(1, 100)], Instant::now());
+
+        assert_eq!(MAX_IN_FLIGHT_PER_PEER, assigned_to(&assigned, 1).len());
+    }
+
+    #[test]
+    fn a_source_is_not_asked_for_a_block_above_its_best_header() {
+        let mut downloads = Downloads::default();
+
+        let assigned = downloads.assign(&wanted(4), &[(1, 2)], Instant::now());
+
+        assert_eq!(vec![[1; 32], [2; 32]], assigned_to(&assigned, 1));
+    }
+
+    #[test]
+    fn what_is_in_flight_or_arrived_is_not_asked_for_again() {
+        let mut downloads = Downloads::default();
+        let now = Instant::now();
+        downloads.assign(&wanted(2), &[(1, 100)], now);
+        downloads.deliver([1; 32], Block::new(1, [0; 32], 0, 0, Vec::new()), 1);
+
+        let again = downloads.assign(&wanted(3), &[(2, 100)], now);
+
+        assert_eq!(vec![[3; 32]], assigned_to(&again, 2));
+    }
+
+    #[test]
+    fn a_released_peers_requests_go_to_another() {
+        let mut downloads = Downloads::default();
+        let now = Instant::now();
+        downloads.assign(&wanted(3), &[(1, 100)], now);
+
+        assert_eq!(3, downloads.release(1));
+        let reassigned = downloads.assign(&wanted(3), &[(2, 100)], now);
+
+        assert_eq!(3, assigned_to(&reassigned, 2).len());
+        assert_eq!(Some(2), downloads.requested_from(&[1; 32]));
+    }
+
+    #[test]
+    fn a_claimed_block_is_asked_of_no_one_else() {
+        let mut downloads = Downloads::default();
+        let now = Instant::now();
+
+        assert!(downloads.claim([1; 32], 1, now));
+        assert!(!downloads.claim([1; 32], 2, now), "already theirs");
+        let assigned = downloads.assign(&wanted(2), &[(2, 100)], now);
+
+        assert_eq!(vec![[2; 32]], assigned_to(&assigned, 2));
+        assert_eq!(Some(1), downloads.requested_from(&[1; 32]));
+    }
+
+    #[test]
+    fn only_the_peer_a_block_was_asked_of_can_cancel_it() {
+        let mut downloads = Downloads::default();
+        downloads.assign(&wanted(1), &[(1, 100)], Instant::now());
+
+        assert!(!downloads.cancel(&[1; 32], 2), "not asked of them");
+        assert_eq!(Some(1), downloads.requested_from(&[1; 32]));
+
+        assert!(downloads.cancel(&[1; 32], 1));
+        assert_eq!(None, downloads.requested_from(&[1; 32]));
+    }
+
+    #[test]
+    fn only_the_lowest_missing_block_asked_for_too_long_ago_is_a_stall() {
+        let mut downloads = Downloads::default();
+        let then = Instant::now();
+        downloads.assign(&wanted(1), &[(1, 100)], then);
+        downloads.assign(&wanted(2), &[(2, 100)], then);
+        let later = then + BLOCK_TIMEOUT;
+
+        assert_eq!(
+            None,
+            downloads.stalled(&[1; 32], later - Duration::from_millis(1))
+        );
+        assert_eq!(Some(1), downloads.stalled(&[1; 32], later));
+        assert_eq!(
+            Some(2),
+            downloads.stalled(&[2; 32], later),
+            "whoever holds the lowest"
+        );
+        downloads.deliver([1; 32], Block::new(1, [0; 32], 0, 0, Vec::new()), 1);
+        assert_eq!(None, downloads.stalled(&[1; 32], later), "delivered");
+    }
+
+    #[test]
+    fn with_too_much_held_only_the_lowest_missing_block_is_asked_for() {
+        let mut downloads = Downloads::default();
+        let mut heavy = Block::new(1, [0; 32], 0, 0, Vec::new());
+        heavy.transactions = vec![crate::transaction::Transaction::coinbase(
+            "x".repeat(MAX_ARRIVED_SIZE),
+            Vec::new(),
+        )];
+        downloads.deliver([9; 32], heavy, 1);
+
+        let assigned = downloads.assign(&wanted(4), &[(1, 100)], Instant::now());
+
+        assert_eq!(vec![[1; 32]], assigned_to(&assigned, 1));
+
+        downloads.take(&[9; 32]);
+        let assigned = downloads.assign(&wanted(4), &[(1, 100)], Instant::now());
+
+        assert_eq!(3, assigned_to(&assigned, 1).len(), "room again");
+    }
+}
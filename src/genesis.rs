@@ -0,0 +1,183 @@
+use crate::params::{genesis_block, Allocation, Network, Params};
+use crate::util::display_hash;
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::path::Path;
+
+/// The `genesis` subcommand. Regenerates each network's committed genesis from
+/// its allocation file in `dir`, or with `check` only verifies them, failing
+/// on the first that no longer matches.
+pub fn run(networks: &[Network], dir: &Path, check: bool) -> Result<()> {
+    for network in networks {
+        let path = dir.join(format!("{network}.toml"));
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+
+        if check {
+            let params = Params::from_allocation(*network, &text).with_context(|| {
+                format!(
+                    "{} is stale; run `cargo run -- genesis {network}` and commit the result",
+                    path.display()
+                )
+            })?;
+            let hash = params.genesis.hash.expect("a derived genesis is sealed");
+            println!(
+                "{}: genesis {} matches",
+                path.display(),
+                display_hash(&hash)
+            );
+        } else {
+            let regenerated = regenerate(*network, &text)?;
+            fs::write(&path, &regenerated)
+                .with_context(|| format!("could not write {}", path.display()))?;
+
+            let params = Params::from_allocation(*network, &regenerated)?;
+            let hash = params.genesis.hash.expect("a derived genesis is sealed");
+            println!("{}: genesis {}", path.display(), display_hash(&hash));
+        }
+    }
+
+    Ok(())
+}
+
+/// The allocation text with its genesis `time`, `nonce` and `hash` replaced
+/// by a freshly mined set. The committed time is kept unless no nonce meets
+/// the target under it.
+fn regenerate(network: Network, text: &str) -> Result<String> {
+    let allocation: Allocation =
+        toml::from_str(text).context("the allocation could not be understood")?;
+    let mut block = genesis_block(network, &allocation);
+
+    while !block.mine()? {
+        block.time = block
+            .time
+            .checked_add(1)
+            .ok_or_else(|| anyhow!("no time left to search"))?;
+    }
+    let hash = block.hash.expect("a mined block has a hash");
+
+    rewrite(
+        text,
+        &[
+            ("time", block.time.to_string()),
+            ("nonce", block.nonce.to_string()),
+            ("hash", format!("\"{}\"", display_hash(&hash))),
+        ],
+    )
+}
+
+/// Line edits rather than a round trip through `toml`, which would drop the
+/// comments that explain what an allocation is for.
+fn rewrite(text: &str, values: &[(&str, String)]) -> Result<String> {
+    let mut in_genesis = false;
+    let mut replaced = Vec::new();
+    let mut lines = Vec::new();
+
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_genesis = trimmed == "[genesis]";
+        }
+
+        let key = trimmed.split('=').next().unwrap_or_default().trim();
+        match values.iter().find(|(name, _)| in_genesis && *name == key) {
+            Some((name, value)) => {
+                lines.push(format!("{name} = {value}"));
+                replaced.push(*name);
+            }
+            None => lines.push(line.to_string()),
+        }
+    }
+
+    if let Some((missing, _)) = values.iter().find(|(name, _)| !replaced.contains(name)) {
+        return Err(anyhow!("the [genesis] table has no {missing} to replace"));
+    }
+
+    Ok(lines.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const COMMITTED: &str = include_str!("../params/regtest.toml");
+
+    /// A directory of its own per call, so neither two runs at once nor one
+    /// left behind by an earlier run can meet another's files.
+    fn scratch(name: &str, allocation: &str) -> std::path::PathBuf {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "avicoin-genesis-{name}-{}-{}",
+            std::process::id(),
+            CALLS.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("regtest.toml"), allocation).unwrap();
+        dir
+    }
+
+    #[test]
+    fn every_committed_allocation_passes_the_check() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("params");
+
+        run(&Network::ALL, &dir, true).unwrap();
+    }
+
+    #[test]
+    fn an_edited_allocation_fails_the_check_and_names_the_fix() {
+        let edited = COMMITTED.replacen("value = 100000000000", "value = 5", 1);
+        let dir = scratch("stale", &edited);
+
+        let error = run(&[Network::Regtest], &dir, true).expect_err("stale genesis");
+
+        assert!(
+            format!("{error:#}").contains("cargo run -- genesis regtest"),
+            "got: {error:#}"
+        );
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn regenerating_an_edited_allocation_makes_it_pass_again() {
+        let edited = COMMITTED.replacen("value = 100000000000", "value = 5", 1);
+        let dir = scratch("regenerate", &edited);
+
+        run(&[Network::Regtest], &dir, false).unwrap();
+
+        run(&[Network::Regtest], &dir, true).expect("the rewritten file should now match");
+        let rewritten = fs::read_to_string(dir.join("regtest.toml")).unwrap();
+        assert!(
+            rewritten.contains("value = 5"),
+            "the edit itself must be kept"
+        );
+        assert!(
+            rewritten.starts_with("# Regtest genesis"),
+            "comments must survive a rewrite"
+        );
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn regenerating_a_current_allocation_changes_nothing() {
+        assert_eq!(COMMITTED, regenerate(Network::Regtest, COMMITTED).unwrap());
+    }
+
+    #[test]
+    fn only_the_genesis_table_is_rewritten() {
+        let text = "[genesis]\ntime = 1\nnonce = 2\nhash = \"ab\"\n\n[other]\nnonce = 9\n";
+
+        let rewritten = rewrite(text, &[("nonce", "7".to_string())]).unwrap();
+
+        assert_eq!(
+            "[genesis]\ntime = 1\nnonce = 7\nhash = \"ab\"\n\n[other]\nnonce = 9\n",
+            rewritten
+        );
+    }
+
+    #[test]
+    fn a_missing_key_is_an_error_rather_than_a_silent_skip() {
+        rewrite("[genesis]\ntime = 1\n", &[("nonce", "7".to_string())])
+            .expect_err("a nonce that was never written cannot be committed");
+    }
+}
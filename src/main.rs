@@ -1,33 +1,95 @@
-use crate::config::get_config;
+use crate::address_book::{AddressBook, ADDRESS_BOOK_FILE};
+use crate::config::{get_invocation, Invocation, Tool};
 use crate::node::{record, Node};
-use crate::protocol::{connect, listen};
+use crate::params::{Network, Params};
+use crate::protocol::listen;
+use crate::util::display_hash;
 use anyhow::{Context, Result};
 use std::net::TcpListener;
 use std::sync::Arc;
 use std::thread;
 
+mod address_book;
 mod block;
 mod block_storage;
+mod blockchain;
 mod byte_reader;
+mod compact;
 mod config;
+mod connections;
+mod console;
+mod difficulty;
+mod download;
+mod genesis;
+mod mempool;
 mod messages;
+mod mining;
+mod misbehavior;
 mod node;
+mod outbound;
+mod params;
 mod protocol;
 mod transaction;
 mod util;
+mod utxo;
 mod wallet;
 
 fn main() -> Result<()> {
-    let node = Node::shared(get_config()?);
+    let config = match get_invocation()? {
+        Invocation::Node(config) => config,
+        Invocation::Tool(Tool::Genesis {
+            network,
+            check,
+            dir,
+        }) => {
+            let networks = match network {
+                Some(network) => vec![network],
+                None => Network::ALL.to_vec(),
+            };
+            return genesis::run(&networks, &dir, check);
+        }
+    };
+
+    // Derived and checked before anything listens: a node whose genesis does
+    // not match its allocation must not join a network, however briefly.
+    let params = Params::of(config.network)?;
+    let genesis = params
+        .genesis
+        .hash
+        .map(|hash| display_hash(&hash))
+        .unwrap_or_default();
+    let network = params.network;
+    let node = Node::shared(config, params);
+    record(
+        &node,
+        format!("On the {network} network, genesis {genesis}"),
+    );
 
-    let (host_address, addresses_to_connect) = {
+    let (host_address, configured, address_book) = {
         let node = node.lock().expect("node lock poisoned");
         (
             node.config.host_address,
-            node.config.addresses_to_connect.clone(),
+            !node.config.addresses_to_connect.is_empty(),
+            node.config.network_dir().join(ADDRESS_BOOK_FILE),
         )
     };
 
+    // A book that cannot be read costs only what it knew: the node starts
+    // without it rather than not at all, and the next save replaces it.
+    match AddressBook::load(&address_book) {
+        Ok(book) => {
+            let known = book.len();
+            node.lock().expect("node lock poisoned").addresses = book;
+            if known > 0 {
+                record(
+                    &node,
+                    format!("Loaded {known} addresses from {}", address_book.display()),
+                );
+            }
+        }
+        Err(e) => record(&node, format!("Starting with no known addresses: {e:#}")),
+    }
+
     // Bound here, not in the thread: a bind failure must fail the process.
     let listener = TcpListener::bind(host_address)
         .with_context(|| format!("could not listen on {host_address}"))?;
@@ -39,21 +101,25 @@ fn main() -> Result<()> {
 
     record(&node, format!("Listening on {host_address}"));
 
-    if addresses_to_connect.is_empty() {
+    let dialling = configured
+        || !node.lock().expect("node lock poisoned").addresses.is_empty();
+    if !dialling {
         record(
             &node,
             "No peers configured; waiting for inbound connections",
         );
     }
 
+    let console_node = Arc::clone(&node);
+    thread::spawn(move || console::run(console_node, std::io::stdin().lock()));
+
+    let download_node = Arc::clone(&node);
+    thread::spawn(move || download::run(download_node));
+
     let listening_node = Arc::clone(&node);
     let handle = thread::spawn(move || listen(listener, listening_node));
 
-    for addr in addresses_to_connect {
-        if let Err(e) = connect(addr, Arc::clone(&node)) {
-            record(&node, format!("Could not connect to {addr}: {e:#}"));
-        }
-    }
+    thread::spawn(move || connections::run(node));
 
     handle
         .join()
@@ -0,0 +1,377 @@
+use crate::transaction::Transaction;
+use crate::utxo::{check_value, UtxoSet};
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+
+/// The most the pool holds, in serialised bytes: what every peer together can
+/// make us keep for them.
+pub const MAX_MEMPOOL_SIZE: usize = 32 * 1024 * 1024;
+
+/// What became of a transaction offered to the pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    Accepted,
+    /// Already held: nothing to do, and nothing to relay.
+    Known,
+    /// Spends an output the held transaction already spends. Whichever
+    /// arrived first stays; a block decides between them.
+    Conflicts([u8; 32]),
+    /// Spends an output the chain does not hold unspent: made up, spent
+    /// already, or not yet confirmed, which we cannot tell apart when we are
+    /// the one behind. Not held, and not held against the sender.
+    MissingInputs,
+    /// Spends a coinbase output the next block could not: valid once it
+    /// matures, so only refused.
+    Immature,
+    /// The pool is at `MAX_MEMPOOL_SIZE`. Refused rather than making room:
+    /// without fees there is nothing to choose what to drop by.
+    Full,
+}
+
+/// Transactions waiting for a block, by txid: what a `getdata` for a
+/// transaction is served from. Each spends only confirmed outputs, so what a
+/// block confirms can never strand a transaction that spent its parent.
+#[derive(Debug)]
+pub struct Mempool {
+    transactions: HashMap<[u8; 32], Transaction>,
+    /// Each output a held transaction spends, and which one spends it.
+    spent: HashMap<([u8; 32], u32), [u8; 32]>,
+    /// What everything held weighs, serialised.
+    size: usize,
+    max_size: usize,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::new(MAX_MEMPOOL_SIZE)
+    }
+}
+
+impl Mempool {
+    pub fn new(max_size: usize) -> Self {
+        Mempool {
+            transactions: HashMap::new(),
+            spent: HashMap::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    /// Checks `transaction` on its own, against the outputs it spends in
+    /// `utxos` and the network's coinbase `maturity`, and against what is
+    /// already held. An error means it could
+    /// never be valid, whoever sent it; any other refusal only that it lost
+    /// a race, or came at the wrong time.
+    pub fn admit(
+        &mut self,
+        transaction: Transaction,
+        utxos: &UtxoSet,
+        maturity: u32,
+    ) -> Result<Admission> {
+        let txid = transaction.get_tx_id();
+        if self.transactions.contains_key(&txid) {
+            return Ok(Admission::Known);
+        }
+        check_transaction(&transaction)?;
+
+        let conflict = transaction.inputs.iter().find_map(|input| {
+            let spends = (input.previous_output.tx_id, input.previous_output.v_out);
+            self.spent.get(&spends).copied()
+        });
+        if let Some(holder) = conflict {
+            return Ok(Admission::Conflicts(holder));
+        }
+
+        let Some(value_in) = utxos.value_in(&transaction) else {
+            return Ok(Admission::MissingInputs);
+        };
+        if !utxos.is_mature(&transaction, maturity) {
+            return Ok(Admission::Immature);
+        }
+        check_value(&transaction, value_in)?;
+
+        let size = transaction.get_raw_format().len();
+        if self.size + size > self.max_size {
+            return Ok(Admission::Full);
+        }
+
+        for input in &transaction.inputs {
+            let spends = (input.previous_output.tx_id, input.previous_output.v_out);
+            self.spent.insert(spends, txid);
+        }
+        self.size += size;
+        self.transactions.insert(txid, transaction);
+        Ok(Admission::Accepted)
+    }
+
+    pub fn get(&self, txid: &[u8; 32]) -> Option<&Transaction> {
+        self.transactions.get(txid)
+    }
+
+    pub fn contains(&self, txid: &[u8; 32]) -> bool {
+        self.transactions.contains_key(txid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8; 32], &Transaction)> {
+        self.transactions.iter()
+    }
+
+    /// Drops what a block has confirmed, and whatever held transaction spent
+    /// the same outputs: it can never confirm now.
+    pub fn remove_confirmed(&mut self, confirmed: &[Transaction]) {
+        for transaction in confirmed {
+            self.remove(&transaction.get_tx_id());
+
+            for input in &transaction.inputs {
+                let spends = (input.previous_output.tx_id, input.previous_output.v_out);
+                if let Some(conflict) = self.spent.get(&spends).copied() {
+                    self.remove(&conflict);
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, txid: &[u8; 32]) {
+        if let Some(removed) = self.transactions.remove(txid) {
+            self.size -= removed.get_raw_format().len();
+            for input in &removed.inputs {
+                self.spent
+                    .remove(&(input.previous_output.tx_id, input.previous_output.v_out));
+            }
+        }
+    }
+}
+
+/// What can be said of a transaction without the outputs it spends: checked
+/// on every transaction offered to the pool, and every one in a block.
+/// Signatures are not checked until Script exists (ADR-0002).
+pub fn check_transaction(transaction: &Transaction) -> Result<()> {
+    if transaction.version != 1 {
+        return Err(anyhow!(
+            "transaction version {} is not 1",
+            transaction.version
+        ));
+    }
+    if transaction.inputs.is_empty() || transaction.outputs.is_empty() {
+        return Err(anyhow!("a transaction needs inputs and outputs"));
+    }
+    if transaction
+        .inputs
+        .iter()
+        .any(|input| input.previous_output.is_null())
+    {
+        return Err(anyhow!("a coinbase is valid only in its block"));
+    }
+
+    let mut spends = HashSet::new();
+    for input in &transaction.inputs {
+        if !spends.insert((input.previous_output.tx_id, input.previous_output.v_out)) {
+            return Err(anyhow!("a transaction spends one output twice"));
+        }
+    }
+
+    transaction
+        .outputs
+        .iter()
+        .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+        .ok_or_else(|| anyhow!("a transaction's outputs overflow"))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Outpoint, TxIn, TxOut};
+    use rstest::rstest;
+
+    /// A coinbase paying 10 to each of four outputs, as if confirmed.
+    fn funding() -> Transaction {
+        Transaction::coinbase(
+            "height 0".to_string(),
+            vec![
+                TxOut {
+                    value: 10,
+                    destiny_pub_key: "someone".to_string(),
+                };
+                4
+            ],
+        )
+    }
+
+    fn utxos() -> UtxoSet {
+        let mut utxos = UtxoSet::default();
+        utxos.apply(&[funding()], 0);
+        utxos
+    }
+
+    /// Output `v_out` of `funding`.
+    fn coin(v_out: u32) -> ([u8; 32], u32) {
+        (funding().get_tx_id(), v_out)
+    }
+
+    fn spending(outpoints: &[([u8; 32], u32)], value: u64) -> Transaction {
+        Transaction {
+            version: 1,
+            inputs: outpoints
+                .iter()
+                .map(|(tx_id, v_out)| TxIn {
+                    previous_output: Outpoint {
+                        tx_id: *tx_id,
+                        v_out: *v_out,
+                    },
+                    signature: "signed".to_string(),
+                    sequence: u32::MAX,
+                })
+                .collect(),
+            outputs: vec![TxOut {
+                value,
+                destiny_pub_key: "someone".to_string(),
+            }],
+            lock_time: 0,
+        }
+    }
+
+    #[test]
+    fn an_admitted_transaction_is_found_by_its_txid() {
+        let mut pool = Mempool::default();
+        let transaction = spending(&[coin(1)], 5);
+        let txid = transaction.get_tx_id();
+
+        assert_eq!(
+            Admission::Accepted,
+            pool.admit(transaction, &utxos(), 1).unwrap()
+        );
+
+        assert!(pool.contains(&txid));
+        assert_eq!(txid, pool.get(&txid).unwrap().get_tx_id());
+        assert!(pool.get(&spending(&[coin(1)], 6).get_tx_id()).is_none());
+    }
+
+    #[test]
+    fn the_same_transaction_twice_is_held_once() {
+        let mut pool = Mempool::default();
+
+        pool.admit(spending(&[coin(1)], 5), &utxos(), 1).unwrap();
+
+        assert_eq!(
+            Admission::Known,
+            pool.admit(spending(&[coin(1)], 5), &utxos(), 1).unwrap()
+        );
+        assert_eq!(1, pool.len());
+    }
+
+    #[test]
+    fn the_first_of_two_spends_of_one_output_is_kept() {
+        let mut pool = Mempool::default();
+        let first = spending(&[coin(1)], 5);
+        let txid = first.get_tx_id();
+        pool.admit(first, &utxos(), 1).unwrap();
+
+        assert_eq!(
+            Admission::Conflicts(txid),
+            pool.admit(spending(&[coin(2), coin(1)], 6), &utxos(), 1)
+                .unwrap()
+        );
+        assert_eq!(1, pool.len());
+    }
+
+    #[test]
+    fn a_confirmed_transaction_and_its_rivals_leave_the_pool() {
+        let mut pool = Mempool::default();
+        let held = spending(&[coin(1)], 5);
+        let unrelated = spending(&[coin(3)], 5);
+        pool.admit(held.clone(), &utxos(), 1).unwrap();
+        pool.admit(unrelated.clone(), &utxos(), 1).unwrap();
+
+        // Confirmed instead of the one we held: same output, different payee.
+        pool.remove_confirmed(&[spending(&[coin(1)], 4)]);
+
+        assert!(!pool.contains(&held.get_tx_id()));
+        assert!(pool.contains(&unrelated.get_tx_id()));
+        assert_eq!(
+            Admission::Accepted,
+            pool.admit(spending(&[coin(1)], 7), &utxos(), 1).unwrap(),
+            "the output it spent is no longer held as spent"
+        );
+    }
+
+    #[rstest]
+    #[case::coinbase(Transaction::coinbase("minted".to_string(), spending(&[], 5).outputs), "coinbase")]
+    #[case::no_inputs(spending(&[], 5), "inputs")]
+    #[case::spends_twice(spending(&[coin(1), coin(1)], 5), "twice")]
+    #[case::wrong_version(Transaction { version: 2, ..spending(&[coin(1)], 5) }, "version")]
+    fn a_transaction_that_can_never_be_valid_is_refused(
+        #[case] transaction: Transaction,
+        #[case] reason: &str,
+    ) {
+        let error = Mempool::default()
+            .admit(transaction, &utxos(), 1)
+            .expect_err("never valid");
+
+        assert!(format!("{error:#}").contains(reason), "got: {error:#}");
+    }
+
+    #[test]
+    fn outputs_that_overflow_are_refused() {
+        let mut transaction = spending(&[coin(1)], u64::MAX);
+        transaction.outputs.push(transaction.outputs[0].clone());
+
+        let error = check_transaction(&transaction).expect_err("overflow");
+
+        assert!(format!("{error:#}").contains("overflow"), "got: {error:#}");
+    }
+
+    #[test]
+    fn a_transaction_spending_what_the_chain_does_not_hold_is_not_pooled() {
+        let mut pool = Mempool::default();
+
+        assert_eq!(
+            Admission::MissingInputs,
+            pool.admit(spending(&[coin(4)], 5), &utxos(), 1).unwrap()
+        );
+        assert_eq!(0, pool.len());
+    }
+
+    #[test]
+    fn paying_out_more_than_the_inputs_hold_is_refused() {
+        let error = Mempool::default()
+            .admit(spending(&[coin(0)], 11), &utxos(), 1)
+            .expect_err("11 out of 10");
+
+        assert!(format!("{error:#}").contains("more than"), "got: {error:#}");
+    }
+
+    #[test]
+    fn a_full_pool_refuses_until_a_block_makes_room() {
+        let held = spending(&[coin(0)], 5);
+        let mut pool = Mempool::new(held.get_raw_format().len());
+        pool.admit(held.clone(), &utxos(), 1).unwrap();
+
+        assert_eq!(
+            Admission::Full,
+            pool.admit(spending(&[coin(1)], 5), &utxos(), 1).unwrap()
+        );
+
+        pool.remove_confirmed(&[held]);
+        assert_eq!(
+            Admission::Accepted,
+            pool.admit(spending(&[coin(1)], 5), &utxos(), 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn spending_a_coinbase_before_it_matures_is_refused_not_fatal() {
+        let mut pool = Mempool::default();
+
+        assert_eq!(
+            Admission::Immature,
+            pool.admit(spending(&[coin(0)], 5), &utxos(), 2).unwrap()
+        );
+        assert_eq!(0, pool.len());
+    }
+}
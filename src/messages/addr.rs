@@ -0,0 +1,126 @@
+use crate::byte_reader::ByteReader;
+use crate::messages::message::Payload;
+use crate::messages::version::{read_address, write_address};
+use crate::util::{command_12, get_compact_int};
+use anyhow::{anyhow, Result};
+use std::net::SocketAddr;
+
+pub const ADDR_COMMAND_NAME: &str = "addr";
+
+/// The most addresses one `addr` may carry, as Bitcoin caps it.
+pub const MAX_ADDR: usize = 1_000;
+
+/// A time (u32) and an 18-byte address.
+pub const TIMED_ADDRESS_LENGTH: usize = 4 + 18;
+
+/// An address, and when the sender last heard from a node on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimedAddress {
+    pub last_seen: u32,
+    pub address: SocketAddr,
+}
+
+/// Addresses of nodes the sender knows of: a compact-size count, then that
+/// many of a time (u32) and an address packed as `version` packs its own.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Addr {
+    pub addresses: Vec<TimedAddress>,
+}
+
+impl Addr {
+    pub fn parse_raw_format(bytes: Vec<u8>) -> Result<Addr> {
+        let mut reader = ByteReader::new(&bytes);
+
+        let count = reader.read_compact()?;
+        if count > MAX_ADDR as u64 {
+            return Err(anyhow!(
+                "an addr holds at most {MAX_ADDR} addresses, got {count}"
+            ));
+        }
+
+        let mut addresses = Vec::with_capacity(reader.capacity_for(count));
+        for _ in 0..count {
+            addresses.push(TimedAddress {
+                last_seen: reader.read_u32()?,
+                address: read_address(&mut reader)?,
+            });
+        }
+
+        if reader.remaining() != 0 {
+            return Err(anyhow!("an addr has {} bytes to spare", reader.remaining()));
+        }
+
+        Ok(Addr { addresses })
+    }
+}
+
+impl Payload for Addr {
+    fn get_raw_format(&self) -> Result<Vec<u8>> {
+        if self.addresses.len() > MAX_ADDR {
+            return Err(anyhow!(
+                "an addr holds at most {MAX_ADDR} addresses, not {}",
+                self.addresses.len()
+            ));
+        }
+
+        let mut raw_format = get_compact_int(self.addresses.len() as u64);
+        for timed in &self.addresses {
+            raw_format.extend_from_slice(&timed.last_seen.to_le_bytes());
+            raw_format.extend_from_slice(&write_address(timed.address));
+        }
+
+        Ok(raw_format)
+    }
+
+    fn get_command_name(&self) -> [u8; 12] {
+        command_12(ADDR_COMMAND_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timed(last_seen: u32, address: &str) -> TimedAddress {
+        TimedAddress {
+            last_seen,
+            address: address.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn an_addr_survives_a_round_trip() {
+        let original = Addr {
+            addresses: vec![
+                timed(1_700_000_000, "127.0.0.1:34352"),
+                timed(7, "[::1]:8333"),
+            ],
+        };
+
+        let parsed = Addr::parse_raw_format(original.get_raw_format().unwrap()).unwrap();
+
+        assert_eq!(original, parsed);
+    }
+
+    #[test]
+    fn an_addr_over_the_cap_is_refused_on_its_count() {
+        let mut raw = get_compact_int(MAX_ADDR as u64 + 1);
+        raw.extend_from_slice(&[0; 22]);
+
+        let error = Addr::parse_raw_format(raw).expect_err("over the cap");
+
+        assert!(format!("{error:#}").contains("at most"), "got: {error:#}");
+    }
+
+    #[test]
+    fn an_addr_with_bytes_to_spare_is_refused() {
+        let mut raw = Addr {
+            addresses: vec![timed(1, "127.0.0.1:1")],
+        }
+        .get_raw_format()
+        .unwrap();
+        raw.push(0);
+
+        Addr::parse_raw_format(raw).expect_err("spare bytes");
+    }
+}
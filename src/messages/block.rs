@@ -0,0 +1,37 @@
+use crate::block::Block;
+use crate::messages::message::Payload;
+use crate::util::command_12;
+use anyhow::Result;
+
+pub const BLOCK_COMMAND_NAME: &str = "block";
+
+/// A whole block on the wire: its header, then its transactions. What it
+/// hashes to is recomputed on receipt, never taken from the sender.
+impl Payload for Block {
+    fn get_raw_format(&self) -> Result<Vec<u8>> {
+        Block::get_raw_format(self)
+    }
+
+    fn get_command_name(&self) -> [u8; 12] {
+        command_12(BLOCK_COMMAND_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::{Network, Params};
+
+    #[test]
+    fn a_block_survives_a_round_trip_and_hashes_the_same() {
+        let genesis = Params::of(Network::Regtest).unwrap().genesis;
+
+        let mut parsed = Block::parse_raw(Payload::get_raw_format(&genesis).unwrap()).unwrap();
+
+        assert_eq!(genesis.hash.unwrap(), parsed.seal().unwrap());
+        assert_eq!(
+            Payload::get_raw_format(&genesis).unwrap(),
+            Payload::get_raw_format(&parsed).unwrap()
+        );
+    }
+}
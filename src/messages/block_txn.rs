@@ -0,0 +1,82 @@
+use crate::byte_reader::ByteReader;
+use crate::messages::message::Payload;
+use crate::transaction::Transaction;
+use crate::util::{command_12, get_compact_int};
+use anyhow::{anyhow, Result};
+
+pub const BLOCK_TXN_COMMAND_NAME: &str = "blocktxn";
+
+/// The answer to a `getblocktxn`: the transactions asked for, in the order
+/// their indexes were.
+#[derive(Clone, Debug)]
+pub struct BlockTxn {
+    pub block_hash: [u8; 32],
+    pub transactions: Vec<Transaction>,
+}
+
+impl BlockTxn {
+    pub fn parse_raw_format(bytes: Vec<u8>) -> Result<BlockTxn> {
+        let mut reader = ByteReader::new(&bytes);
+        let block_hash = reader.read_array::<32>()?;
+
+        let count = reader.read_compact()?;
+        let mut transactions = Vec::with_capacity(reader.capacity_for(count));
+        for _ in 0..count {
+            transactions.push(Transaction::parse_raw(&mut reader)?);
+        }
+
+        if reader.remaining() != 0 {
+            return Err(anyhow!(
+                "blocktxn has {} bytes to spare",
+                reader.remaining()
+            ));
+        }
+
+        Ok(BlockTxn {
+            block_hash,
+            transactions,
+        })
+    }
+}
+
+impl Payload for BlockTxn {
+    fn get_raw_format(&self) -> Result<Vec<u8>> {
+        let mut raw_format = Vec::from(self.block_hash);
+        raw_format.extend(get_compact_int(self.transactions.len() as u64));
+        for transaction in &self.transactions {
+            raw_format.extend(transaction.get_raw_format());
+        }
+
+        Ok(raw_format)
+    }
+
+    fn get_command_name(&self) -> [u8; 12] {
+        command_12(BLOCK_TXN_COMMAND_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TxOut;
+
+    #[test]
+    fn a_blocktxn_survives_a_round_trip() {
+        let original = BlockTxn {
+            block_hash: [7; 32],
+            transactions: vec![Transaction::coinbase(
+                "height 1".to_string(),
+                vec![TxOut {
+                    value: 50,
+                    destiny_pub_key: "miner".to_string(),
+                }],
+            )],
+        };
+        let raw = original.get_raw_format().unwrap();
+
+        let parsed = BlockTxn::parse_raw_format(raw.clone()).unwrap();
+
+        assert_eq!(original.block_hash, parsed.block_hash);
+        assert_eq!(raw, parsed.get_raw_format().unwrap());
+    }
+}
@@ -0,0 +1,283 @@
+use crate::block::{Block, BlockHeader};
+use crate::byte_reader::ByteReader;
+use crate::messages::message::Payload;
+use crate::transaction::Transaction;
+use crate::util::{command_12, get_compact_int};
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+pub const COMPACT_BLOCK_COMMAND_NAME: &str = "cmpctblock";
+
+pub const SHORT_ID_LENGTH: usize = 6;
+
+/// Six bytes of a transaction's id, salted per announcement so no one can
+/// mine a transaction to collide with another in every block.
+pub type ShortId = [u8; SHORT_ID_LENGTH];
+
+/// A transaction sent whole in a compact block, at its place in the block.
+#[derive(Clone, Debug)]
+pub struct PrefilledTransaction {
+    pub index: u32,
+    pub transaction: Transaction,
+}
+
+/// A block announced as its header and a short id per transaction, for a
+/// peer to rebuild from the transactions it already holds. The coinbase,
+/// which no one else can hold, is sent whole.
+#[derive(Clone, Debug)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    /// Salts the short ids.
+    pub nonce: u64,
+    pub short_ids: Vec<ShortId>,
+    /// In ascending order of index.
+    pub prefilled: Vec<PrefilledTransaction>,
+    /// The header's hash and the nonce, already hashed: what every short id
+    /// starts from, worked out once rather than per transaction looked up.
+    salt: Sha256,
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block, nonce: u64) -> Result<CompactBlock> {
+        let header = block.header()?;
+        let salt = salt(&header, nonce);
+        let mut short_ids = Vec::with_capacity(block.transactions.len());
+        let mut prefilled = Vec::new();
+
+        for (index, transaction) in block.transactions.iter().enumerate() {
+            if transaction.is_coinbase() {
+                prefilled.push(PrefilledTransaction {
+                    index: index as u32,
+                    transaction: transaction.clone(),
+                });
+            } else {
+                short_ids.push(short_id(&salt, &transaction.get_tx_id()));
+            }
+        }
+
+        Ok(CompactBlock {
+            header,
+            nonce,
+            short_ids,
+            prefilled,
+            salt,
+        })
+    }
+
+    pub fn short_id(&self, txid: &[u8; 32]) -> ShortId {
+        short_id(&self.salt, txid)
+    }
+
+    /// How many transactions the block holds, short ids and prefilled alike.
+    pub fn transaction_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+
+    pub fn parse_raw_format(bytes: Vec<u8>) -> Result<CompactBlock> {
+        let mut reader = ByteReader::new(&bytes);
+        let header = BlockHeader::parse_raw(&mut reader)?;
+        let nonce = reader.read_u64()?;
+
+        let short_id_count = reader.read_compact()?;
+        let mut short_ids = Vec::with_capacity(reader.capacity_for(short_id_count));
+        for _ in 0..short_id_count {
+            short_ids.push(reader.read_array::<SHORT_ID_LENGTH>()?);
+        }
+
+        let prefilled_count = reader.read_compact()?;
+        let total = short_id_count.saturating_add(prefilled_count);
+        let mut prefilled: Vec<PrefilledTransaction> =
+            Vec::with_capacity(reader.capacity_for(prefilled_count));
+        for _ in 0..prefilled_count {
+            let index = reader.read_compact()?;
+            if index >= total
+                || prefilled
+                    .last()
+                    .is_some_and(|last| u64::from(last.index) >= index)
+            {
+                return Err(anyhow!(
+                    "a compact block of {total} transactions prefills index {index} out of order"
+                ));
+            }
+            prefilled.push(PrefilledTransaction {
+                index: index as u32,
+                transaction: Transaction::parse_raw(&mut reader)?,
+            });
+        }
+
+        if total == 0 {
+            return Err(anyhow!("a compact block holds no transactions"));
+        }
+        if reader.remaining() != 0 {
+            return Err(anyhow!(
+                "cmpctblock has {} bytes to spare",
+                reader.remaining()
+            ));
+        }
+
+        Ok(CompactBlock {
+            header,
+            nonce,
+            short_ids,
+            prefilled,
+            salt: salt(&header, nonce),
+        })
+    }
+}
+
+fn salt(header: &BlockHeader, nonce: u64) -> Sha256 {
+    Sha256::new()
+        .chain_update(header.hash())
+        .chain_update(nonce.to_le_bytes())
+}
+
+/// The double SHA-256 of the header's hash, the nonce and `txid`, cut short.
+fn short_id(salt: &Sha256, txid: &[u8; 32]) -> ShortId {
+    let hash: [u8; 32] = Sha256::digest(salt.clone().chain_update(txid).finalize()).into();
+
+    *hash
+        .first_chunk::<SHORT_ID_LENGTH>()
+        .expect("a hash is longer than a short id")
+}
+
+impl Payload for CompactBlock {
+    fn get_raw_format(&self) -> Result<Vec<u8>> {
+        let mut raw_format = Vec::new();
+        raw_format.extend_from_slice(&self.header.get_raw_format());
+        raw_format.extend(self.nonce.to_le_bytes());
+
+        raw_format.extend(get_compact_int(self.short_ids.len() as u64));
+        for short_id in &self.short_ids {
+            raw_format.extend_from_slice(short_id);
+        }
+
+        raw_format.extend(get_compact_int(self.prefilled.len() as u64));
+        for prefilled in &self.prefilled {
+            raw_format.extend(get_compact_int(u64::from(prefilled.index)));
+            raw_format.extend(prefilled.transaction.get_raw_format());
+        }
+
+        Ok(raw_format)
+    }
+
+    fn get_command_name(&self) -> [u8; 12] {
+        command_12(COMPACT_BLOCK_COMMAND_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Outpoint, TxIn, TxOut};
+
+    fn a_spend(tag: u8) -> Transaction {
+        Transaction {
+            version: 1,
+            inputs: vec![TxIn {
+                previous_output: Outpoint {
+                    tx_id: [tag; 32],
+                    v_out: 0,
+                },
+                signature: "signed".to_string(),
+                sequence: u32::MAX,
+            }],
+            outputs: vec![TxOut {
+                value: 10,
+                destiny_pub_key: "payee".to_string(),
+            }],
+            lock_time: 0,
+        }
+    }
+
+    fn a_block() -> Block {
+        let coinbase = Transaction::coinbase(
+            "height 1".to_string(),
+            vec![TxOut {
+                value: 50,
+                destiny_pub_key: "miner".to_string(),
+            }],
+        );
+        let mut block = Block::new(
+            1,
+            [0; 32],
+            1,
+            0x207fffff,
+            vec![coinbase, a_spend(1), a_spend(2)],
+        );
+        block.seal().unwrap();
+        block
+    }
+
+    #[test]
+    fn a_compact_block_sends_the_coinbase_whole_and_the_rest_as_short_ids() {
+        let block = a_block();
+
+        let compact = CompactBlock::new(&block, 7).unwrap();
+
+        assert_eq!(block.hash.unwrap(), compact.header.hash());
+        assert_eq!(3, compact.transaction_count());
+        assert_eq!(
+            vec![0],
+            compact
+                .prefilled
+                .iter()
+                .map(|prefilled| prefilled.index)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            compact.short_id(&block.transactions[2].get_tx_id()),
+            compact.short_ids[1]
+        );
+    }
+
+    #[test]
+    fn a_compact_block_survives_a_round_trip() {
+        let original = CompactBlock::new(&a_block(), 7).unwrap();
+        let raw = original.get_raw_format().unwrap();
+
+        let parsed = CompactBlock::parse_raw_format(raw.clone()).unwrap();
+
+        assert_eq!(original.header, parsed.header);
+        assert_eq!(original.short_ids, parsed.short_ids);
+        assert_eq!(raw, parsed.get_raw_format().unwrap());
+    }
+
+    #[test]
+    fn a_short_id_is_the_start_of_the_hash_of_block_nonce_and_txid() {
+        let block = a_block();
+        let txid = block.transactions[1].get_tx_id();
+        let mut salted = Vec::new();
+        salted.extend(block.hash.unwrap());
+        salted.extend(7u64.to_le_bytes());
+        salted.extend(txid);
+
+        let compact = CompactBlock::new(&block, 7).unwrap();
+
+        assert_eq!(
+            crate::util::get_hash(&salted)[..SHORT_ID_LENGTH],
+            compact.short_id(&txid)
+        );
+    }
+
+    #[test]
+    fn short_ids_differ_with_the_nonce() {
+        let block = a_block();
+        let txid = block.transactions[1].get_tx_id();
+
+        assert_ne!(
+            CompactBlock::new(&block, 1).unwrap().short_id(&txid),
+            CompactBlock::new(&block, 2).unwrap().short_id(&txid)
+        );
+    }
+
+    #[test]
+    fn a_prefilled_index_past_the_end_is_refused() {
+        let mut compact = CompactBlock::new(&a_block(), 7).unwrap();
+        compact.prefilled[0].index = 3;
+
+        let error = CompactBlock::parse_raw_format(compact.get_raw_format().unwrap())
+            .expect_err("no such slot");
+
+        assert!(format!("{error:#}").contains("index 3"), "got: {error:#}");
+    }
+}
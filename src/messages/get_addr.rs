@@ -0,0 +1,50 @@
+use crate::messages::message::Payload;
+use crate::util::command_12;
+use anyhow::{anyhow, Result};
+
+pub const GET_ADDR_COMMAND_NAME: &str = "getaddr";
+
+/// Asks a peer for the addresses it knows, answered with an `addr`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GetAddr;
+
+impl GetAddr {
+    pub fn parse_raw_format(bytes: Vec<u8>) -> Result<GetAddr> {
+        if !bytes.is_empty() {
+            return Err(anyhow!(
+                "getaddr carries no payload, got {} bytes",
+                bytes.len()
+            ));
+        }
+
+        Ok(GetAddr)
+    }
+}
+
+impl Payload for GetAddr {
+    fn get_raw_format(&self) -> Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    fn get_command_name(&self) -> [u8; 12] {
+        command_12(GET_ADDR_COMMAND_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_getaddr_survives_a_round_trip() {
+        let raw = GetAddr.get_raw_format().unwrap();
+
+        assert!(raw.is_empty());
+        assert_eq!(GetAddr, GetAddr::parse_raw_format(raw).unwrap());
+    }
+
+    #[test]
+    fn a_getaddr_with_a_body_is_refused() {
+        GetAddr::parse_raw_format(vec![0]).expect_err("a getaddr is empty");
+    }
+}
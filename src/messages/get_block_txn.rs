@@ -0,0 +1,111 @@
+use crate::blockchain::MAX_BLOCK_SIZE;
+use crate::byte_reader::ByteReader;
+use crate::messages::message::Payload;
+use crate::util::{command_12, get_compact_int};
+use anyhow::{anyhow, Result};
+
+pub const GET_BLOCK_TXN_COMMAND_NAME: &str = "getblocktxn";
+
+/// The smallest a transaction serializes to: a version, no inputs, no
+/// outputs and a lock time.
+const MIN_TRANSACTION_LENGTH: usize = 4 + 1 + 1 + 4;
+/// More than any block under `MAX_BLOCK_SIZE` can hold, and so more indexes
+/// than a `getblocktxn` can sensibly ask for.
+pub const MAX_BLOCK_TRANSACTIONS: usize = MAX_BLOCK_SIZE / MIN_TRANSACTION_LENGTH;
+
+/// Asks for the transactions of a compact block that the mempool could not
+/// supply, by their place in the block, ascending.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GetBlockTxn {
+    pub block_hash: [u8; 32],
+    pub indexes: Vec<u32>,
+}
+
+impl GetBlockTxn {
+    pub fn parse_raw_format(bytes: Vec<u8>) -> Result<GetBlockTxn> {
+        let mut reader = ByteReader::new(&bytes);
+        let block_hash = reader.read_array::<32>()?;
+
+        let count = reader.read_compact()?;
+        if count > MAX_BLOCK_TRANSACTIONS as u64 {
+            return Err(anyhow!(
+                "a getblocktxn asks for at most {MAX_BLOCK_TRANSACTIONS}, got {count}"
+            ));
+        }
+
+        let mut indexes: Vec<u32> = Vec::with_capacity(reader.capacity_for(count));
+        for _ in 0..count {
+            let index = reader.read_compact()?;
+            if index >= MAX_BLOCK_TRANSACTIONS as u64
+                || indexes.last().is_some_and(|last| u64::from(*last) >= index)
+            {
+                return Err(anyhow!("a getblocktxn asks for index {index} out of order"));
+            }
+            indexes.push(index as u32);
+        }
+
+        if reader.remaining() != 0 {
+            return Err(anyhow!(
+                "getblocktxn has {} bytes to spare",
+                reader.remaining()
+            ));
+        }
+
+        Ok(GetBlockTxn {
+            block_hash,
+            indexes,
+        })
+    }
+}
+
+impl Payload for GetBlockTxn {
+    fn get_raw_format(&self) -> Result<Vec<u8>> {
+        let mut raw_format = Vec::from(self.block_hash);
+        raw_format.extend(get_compact_int(self.indexes.len() as u64));
+        for index in &self.indexes {
+            raw_format.extend(get_compact_int(u64::from(*index)));
+        }
+
+        Ok(raw_format)
+    }
+
+    fn get_command_name(&self) -> [u8; 12] {
+        command_12(GET_BLOCK_TXN_COMMAND_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[test]
+    fn a_getblocktxn_survives_a_round_trip() {
+        let original = GetBlockTxn {
+            block_hash: [7; 32],
+            indexes: vec![1, 2, 300],
+        };
+
+        let parsed = GetBlockTxn::parse_raw_format(original.get_raw_format().unwrap()).unwrap();
+
+        assert_eq!(original, parsed);
+    }
+
+    #[rstest]
+    #[case::repeated(vec![1, 1])]
+    #[case::descending(vec![2, 1])]
+    fn indexes_out_of_order_are_refused(#[case] indexes: Vec<u32>) {
+        let request = GetBlockTxn {
+            block_hash: [7; 32],
+            indexes,
+        };
+
+        let error = GetBlockTxn::parse_raw_format(request.get_raw_format().unwrap())
+            .expect_err("out of order");
+
+        assert!(
+            format!("{error:#}").contains("out of order"),
+            "got: {error:#}"
+        );
+    }
+}
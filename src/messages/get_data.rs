@@ -0,0 +1,46 @@
+use crate::messages::inventory::{read_inventory, write_inventory, Inventory};
+use crate::messages::message::Payload;
+use crate::util::command_12;
+use anyhow::Result;
+
+pub const GET_DATA_COMMAND_NAME: &str = "getdata";
+
+/// Asks a peer for the full objects behind some inventory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GetData {
+    pub inventory: Vec<Inventory>,
+}
+
+impl GetData {
+    pub fn parse_raw_format(bytes: Vec<u8>) -> Result<GetData> {
+        Ok(GetData {
+            inventory: read_inventory(&bytes)?,
+        })
+    }
+}
+
+impl Payload for GetData {
+    fn get_raw_format(&self) -> Result<Vec<u8>> {
+        Ok(write_inventory(&self.inventory))
+    }
+
+    fn get_command_name(&self) -> [u8; 12] {
+        command_12(GET_DATA_COMMAND_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_getdata_survives_a_round_trip() {
+        let original = GetData {
+            inventory: vec![Inventory::Block([7; 32])],
+        };
+
+        let parsed = GetData::parse_raw_format(original.get_raw_format().unwrap()).unwrap();
+
+        assert_eq!(original, parsed);
+    }
+}
@@ -0,0 +1,126 @@
+use crate::byte_reader::ByteReader;
+use crate::messages::message::Payload;
+use crate::messages::version::PROTOCOL_VERSION;
+use crate::util::{command_12, get_compact_int};
+use anyhow::{anyhow, Result};
+
+pub const GET_HEADERS_COMMAND_NAME: &str = "getheaders";
+
+/// Enough for a chain of 2^90 blocks under the locator's doubling steps, so
+/// only a peer padding its request reaches it.
+pub const MAX_LOCATOR_LENGTH: usize = 101;
+
+/// Asks for the headers after the newest `locator` entry the peer shares with
+/// us, up to `stop`, or as many as one answer holds if `stop` is all zeroes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GetHeaders {
+    pub protocol_version: u32,
+    pub locator: Vec<[u8; 32]>,
+    pub stop: [u8; 32],
+}
+
+impl GetHeaders {
+    pub fn new(locator: Vec<[u8; 32]>) -> Self {
+        GetHeaders {
+            protocol_version: PROTOCOL_VERSION,
+            locator,
+            stop: [0; 32],
+        }
+    }
+
+    pub fn parse_raw_format(bytes: Vec<u8>) -> Result<GetHeaders> {
+        let mut reader = ByteReader::new(&bytes);
+        let protocol_version = reader.read_u32()?;
+
+        // Before allocating: the count is the peer's claim, not a length.
+        let count = reader.read_compact()?;
+        if count > MAX_LOCATOR_LENGTH as u64 {
+            return Err(anyhow!(
+                "a locator holds at most {MAX_LOCATOR_LENGTH} hashes, got {count}"
+            ));
+        }
+
+        let mut locator = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            locator.push(reader.read_array::<32>()?);
+        }
+        let stop = reader.read_array::<32>()?;
+
+        if reader.remaining() != 0 {
+            return Err(anyhow!(
+                "getheaders has {} bytes to spare",
+                reader.remaining()
+            ));
+        }
+
+        Ok(GetHeaders {
+            protocol_version,
+            locator,
+            stop,
+        })
+    }
+}
+
+impl Payload for GetHeaders {
+    fn get_raw_format(&self) -> Result<Vec<u8>> {
+        let mut raw_format = Vec::new();
+        raw_format.extend_from_slice(&self.protocol_version.to_le_bytes());
+        raw_format.extend(get_compact_int(self.locator.len() as u64));
+        for hash in &self.locator {
+            raw_format.extend_from_slice(hash);
+        }
+        raw_format.extend_from_slice(&self.stop);
+
+        Ok(raw_format)
+    }
+
+    fn get_command_name(&self) -> [u8; 12] {
+        command_12(GET_HEADERS_COMMAND_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::genesis_only(1)]
+    #[case::typical(25)]
+    #[case::longest(MAX_LOCATOR_LENGTH)]
+    fn a_getheaders_survives_a_round_trip(#[case] length: usize) {
+        let mut original = GetHeaders::new((0..length).map(|i| [i as u8; 32]).collect());
+        original.stop = [0xab; 32];
+
+        let parsed = GetHeaders::parse_raw_format(original.get_raw_format().unwrap()).unwrap();
+
+        assert_eq!(original, parsed);
+    }
+
+    #[test]
+    fn an_oversized_locator_is_refused_on_its_count_alone() {
+        let mut raw = PROTOCOL_VERSION.to_le_bytes().to_vec();
+        raw.extend(get_compact_int(u32::MAX as u64));
+
+        let error = GetHeaders::parse_raw_format(raw)
+            .expect_err("four billion hashes must not be allocated for");
+
+        assert!(format!("{error:#}").contains("at most"), "got: {error:#}");
+    }
+
+    #[test]
+    fn a_getheaders_missing_its_stop_hash_is_refused() {
+        let mut raw = GetHeaders::new(vec![[1; 32]]).get_raw_format().unwrap();
+        raw.truncate(raw.len() - 1);
+
+        GetHeaders::parse_raw_format(raw).expect_err("a truncated stop hash");
+    }
+
+    #[test]
+    fn a_getheaders_with_bytes_to_spare_is_refused() {
+        let mut raw = GetHeaders::new(vec![[1; 32]]).get_raw_format().unwrap();
+        raw.push(0);
+
+        GetHeaders::parse_raw_format(raw).expect_err("trailing bytes");
+    }
+}
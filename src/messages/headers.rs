@@ -0,0 +1,119 @@
+use crate::block::{BlockHeader, BLOCK_HEADER_LENGTH};
+use crate::blockchain::MAX_HEADERS;
+use crate::byte_reader::ByteReader;
+use crate::messages::message::Payload;
+use crate::util::{command_12, get_compact_int};
+use anyhow::{anyhow, Result};
+
+pub const HEADERS_COMMAND_NAME: &str = "headers";
+
+/// The answer to a `getheaders`: consecutive headers, oldest first. Bare 80
+/// byte headers, without Bitcoin's always-zero transaction count after each.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Headers {
+    pub headers: Vec<BlockHeader>,
+}
+
+impl Headers {
+    pub fn parse_raw_format(bytes: Vec<u8>) -> Result<Headers> {
+        let mut reader = ByteReader::new(&bytes);
+
+        let count = reader.read_compact()?;
+        if count > MAX_HEADERS as u64 {
+            return Err(anyhow!(
+                "a headers message holds at most {MAX_HEADERS}, got {count}"
+            ));
+        }
+
+        let mut headers = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            headers.push(BlockHeader::parse_raw(&mut reader)?);
+        }
+
+        if reader.remaining() != 0 {
+            return Err(anyhow!("headers has {} bytes to spare", reader.remaining()));
+        }
+
+        Ok(Headers { headers })
+    }
+}
+
+impl Payload for Headers {
+    fn get_raw_format(&self) -> Result<Vec<u8>> {
+        let mut raw_format = Vec::with_capacity(9 + self.headers.len() * BLOCK_HEADER_LENGTH);
+        raw_format.extend(get_compact_int(self.headers.len() as u64));
+        for header in &self.headers {
+            raw_format.extend_from_slice(&header.get_raw_format());
+        }
+
+        Ok(raw_format)
+    }
+
+    fn get_command_name(&self) -> [u8; 12] {
+        command_12(HEADERS_COMMAND_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn a_header(nonce: u32) -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            previous_block_hash: [1; 32],
+            merkle_root_hash: [2; 32],
+            time: 1_785_369_600,
+            n_bits: 0x207fffff,
+            nonce,
+        }
+    }
+
+    #[rstest]
+    #[case::none(0)]
+    #[case::one(1)]
+    #[case::a_full_batch(MAX_HEADERS)]
+    fn headers_survive_a_round_trip(#[case] count: usize) {
+        let original = Headers {
+            headers: (0..count as u32).map(a_header).collect(),
+        };
+
+        let parsed = Headers::parse_raw_format(original.get_raw_format().unwrap()).unwrap();
+
+        assert_eq!(original, parsed);
+    }
+
+    #[test]
+    fn more_headers_than_one_answer_holds_are_refused_on_the_count_alone() {
+        let raw = get_compact_int(MAX_HEADERS as u64 + 1);
+
+        let error = Headers::parse_raw_format(raw).expect_err("over the cap");
+
+        assert!(format!("{error:#}").contains("at most"), "got: {error:#}");
+    }
+
+    #[test]
+    fn a_truncated_header_is_refused() {
+        let mut raw = Headers {
+            headers: vec![a_header(0)],
+        }
+        .get_raw_format()
+        .unwrap();
+        raw.pop();
+
+        Headers::parse_raw_format(raw).expect_err("79 bytes are not a header");
+    }
+
+    #[test]
+    fn headers_with_bytes_to_spare_are_refused() {
+        let mut raw = Headers {
+            headers: vec![a_header(0)],
+        }
+        .get_raw_format()
+        .unwrap();
+        raw.push(0);
+
+        Headers::parse_raw_format(raw).expect_err("trailing bytes");
+    }
+}
@@ -0,0 +1,47 @@
+use crate::messages::inventory::{read_inventory, write_inventory, Inventory};
+use crate::messages::message::Payload;
+use crate::util::command_12;
+use anyhow::Result;
+
+pub const INV_COMMAND_NAME: &str = "inv";
+
+/// Tells a peer what we have that it may not: it asks with `getdata` for
+/// whatever it wants of it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Inv {
+    pub inventory: Vec<Inventory>,
+}
+
+impl Inv {
+    pub fn parse_raw_format(bytes: Vec<u8>) -> Result<Inv> {
+        Ok(Inv {
+            inventory: read_inventory(&bytes)?,
+        })
+    }
+}
+
+impl Payload for Inv {
+    fn get_raw_format(&self) -> Result<Vec<u8>> {
+        Ok(write_inventory(&self.inventory))
+    }
+
+    fn get_command_name(&self) -> [u8; 12] {
+        command_12(INV_COMMAND_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_inv_survives_a_round_trip() {
+        let original = Inv {
+            inventory: vec![Inventory::Tx([3; 32]), Inventory::Block([4; 32])],
+        };
+
+        let parsed = Inv::parse_raw_format(original.get_raw_format().unwrap()).unwrap();
+
+        assert_eq!(original, parsed);
+    }
+}
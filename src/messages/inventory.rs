@@ -0,0 +1,119 @@
+use crate::byte_reader::ByteReader;
+use crate::util::get_compact_int;
+use anyhow::{anyhow, Result};
+
+/// The most entries one inventory list may carry, as Bitcoin caps it.
+pub const MAX_INVENTORY: usize = 50_000;
+
+/// A type (u32) and a hash: what each entry weighs on the wire.
+pub const INVENTORY_LENGTH: usize = 4 + 32;
+
+const TX_TYPE: u32 = 1;
+const BLOCK_TYPE: u32 = 2;
+
+/// One thing a peer can be asked for by hash: a type (u32) and 32 bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Inventory {
+    Tx([u8; 32]),
+    Block([u8; 32]),
+}
+
+impl Inventory {
+    fn write(&self, raw_format: &mut Vec<u8>) {
+        let (kind, hash) = match self {
+            Inventory::Tx(txid) => (TX_TYPE, txid),
+            Inventory::Block(hash) => (BLOCK_TYPE, hash),
+        };
+
+        raw_format.extend_from_slice(&kind.to_le_bytes());
+        raw_format.extend_from_slice(hash);
+    }
+
+    fn read(reader: &mut ByteReader) -> Result<Inventory> {
+        let kind = reader.read_u32()?;
+        let hash = reader.read_array::<32>()?;
+
+        match kind {
+            TX_TYPE => Ok(Inventory::Tx(hash)),
+            BLOCK_TYPE => Ok(Inventory::Block(hash)),
+            other => Err(anyhow!("unknown inventory type {other}")),
+        }
+    }
+}
+
+/// A compact-size count, then that many entries; no bytes to spare.
+pub fn write_inventory(inventory: &[Inventory]) -> Vec<u8> {
+    let mut raw_format = get_compact_int(inventory.len() as u64);
+    for item in inventory {
+        item.write(&mut raw_format);
+    }
+
+    raw_format
+}
+
+pub fn read_inventory(bytes: &[u8]) -> Result<Vec<Inventory>> {
+    let mut reader = ByteReader::new(bytes);
+
+    let count = reader.read_compact()?;
+    if count > MAX_INVENTORY as u64 {
+        return Err(anyhow!(
+            "an inventory holds at most {MAX_INVENTORY} entries, got {count}"
+        ));
+    }
+
+    let mut inventory = Vec::with_capacity(reader.capacity_for(count));
+    for _ in 0..count {
+        inventory.push(Inventory::read(&mut reader)?);
+    }
+
+    if reader.remaining() != 0 {
+        return Err(anyhow!(
+            "an inventory has {} bytes to spare",
+            reader.remaining()
+        ));
+    }
+
+    Ok(inventory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_inventory_survives_a_round_trip() {
+        let original = vec![Inventory::Block([1; 32]), Inventory::Tx([2; 32])];
+
+        assert_eq!(
+            original,
+            read_inventory(&write_inventory(&original)).unwrap()
+        );
+    }
+
+    #[test]
+    fn an_unknown_inventory_type_is_refused() {
+        let mut raw = write_inventory(&[Inventory::Block([1; 32])]);
+        raw[1] = 0x7f;
+
+        let error = read_inventory(&raw).expect_err("not a type we know");
+
+        assert!(format!("{error:#}").contains("unknown"), "got: {error:#}");
+    }
+
+    #[test]
+    fn an_oversized_inventory_is_refused_on_its_count_alone() {
+        let raw = get_compact_int(MAX_INVENTORY as u64 + 1);
+
+        let error = read_inventory(&raw).expect_err("over the cap");
+
+        assert!(format!("{error:#}").contains("at most"), "got: {error:#}");
+    }
+
+    #[test]
+    fn an_inventory_with_bytes_to_spare_is_refused() {
+        let mut raw = write_inventory(&[Inventory::Block([1; 32])]);
+        raw.push(0);
+
+        read_inventory(&raw).expect_err("trailing bytes");
+    }
+}
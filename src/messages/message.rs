@@ -1,14 +1,88 @@
+use crate::block::{Block, BLOCK_HEADER_LENGTH};
+use crate::blockchain::{MAX_BLOCK_SIZE, MAX_HEADERS};
 use crate::byte_reader::ByteReader;
+use crate::messages::addr::{Addr, ADDR_COMMAND_NAME, MAX_ADDR, TIMED_ADDRESS_LENGTH};
+use crate::messages::block::BLOCK_COMMAND_NAME;
+use crate::messages::block_txn::{BlockTxn, BLOCK_TXN_COMMAND_NAME};
+use crate::messages::compact_block::{CompactBlock, COMPACT_BLOCK_COMMAND_NAME};
+use crate::messages::get_addr::{GetAddr, GET_ADDR_COMMAND_NAME};
+use crate::messages::get_block_txn::{
+    GetBlockTxn, GET_BLOCK_TXN_COMMAND_NAME, MAX_BLOCK_TRANSACTIONS,
+};
+use crate::messages::get_data::{GetData, GET_DATA_COMMAND_NAME};
+use crate::messages::get_headers::{GetHeaders, GET_HEADERS_COMMAND_NAME, MAX_LOCATOR_LENGTH};
+use crate::messages::headers::{Headers, HEADERS_COMMAND_NAME};
+use crate::messages::inv::{Inv, INV_COMMAND_NAME};
+use crate::messages::inventory::{INVENTORY_LENGTH, MAX_INVENTORY};
+use crate::messages::not_found::{NotFound, NOT_FOUND_COMMAND_NAME};
 use crate::messages::ping::{Ping, PING_COMMAND_NAME};
 use crate::messages::pong::{Pong, PONG_COMMAND_NAME};
+use crate::messages::tx::{parse_transaction, TX_COMMAND_NAME};
 use crate::messages::verack::{Verack, VERACK_COMMAND_NAME};
-use crate::messages::version::{Version, VERSION_COMMAND_NAME};
+use crate::messages::version::{Version, MAX_VERSION_PAYLOAD_LENGTH, VERSION_COMMAND_NAME};
+use crate::params::Magic;
+use crate::transaction::Transaction;
 use crate::util::{get_hash, parse_command_12};
 use anyhow::{anyhow, Result};
+use std::fmt;
 
-const MAGIC_BYTES: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
 const HEADER_LENGTH: usize = 24;
-const MAX_PAYLOAD_SIZE: u32 = 32 * 1024 * 1024;
+
+/// The widest a compact-size count can be.
+const MAX_COUNT_LENGTH: usize = 9;
+
+/// The widest a compact-size index into a block can be.
+const MAX_INDEX_LENGTH: usize = 5;
+
+/// What a command we do not know may weigh: one added since us, which gets
+/// room for anything up to a block before it is skipped.
+const MAX_UNKNOWN_PAYLOAD_SIZE: usize = MAX_BLOCK_SIZE;
+
+/// The most a payload under `command` can weigh and still parse. Checked on the
+/// header alone, so a peer cannot make us buffer a payload its command could
+/// never fill.
+fn max_payload_size(command: &str) -> usize {
+    match command {
+        PING_COMMAND_NAME | PONG_COMMAND_NAME => 8,
+        VERSION_COMMAND_NAME => MAX_VERSION_PAYLOAD_LENGTH,
+        VERACK_COMMAND_NAME | GET_ADDR_COMMAND_NAME => 0,
+        GET_HEADERS_COMMAND_NAME => 4 + MAX_COUNT_LENGTH + 32 * MAX_LOCATOR_LENGTH + 32,
+        HEADERS_COMMAND_NAME => MAX_COUNT_LENGTH + BLOCK_HEADER_LENGTH * MAX_HEADERS,
+        INV_COMMAND_NAME | GET_DATA_COMMAND_NAME | NOT_FOUND_COMMAND_NAME => {
+            MAX_COUNT_LENGTH + INVENTORY_LENGTH * MAX_INVENTORY
+        }
+        ADDR_COMMAND_NAME => MAX_COUNT_LENGTH + TIMED_ADDRESS_LENGTH * MAX_ADDR,
+        TX_COMMAND_NAME | BLOCK_COMMAND_NAME => MAX_BLOCK_SIZE,
+        COMPACT_BLOCK_COMMAND_NAME | BLOCK_TXN_COMMAND_NAME => MAX_BLOCK_SIZE,
+        GET_BLOCK_TXN_COMMAND_NAME => {
+            32 + MAX_COUNT_LENGTH + MAX_INDEX_LENGTH * MAX_BLOCK_TRANSACTIONS
+        }
+        _ => MAX_UNKNOWN_PAYLOAD_SIZE,
+    }
+}
+
+/// The command a frame we built is sent under.
+pub fn command_of(frame: &[u8]) -> &str {
+    frame
+        .get(4..16)
+        .and_then(|bytes| parse_command_12(bytes.try_into().ok()?).ok())
+        .unwrap_or_default()
+}
+
+/// A frame whose payload does not match its checksum. Its header was sound,
+/// so unlike any other parse failure the stream can skip the frame and go on.
+#[derive(Debug)]
+pub struct BadChecksum {
+    pub frame_length: usize,
+}
+
+impl fmt::Display for BadChecksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Invalid checksum")
+    }
+}
+
+impl std::error::Error for BadChecksum {}
 
 #[derive(Clone, Debug)]
 pub struct Message<T> {
@@ -18,7 +92,7 @@ pub struct Message<T> {
 
 #[derive(Clone, Debug)]
 pub struct Header {
-    magic_bytes: [u8; 4],
+    magic_bytes: Magic,
     command_name: [u8; 12],
     payload_size: u32,
     checksum: [u8; 4],
@@ -35,10 +109,24 @@ pub enum MessageReceived {
     PongMessage(Message<Pong>),
     VersionMessage(Message<Version>),
     VerackMessage,
+    GetHeadersMessage(Message<GetHeaders>),
+    HeadersMessage(Message<Headers>),
+    InvMessage(Message<Inv>),
+    GetDataMessage(Message<GetData>),
+    NotFoundMessage(Message<NotFound>),
+    TxMessage(Message<Transaction>),
+    BlockMessage(Message<Block>),
+    GetAddrMessage,
+    AddrMessage(Message<Addr>),
+    CompactBlockMessage(Message<CompactBlock>),
+    GetBlockTxnMessage(Message<GetBlockTxn>),
+    BlockTxnMessage(Message<BlockTxn>),
+    /// A well-framed message under a command we do not know, by its name.
+    UnknownMessage(String),
 }
 
 impl Header {
-    fn from_payload<T: Payload>(payload: &T) -> Result<Header> {
+    fn from_payload<T: Payload>(magic: Magic, payload: &T) -> Result<Header> {
         let payload_bytes = payload.get_raw_format()?;
         let payload_size = payload_bytes.len() as u32;
         let payload_hash = get_hash(&payload_bytes);
@@ -48,7 +136,7 @@ impl Header {
             .expect("Invalid hashing array");
 
         Ok(Header {
-            magic_bytes: MAGIC_BYTES,
+            magic_bytes: magic,
             command_name: payload.get_command_name(),
             payload_size,
             checksum,
@@ -66,15 +154,15 @@ impl Header {
         raw_format
     }
 
-    fn from_raw_format(bytes: &[u8]) -> Result<Header> {
+    fn from_raw_format(magic: Magic, bytes: &[u8]) -> Result<Header> {
         if bytes.len() < HEADER_LENGTH {
             return Err(anyhow!("Bytes smaller than header size"));
         }
         let mut reader = ByteReader::new(bytes);
 
         let magic_bytes = reader.read_array::<4>()?;
-        if magic_bytes != MAGIC_BYTES {
-            return Err(anyhow!("Invalid magic bytes"));
+        if magic_bytes != magic {
+            return Err(anyhow!("Invalid magic bytes: not on this network"));
         }
 
         let command_name = reader.read_array::<12>()?;
@@ -95,9 +183,9 @@ impl<T> Message<T>
 where
     T: Payload,
 {
-    pub fn new(payload: T) -> Result<Message<T>> {
+    pub fn new(magic: Magic, payload: T) -> Result<Message<T>> {
         Ok(Message {
-            header: Header::from_payload(&payload)?,
+            header: Header::from_payload(magic, &payload)?,
             payload,
         })
     }
@@ -113,16 +201,48 @@ where
 }
 
 impl MessageReceived {
-    pub(crate) fn try_parse_message(buffer: &[u8]) -> Result<(Option<MessageReceived>, usize)> {
+    pub fn command_name(&self) -> &str {
+        match self {
+            MessageReceived::PingMessage(_) => PING_COMMAND_NAME,
+            MessageReceived::PongMessage(_) => PONG_COMMAND_NAME,
+            MessageReceived::VersionMessage(_) => VERSION_COMMAND_NAME,
+            MessageReceived::VerackMessage => VERACK_COMMAND_NAME,
+            MessageReceived::GetHeadersMessage(_) => GET_HEADERS_COMMAND_NAME,
+            MessageReceived::HeadersMessage(_) => HEADERS_COMMAND_NAME,
+            MessageReceived::InvMessage(_) => INV_COMMAND_NAME,
+            MessageReceived::GetDataMessage(_) => GET_DATA_COMMAND_NAME,
+            MessageReceived::NotFoundMessage(_) => NOT_FOUND_COMMAND_NAME,
+            MessageReceived::TxMessage(_) => TX_COMMAND_NAME,
+            MessageReceived::BlockMessage(_) => BLOCK_COMMAND_NAME,
+            MessageReceived::GetAddrMessage => GET_ADDR_COMMAND_NAME,
+            MessageReceived::AddrMessage(_) => ADDR_COMMAND_NAME,
+            MessageReceived::CompactBlockMessage(_) => COMPACT_BLOCK_COMMAND_NAME,
+            MessageReceived::GetBlockTxnMessage(_) => GET_BLOCK_TXN_COMMAND_NAME,
+            MessageReceived::BlockTxnMessage(_) => BLOCK_TXN_COMMAND_NAME,
+            MessageReceived::UnknownMessage(command) => command,
+        }
+    }
+
+    pub(crate) fn try_parse_message(
+        magic: Magic,
+        buffer: &[u8],
+    ) -> Result<(Option<MessageReceived>, usize)> {
         if buffer.len() < HEADER_LENGTH {
             return Ok((None, 0));
         }
 
-        let header = Header::from_raw_format(&buffer[..HEADER_LENGTH])?;
+        let header = Header::from_raw_format(magic, &buffer[..HEADER_LENGTH])?;
+        let command_name = parse_command_12(&header.command_name)?;
 
         // Size before completeness, or an absurd claim reads as a message still arriving.
-        if header.payload_size > MAX_PAYLOAD_SIZE {
-            return Err(anyhow!("Payload too large: {}", header.payload_size));
+        let limit = max_payload_size(command_name);
+        if header.payload_size as usize > limit {
+            return Err(anyhow!(
+                "Payload too large for {}: {} bytes, at most {}",
+                command_name,
+                header.payload_size,
+                limit
+            ));
         }
 
         if buffer.len() < HEADER_LENGTH + header.payload_size as usize {
@@ -138,11 +258,12 @@ impl MessageReceived {
         let generated_checksum = hash.first_chunk::<4>().expect("Invalid hashing array");
 
         if header.checksum != *generated_checksum {
-            return Err(anyhow!("Invalid checksum"));
+            return Err(BadChecksum {
+                frame_length: HEADER_LENGTH + header.payload_size as usize,
+            }
+            .into());
         }
 
-        let command_name = parse_command_12(&header.command_name)?;
-
         let bytes_read = HEADER_LENGTH + header.payload_size as usize;
 
         let message = match command_name {
@@ -164,18 +285,76 @@ impl MessageReceived {
                 Verack::parse_raw_format(bytes)?;
                 MessageReceived::VerackMessage
             }
-            _ => return Err(anyhow!("Unknown command: {}", command_name)),
+            GET_HEADERS_COMMAND_NAME => MessageReceived::GetHeadersMessage(Message {
+                header,
+                payload: GetHeaders::parse_raw_format(bytes)?,
+            }),
+            HEADERS_COMMAND_NAME => MessageReceived::HeadersMessage(Message {
+                header,
+                payload: Headers::parse_raw_format(bytes)?,
+            }),
+            INV_COMMAND_NAME => MessageReceived::InvMessage(Message {
+                header,
+                payload: Inv::parse_raw_format(bytes)?,
+            }),
+            GET_DATA_COMMAND_NAME => MessageReceived::GetDataMessage(Message {
+                header,
+                payload: GetData::parse_raw_format(bytes)?,
+            }),
+            NOT_FOUND_COMMAND_NAME => MessageReceived::NotFoundMessage(Message {
+                header,
+                payload: NotFound::parse_raw_format(bytes)?,
+            }),
+            TX_COMMAND_NAME => MessageReceived::TxMessage(Message {
+                header,
+                payload: parse_transaction(bytes)?,
+            }),
+            BLOCK_COMMAND_NAME => MessageReceived::BlockMessage(Message {
+                header,
+                payload: Block::parse_raw(bytes)?,
+            }),
+            GET_ADDR_COMMAND_NAME => {
+                GetAddr::parse_raw_format(bytes)?;
+                MessageReceived::GetAddrMessage
+            }
+            ADDR_COMMAND_NAME => MessageReceived::AddrMessage(Message {
+                header,
+                payload: Addr::parse_raw_format(bytes)?,
+            }),
+            COMPACT_BLOCK_COMMAND_NAME => MessageReceived::CompactBlockMessage(Message {
+                header,
+                payload: CompactBlock::parse_raw_format(bytes)?,
+            }),
+            GET_BLOCK_TXN_COMMAND_NAME => MessageReceived::GetBlockTxnMessage(Message {
+                header,
+                payload: GetBlockTxn::parse_raw_format(bytes)?,
+            }),
+            BLOCK_TXN_COMMAND_NAME => MessageReceived::BlockTxnMessage(Message {
+                header,
+                payload: BlockTxn::parse_raw_format(bytes)?,
+            }),
+            // Skipped, not refused: a command we do not know may be one a
+            // newer node added, and its framing has already proven sound.
+            _ => MessageReceived::UnknownMessage(command_name.to_string()),
         };
 
         Ok((Some(message), bytes_read))
     }
 }
 
+#[cfg(test)]
+pub(crate) const TEST_MAGIC: Magic = crate::params::Network::Main.magic();
+
 #[cfg(test)]
 pub(crate) fn header_claiming(payload_size: u32) -> Vec<u8> {
+    command_header_claiming(PING_COMMAND_NAME, payload_size)
+}
+
+#[cfg(test)]
+pub(crate) fn command_header_claiming(command: &str, payload_size: u32) -> Vec<u8> {
     let mut header = Vec::new();
-    header.extend_from_slice(&MAGIC_BYTES);
-    header.extend_from_slice(&crate::util::command_12(PING_COMMAND_NAME));
+    header.extend_from_slice(&TEST_MAGIC);
+    header.extend_from_slice(&crate::util::command_12(command));
     header.extend_from_slice(&payload_size.to_le_bytes());
     header.extend_from_slice(&[0u8; 4]);
     header
@@ -189,30 +368,148 @@ mod tests {
     fn a_real_ping() -> (Vec<u8>, u64) {
         let ping = Ping::new();
         let nonce = ping.nonce;
-        (Message::new(ping).unwrap().get_raw_format().unwrap(), nonce)
+        (
+            Message::new(TEST_MAGIC, ping)
+                .unwrap()
+                .get_raw_format()
+                .unwrap(),
+            nonce,
+        )
     }
 
+    const EVERY_COMMAND: [&str; 16] = [
+        PING_COMMAND_NAME,
+        PONG_COMMAND_NAME,
+        VERSION_COMMAND_NAME,
+        VERACK_COMMAND_NAME,
+        GET_HEADERS_COMMAND_NAME,
+        HEADERS_COMMAND_NAME,
+        INV_COMMAND_NAME,
+        GET_DATA_COMMAND_NAME,
+        NOT_FOUND_COMMAND_NAME,
+        TX_COMMAND_NAME,
+        BLOCK_COMMAND_NAME,
+        GET_ADDR_COMMAND_NAME,
+        ADDR_COMMAND_NAME,
+        COMPACT_BLOCK_COMMAND_NAME,
+        GET_BLOCK_TXN_COMMAND_NAME,
+        BLOCK_TXN_COMMAND_NAME,
+    ];
+
     #[rstest]
-    #[case::one_over(MAX_PAYLOAD_SIZE + 1)]
+    #[case::one_over(MAX_BLOCK_SIZE as u32 + 1)]
     #[case::four_gigabytes(u32::MAX)]
     fn an_oversized_payload_is_rejected_on_the_header_alone(#[case] claimed: u32) {
-        let header = header_claiming(claimed);
+        let header = command_header_claiming(BLOCK_COMMAND_NAME, claimed);
         assert_eq!(HEADER_LENGTH, header.len());
 
-        let error = MessageReceived::try_parse_message(&header)
+        let error = MessageReceived::try_parse_message(TEST_MAGIC, &header)
             .expect_err("an oversized claim must be refused before its bytes are awaited");
 
         assert!(format!("{error:#}").contains("too large"), "got: {error:#}");
     }
 
+    #[rstest]
+    #[case::ping(PING_COMMAND_NAME, 8)]
+    #[case::pong(PONG_COMMAND_NAME, 8)]
+    #[case::version(VERSION_COMMAND_NAME, MAX_VERSION_PAYLOAD_LENGTH)]
+    #[case::verack(VERACK_COMMAND_NAME, 0)]
+    #[case::getaddr(GET_ADDR_COMMAND_NAME, 0)]
+    #[case::block(BLOCK_COMMAND_NAME, MAX_BLOCK_SIZE)]
+    fn each_command_has_its_own_limit(#[case] command: &str, #[case] limit: usize) {
+        assert_eq!(limit, max_payload_size(command));
+
+        let error = MessageReceived::try_parse_message(
+            TEST_MAGIC,
+            &command_header_claiming(command, limit as u32 + 1),
+        )
+        .expect_err("one byte over the command's limit");
+
+        assert!(format!("{error:#}").contains("too large"), "got: {error:#}");
+    }
+
     #[test]
-    fn a_payload_at_the_limit_is_not_rejected_for_being_too_large() {
-        let result = MessageReceived::try_parse_message(&header_claiming(MAX_PAYLOAD_SIZE));
+    fn a_payload_at_its_commands_limit_is_not_rejected_for_being_too_large() {
+        // An empty payload is complete on its header, so nothing is awaited.
+        for command in EVERY_COMMAND
+            .into_iter()
+            .filter(|c| max_payload_size(c) > 0)
+        {
+            let limit = max_payload_size(command) as u32;
+
+            let result = MessageReceived::try_parse_message(
+                TEST_MAGIC,
+                &command_header_claiming(command, limit),
+            );
+
+            assert!(
+                matches!(result, Ok((None, 0))),
+                "a {command} claiming exactly its limit is legal and merely incomplete, \
+                 got: {result:?}"
+            );
+        }
+    }
 
-        assert!(
-            matches!(result, Ok((None, 0))),
-            "a claim exactly at the limit is legal and merely incomplete, got: {result:?}"
-        );
+    #[test]
+    fn an_unknown_command_is_held_to_a_limit_of_its_own() {
+        let header = command_header_claiming("bogus", MAX_UNKNOWN_PAYLOAD_SIZE as u32 + 1);
+
+        let error = MessageReceived::try_parse_message(TEST_MAGIC, &header)
+            .expect_err("not knowing a command is no reason to buffer anything");
+
+        assert!(format!("{error:#}").contains("too large"), "got: {error:#}");
+    }
+
+    #[test]
+    fn a_largest_possible_message_of_each_kind_fits_its_limit() {
+        use crate::messages::addr::TimedAddress;
+        use crate::messages::inventory::Inventory;
+
+        let address = TimedAddress {
+            last_seen: u32::MAX,
+            address: "[::1]:65535".parse().unwrap(),
+        };
+        let largest: Vec<(&str, Vec<u8>)> = vec![
+            (
+                GET_HEADERS_COMMAND_NAME,
+                GetHeaders::new(vec![[0; 32]; MAX_LOCATOR_LENGTH])
+                    .get_raw_format()
+                    .unwrap(),
+            ),
+            (
+                INV_COMMAND_NAME,
+                Inv {
+                    inventory: vec![Inventory::Tx([0; 32]); MAX_INVENTORY],
+                }
+                .get_raw_format()
+                .unwrap(),
+            ),
+            (
+                ADDR_COMMAND_NAME,
+                Addr {
+                    addresses: vec![address; MAX_ADDR],
+                }
+                .get_raw_format()
+                .unwrap(),
+            ),
+            (
+                GET_BLOCK_TXN_COMMAND_NAME,
+                GetBlockTxn {
+                    block_hash: [0; 32],
+                    indexes: (0..MAX_BLOCK_TRANSACTIONS as u32).collect(),
+                }
+                .get_raw_format()
+                .unwrap(),
+            ),
+        ];
+
+        for (command, payload) in largest {
+            assert!(
+                payload.len() <= max_payload_size(command),
+                "a full {command} weighs {} bytes, over its limit",
+                payload.len()
+            );
+        }
     }
 
     #[rstest]
@@ -224,8 +521,9 @@ mod tests {
     fn an_incomplete_message_asks_for_more_bytes(#[case] available: usize) {
         let (message, _) = a_real_ping();
 
-        let (parsed, consumed) = MessageReceived::try_parse_message(&message[..available])
-            .expect("a partial message is not an error");
+        let (parsed, consumed) =
+            MessageReceived::try_parse_message(TEST_MAGIC, &message[..available])
+                .expect("a partial message is not an error");
 
         assert!(parsed.is_none(), "{available} bytes should not parse");
         assert_eq!(
@@ -238,7 +536,7 @@ mod tests {
     fn a_complete_message_parses_back_to_what_was_serialized() {
         let (message, nonce) = a_real_ping();
 
-        let (parsed, consumed) = MessageReceived::try_parse_message(&message).unwrap();
+        let (parsed, consumed) = MessageReceived::try_parse_message(TEST_MAGIC, &message).unwrap();
 
         match parsed {
             Some(MessageReceived::PingMessage(ping)) => assert_eq!(nonce, ping.payload.nonce),
@@ -253,7 +551,7 @@ mod tests {
         let first_length = buffer.len();
         buffer.extend_from_slice(&a_real_ping().0);
 
-        let (parsed, consumed) = MessageReceived::try_parse_message(&buffer).unwrap();
+        let (parsed, consumed) = MessageReceived::try_parse_message(TEST_MAGIC, &buffer).unwrap();
 
         assert!(parsed.is_some());
         assert_eq!(first_length, consumed, "only the first message is consumed");
@@ -264,28 +562,71 @@ mod tests {
         let (mut message, _) = a_real_ping();
         message[0] ^= 0xff;
 
-        MessageReceived::try_parse_message(&message)
+        MessageReceived::try_parse_message(TEST_MAGIC, &message)
             .expect_err("a message from another network must not be parsed");
     }
 
+    #[test]
+    fn another_of_our_networks_is_as_foreign_as_anyone_else() {
+        use crate::params::Network;
+
+        let message = Message::new(Network::Test.magic(), Ping::new())
+            .unwrap()
+            .get_raw_format()
+            .unwrap();
+
+        let error = MessageReceived::try_parse_message(Network::Main.magic(), &message)
+            .expect_err("a test node's traffic must not parse on mainnet");
+
+        assert!(format!("{error:#}").contains("magic"), "got: {error:#}");
+    }
+
     #[test]
     fn a_corrupted_payload_fails_its_checksum() {
         let (mut message, _) = a_real_ping();
         let last = message.len() - 1;
         message[last] ^= 0xff;
 
-        let error = MessageReceived::try_parse_message(&message)
+        let error = MessageReceived::try_parse_message(TEST_MAGIC, &message)
             .expect_err("a payload that does not match its checksum must be rejected");
 
         assert!(format!("{error:#}").contains("checksum"), "got: {error:#}");
+        assert_eq!(
+            Some(message.len()),
+            error
+                .downcast_ref::<BadChecksum>()
+                .map(|bad| bad.frame_length),
+            "the whole frame is what a reader skips"
+        );
     }
 
     #[test]
-    fn an_unknown_command_is_rejected() {
+    fn an_unknown_command_parses_to_its_name_and_is_consumed_whole() {
         let (mut message, _) = a_real_ping();
         message[4..16].copy_from_slice(&crate::util::command_12("notacommand"));
 
-        MessageReceived::try_parse_message(&message)
-            .expect_err("a command this node does not implement must be refused");
+        let (parsed, consumed) = MessageReceived::try_parse_message(TEST_MAGIC, &message)
+            .expect("a well-framed command we do not know is skipped, not refused");
+
+        match parsed {
+            Some(MessageReceived::UnknownMessage(command)) => assert_eq!("notacommand", command),
+            other => panic!("expected an unknown message, got {other:?}"),
+        }
+        assert_eq!(message.len(), consumed);
+    }
+
+    #[test]
+    fn an_unknown_command_must_still_match_its_checksum() {
+        let (mut message, _) = a_real_ping();
+        message[4..16].copy_from_slice(&crate::util::command_12("notacommand"));
+        let last = message.len() - 1;
+        message[last] ^= 0xff;
+
+        let error = MessageReceived::try_parse_message(TEST_MAGIC, &message).unwrap_err();
+
+        assert!(
+            error.downcast_ref::<BadChecksum>().is_some(),
+            "got: {error:#}"
+        );
     }
 }
@@ -1,5 +1,18 @@
+pub mod addr;
+pub mod block;
+pub mod block_txn;
+pub mod compact_block;
+pub mod get_addr;
+pub mod get_block_txn;
+pub mod get_data;
+pub mod get_headers;
+pub mod headers;
+pub mod inv;
+pub mod inventory;
 pub mod message;
+pub mod not_found;
 pub mod ping;
 pub mod pong;
+pub mod tx;
 pub mod verack;
 pub mod version;
@@ -0,0 +1,47 @@
+use crate::messages::inventory::{read_inventory, write_inventory, Inventory};
+use crate::messages::message::Payload;
+use crate::util::command_12;
+use anyhow::Result;
+
+pub const NOT_FOUND_COMMAND_NAME: &str = "notfound";
+
+/// The part of a `getdata` we could not serve, so the asker can look
+/// elsewhere rather than wait for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NotFound {
+    pub inventory: Vec<Inventory>,
+}
+
+impl NotFound {
+    pub fn parse_raw_format(bytes: Vec<u8>) -> Result<NotFound> {
+        Ok(NotFound {
+            inventory: read_inventory(&bytes)?,
+        })
+    }
+}
+
+impl Payload for NotFound {
+    fn get_raw_format(&self) -> Result<Vec<u8>> {
+        Ok(write_inventory(&self.inventory))
+    }
+
+    fn get_command_name(&self) -> [u8; 12] {
+        command_12(NOT_FOUND_COMMAND_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_notfound_survives_a_round_trip() {
+        let original = NotFound {
+            inventory: vec![Inventory::Block([5; 32])],
+        };
+
+        let parsed = NotFound::parse_raw_format(original.get_raw_format().unwrap()).unwrap();
+
+        assert_eq!(original, parsed);
+    }
+}
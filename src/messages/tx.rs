@@ -0,0 +1,71 @@
+use crate::byte_reader::ByteReader;
+use crate::messages::message::Payload;
+use crate::transaction::Transaction;
+use crate::util::command_12;
+use anyhow::{anyhow, Result};
+
+pub const TX_COMMAND_NAME: &str = "tx";
+
+/// The whole payload is the transaction: bytes left over are refused, or two
+/// encodings of one txid could travel.
+pub fn parse_transaction(bytes: Vec<u8>) -> Result<Transaction> {
+    let mut reader = ByteReader::new(&bytes);
+    let transaction = Transaction::parse_raw(&mut reader)?;
+
+    if reader.remaining() != 0 {
+        return Err(anyhow!(
+            "a transaction has {} bytes to spare",
+            reader.remaining()
+        ));
+    }
+
+    Ok(transaction)
+}
+
+/// One transaction on the wire, in the format its txid is the hash of.
+impl Payload for Transaction {
+    fn get_raw_format(&self) -> Result<Vec<u8>> {
+        Ok(Transaction::get_raw_format(self))
+    }
+
+    fn get_command_name(&self) -> [u8; 12] {
+        command_12(TX_COMMAND_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TxOut;
+
+    fn a_transaction() -> Transaction {
+        Transaction::coinbase(
+            "height 7".to_string(),
+            vec![TxOut {
+                value: 50,
+                destiny_pub_key: "miner".to_string(),
+            }],
+        )
+    }
+
+    #[test]
+    fn a_transaction_survives_a_round_trip_with_the_same_txid() {
+        let original = a_transaction();
+        let raw = Payload::get_raw_format(&original).unwrap();
+
+        let parsed = parse_transaction(raw.clone()).unwrap();
+
+        assert_eq!(original.get_tx_id(), parsed.get_tx_id());
+        assert_eq!(raw, Payload::get_raw_format(&parsed).unwrap());
+    }
+
+    #[test]
+    fn a_transaction_with_bytes_to_spare_is_refused() {
+        let mut raw = Payload::get_raw_format(&a_transaction()).unwrap();
+        raw.push(0);
+
+        let error = parse_transaction(raw).expect_err("trailing bytes");
+
+        assert!(format!("{error:#}").contains("spare"), "got: {error:#}");
+    }
+}
@@ -1,53 +1,143 @@
 use crate::byte_reader::ByteReader;
+use crate::messages::block_txn::BLOCK_TXN_COMMAND_NAME;
+use crate::messages::compact_block::COMPACT_BLOCK_COMMAND_NAME;
+use crate::messages::get_block_txn::GET_BLOCK_TXN_COMMAND_NAME;
 use crate::messages::message::Payload;
-use crate::util::command_12;
-use anyhow::{anyhow, Result};
+use crate::messages::ping::PING_COMMAND_NAME;
+use crate::messages::pong::PONG_COMMAND_NAME;
+use crate::messages::verack::VERACK_COMMAND_NAME;
+use crate::util::{command_12, get_compact_int, unix_time};
+use anyhow::{anyhow, Context, Result};
 use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 
 pub const VERSION_COMMAND_NAME: &str = "version";
-pub const PROTOCOL_VERSION: u32 = 1;
-pub const VERSION_PAYLOAD_LENGTH: usize = 30;
+/// What we speak. From 2 on, a well-framed command a node does not know is
+/// skipped rather than the end of the connection, so a message added later
+/// only needs gating on the version that introduced it. 3 gave `version` its
+/// services, time, user agent and height; 4 added compact blocks.
+pub const PROTOCOL_VERSION: u32 = 4;
+/// The oldest we talk to: before 3, `version` had another shape, and neither
+/// side can parse the other's.
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+/// The first to skip a command it does not know. Before it, a node spoke
+/// only the handshake and ping/pong, and hung up on anything else.
+pub const SKIPS_UNKNOWN_VERSION: u32 = 2;
+/// The first to announce blocks as `cmpctblock` and answer `getblocktxn`.
+pub const COMPACT_BLOCKS_VERSION: u32 = 4;
+
+/// Longer than any real one, and short enough that a peer cannot pad its
+/// `version` with a name.
+pub const MAX_USER_AGENT_LENGTH: usize = 256;
+/// Everything but the user agent.
+const FIXED_FIELDS_LENGTH: usize = 4 + 8 + 4 + 8 + 18 + 4;
+/// A version whose user agent is as long as it may be, its length prefix
+/// included.
+pub const MAX_VERSION_PAYLOAD_LENGTH: usize = FIXED_FIELDS_LENGTH + 3 + MAX_USER_AGENT_LENGTH;
+
+/// What we call ourselves to peers.
+pub const USER_AGENT: &str = concat!("/avicoin:", env!("CARGO_PKG_VERSION"), "/");
+
+/// What a node offers its peers, one bit each. Bits we do not know are kept,
+/// not refused: they are a newer node's to define.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Services(pub u64);
+
+impl Services {
+    /// Validates every block and transaction itself.
+    pub const FULL_NODE: Services = Services(1 << 0);
+    /// Keeps the blocks it has connected, and answers `getdata` for them.
+    pub const SERVES_BLOCKS: Services = Services(1 << 1);
+    pub const OURS: Services = Services(Services::FULL_NODE.0 | Services::SERVES_BLOCKS.0);
+
+    pub fn has(self, wanted: Services) -> bool {
+        self.0 & wanted.0 == wanted.0
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Version {
     pub protocol_version: u32,
+    pub services: Services,
+    /// When it was sent, by the sender's clock.
+    pub timestamp: u32,
     pub nonce: u64,
     pub listen_address: SocketAddr,
+    pub user_agent: String,
+    /// The height of the sender's chain: how far ahead of us it claims to be.
+    pub best_height: u32,
 }
 
 impl Version {
-    pub fn new(nonce: u64, listen_address: SocketAddr) -> Self {
+    pub fn new(nonce: u64, listen_address: SocketAddr, best_height: u32) -> Self {
         Version {
             protocol_version: PROTOCOL_VERSION,
+            services: Services::OURS,
+            timestamp: unix_time(),
             nonce,
             listen_address,
+            user_agent: USER_AGENT.to_string(),
+            best_height,
         }
     }
 
     pub fn parse_raw_format(bytes: Vec<u8>) -> Result<Version> {
-        if bytes.len() != VERSION_PAYLOAD_LENGTH {
+        let mut reader = ByteReader::new(&bytes);
+
+        let protocol_version = reader.read_u32()?;
+        let services = Services(reader.read_u64()?);
+        let timestamp = reader.read_u32()?;
+        let nonce = reader.read_u64()?;
+        let listen_address = read_address(&mut reader)?;
+
+        // Before allocating: the length is the peer's claim.
+        let length = reader.read_compact()?;
+        if length > MAX_USER_AGENT_LENGTH as u64 {
             return Err(anyhow!(
-                "a version is {VERSION_PAYLOAD_LENGTH} bytes, got {}",
-                bytes.len()
+                "a user agent is at most {MAX_USER_AGENT_LENGTH} bytes, got {length}"
             ));
         }
+        let user_agent = String::from_utf8(reader.read_bytes(length as usize)?)
+            .context("a user agent is UTF-8")?;
 
-        let mut reader = ByteReader::new(&bytes);
+        let best_height = reader.read_u32()?;
+
+        if reader.remaining() != 0 {
+            return Err(anyhow!(
+                "a version has {} bytes to spare",
+                reader.remaining()
+            ));
+        }
 
         Ok(Version {
-            protocol_version: reader.read_u32()?,
-            nonce: reader.read_u64()?,
-            listen_address: read_address(&mut reader)?,
+            protocol_version,
+            services,
+            timestamp,
+            nonce,
+            listen_address,
+            user_agent,
+            best_height,
         })
     }
 }
 
 impl Payload for Version {
     fn get_raw_format(&self) -> Result<Vec<u8>> {
+        if self.user_agent.len() > MAX_USER_AGENT_LENGTH {
+            return Err(anyhow!(
+                "a user agent is at most {MAX_USER_AGENT_LENGTH} bytes, not {}",
+                self.user_agent.len()
+            ));
+        }
+
         let mut raw_format = Vec::new();
         raw_format.extend_from_slice(&self.protocol_version.to_le_bytes());
+        raw_format.extend_from_slice(&self.services.0.to_le_bytes());
+        raw_format.extend_from_slice(&self.timestamp.to_le_bytes());
         raw_format.extend_from_slice(&self.nonce.to_le_bytes());
         raw_format.extend_from_slice(&write_address(self.listen_address));
+        raw_format.extend(get_compact_int(self.user_agent.len() as u64));
+        raw_format.extend_from_slice(self.user_agent.as_bytes());
+        raw_format.extend_from_slice(&self.best_height.to_le_bytes());
 
         Ok(raw_format)
     }
@@ -57,8 +147,33 @@ impl Payload for Version {
     }
 }
 
+/// What two sides speak to each other: the older of the two, so neither is
+/// sent what it does not know. A peer older than `MIN_PROTOCOL_VERSION` is
+/// not talked to at all.
+pub fn negotiate(theirs: u32) -> Result<u32> {
+    if theirs < MIN_PROTOCOL_VERSION {
+        return Err(anyhow!(
+            "speaks protocol {theirs}, older than the oldest we talk to, {MIN_PROTOCOL_VERSION}"
+        ));
+    }
+
+    Ok(theirs.min(PROTOCOL_VERSION))
+}
+
+/// The version that introduced `command`. A peer that negotiated an older
+/// one is never sent it; what we do not list came with `SKIPS_UNKNOWN_VERSION`.
+pub fn introduced_in(command: &str) -> u32 {
+    match command {
+        VERSION_COMMAND_NAME | VERACK_COMMAND_NAME | PING_COMMAND_NAME | PONG_COMMAND_NAME => 1,
+        COMPACT_BLOCK_COMMAND_NAME | GET_BLOCK_TXN_COMMAND_NAME | BLOCK_TXN_COMMAND_NAME => {
+            COMPACT_BLOCKS_VERSION
+        }
+        _ => SKIPS_UNKNOWN_VERSION,
+    }
+}
+
 // IPv4 mapped into IPv6, so a v4 peer and a v6 peer parse through one path.
-fn write_address(address: SocketAddr) -> [u8; 18] {
+pub fn write_address(address: SocketAddr) -> [u8; 18] {
     let mut out = [0u8; 18];
 
     let ip = match address.ip() {
@@ -71,7 +186,7 @@ fn write_address(address: SocketAddr) -> [u8; 18] {
     out
 }
 
-fn read_address(reader: &mut ByteReader) -> Result<SocketAddr> {
+pub fn read_address(reader: &mut ByteReader) -> Result<SocketAddr> {
     let mapped = Ipv6Addr::from(reader.read_array::<16>()?);
     let port = reader.read_u16()?;
 
@@ -95,17 +210,49 @@ mod tests {
     #[case::v6_loopback("[::1]:34352")]
     #[case::v6_full("[2001:db8::dead:beef]:8333")]
     fn a_version_survives_a_round_trip(#[case] listen_address: &str) {
-        let original = Version::new(0xdead_beef_cafe_f00d, listen_address.parse().unwrap());
+        let original = Version::new(0xdead_beef_cafe_f00d, listen_address.parse().unwrap(), 1234);
 
         let parsed = Version::parse_raw_format(original.get_raw_format().unwrap()).unwrap();
 
         assert_eq!(original, parsed);
-        assert_eq!(listen_address.parse::<SocketAddr>().unwrap(), parsed.listen_address);
+        assert_eq!(
+            listen_address.parse::<SocketAddr>().unwrap(),
+            parsed.listen_address
+        );
+    }
+
+    #[rstest]
+    #[case::older(MIN_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION)]
+    #[case::same(PROTOCOL_VERSION, PROTOCOL_VERSION)]
+    #[case::newer(PROTOCOL_VERSION + 1, PROTOCOL_VERSION)]
+    fn two_sides_speak_the_older_of_their_versions(#[case] theirs: u32, #[case] spoken: u32) {
+        assert_eq!(spoken, negotiate(theirs).unwrap());
+    }
+
+    #[rstest]
+    #[case::handshake(VERACK_COMMAND_NAME, 1)]
+    #[case::ping(PING_COMMAND_NAME, 1)]
+    #[case::getheaders("getheaders", SKIPS_UNKNOWN_VERSION)]
+    #[case::getaddr("getaddr", SKIPS_UNKNOWN_VERSION)]
+    #[case::inv("inv", SKIPS_UNKNOWN_VERSION)]
+    #[case::compact(COMPACT_BLOCK_COMMAND_NAME, COMPACT_BLOCKS_VERSION)]
+    fn each_command_is_sent_only_from_the_version_that_introduced_it(
+        #[case] command: &str,
+        #[case] version: u32,
+    ) {
+        assert_eq!(version, introduced_in(command));
+    }
+
+    #[test]
+    fn a_peer_older_than_the_oldest_we_talk_to_is_refused() {
+        let error = negotiate(MIN_PROTOCOL_VERSION - 1).expect_err("too old");
+
+        assert!(format!("{error:#}").contains("older"), "got: {error:#}");
     }
 
     #[test]
     fn a_v4_address_does_not_come_back_as_a_mapped_v6_one() {
-        let original = Version::new(1, "127.0.0.1:34352".parse().unwrap());
+        let original = Version::new(1, "127.0.0.1:34352".parse().unwrap(), 0);
 
         let parsed = Version::parse_raw_format(original.get_raw_format().unwrap()).unwrap();
 
@@ -117,7 +264,7 @@ mod tests {
 
     #[test]
     fn a_truncated_version_is_refused_rather_than_filled_in() {
-        let complete = Version::new(1, "127.0.0.1:1".parse().unwrap())
+        let complete = Version::new(1, "127.0.0.1:1".parse().unwrap(), 0)
             .get_raw_format()
             .unwrap();
 
@@ -132,12 +279,58 @@ mod tests {
 
     #[test]
     fn a_version_with_bytes_to_spare_is_refused_rather_than_truncated() {
-        let mut padded = Version::new(1, "127.0.0.1:1".parse().unwrap())
+        let mut padded = Version::new(1, "127.0.0.1:1".parse().unwrap(), 0)
             .get_raw_format()
             .unwrap();
         padded.push(0);
 
         Version::parse_raw_format(padded)
-            .expect_err("a version is a fixed 30 bytes, and something longer is not one");
+            .expect_err("a version ends at its height, and something longer is not one");
+    }
+
+    fn with_user_agent(user_agent: String) -> Version {
+        Version {
+            user_agent,
+            ..Version::new(1, "127.0.0.1:1".parse().unwrap(), 0)
+        }
+    }
+
+    #[test]
+    fn the_longest_user_agent_makes_the_longest_version() {
+        let longest = with_user_agent("a".repeat(MAX_USER_AGENT_LENGTH));
+
+        let raw = longest.get_raw_format().unwrap();
+
+        assert_eq!(MAX_VERSION_PAYLOAD_LENGTH, raw.len());
+        assert_eq!(longest, Version::parse_raw_format(raw).unwrap());
+    }
+
+    #[test]
+    fn a_user_agent_over_the_limit_is_refused_on_its_length_alone() {
+        let raw = with_user_agent("a".repeat(MAX_USER_AGENT_LENGTH))
+            .get_raw_format()
+            .unwrap();
+        let mut longer = raw[..FIXED_FIELDS_LENGTH - 4].to_vec();
+        longer.extend(get_compact_int(MAX_USER_AGENT_LENGTH as u64 + 1));
+
+        let error = Version::parse_raw_format(longer).expect_err("over the cap");
+
+        assert!(format!("{error:#}").contains("at most"), "got: {error:#}");
+        with_user_agent("a".repeat(MAX_USER_AGENT_LENGTH + 1))
+            .get_raw_format()
+            .expect_err("nor is one sent");
+    }
+
+    #[test]
+    fn services_we_do_not_know_are_kept() {
+        let mut original = Version::new(1, "127.0.0.1:1".parse().unwrap(), 0);
+        original.services = Services(Services::OURS.0 | 1 << 40);
+
+        let parsed = Version::parse_raw_format(original.get_raw_format().unwrap()).unwrap();
+
+        assert_eq!(original.services, parsed.services);
+        assert!(parsed.services.has(Services::FULL_NODE));
+        assert!(parsed.services.has(Services::OURS));
+        assert!(!Services::FULL_NODE.has(Services::SERVES_BLOCKS));
     }
 }
@@ -0,0 +1,251 @@
+use crate::block::Block;
+use crate::blockchain::MAX_BLOCK_SIZE;
+use crate::node::{record, Node, SharedNode};
+use crate::params::{subsidy, Network};
+use crate::transaction::{Transaction, TxOut};
+use crate::util::{display_hash, unix_time};
+use anyhow::{anyhow, Context, Result};
+use secp256k1::PublicKey;
+use std::str::FromStr;
+
+/// Mines `count` blocks on the tip, each confirming what of the mempool it
+/// can and paying its subsidy and their fees to `to`, and connects them. Regtest only: elsewhere blocks come from a miner
+/// competing for them, not on command. A batch that fails partway keeps, and
+/// announces, what it connected before the failure.
+pub fn generate(node: &SharedNode, count: u32, to: &str) -> Result<Vec<[u8; 32]>> {
+    let network = node.lock().expect("node lock poisoned").params.network;
+    if network != Network::Regtest {
+        return Err(anyhow!(
+            "blocks are generated on demand only on regtest, not {network}"
+        ));
+    }
+    // A public key until addresses exist (ADR-0005): it is what `TxOut` locks to.
+    PublicKey::from_str(to).with_context(|| format!("{to:?} is not a public key"))?;
+
+    // Grown as blocks connect rather than sized by `count`, which is
+    // whatever was typed.
+    let mut generated = Vec::new();
+    let outcome =
+        (0..count).try_for_each(|_| generate_one(node, to).map(|hash| generated.push(hash)));
+
+    // The tip alone: a peer that hears of it asks for the headers that lead
+    // there, so the blocks below need no announcement of their own.
+    if !generated.is_empty() {
+        let mut node = node.lock().expect("node lock poisoned");
+        let node = &mut *node;
+        node.peers
+            .announce_block(node.params.magic, node.chain.tip())?;
+    }
+
+    outcome.with_context(|| format!("stopped after {} of {count} blocks", generated.len()))?;
+    Ok(generated)
+}
+
+fn generate_one(node: &SharedNode, to: &str) -> Result<[u8; 32]> {
+    let mut block = {
+        let node = node.lock().expect("node lock poisoned");
+        template(&node, to)?
+    };
+
+    // Outside the lock: regtest's search is instant, but a search is
+    // still no reason to stall every peer.
+    if !block.mine()? {
+        return Err(anyhow!(
+            "no nonce meets the target; change the time and retry"
+        ));
+    }
+
+    let (hash, height) = {
+        let mut node = node.lock().expect("node lock poisoned");
+        let node = &mut *node;
+        let confirmed = block.transactions.clone();
+        let hash = node.chain.connect(block, &node.params)?;
+        node.mempool.remove_confirmed(&confirmed);
+        (hash, node.chain.height())
+    };
+
+    record(
+        node,
+        format!("Generated block {} at height {height}", display_hash(&hash)),
+    );
+    Ok(hash)
+}
+
+/// The next block on `node`'s tip, unmined: every pooled transaction the
+/// chain would let it spend, while there is room, and a coinbase to `to`.
+fn template(node: &Node, to: &str) -> Result<Block> {
+    let chain = &node.chain;
+    let height = chain.height() + 1;
+    let mut coinbase = Transaction::coinbase(
+        format!("height {height}"),
+        vec![TxOut {
+            value: subsidy(height),
+            destiny_pub_key: to.to_string(),
+        }],
+    );
+
+    // The wall clock, unless it would break median-time-past: blocks generated
+    // faster than one a second must still each move time forward.
+    let time = unix_time().max(chain.median_time_past() + 1);
+    let mut block = Block::new(
+        1,
+        chain.tip_hash(),
+        time,
+        chain.next_n_bits(&node.params)?,
+        vec![coinbase.clone()],
+    );
+
+    // Less 8 bytes, the most the count of transactions can grow by.
+    let mut room = MAX_BLOCK_SIZE - block.size() - 8;
+    let mut fees = 0u64;
+    for transaction in node.mempool.iter().map(|(_, transaction)| transaction) {
+        let size = transaction.get_raw_format().len();
+        if size > room {
+            continue;
+        }
+        // Pooled transactions spend only confirmed outputs and none the same
+        // one, so each can be checked on its own.
+        let Ok(fee) = chain.utxos().check_spends(
+            &[coinbase.clone(), transaction.clone()],
+            height,
+            node.params.coinbase_maturity,
+        ) else {
+            continue;
+        };
+        room -= size;
+        fees = fees.saturating_add(fee);
+        block.transactions.push(transaction.clone());
+    }
+
+    coinbase.outputs[0].value = subsidy(height).saturating_add(fees);
+    block.transactions[0] = coinbase;
+    Ok(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::node::Node;
+    use crate::params::Params;
+
+    const KEY_ONE: &str = "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798";
+
+    fn a_node(network: Network) -> SharedNode {
+        Node::shared(
+            Config {
+                network,
+                host_address: "127.0.0.1:34352".parse().unwrap(),
+                addresses_to_connect: Vec::new(),
+                data_dir: "data".into(),
+                max_inbound: crate::node::MAX_INBOUND,
+                max_outbound: crate::node::MAX_OUTBOUND,
+            },
+            Params::of(network).unwrap(),
+        )
+    }
+
+    #[test]
+    fn generated_blocks_extend_the_chain_one_each() {
+        let node = a_node(Network::Regtest);
+
+        let hashes = generate(&node, 3, KEY_ONE).unwrap();
+
+        let node = node.lock().unwrap();
+        assert_eq!(3, node.chain.height());
+        assert_eq!(hashes[2], node.chain.tip_hash());
+    }
+
+    #[test]
+    fn each_generated_coinbase_pays_the_whole_subsidy_to_the_address() {
+        let node = a_node(Network::Regtest);
+
+        generate(&node, 1, KEY_ONE).unwrap();
+
+        let node = node.lock().unwrap();
+        let coinbase = &node.chain.tip().transactions[0];
+        assert!(coinbase.is_coinbase());
+        assert_eq!(subsidy(1), coinbase.outputs[0].value);
+        assert_eq!(KEY_ONE, coinbase.outputs[0].destiny_pub_key);
+    }
+
+    #[test]
+    fn a_pooled_transaction_is_confirmed_and_its_fee_paid_to_the_address() {
+        let node = a_node(Network::Regtest);
+        let funding = &Params::of(Network::Regtest).unwrap().genesis.transactions[0];
+        let pending = Transaction {
+            version: 1,
+            inputs: vec![crate::transaction::TxIn {
+                previous_output: crate::transaction::Outpoint {
+                    tx_id: funding.get_tx_id(),
+                    v_out: 0,
+                },
+                signature: "signed".to_string(),
+                sequence: u32::MAX,
+            }],
+            outputs: vec![TxOut {
+                value: 5,
+                destiny_pub_key: "someone".to_string(),
+            }],
+            lock_time: 0,
+        };
+        let fee = funding.outputs[0].value - 5;
+        {
+            let mut locked = node.lock().unwrap();
+            let locked = &mut *locked;
+            locked
+                .mempool
+                .admit(
+                    pending.clone(),
+                    locked.chain.utxos(),
+                    locked.params.coinbase_maturity,
+                )
+                .unwrap();
+        }
+
+        generate(&node, 1, KEY_ONE).unwrap();
+
+        let node = node.lock().unwrap();
+        let tip = node.chain.tip();
+        assert_eq!(2, tip.transactions.len());
+        assert_eq!(pending.get_tx_id(), tip.transactions[1].get_tx_id());
+        assert_eq!(subsidy(1) + fee, tip.transactions[0].outputs[0].value);
+        assert_eq!(0, node.mempool.len(), "confirmed, so no longer pending");
+    }
+
+    #[test]
+    fn many_blocks_within_one_second_still_move_time_forward() {
+        let node = a_node(Network::Regtest);
+
+        generate(&node, 30, KEY_ONE)
+            .expect("median-time-past must not refuse a burst of generated blocks");
+    }
+
+    #[test]
+    fn generating_on_another_network_is_refused() {
+        let node = a_node(Network::Test);
+
+        let error = generate(&node, 1, KEY_ONE).expect_err("test blocks are mined, not ordered");
+
+        assert!(format!("{error:#}").contains("regtest"), "got: {error:#}");
+        assert_eq!(0, node.lock().unwrap().chain.height());
+    }
+
+    #[test]
+    fn a_count_off_regtest_is_refused_before_anything_is_set_aside_for_it() {
+        let node = a_node(Network::Main);
+
+        let error = generate(&node, u32::MAX, KEY_ONE).expect_err("main blocks are mined");
+
+        assert!(format!("{error:#}").contains("regtest"), "got: {error:#}");
+    }
+
+    #[test]
+    fn an_address_that_is_not_a_key_is_refused_before_anything_is_mined() {
+        let node = a_node(Network::Regtest);
+
+        generate(&node, 1, "not-a-key").expect_err("coins sent nowhere are lost");
+
+        assert_eq!(0, node.lock().unwrap().chain.height());
+    }
+}
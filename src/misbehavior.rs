@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// The score at which a peer is dropped and its address banned.
+pub const BAN_SCORE: u32 = 100;
+pub const BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Something a peer did that an honest one would not. Each costs its weight
+/// in score; what it sent is otherwise ignored, so a peer is only dropped for
+/// the sum, or for what cannot be ignored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// A frame whose payload does not hash to its checksum: garbled, or made
+    /// up. The frame is skipped.
+    BadChecksum,
+    /// Headers that fail their checks or do not form a chain, whether a
+    /// `headers` answer or a compact block's. Proof of work is not met by
+    /// accident, nor a chain broken.
+    InvalidHeader,
+    /// A block whose body fails its checks. We asked for it by a header that
+    /// passed, so the body is wrong on purpose.
+    InvalidBlock,
+    /// A transaction that fails the checks it could never pass, or pays out
+    /// more than it spends. Relayed only after those checks, so sent on
+    /// purpose.
+    InvalidTransaction,
+    /// Headers or a block we never asked this peer for.
+    UnsolicitedData,
+    /// A message out of the handshake's order: anything before it completes,
+    /// a verack before any version, or a second version.
+    HandshakeViolation,
+    /// A pong whose nonce is none we sent, or one already answered.
+    UnknownPong,
+    /// A `getdata` asking for more than their outbound budget holds at once.
+    /// What did not fit goes unanswered.
+    ExcessiveRequest,
+}
+
+impl Misbehavior {
+    pub fn weight(self) -> u32 {
+        match self {
+            Misbehavior::BadChecksum => 20,
+            Misbehavior::InvalidHeader => BAN_SCORE,
+            Misbehavior::InvalidBlock => BAN_SCORE,
+            Misbehavior::InvalidTransaction => BAN_SCORE,
+            Misbehavior::UnsolicitedData => 10,
+            Misbehavior::HandshakeViolation => 50,
+            Misbehavior::UnknownPong => 10,
+            Misbehavior::ExcessiveRequest => 20,
+        }
+    }
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Misbehavior::BadChecksum => "bad checksum",
+            Misbehavior::InvalidHeader => "invalid header",
+            Misbehavior::InvalidBlock => "invalid block",
+            Misbehavior::InvalidTransaction => "invalid transaction",
+            Misbehavior::UnsolicitedData => "unsolicited data",
+            Misbehavior::HandshakeViolation => "handshake violation",
+            Misbehavior::UnknownPong => "unknown pong",
+            Misbehavior::ExcessiveRequest => "excessive request",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Addresses refused until their ban expires. Keyed by IP, not socket
+/// address: a banned peer reconnects from a new port as easily as the old.
+#[derive(Debug, Default)]
+pub struct BanList {
+    until: HashMap<IpAddr, Instant>,
+}
+
+impl BanList {
+    pub fn ban(&mut self, ip: IpAddr, now: Instant) {
+        self.until.insert(ip, now + BAN_DURATION);
+    }
+
+    /// Forgets the ban once it has expired.
+    pub fn is_banned(&mut self, ip: IpAddr, now: Instant) -> bool {
+        match self.until.get(&ip) {
+            Some(until) if *until > now => true,
+            Some(_) => {
+                self.until.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn an_invalid_block_alone_is_enough_for_a_ban() {
+        assert!(Misbehavior::InvalidHeader.weight() >= BAN_SCORE);
+        assert!(Misbehavior::InvalidBlock.weight() >= BAN_SCORE);
+        assert!(Misbehavior::InvalidTransaction.weight() >= BAN_SCORE);
+        for lesser in [
+            Misbehavior::BadChecksum,
+            Misbehavior::UnsolicitedData,
+            Misbehavior::HandshakeViolation,
+            Misbehavior::ExcessiveRequest,
+        ] {
+            assert!(
+                lesser.weight() < BAN_SCORE,
+                "{lesser} alone should be forgiven"
+            );
+        }
+    }
+
+    #[test]
+    fn a_ban_holds_until_it_expires_and_is_then_forgotten() {
+        let now = Instant::now();
+        let mut bans = BanList::default();
+        bans.ban(ip(1), now);
+
+        assert!(bans.is_banned(ip(1), now + BAN_DURATION - Duration::from_secs(1)));
+        assert!(!bans.is_banned(ip(2), now), "only the address banned");
+
+        assert!(!bans.is_banned(ip(1), now + BAN_DURATION));
+        assert!(bans.until.is_empty());
+    }
+
+    #[test]
+    fn a_ban_covers_every_port_of_the_address() {
+        let now = Instant::now();
+        let mut bans = BanList::default();
+        let banned: std::net::SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        bans.ban(banned.ip(), now);
+
+        let returning: std::net::SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        assert!(bans.is_banned(returning.ip(), now));
+    }
+}
@@ -1,12 +1,44 @@
+use crate::address_book::AddressBook;
+use crate::block::Block;
+use crate::blockchain::{Blockchain, MAX_FUTURE_DRIFT};
 use crate::config::Config;
+use crate::connections::ConfiguredPeers;
+use crate::download::Downloads;
+use crate::mempool::Mempool;
+use crate::messages::compact_block::CompactBlock;
+use crate::messages::inv::Inv;
+use crate::messages::inv::INV_COMMAND_NAME;
+use crate::messages::inventory::Inventory;
+use crate::messages::message::{command_of, Message};
+use crate::messages::version::{introduced_in, Services, Version};
+use crate::misbehavior::BanList;
+use crate::outbound::{Frame, Outbound, Priority, Sent};
+use crate::params::{Magic, Params};
 use rand::Rng;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::SocketAddr;
-use std::sync::mpsc::{SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
-
-pub const MAX_PEERS: usize = 32;
-pub const OUTBOUND_QUEUE: usize = 128;
+use std::time::{Duration, Instant};
+
+/// How many connections of each origin the table admits unless configured
+/// otherwise. Counted apart, so peers dialling in cannot take the slots of
+/// the ones we chose to dial.
+pub const MAX_INBOUND: usize = 24;
+pub const MAX_OUTBOUND: usize = 16;
+/// Feelers open at once. Beside the outbound slots, not out of them: testing
+/// an address must never cost the node a peer.
+pub const MAX_FEELERS: usize = 1;
+/// How much of what a peer has seen we remember, per peer. Forgetting costs
+/// at most a repeated announcement, which it ignores.
+pub const MAX_KNOWN_INVENTORY: usize = 5_000;
+/// How far our clock may stray from the network's before the log says so:
+/// half the future limit, so the warning comes before blocks are refused.
+pub const CLOCK_WARNING_OFFSET: i64 = MAX_FUTURE_DRIFT as i64 / 2;
+/// Fewer peers than this are not a network to set a clock by.
+pub const MIN_CLOCK_SAMPLES: usize = 5;
+/// How many distinct unknown commands from one peer are logged; a peer can
+/// make up more than the log should hold.
+pub const MAX_LOGGED_UNKNOWN: usize = 8;
 
 pub type PeerId = u64;
 pub type SharedNode = Arc<Mutex<Node>>;
@@ -15,6 +47,9 @@ pub type SharedNode = Arc<Mutex<Node>>;
 pub enum Origin {
     Dialled,
     Accepted,
+    /// Dialled only to learn whether anything answers there: closed once the
+    /// handshake completes, and never synced with.
+    Feeler,
 }
 
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
@@ -49,39 +84,213 @@ impl Handshake {
     }
 }
 
+/// Where headers-first sync stands with one peer. Every ready peer is asked,
+/// and what each answers is judged by its work alone, so no one peer decides
+/// the chain we follow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HeaderSync {
+    #[default]
+    Idle,
+    /// A `getheaders` is outstanding; only now is a `headers` welcome.
+    Requested,
+    /// As `Requested`, but they have announced a block since we asked, which
+    /// the answer may predate: another request follows it.
+    Outdated,
+    /// Their last answer was short of a full batch: they have nothing newer.
+    CaughtUp,
+}
+
 #[derive(Debug)]
 pub struct PeerHandle {
     pub address: SocketAddr,
     pub origin: Origin,
+    /// Where their `version` says they listen. For a peer we accepted, the
+    /// address it could be dialled back on, unlike `address`.
+    pub listen_address: Option<SocketAddr>,
+    /// The node nonce from their `version`: which node this is, however many
+    /// connections it arrives on.
+    pub nonce: Option<u64>,
     pub handshake: Handshake,
-    outbound: SyncSender<Vec<u8>>,
+    pub header_sync: HeaderSync,
+    /// The height of the newest header they have sent us: which blocks they
+    /// can be asked for.
+    pub best_height: u32,
+    /// What they have sent us or we have announced to them, so nothing is
+    /// announced to a peer twice.
+    pub known: KnownInventory,
+    /// The summed weight of their misbehavior; at `BAN_SCORE` they are
+    /// banned.
+    pub score: u32,
+    /// The protocol both sides speak, once their `version` has said theirs;
+    /// a message introduced after it is not sent them.
+    pub protocol_version: Option<u32>,
+    /// What their `version` says they offer.
+    pub services: Services,
+    /// What software their `version` says they run. Theirs to choose, so it
+    /// is shown, never trusted.
+    pub user_agent: String,
+    /// The chain height their `version` claimed: whether they may be ahead
+    /// of us before they have shown a header.
+    pub start_height: u32,
+    /// When their `version` was sent, by their clock.
+    pub timestamp: u32,
+    /// How far their clock ran ahead of ours when their `version` arrived;
+    /// negative if behind.
+    pub clock_offset: Option<i64>,
+    /// The round trip of their latest pong.
+    pub latency: Option<Duration>,
+    /// Well-framed messages under a command we do not know, skipped.
+    pub unknown_messages: u64,
+    /// The unknown commands already logged, up to `MAX_LOGGED_UNKNOWN`:
+    /// each is logged once, and the rest only counted.
+    unknown_commands: HashSet<String>,
+    outbound: Outbound,
+}
+
+impl PeerHandle {
+    /// Whether the protocol they speak has `command`. Until their `version`
+    /// says, only the handshake is sent them anyway.
+    pub fn speaks(&self, command: &str) -> bool {
+        self.protocol_version
+            .is_none_or(|version| version >= introduced_in(command))
+    }
+}
+
+/// A bounded set that forgets its oldest entries first.
+#[derive(Debug, Default)]
+pub struct KnownInventory {
+    items: HashSet<Inventory>,
+    order: VecDeque<Inventory>,
+}
+
+impl KnownInventory {
+    /// Whether it was news.
+    pub fn insert(&mut self, item: Inventory) -> bool {
+        if !self.items.insert(item) {
+            return false;
+        }
+
+        self.order.push_back(item);
+        while self.order.len() > MAX_KNOWN_INVENTORY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.items.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, item: &Inventory) -> bool {
+        self.items.contains(item)
+    }
+}
+
+/// The pings a connection has sent and not yet had answered, oldest first.
+/// Shared by its writer, which sends them, and its reader, which takes the
+/// pongs.
+#[derive(Debug)]
+pub struct Pings {
+    awaiting: VecDeque<(u64, Instant)>,
+}
+
+impl Default for Pings {
+    fn default() -> Self {
+        Pings::new()
+    }
+}
+
+impl Pings {
+    pub const fn new() -> Self {
+        Pings {
+            awaiting: VecDeque::new(),
+        }
+    }
+
+    pub fn sent(&mut self, nonce: u64, at: Instant) {
+        self.awaiting.push_back((nonce, at));
+    }
+
+    /// The round trip of the ping `nonce` answers, or `None` if it answers
+    /// none we are waiting on. Pongs come in order, so the pings before it
+    /// are forgotten with it.
+    pub fn answered(&mut self, nonce: u64, at: Instant) -> Option<Duration> {
+        let position = self.awaiting.iter().position(|(sent, _)| *sent == nonce)?;
+        let (_, sent_at) = self.awaiting.drain(..=position).next_back()?;
+        Some(at.saturating_duration_since(sent_at))
+    }
+
+    /// How long the oldest ping has gone unanswered, if longer than
+    /// `timeout`.
+    pub fn overdue(&self, now: Instant, timeout: Duration) -> Option<Duration> {
+        let (_, oldest) = self.awaiting.front()?;
+        Some(now.saturating_duration_since(*oldest)).filter(|waited| *waited >= timeout)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Refused {
-    AtCapacity,
+    InboundAtCapacity,
+    OutboundAtCapacity,
+    FeelerAtCapacity,
     AlreadyDialled,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct PeerTable {
     peers: HashMap<PeerId, PeerHandle>,
     next_id: PeerId,
+    max_inbound: usize,
+    max_outbound: usize,
+    /// Whether the network's clock was last found too far from ours, so the
+    /// warning is given once per straying rather than once per peer.
+    clock_skewed: bool,
+}
+
+/// Our clock crossing `CLOCK_WARNING_OFFSET` from the network's median, by
+/// that median's offset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockSkew {
+    Strayed(i64),
+    Recovered(i64),
+}
+
+impl Default for PeerTable {
+    fn default() -> Self {
+        PeerTable::new(MAX_INBOUND, MAX_OUTBOUND)
+    }
 }
 
 impl PeerTable {
+    pub fn new(max_inbound: usize, max_outbound: usize) -> Self {
+        PeerTable {
+            peers: HashMap::new(),
+            next_id: 0,
+            max_inbound,
+            max_outbound,
+            clock_skewed: false,
+        }
+    }
+
     pub fn register(
         &mut self,
         address: SocketAddr,
         origin: Origin,
-        outbound: SyncSender<Vec<u8>>,
+        outbound: Outbound,
     ) -> Result<PeerId, Refused> {
         if origin == Origin::Dialled && self.dialled(address) {
             return Err(Refused::AlreadyDialled);
         }
 
-        if self.peers.len() >= MAX_PEERS {
-            return Err(Refused::AtCapacity);
+        match origin {
+            Origin::Accepted if self.count(Origin::Accepted) >= self.max_inbound => {
+                return Err(Refused::InboundAtCapacity);
+            }
+            Origin::Dialled if self.count(Origin::Dialled) >= self.max_outbound => {
+                return Err(Refused::OutboundAtCapacity);
+            }
+            Origin::Feeler if self.count(Origin::Feeler) >= MAX_FEELERS => {
+                return Err(Refused::FeelerAtCapacity);
+            }
+            _ => {}
         }
 
         let id = self.next_id;
@@ -91,7 +300,22 @@ impl PeerTable {
             PeerHandle {
                 address,
                 origin,
+                listen_address: None,
+                nonce: None,
                 handshake: Handshake::default(),
+                header_sync: HeaderSync::default(),
+                best_height: 0,
+                known: KnownInventory::default(),
+                score: 0,
+                protocol_version: None,
+                services: Services::default(),
+                user_agent: String::new(),
+                start_height: 0,
+                timestamp: 0,
+                clock_offset: None,
+                latency: None,
+                unknown_messages: 0,
+                unknown_commands: HashSet::new(),
                 outbound,
             },
         );
@@ -105,6 +329,178 @@ impl PeerTable {
             .any(|peer| peer.origin == Origin::Dialled && peer.address == address)
     }
 
+    pub fn dialled_count(&self) -> usize {
+        self.count(Origin::Dialled)
+    }
+
+    fn count(&self, origin: Origin) -> usize {
+        self.peers
+            .values()
+            .filter(|peer| peer.origin == origin)
+            .count()
+    }
+
+    /// Every address a connected peer is reachable on: what we dialled, and
+    /// where each peer says it listens. None of them is worth dialling again.
+    pub fn addresses(&self) -> HashSet<SocketAddr> {
+        self.peers
+            .values()
+            .flat_map(|peer| {
+                let dialled = (peer.origin != Origin::Accepted).then_some(peer.address);
+                dialled.into_iter().chain(peer.listen_address)
+            })
+            .collect()
+    }
+
+    pub fn set_protocol_version(&mut self, id: PeerId, version: u32) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.protocol_version = Some(version);
+        }
+    }
+
+    pub fn protocol_version_of(&self, id: PeerId) -> Option<u32> {
+        self.peers.get(&id)?.protocol_version
+    }
+
+    /// Counts one more unknown message from `id`, under `command`, and
+    /// returns how many so far, and whether `command` is one not yet logged
+    /// and still worth logging.
+    pub fn skipped_unknown(&mut self, id: PeerId, command: &str) -> Option<(u64, bool)> {
+        let peer = self.peers.get_mut(&id)?;
+        peer.unknown_messages += 1;
+        let first = peer.unknown_commands.len() < MAX_LOGGED_UNKNOWN
+            && peer.unknown_commands.insert(command.to_string());
+        Some((peer.unknown_messages, first))
+    }
+
+    pub fn unknown_messages_of(&self, id: PeerId) -> Option<u64> {
+        self.peers.get(&id).map(|peer| peer.unknown_messages)
+    }
+
+    /// Records what their `version` says of them.
+    /// `now` is when their `version` arrived, by our clock.
+    pub fn describe(&mut self, id: PeerId, version: &Version, now: u32) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.listen_address = Some(version.listen_address);
+            peer.services = version.services;
+            peer.user_agent = version.user_agent.clone();
+            peer.start_height = version.best_height;
+            peer.timestamp = version.timestamp;
+            peer.clock_offset = Some(i64::from(version.timestamp) - i64::from(now));
+        }
+    }
+
+    /// The median of our peers' clock offsets, once there are enough of them.
+    /// A median, so no one peer moves it however far it lies.
+    pub fn network_offset(&self) -> Option<i64> {
+        let mut offsets: Vec<i64> = self
+            .peers
+            .values()
+            .filter_map(|peer| peer.clock_offset)
+            .collect();
+        if offsets.len() < MIN_CLOCK_SAMPLES {
+            return None;
+        }
+
+        offsets.sort_unstable();
+        Some(offsets[offsets.len() / 2])
+    }
+
+    /// What time the network's median clock says it is. Shown, not used to
+    /// validate: peers that lie together would otherwise move our future limit.
+    pub fn adjusted_time(&self, now: u32) -> u32 {
+        let offset = self.network_offset().unwrap_or(0);
+        u32::try_from((i64::from(now) + offset).max(0)).unwrap_or(u32::MAX)
+    }
+
+    /// Whether our clock has just strayed from the network's, or just come
+    /// back; `None` if neither.
+    pub fn reckon_clock(&mut self) -> Option<ClockSkew> {
+        let offset = self.network_offset()?;
+        let skewed = offset.abs() > CLOCK_WARNING_OFFSET;
+        if skewed == self.clock_skewed {
+            return None;
+        }
+
+        self.clock_skewed = skewed;
+        Some(if skewed {
+            ClockSkew::Strayed(offset)
+        } else {
+            ClockSkew::Recovered(offset)
+        })
+    }
+
+    pub fn get(&self, id: PeerId) -> Option<&PeerHandle> {
+        self.peers.get(&id)
+    }
+
+    pub fn set_latency(&mut self, id: PeerId, latency: Duration) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.latency = Some(latency);
+        }
+    }
+
+    pub fn start_height_of(&self, id: PeerId) -> Option<u32> {
+        self.peers.get(&id).map(|peer| peer.start_height)
+    }
+
+    /// Records which node `id` is and where it says it listens, and returns
+    /// another connection already identified as the same one, if there is
+    /// one. A nonce is only the peer's word, so the two must also share an
+    /// address, connected to or listened on, before they count as one node.
+    pub fn identify(
+        &mut self,
+        id: PeerId,
+        nonce: u64,
+        listen_address: SocketAddr,
+    ) -> Option<(PeerId, Origin)> {
+        let peer = self.peers.get_mut(&id)?;
+        peer.nonce = Some(nonce);
+        peer.listen_address = Some(listen_address);
+        let ours = [Some(peer.address), peer.listen_address];
+
+        self.peers
+            .iter()
+            .find(|(other, peer)| {
+                **other != id
+                    && peer.nonce == Some(nonce)
+                    && [Some(peer.address), peer.listen_address]
+                        .iter()
+                        .any(|address| address.is_some() && ours.contains(address))
+            })
+            .map(|(other, peer)| (*other, peer.origin))
+    }
+
+    /// Adds `weight` to their score, and returns the new one; `None` once
+    /// they are gone.
+    pub fn misbehaved(&mut self, id: PeerId, weight: u32) -> Option<u32> {
+        let peer = self.peers.get_mut(&id)?;
+        peer.score = peer.score.saturating_add(weight);
+        Some(peer.score)
+    }
+
+    /// Makes room on a full side for a newcomer of `origin` by removing the
+    /// worst-scoring peer there, the newest of equals. A side where no one
+    /// has misbehaved is left as it is, and the newcomer refused.
+    pub fn evict_worst(&mut self, origin: Origin) -> Option<(PeerId, PeerHandle)> {
+        let cap = match origin {
+            Origin::Accepted => self.max_inbound,
+            Origin::Dialled => self.max_outbound,
+            Origin::Feeler => MAX_FEELERS,
+        };
+        if self.count(origin) < cap {
+            return None;
+        }
+
+        let (id, _) = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.origin == origin && peer.score > 0)
+            .max_by_key(|(id, peer)| (peer.score, **id))?;
+        let id = *id;
+        self.peers.remove(&id).map(|peer| (id, peer))
+    }
+
     pub fn remove(&mut self, id: PeerId) -> Option<PeerHandle> {
         self.peers.remove(&id)
     }
@@ -137,34 +533,186 @@ impl PeerTable {
         self.peers.get(&id).map(|peer| peer.handshake)
     }
 
+    pub fn score_of(&self, id: PeerId) -> Option<u32> {
+        self.peers.get(&id).map(|peer| peer.score)
+    }
+
+    pub fn header_sync_of(&self, id: PeerId) -> Option<HeaderSync> {
+        self.peers.get(&id).map(|peer| peer.header_sync)
+    }
+
+    pub fn set_header_sync(&mut self, id: PeerId, state: HeaderSync) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.header_sync = state;
+        }
+    }
+
+    pub fn saw_height(&mut self, id: PeerId, height: u32) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.best_height = peer.best_height.max(height);
+        }
+    }
+
+    /// Ready peers that serve blocks and have shown us a header past genesis,
+    /// with the height of the newest: whom block bodies can be downloaded
+    /// from.
+    pub fn sources(&self) -> Vec<(PeerId, u32)> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| {
+                peer.handshake.is_ready()
+                    && peer.services.has(Services::SERVES_BLOCKS)
+                    && peer.best_height > 0
+            })
+            .map(|(id, peer)| (*id, peer.best_height))
+            .collect()
+    }
+
+    pub fn mark_known(&mut self, id: PeerId, inventory: &[Inventory]) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            for item in inventory {
+                peer.known.insert(*item);
+            }
+        }
+    }
+
+    /// Tells every ready peer whichever of `inventory` it has not yet seen.
+    /// A peer still in its handshake is told nothing: it has not said which
+    /// network it is on. Blocks are announced at once; transactions are left
+    /// for the peer's writer to batch into its next `inv`, so neither when
+    /// one is told nor what it is told with gives away where a transaction
+    /// started. How many peers were told anything.
+    pub fn announce(&mut self, magic: Magic, inventory: &[Inventory]) -> anyhow::Result<usize> {
+        let mut told = 0;
+        let mut failed = Vec::new();
+
+        for (id, peer) in self.peers.iter_mut() {
+            if !peer.handshake.is_ready() || !peer.speaks(INV_COMMAND_NAME) {
+                continue;
+            }
+
+            let mut blocks = Vec::new();
+            let mut trickled = false;
+            for item in inventory {
+                if !peer.known.insert(*item) {
+                    continue;
+                }
+                match item {
+                    Inventory::Block(_) => blocks.push(*item),
+                    Inventory::Tx(txid) => {
+                        trickled |= peer.outbound.trickle(*txid) == Sent::Queued;
+                    }
+                }
+            }
+
+            // A skipped transaction only costs them asking; a block is how
+            // they learn the chain moved, and one that does not fit costs the
+            // peer.
+            let sent = if blocks.is_empty() {
+                Sent::Skipped
+            } else {
+                let bytes = Message::new(magic, Inv { inventory: blocks })?.get_raw_format()?;
+                peer.outbound.send(bytes, Priority::Essential)
+            };
+            match sent {
+                Sent::Failed => failed.push(*id),
+                Sent::Queued => told += 1,
+                Sent::Skipped if trickled => told += 1,
+                Sent::Skipped => {}
+            }
+        }
+
+        for id in failed {
+            self.peers.remove(&id);
+        }
+
+        Ok(told)
+    }
+
+    /// Tells every ready peer that has not seen it of a block we have just
+    /// connected: as a compact block to a peer that speaks them, which can
+    /// usually rebuild it from its mempool without asking for anything, and
+    /// as an `inv` to the rest. How many peers were told.
+    pub fn announce_block(&mut self, magic: Magic, block: &Block) -> anyhow::Result<usize> {
+        let hash = block
+            .hash
+            .ok_or_else(|| anyhow::anyhow!("only a sealed block can be announced"))?;
+        let item = Inventory::Block(hash);
+        let compact = CompactBlock::new(block, rand::rng().next_u64())?;
+        let compact = Message::new(magic, compact)?.get_raw_format()?;
+        let inv = Message::new(
+            magic,
+            Inv {
+                inventory: vec![item],
+            },
+        )?
+        .get_raw_format()?;
+
+        // A peer told by the first is known to have seen it by the second.
+        let told = self.broadcast(compact, |peer| peer.known.insert(item))
+            + self.broadcast(inv, |peer| peer.known.insert(item));
+        Ok(told)
+    }
+
     pub fn ids(&self) -> Vec<PeerId> {
         self.peers.keys().copied().collect()
     }
 
-    pub fn send_to(&mut self, id: PeerId, message: Vec<u8>) -> bool {
+    /// Whether a reply of `size` bytes would fit what `id` has waiting, for
+    /// asking before `send_to` would drop them over it.
+    pub fn has_room(&self, id: PeerId, size: usize) -> bool {
+        self.peers
+            .get(&id)
+            .is_some_and(|peer| peer.outbound.fits(size, Priority::Essential))
+    }
+
+    /// Queues `message` for `id`. `Skipped` if it is a command the protocol
+    /// they speak lacks: not sent, and no fault of theirs. `Failed` if they
+    /// are gone, or removed for being unable to take it.
+    pub fn send_to(&mut self, id: PeerId, message: impl Into<Frame>) -> Sent {
         let Some(peer) = self.peers.get(&id) else {
-            return false;
+            return Sent::Failed;
         };
+        let message = message.into();
+        if !peer.speaks(command_of(&message)) {
+            return Sent::Skipped;
+        }
 
-        match peer.outbound.try_send(message) {
-            Ok(()) => true,
-            Err(_) => {
+        match peer.outbound.send(message, Priority::Essential) {
+            Sent::Queued => Sent::Queued,
+            Sent::Skipped | Sent::Failed => {
                 self.peers.remove(&id);
-                false
+                Sent::Failed
             }
         }
     }
 
-    /// `try_send`, because a blocking send would hold the node's lock on one
-    /// stalled socket and stop delivery to everyone else.
-    pub fn broadcast(&mut self, message: &[u8]) -> usize {
+    /// Sends `message` to every peer `to` picks. Framed once and shared:
+    /// every peer's queue holds the same buffer. Never blocks, because a
+    /// blocking send would hold the node's lock on one stalled socket and stop
+    /// delivery to everyone else. A peer still in its handshake is skipped,
+    /// and never offered to `to`: until then it is sent nothing but our
+    /// version. So is one whose protocol lacks the command.
+    pub fn broadcast(
+        &mut self,
+        message: impl Into<Frame>,
+        mut to: impl FnMut(&mut PeerHandle) -> bool,
+    ) -> usize {
+        let message = message.into();
+        let command = command_of(&message);
         let mut delivered = 0;
         let mut failed = Vec::new();
 
-        for (id, peer) in &self.peers {
-            match peer.outbound.try_send(message.to_vec()) {
-                Ok(()) => delivered += 1,
-                Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => failed.push(*id),
+        for (id, peer) in self.peers.iter_mut() {
+            if !peer.handshake.is_ready() || !peer.speaks(command) || !to(peer) {
+                continue;
+            }
+            match peer
+                .outbound
+                .send(Arc::clone(&message), Priority::Essential)
+            {
+                Sent::Queued => delivered += 1,
+                Sent::Skipped | Sent::Failed => failed.push(*id),
             }
         }
 
@@ -213,17 +761,31 @@ impl Default for Log {
 #[derive(Debug)]
 pub struct Node {
     pub config: Config,
+    pub params: Params,
+    pub chain: Blockchain,
     pub peers: PeerTable,
+    pub downloads: Downloads,
+    pub mempool: Mempool,
+    pub addresses: AddressBook,
+    pub configured: ConfiguredPeers,
+    pub bans: BanList,
     pub log: Log,
     /// Minted once per run so a node can recognise a connection to itself.
     pub nonce: u64,
 }
 
 impl Node {
-    pub fn shared(config: Config) -> SharedNode {
+    pub fn shared(config: Config, params: Params) -> SharedNode {
         Arc::new(Mutex::new(Self {
+            configured: ConfiguredPeers::new(&config.addresses_to_connect, Instant::now()),
+            peers: PeerTable::new(config.max_inbound, config.max_outbound),
             config,
-            peers: PeerTable::default(),
+            chain: Blockchain::new(params.genesis.clone()),
+            params,
+            downloads: Downloads::default(),
+            mempool: Mempool::default(),
+            addresses: AddressBook::default(),
+            bans: BanList::default(),
             log: Log::default(),
             nonce: rand::rng().next_u64(),
         }))
@@ -251,23 +813,32 @@ pub fn record(node: &SharedNode, entry: impl Into<String>) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::outbound::{queue, Queue, OUTBOUND_BUDGET};
+    use crate::params::Network;
     use rstest::rstest;
-    use std::sync::mpsc::{sync_channel, Receiver};
     use std::thread;
 
     fn config() -> Config {
         Config {
+            network: Network::Main,
             host_address: "127.0.0.1:34352".parse().unwrap(),
             addresses_to_connect: Vec::new(),
+            data_dir: "data".into(),
+            max_inbound: MAX_INBOUND,
+            max_outbound: MAX_OUTBOUND,
         }
     }
 
+    fn a_node() -> SharedNode {
+        Node::shared(config(), Params::of(Network::Main).unwrap())
+    }
+
     fn address(port: u16) -> SocketAddr {
         format!("127.0.0.1:{port}").parse().unwrap()
     }
 
-    fn a_peer(table: &mut PeerTable, port: u16) -> (PeerId, Receiver<Vec<u8>>) {
-        let (outbound, queued) = sync_channel(OUTBOUND_QUEUE);
+    fn a_peer(table: &mut PeerTable, port: u16) -> (PeerId, Queue) {
+        let (outbound, queued) = queue(OUTBOUND_BUDGET);
         let id = table
             .register(address(port), Origin::Accepted, outbound)
             .expect("registering the first peers should succeed");
@@ -276,7 +847,7 @@ mod tests {
 
     #[test]
     fn every_connection_thread_holds_the_same_node_not_a_copy() {
-        let node = Node::shared(config());
+        let node = a_node();
 
         let threads: Vec<_> = (0..4)
             .map(|_| {
@@ -340,7 +911,7 @@ mod tests {
 
     #[test]
     fn recording_reaches_the_nodes_log() {
-        let node = Node::shared(config());
+        let node = a_node();
 
         record(&node, "something happened");
         record(&node, format!("and then {}", "something else"));
@@ -353,7 +924,7 @@ mod tests {
 
     #[test]
     fn recording_from_a_connection_thread_reaches_the_same_log() {
-        let node = Node::shared(config());
+        let node = a_node();
 
         let writer = Arc::clone(&node);
         thread::spawn(move || record(&writer, "from another thread"))
@@ -389,26 +960,69 @@ mod tests {
     fn broadcast_reaches_every_peer() {
         let mut table = PeerTable::default();
         let queues: Vec<_> = (0..3)
-            .map(|index| a_peer(&mut table, 5000 + index).1)
+            .map(|index| a_ready_peer(&mut table, 5000 + index).1)
             .collect();
 
-        assert_eq!(3, table.broadcast(b"a block"));
+        assert_eq!(3, table.broadcast(&b"a block"[..], |_| true));
 
         for queued in &queues {
-            assert_eq!(b"a block".to_vec(), queued.try_recv().unwrap());
+            assert_eq!(&b"a block"[..], &*queued.try_recv().unwrap());
             assert!(queued.try_recv().is_err(), "one broadcast, one message");
         }
     }
 
+    #[test]
+    fn broadcast_shares_one_buffer_between_every_peer() {
+        let mut table = PeerTable::default();
+        let (_, to_first) = a_ready_peer(&mut table, 5000);
+        let (_, to_second) = a_ready_peer(&mut table, 5001);
+
+        assert_eq!(2, table.broadcast(vec![0; 1024], |_| true));
+
+        let first = to_first.try_recv().unwrap();
+        let second = to_second.try_recv().unwrap();
+        assert!(
+            Arc::ptr_eq(&first, &second),
+            "framed once, not copied per peer"
+        );
+    }
+
+    #[test]
+    fn broadcast_reaches_only_the_peers_it_is_told_to() {
+        let mut table = PeerTable::default();
+        let (_, to_picked) = a_ready_peer(&mut table, 5000);
+        let (_, to_passed_over) = a_ready_peer(&mut table, 5001);
+
+        let told = table.broadcast(&b"a block"[..], |peer| peer.address.port() == 5000);
+
+        assert_eq!(1, told);
+        assert_eq!(&b"a block"[..], &*to_picked.try_recv().unwrap());
+        assert!(to_passed_over.try_recv().is_err());
+        assert_eq!(2, table.len(), "passed over, not dropped");
+    }
+
+    #[test]
+    fn broadcast_skips_a_peer_still_in_its_handshake() {
+        let mut table = PeerTable::default();
+        let (_, to_ready) = a_ready_peer(&mut table, 5000);
+        let (_, to_unready) = a_peer(&mut table, 5001);
+
+        assert_eq!(1, table.broadcast(&b"a block"[..], |_| true));
+
+        assert_eq!(&b"a block"[..], &*to_ready.try_recv().unwrap());
+        assert!(to_unready.try_recv().is_err());
+        assert_eq!(2, table.len(), "skipped, not dropped");
+    }
+
     #[test]
     fn send_to_reaches_exactly_one_peer() {
         let mut table = PeerTable::default();
         let (first, to_first) = a_peer(&mut table, 5000);
         let (_, to_second) = a_peer(&mut table, 5001);
 
-        assert!(table.send_to(first, b"just for you".to_vec()));
+        assert_eq!(Sent::Queued, table.send_to(first, b"just for you".to_vec()));
 
-        assert_eq!(b"just for you".to_vec(), to_first.try_recv().unwrap());
+        assert_eq!(&b"just for you"[..], &*to_first.try_recv().unwrap());
         assert!(
             to_second.try_recv().is_err(),
             "send_to must not reach anyone else"
@@ -420,77 +1034,111 @@ mod tests {
         let mut table = PeerTable::default();
         let (_, to_first) = a_peer(&mut table, 5000);
 
-        assert!(!table.send_to(404, b"nobody".to_vec()));
+        assert_eq!(Sent::Failed, table.send_to(404, b"nobody".to_vec()));
         assert!(to_first.try_recv().is_err());
     }
 
     #[test]
     fn a_peer_that_never_drains_does_not_hold_up_the_others() {
         let mut table = PeerTable::default();
-        let (stalled, never_drained) = a_peer(&mut table, 5000);
-        let (_, to_second) = a_peer(&mut table, 5001);
-        let (_, to_third) = a_peer(&mut table, 5002);
+        let (stalled, never_drained) = a_ready_peer(&mut table, 5000);
+        let (_, to_second) = a_ready_peer(&mut table, 5001);
+        let (_, to_third) = a_ready_peer(&mut table, 5002);
 
-        for _ in 0..OUTBOUND_QUEUE {
-            assert!(table.send_to(stalled, b"backlog".to_vec()));
-        }
+        assert_eq!(
+            Sent::Queued,
+            table.send_to(stalled, vec![0; OUTBOUND_BUDGET])
+        );
 
         assert_eq!(
             2,
-            table.broadcast(b"a block"),
+            table.broadcast(&b"a block"[..], |_| true),
             "the two healthy peers must still be reached"
         );
-        assert_eq!(b"a block".to_vec(), to_second.try_recv().unwrap());
-        assert_eq!(b"a block".to_vec(), to_third.try_recv().unwrap());
+        assert_eq!(&b"a block"[..], &*to_second.try_recv().unwrap());
+        assert_eq!(&b"a block"[..], &*to_third.try_recv().unwrap());
 
         assert_eq!(2, table.len(), "the stalled peer is dropped, not buffered");
         drop(never_drained);
     }
 
     #[test]
-    fn a_peers_queue_does_not_grow_past_the_bound() {
+    fn a_peers_queue_does_not_grow_past_its_byte_budget() {
         let mut table = PeerTable::default();
         let (stalled, never_drained) = a_peer(&mut table, 5000);
+        let chunk = 1024;
 
-        for queued_so_far in 0..OUTBOUND_QUEUE {
-            assert!(
-                table.send_to(stalled, b"backlog".to_vec()),
-                "the bound is {OUTBOUND_QUEUE}, so message {queued_so_far} should fit"
+        for queued_so_far in 0..OUTBOUND_BUDGET / chunk {
+            assert_eq!(
+                Sent::Queued,
+                table.send_to(stalled, vec![0; chunk]),
+                "the budget is {OUTBOUND_BUDGET} bytes, so chunk {queued_so_far} should fit"
             );
         }
 
-        assert!(
-            !table.send_to(stalled, b"one too many".to_vec()),
-            "a queue past {OUTBOUND_QUEUE} is unbounded buffering, not backpressure"
+        assert_eq!(
+            Sent::Failed,
+            table.send_to(stalled, b"one too many".to_vec()),
+            "a queue past its budget is unbounded buffering, not backpressure"
         );
         assert!(table.is_empty(), "a peer that cannot keep up is dropped");
 
-        let drained = std::iter::from_fn(|| never_drained.try_recv().ok()).count();
+        let drained: usize = std::iter::from_fn(|| never_drained.try_recv().ok())
+            .map(|message| message.len())
+            .sum();
         assert_eq!(
-            OUTBOUND_QUEUE, drained,
-            "exactly the bound should have been buffered"
+            OUTBOUND_BUDGET, drained,
+            "exactly the budget should have been buffered"
         );
     }
 
+    #[test]
+    fn a_transaction_announcement_that_does_not_fit_is_dropped_before_the_peer() {
+        let magic = crate::messages::message::TEST_MAGIC;
+        let mut table = PeerTable::default();
+        let (slow, queued) = a_ready_peer(&mut table, 5000);
+        assert_eq!(
+            Sent::Queued,
+            table.send_to(slow, vec![0; OUTBOUND_BUDGET - 8])
+        );
+
+        assert_eq!(0, table.announce(magic, &[Inventory::Tx([2; 32])]).unwrap());
+        assert_eq!(
+            1,
+            table.len(),
+            "the peer stays; only the announcement is lost"
+        );
+
+        assert_eq!(
+            0,
+            table.announce(magic, &[Inventory::Block([1; 32])]).unwrap()
+        );
+        assert!(
+            table.is_empty(),
+            "a block it cannot be told of costs the peer"
+        );
+        drop(queued);
+    }
+
     #[test]
     fn a_peer_whose_writer_is_gone_is_dropped() {
         let mut table = PeerTable::default();
         let (id, queued) = a_peer(&mut table, 5000);
         drop(queued);
 
-        assert!(!table.send_to(id, b"into the void".to_vec()));
+        assert_eq!(Sent::Failed, table.send_to(id, b"into the void".to_vec()));
         assert!(table.is_empty(), "a peer with no writer is not a peer");
     }
 
     #[test]
     fn dialling_the_same_address_twice_registers_one_peer() {
         let mut table = PeerTable::default();
-        let (outbound, _first) = sync_channel(OUTBOUND_QUEUE);
+        let (outbound, _first) = queue(OUTBOUND_BUDGET);
         table
             .register(address(5000), Origin::Dialled, outbound)
             .unwrap();
 
-        let (outbound, _second) = sync_channel(OUTBOUND_QUEUE);
+        let (outbound, _second) = queue(OUTBOUND_BUDGET);
         assert_eq!(
             Err(Refused::AlreadyDialled),
             table.register(address(5000), Origin::Dialled, outbound)
@@ -501,25 +1149,139 @@ mod tests {
     #[test]
     fn a_peer_that_dialled_us_is_not_confused_with_one_we_dialled() {
         let mut table = PeerTable::default();
-        let (outbound, _dialled) = sync_channel(OUTBOUND_QUEUE);
+        let (outbound, _dialled) = queue(OUTBOUND_BUDGET);
         table
             .register(address(5000), Origin::Dialled, outbound)
             .unwrap();
 
         // An accepted connection shows an ephemeral source port, so it cannot be
         // matched against a listen address until M2's version nonce exists.
-        let (outbound, _accepted) = sync_channel(OUTBOUND_QUEUE);
+        let (outbound, _accepted) = queue(OUTBOUND_BUDGET);
         assert!(table
             .register(address(5000), Origin::Accepted, outbound)
             .is_ok());
         assert_eq!(2, table.len());
     }
 
+    #[test]
+    fn a_connected_peer_is_reachable_where_we_dialled_it_and_where_it_listens() {
+        let mut table = PeerTable::default();
+        let (outbound, _dialled) = queue(OUTBOUND_BUDGET);
+        table
+            .register(address(5000), Origin::Dialled, outbound)
+            .unwrap();
+        let (accepted, _queued) = a_peer(&mut table, 40_000);
+        table.describe(accepted, &Version::new(7, address(6000), 0), 0);
+
+        assert_eq!(1, table.dialled_count());
+        assert_eq!(
+            HashSet::from([address(5000), address(6000)]),
+            table.addresses(),
+            "an accepted peer's source port is no one's listen address"
+        );
+    }
+
+    #[test]
+    fn only_a_peer_that_serves_blocks_is_a_source_of_them() {
+        let mut table = PeerTable::default();
+        let (serving, _first) = a_ready_peer(&mut table, 5000);
+        let (pruned, _second) = a_ready_peer(&mut table, 5001);
+        table.describe(serving, &Version::new(1, address(5000), 0), 0);
+        table.describe(
+            pruned,
+            &Version {
+                services: Services::FULL_NODE,
+                ..Version::new(2, address(5001), 0)
+            },
+            0,
+        );
+        table.saw_height(serving, 3);
+        table.saw_height(pruned, 3);
+
+        assert_eq!(vec![(serving, 3)], table.sources());
+    }
+
+    /// Peers whose `version`s were sent `offsets` seconds from our `NOW`.
+    fn peers_with_clocks(table: &mut PeerTable, offsets: &[i64]) {
+        const NOW: u32 = 1_000_000;
+        for (port, offset) in (5000..).zip(offsets) {
+            let (id, _queued) = a_ready_peer(table, port);
+            let version = Version {
+                timestamp: (i64::from(NOW) + offset) as u32,
+                ..Version::new(port.into(), address(port), 0)
+            };
+            table.describe(id, &version, NOW);
+        }
+    }
+
+    #[rstest]
+    #[case::too_few_to_say(&[600, 600, 600, 600], None)]
+    #[case::one_liar_among_honest_clocks(&[0, 1, -2, 3, 86_400], Some(1))]
+    #[case::a_network_ahead_of_us(&[400, 380, 420, 0, 390], Some(390))]
+    fn the_network_offset_is_the_median_of_enough_peers(
+        #[case] offsets: &[i64],
+        #[case] expected: Option<i64>,
+    ) {
+        let mut table = PeerTable::default();
+        peers_with_clocks(&mut table, offsets);
+
+        assert_eq!(expected, table.network_offset());
+        assert_eq!(
+            1_000_000 + expected.unwrap_or(0),
+            i64::from(table.adjusted_time(1_000_000))
+        );
+    }
+
+    #[test]
+    fn a_straying_clock_is_reported_once_and_again_when_it_recovers() {
+        let mut table = PeerTable::default();
+        peers_with_clocks(&mut table, &[-400; MIN_CLOCK_SAMPLES]);
+
+        assert_eq!(Some(ClockSkew::Strayed(-400)), table.reckon_clock());
+        peers_with_clocks(&mut table, &[-500]);
+        assert_eq!(None, table.reckon_clock(), "still astray, already said");
+
+        peers_with_clocks(&mut table, &[0; MIN_CLOCK_SAMPLES + 2]);
+        assert_eq!(Some(ClockSkew::Recovered(0)), table.reckon_clock());
+        assert_eq!(None, table.reckon_clock());
+    }
+
+    #[test]
+    fn a_second_connection_from_the_same_node_is_recognised_by_its_nonce() {
+        let mut table = PeerTable::default();
+        let (first, _first) = a_peer(&mut table, 40_000);
+        let (outbound, _second) = queue(OUTBOUND_BUDGET);
+        let second = table
+            .register(address(5000), Origin::Dialled, outbound)
+            .unwrap();
+        let (third, _third) = a_peer(&mut table, 40_001);
+
+        assert_eq!(None, table.identify(first, 7, address(5000)));
+        assert_eq!(None, table.identify(third, 8, address(5001)));
+        assert_eq!(
+            Some((first, Origin::Accepted)),
+            table.identify(second, 7, address(5000))
+        );
+    }
+
+    #[test]
+    fn a_nonce_shared_by_connections_with_no_address_in_common_is_no_match() {
+        let mut table = PeerTable::default();
+        let (first, _first) = a_peer(&mut table, 40_000);
+        let (outbound, _second) = queue(OUTBOUND_BUDGET);
+        let second = table
+            .register(address(5000), Origin::Dialled, outbound)
+            .unwrap();
+
+        assert_eq!(None, table.identify(second, 7, address(5000)));
+        assert_eq!(None, table.identify(first, 7, address(6000)));
+    }
+
     #[test]
     fn each_node_mints_its_own_nonce() {
         assert_ne!(
-            Node::shared(config()).lock().unwrap().nonce,
-            Node::shared(config()).lock().unwrap().nonce,
+            a_node().lock().unwrap().nonce,
+            a_node().lock().unwrap().nonce,
             "a shared nonce cannot tell a self-connection from a peer"
         );
     }
@@ -545,6 +1307,220 @@ mod tests {
         assert!(table.handshake_of(id).unwrap().is_ready());
     }
 
+    fn a_ready_peer(table: &mut PeerTable, port: u16) -> (PeerId, Queue) {
+        let (id, queued) = a_peer(table, port);
+        table
+            .advance_handshake(id, HandshakeEvent::Version)
+            .unwrap();
+        table.advance_handshake(id, HandshakeEvent::Verack).unwrap();
+        (id, queued)
+    }
+
+    fn announced(queued: &Queue) -> Vec<Inventory> {
+        let bytes = queued.try_recv().expect("an announcement");
+        match crate::messages::message::MessageReceived::try_parse_message(
+            crate::messages::message::TEST_MAGIC,
+            &bytes,
+        )
+        .unwrap()
+        {
+            (Some(crate::messages::message::MessageReceived::InvMessage(inv)), _) => {
+                inv.payload.inventory
+            }
+            other => panic!("expected an inv, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn an_announcement_tells_each_peer_only_what_it_has_not_seen() {
+        let magic = crate::messages::message::TEST_MAGIC;
+        let mut table = PeerTable::default();
+        let (sender, to_sender) = a_ready_peer(&mut table, 5000);
+        let (_, to_other) = a_ready_peer(&mut table, 5001);
+        let (block, tx) = (Inventory::Block([1; 32]), Inventory::Tx([2; 32]));
+        table.mark_known(sender, &[block]);
+
+        assert_eq!(2, table.announce(magic, &[block, tx]).unwrap());
+
+        assert!(to_sender.try_recv().is_err(), "it sent us the block");
+        assert_eq!(vec![[2; 32]], to_sender.announcements(10));
+        assert_eq!(vec![block], announced(&to_other));
+        assert_eq!(
+            vec![[2; 32]],
+            to_other.announcements(10),
+            "a transaction waits for the writer's next batch"
+        );
+
+        assert_eq!(0, table.announce(magic, &[block, tx]).unwrap());
+        assert!(to_sender.try_recv().is_err() && to_other.try_recv().is_err());
+        assert!(to_sender.announcements(10).is_empty() && to_other.announcements(10).is_empty());
+    }
+
+    #[test]
+    fn a_block_is_announced_compactly_to_peers_that_speak_compact_blocks() {
+        use crate::messages::message::MessageReceived;
+        use crate::messages::version::COMPACT_BLOCKS_VERSION;
+
+        let magic = crate::messages::message::TEST_MAGIC;
+        let mut table = PeerTable::default();
+        let (current, to_current) = a_ready_peer(&mut table, 5000);
+        let (older, to_older) = a_ready_peer(&mut table, 5001);
+        let (sender, to_sender) = a_ready_peer(&mut table, 5002);
+        table.set_protocol_version(current, COMPACT_BLOCKS_VERSION);
+        table.set_protocol_version(older, COMPACT_BLOCKS_VERSION - 1);
+        let block = Params::of(crate::params::Network::Regtest).unwrap().genesis;
+        let hash = block.hash.unwrap();
+        table.mark_known(sender, &[Inventory::Block(hash)]);
+
+        assert_eq!(2, table.announce_block(magic, &block).unwrap());
+
+        let bytes = to_current.try_recv().expect("an announcement");
+        match MessageReceived::try_parse_message(magic, &bytes).unwrap() {
+            (Some(MessageReceived::CompactBlockMessage(compact)), _) => {
+                assert_eq!(hash, compact.payload.header.hash())
+            }
+            other => panic!("expected a cmpctblock, got {other:?}"),
+        }
+        assert_eq!(vec![Inventory::Block(hash)], announced(&to_older));
+        assert!(to_sender.try_recv().is_err(), "it sent us the block");
+    }
+
+    #[test]
+    fn a_block_announcement_shares_one_buffer_between_every_peer() {
+        let magic = crate::messages::message::TEST_MAGIC;
+        let mut table = PeerTable::default();
+        let (_, to_first) = a_ready_peer(&mut table, 5000);
+        let (_, to_second) = a_ready_peer(&mut table, 5001);
+        let block = Params::of(crate::params::Network::Regtest).unwrap().genesis;
+
+        assert_eq!(2, table.announce_block(magic, &block).unwrap());
+
+        let first = to_first.try_recv().unwrap();
+        let second = to_second.try_recv().unwrap();
+        assert!(
+            Arc::ptr_eq(&first, &second),
+            "framed once, not copied per peer"
+        );
+    }
+
+    #[test]
+    fn a_peer_that_speaks_only_the_handshake_is_sent_nothing_newer() {
+        use crate::messages::get_addr::GetAddr;
+        use crate::messages::get_headers::GetHeaders;
+        use crate::messages::message::MessageReceived;
+        use crate::messages::ping::Ping;
+        use crate::messages::version::SKIPS_UNKNOWN_VERSION;
+
+        let magic = crate::messages::message::TEST_MAGIC;
+        let mut table = PeerTable::default();
+        let (older, to_older) = a_ready_peer(&mut table, 5000);
+        table.set_protocol_version(older, SKIPS_UNKNOWN_VERSION - 1);
+        let block = Params::of(crate::params::Network::Regtest).unwrap().genesis;
+        let getaddr = Message::new(magic, GetAddr).unwrap();
+        let getheaders = Message::new(magic, GetHeaders::new(Vec::new())).unwrap();
+        let ping = Message::new(magic, Ping { nonce: 1 }).unwrap();
+
+        assert_eq!(
+            Sent::Skipped,
+            table.send_to(older, getaddr.get_raw_format().unwrap())
+        );
+        assert_eq!(
+            Sent::Skipped,
+            table.send_to(older, getheaders.get_raw_format().unwrap())
+        );
+        let inventory = [Inventory::Block([1; 32]), Inventory::Tx([2; 32])];
+        assert_eq!(0, table.announce(magic, &inventory).unwrap());
+        assert_eq!(0, table.announce_block(magic, &block).unwrap());
+        assert_eq!(
+            Sent::Queued,
+            table.send_to(older, ping.get_raw_format().unwrap())
+        );
+
+        match MessageReceived::try_parse_message(magic, &to_older.try_recv().unwrap()).unwrap() {
+            (Some(MessageReceived::PingMessage(_)), _) => {}
+            other => panic!("expected only the ping, got {other:?}"),
+        }
+        assert!(to_older.try_recv().is_err());
+        assert!(to_older.announcements(10).is_empty());
+        assert_eq!(vec![older], table.ids(), "dropped, not disconnected over");
+    }
+
+    #[test]
+    fn nothing_is_announced_to_a_peer_still_in_its_handshake() {
+        let mut table = PeerTable::default();
+        let (_, queued) = a_peer(&mut table, 5000);
+
+        assert_eq!(
+            0,
+            table
+                .announce(
+                    crate::messages::message::TEST_MAGIC,
+                    &[Inventory::Block([1; 32])]
+                )
+                .unwrap()
+        );
+        assert!(queued.try_recv().is_err());
+    }
+
+    #[test]
+    fn a_pong_answers_its_ping_and_every_one_before_it() {
+        let start = Instant::now();
+        let mut pings = Pings::new();
+        pings.sent(1, start);
+        pings.sent(2, start + Duration::from_secs(1));
+        pings.sent(3, start + Duration::from_secs(2));
+
+        assert_eq!(
+            Some(Duration::from_millis(300)),
+            pings.answered(2, start + Duration::from_millis(1300))
+        );
+        assert_eq!(None, pings.answered(1, start), "skipped by the later pong");
+        assert_eq!(None, pings.answered(2, start), "already answered");
+        assert_eq!(None, pings.answered(7, start), "never sent");
+        assert!(pings.answered(3, start + Duration::from_secs(2)).is_some());
+    }
+
+    #[test]
+    fn a_ping_is_overdue_once_the_oldest_has_waited_the_timeout() {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(60);
+        let mut pings = Pings::new();
+        assert_eq!(None, pings.overdue(start + timeout, timeout), "none sent");
+
+        pings.sent(1, start);
+        pings.sent(2, start + Duration::from_secs(30));
+
+        assert_eq!(
+            None,
+            pings.overdue(start + Duration::from_secs(59), timeout)
+        );
+        assert_eq!(Some(timeout), pings.overdue(start + timeout, timeout));
+        pings.answered(1, start + timeout);
+        assert_eq!(None, pings.overdue(start + timeout, timeout));
+    }
+
+    #[test]
+    fn known_inventory_forgets_the_oldest_first() {
+        let mut known = KnownInventory::default();
+
+        for index in 0..=MAX_KNOWN_INVENTORY as u32 {
+            let mut hash = [0; 32];
+            hash[..4].copy_from_slice(&index.to_le_bytes());
+            assert!(known.insert(Inventory::Block(hash)));
+        }
+
+        assert!(
+            !known.contains(&Inventory::Block([0; 32])),
+            "the first is forgotten"
+        );
+        let mut last = [0; 32];
+        last[..4].copy_from_slice(&(MAX_KNOWN_INVENTORY as u32).to_le_bytes());
+        assert!(
+            !known.insert(Inventory::Block(last)),
+            "the last is remembered"
+        );
+    }
+
     #[rstest]
     #[case::verack_first(Handshake::AwaitingVersion, HandshakeEvent::Verack)]
     #[case::two_versions(Handshake::AwaitingVerack, HandshakeEvent::Version)]
@@ -571,22 +1547,108 @@ mod tests {
         assert_eq!(None, table.handshake_of(id));
     }
 
-    #[test]
-    fn a_full_table_refuses_the_next_peer() {
-        let mut table = PeerTable::default();
+    #[rstest]
+    #[case::inbound(Origin::Accepted, Refused::InboundAtCapacity)]
+    #[case::outbound(Origin::Dialled, Refused::OutboundAtCapacity)]
+    #[case::feeler(Origin::Feeler, Refused::FeelerAtCapacity)]
+    fn a_full_side_refuses_the_next_peer_of_its_origin(
+        #[case] origin: Origin,
+        #[case] refused: Refused,
+    ) {
+        let mut table = PeerTable::new(3, 2);
+        let cap = match origin {
+            Origin::Accepted => 3,
+            Origin::Dialled => 2,
+            Origin::Feeler => MAX_FEELERS,
+        };
         let mut queues = Vec::new();
 
-        for index in 0..MAX_PEERS {
-            queues.push(a_peer(&mut table, 5000 + index as u16).1);
+        for index in 0..cap {
+            let (outbound, queued) = queue(OUTBOUND_BUDGET);
+            table
+                .register(address(5000 + index as u16), origin, outbound)
+                .expect("the table should admit peers up to its bound");
+            queues.push(queued);
         }
-        assert_eq!(MAX_PEERS, table.len());
 
-        let (outbound, _refused) = sync_channel(OUTBOUND_QUEUE);
+        let (outbound, _refused) = queue(OUTBOUND_BUDGET);
         assert_eq!(
-            Err(Refused::AtCapacity),
-            table.register(address(6000), Origin::Accepted, outbound),
+            Err(refused),
+            table.register(address(6000), origin, outbound),
             "the policy is to refuse the newcomer, not to evict an established peer"
         );
-        assert_eq!(MAX_PEERS, table.len());
+        assert_eq!(cap, table.len());
+    }
+
+    #[test]
+    fn a_feeler_takes_no_outbound_slot() {
+        let mut table = PeerTable::new(1, 1);
+        let (outbound, _feeler) = queue(OUTBOUND_BUDGET);
+        table
+            .register(address(5000), Origin::Feeler, outbound)
+            .unwrap();
+        let (outbound, _dialled) = queue(OUTBOUND_BUDGET);
+
+        assert!(table
+            .register(address(5001), Origin::Dialled, outbound)
+            .is_ok());
+        assert_eq!(1, table.dialled_count());
+        assert!(
+            table.addresses().contains(&address(5000)),
+            "nor dialled again while it is open"
+        );
+    }
+
+    #[test]
+    fn a_full_side_gives_up_its_worst_scoring_peer() {
+        let mut table = PeerTable::new(3, 1);
+        let (first, _first) = a_peer(&mut table, 5000);
+        let (worst, _worst) = a_peer(&mut table, 5001);
+        let (_, _third) = a_peer(&mut table, 5002);
+        table.misbehaved(first, 10);
+        table.misbehaved(worst, 20);
+
+        let (evicted, handle) = table.evict_worst(Origin::Accepted).unwrap();
+
+        assert_eq!(worst, evicted);
+        assert_eq!(address(5001), handle.address);
+        assert_eq!(2, table.len());
+        assert!(
+            table.evict_worst(Origin::Accepted).is_none(),
+            "a side with room evicts no one"
+        );
+    }
+
+    #[test]
+    fn a_full_side_where_no_one_has_misbehaved_evicts_no_one() {
+        let mut table = PeerTable::new(2, 1);
+        let mut queues = Vec::new();
+        for port in [5000, 5001] {
+            queues.push(a_peer(&mut table, port).1);
+        }
+
+        assert!(table.evict_worst(Origin::Accepted).is_none());
+        assert_eq!(2, table.len());
+    }
+
+    #[test]
+    fn peers_dialling_in_cannot_take_the_slots_of_the_ones_we_dial() {
+        let mut table = PeerTable::new(2, 1);
+        let mut queues = Vec::new();
+
+        for port in [5000, 5001] {
+            queues.push(a_peer(&mut table, port).1);
+        }
+        let (outbound, _refused) = queue(OUTBOUND_BUDGET);
+        assert_eq!(
+            Err(Refused::InboundAtCapacity),
+            table.register(address(5002), Origin::Accepted, outbound)
+        );
+
+        let (outbound, _dialled) = queue(OUTBOUND_BUDGET);
+        assert!(table
+            .register(address(6000), Origin::Dialled, outbound)
+            .is_ok());
+        assert_eq!(3, table.len());
     }
 }
@@ -0,0 +1,237 @@
+use crate::messages::inventory::INVENTORY_LENGTH;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A framed message as it waits to be written. Reference-counted, so one
+/// sent to every peer is one allocation however many writers hold it.
+pub type Frame = Arc<[u8]>;
+
+/// How many bytes may wait to be written to one peer. A peer whose queue
+/// holds this much is not reading, and more buffering would only hide it.
+pub const OUTBOUND_BUDGET: usize = 4 * 1024 * 1024;
+
+/// How much of a budget relay traffic may fill. The rest is kept for what
+/// the peer is owed, so announcements run out of room first.
+pub const RELAY_SHARE: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// Replies, and anything else the peer cannot do without. One that does
+    /// not fit costs the peer.
+    Essential,
+    /// Announcements that only save the peer asking. One that does not fit
+    /// is dropped in the peer's place.
+    Relay,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sent {
+    Queued,
+    /// A relay message that did not fit, or, from `PeerTable::send_to`, one
+    /// the peer's protocol lacks: dropped, and not held against the peer.
+    Skipped,
+    /// The peer is over its budget, or its writer is gone: drop it.
+    Failed,
+}
+
+/// Transactions waiting for the writer's next `inv`, oldest first.
+type Announcements = Arc<Mutex<Vec<[u8; 32]>>>;
+
+/// The sending half of a peer's queue: the table's, which enqueues only
+/// under the node's lock, so the check and the charge cannot interleave.
+#[derive(Clone, Debug)]
+pub struct Outbound {
+    sender: Sender<Frame>,
+    queued: Arc<AtomicUsize>,
+    budget: usize,
+    announcing: Announcements,
+}
+
+/// The receiving half: the writer's. Bytes leave the budget as they are
+/// taken off the queue.
+#[derive(Debug)]
+pub struct Queue {
+    receiver: Receiver<Frame>,
+    queued: Arc<AtomicUsize>,
+    announcing: Announcements,
+}
+
+/// A queue holding at most `budget` bytes of framed messages.
+pub fn queue(budget: usize) -> (Outbound, Queue) {
+    let (sender, receiver) = mpsc::channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+    let announcing = Announcements::default();
+
+    (
+        Outbound {
+            sender,
+            queued: Arc::clone(&queued),
+            budget,
+            announcing: Arc::clone(&announcing),
+        },
+        Queue {
+            receiver,
+            queued,
+            announcing,
+        },
+    )
+}
+
+impl Outbound {
+    /// Never blocks. A message larger than the whole budget still goes to a
+    /// peer with nothing waiting, or no block that size could be served.
+    pub fn send(&self, message: impl Into<Frame>, priority: Priority) -> Sent {
+        let message = message.into();
+        let size = message.len();
+
+        if !self.fits(size, priority) {
+            return match priority {
+                Priority::Essential => Sent::Failed,
+                Priority::Relay => Sent::Skipped,
+            };
+        }
+
+        self.queued.fetch_add(size, Ordering::AcqRel);
+        match self.sender.send(message) {
+            Ok(()) => Sent::Queued,
+            Err(_) => {
+                self.queued.fetch_sub(size, Ordering::AcqRel);
+                Sent::Failed
+            }
+        }
+    }
+
+    /// Leaves a transaction for the writer to announce in its next batch,
+    /// rather than in an `inv` of its own. Relay traffic: what waits to be
+    /// announced counts against the relay share with what is queued, and one
+    /// that does not fit is skipped.
+    pub fn trickle(&self, txid: [u8; 32]) -> Sent {
+        let mut announcing = self.announcing.lock().expect("announcing lock poisoned");
+        let waiting =
+            self.queued.load(Ordering::Acquire) + (announcing.len() + 1) * INVENTORY_LENGTH;
+        if waiting > self.limit(Priority::Relay) {
+            return Sent::Skipped;
+        }
+
+        announcing.push(txid);
+        Sent::Queued
+    }
+
+    /// Whether `size` more bytes of `priority` would be taken now.
+    pub fn fits(&self, size: usize, priority: Priority) -> bool {
+        let queued = self.queued.load(Ordering::Acquire);
+        queued == 0 || queued + size <= self.limit(priority)
+    }
+
+    fn limit(&self, priority: Priority) -> usize {
+        match priority {
+            Priority::Essential => self.budget,
+            Priority::Relay => self.budget / RELAY_SHARE,
+        }
+    }
+}
+
+impl Queue {
+    pub fn recv(&self) -> Result<Frame, mpsc::RecvError> {
+        self.receiver.recv().map(|message| self.taken(message))
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Frame, RecvTimeoutError> {
+        self.receiver
+            .recv_timeout(timeout)
+            .map(|message| self.taken(message))
+    }
+
+    pub fn try_recv(&self) -> Result<Frame, TryRecvError> {
+        self.receiver.try_recv().map(|message| self.taken(message))
+    }
+
+    /// Up to `max` of the transactions left to announce, oldest first. The
+    /// rest wait for the next batch.
+    pub fn announcements(&self, max: usize) -> Vec<[u8; 32]> {
+        let mut announcing = self.announcing.lock().expect("announcing lock poisoned");
+        let taken = announcing.len().min(max);
+        announcing.drain(..taken).collect()
+    }
+
+    #[cfg(test)]
+    pub fn try_iter(&self) -> impl Iterator<Item = Frame> + '_ {
+        std::iter::from_fn(|| self.try_recv().ok())
+    }
+
+    fn taken(&self, message: Frame) -> Frame {
+        self.queued.fetch_sub(message.len(), Ordering::AcqRel);
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_queue_takes_messages_up_to_its_budget_in_bytes() {
+        let (outbound, queue) = queue(10);
+
+        assert_eq!(Sent::Queued, outbound.send(vec![0; 6], Priority::Essential));
+        assert_eq!(Sent::Queued, outbound.send(vec![0; 4], Priority::Essential));
+        assert_eq!(Sent::Failed, outbound.send(vec![0; 1], Priority::Essential));
+
+        queue.try_recv().unwrap();
+        assert_eq!(
+            Sent::Queued,
+            outbound.send(vec![0; 6], Priority::Essential),
+            "what the writer takes leaves the budget"
+        );
+    }
+
+    #[test]
+    fn relay_fills_only_its_share_and_is_skipped_past_it() {
+        let (outbound, _queue) = queue(10);
+
+        assert_eq!(Sent::Queued, outbound.send(vec![0; 5], Priority::Relay));
+        assert_eq!(Sent::Skipped, outbound.send(vec![0; 1], Priority::Relay));
+        assert_eq!(
+            Sent::Queued,
+            outbound.send(vec![0; 5], Priority::Essential),
+            "the rest is kept for what the peer is owed"
+        );
+    }
+
+    #[test]
+    fn announcements_wait_within_the_relay_share_and_leave_in_batches() {
+        let (outbound, queue) = queue(4 * INVENTORY_LENGTH);
+
+        assert_eq!(Sent::Queued, outbound.trickle([1; 32]));
+        assert_eq!(Sent::Queued, outbound.trickle([2; 32]));
+        assert_eq!(Sent::Skipped, outbound.trickle([3; 32]), "past the share");
+
+        assert_eq!(vec![[1; 32]], queue.announcements(1));
+        assert_eq!(Sent::Queued, outbound.trickle([3; 32]));
+        assert_eq!(vec![[2; 32], [3; 32]], queue.announcements(10));
+        assert!(queue.announcements(10).is_empty());
+    }
+
+    #[test]
+    fn a_message_larger_than_the_budget_goes_to_an_empty_queue() {
+        let (outbound, queue) = queue(10);
+
+        assert_eq!(
+            Sent::Queued,
+            outbound.send(vec![0; 64], Priority::Essential)
+        );
+        assert_eq!(Sent::Failed, outbound.send(vec![0; 1], Priority::Essential));
+        assert_eq!(64, queue.try_recv().unwrap().len());
+    }
+
+    #[test]
+    fn a_queue_whose_writer_is_gone_fails_and_charges_nothing() {
+        let (outbound, queue) = queue(10);
+        drop(queue);
+
+        assert_eq!(Sent::Failed, outbound.send(vec![0; 4], Priority::Essential));
+        assert_eq!(0, outbound.queued.load(Ordering::Acquire));
+    }
+}
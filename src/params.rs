@@ -0,0 +1,339 @@
+use crate::block::Block;
+use crate::difficulty::Target;
+use crate::transaction::{Transaction, TxOut};
+use crate::util::display_hash;
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::fmt;
+
+pub type Magic = [u8; 4];
+
+/// `TxOut.value` counts atoms; the AVI is a display unit (ADR-0006).
+pub const ATOMS_PER_AVI: u64 = 100_000_000;
+pub const HALVING_INTERVAL: u32 = 20_160;
+
+/// What a coinbase at `height` may mint before fees: 50 AVI, halved by
+/// right-shift every `HALVING_INTERVAL` blocks. Shared by every network.
+pub fn subsidy(height: u32) -> u64 {
+    let halvings = height / HALVING_INTERVAL;
+
+    // A shift of 64 or more is an overflow in Rust, not zero.
+    if halvings >= u64::BITS {
+        return 0;
+    }
+    (50 * ATOMS_PER_AVI) >> halvings
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    #[default]
+    Main,
+    Test,
+    Regtest,
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Network::Main => "main",
+            Network::Test => "test",
+            Network::Regtest => "regtest",
+        })
+    }
+}
+
+/// The consensus values a network is chosen by, before genesis is derived
+/// from them. Varied by picking a network, never by editing a running node.
+struct Constants {
+    magic: Magic,
+    address_version: u8,
+    starting_n_bits: u32,
+    coinbase_maturity: u32,
+    retargets: bool,
+    allocation: &'static str,
+}
+
+const MAIN: Constants = Constants {
+    magic: *b"AVI1",
+    address_version: 0x17,
+    // Tuned for a throttled miner on cheap hosting: about a million hashes.
+    starting_n_bits: 0x1e0fffff,
+    coinbase_maturity: 100,
+    retargets: true,
+    allocation: include_str!("../params/main.toml"),
+};
+
+const TEST: Constants = Constants {
+    magic: *b"AVIT",
+    // Not 0x17, so a test address can never be pasted into a mainnet wallet.
+    address_version: 0x41,
+    starting_n_bits: 0x1f00ffff,
+    coinbase_maturity: 10,
+    retargets: true,
+    allocation: include_str!("../params/test.toml"),
+};
+
+const REGTEST: Constants = Constants {
+    magic: *b"AVIR",
+    address_version: 0x41,
+    // Met by every other hash: proof-of-work is present, and instant.
+    starting_n_bits: 0x207fffff,
+    coinbase_maturity: 1,
+    // Fixed, so a burst of generated blocks does not make the next one hard.
+    retargets: false,
+    allocation: include_str!("../params/regtest.toml"),
+};
+
+impl Network {
+    pub const ALL: [Network; 3] = [Network::Main, Network::Test, Network::Regtest];
+
+    const fn constants(self) -> &'static Constants {
+        match self {
+            Network::Main => &MAIN,
+            Network::Test => &TEST,
+            Network::Regtest => &REGTEST,
+        }
+    }
+
+    pub const fn magic(self) -> Magic {
+        self.constants().magic
+    }
+}
+
+/// One network parameter set, with the genesis block derived from it. Two
+/// networks whose parameters differ have different genesis hashes, so their
+/// chains cannot silently merge however the magic bytes are set.
+#[derive(Clone, Debug)]
+pub struct Params {
+    pub network: Network,
+    pub magic: Magic,
+    pub address_version: u8,
+    pub starting_n_bits: u32,
+    pub coinbase_maturity: u32,
+    /// Whether each block's target follows its ancestors (ADR-0009), or stays
+    /// at `starting_n_bits` forever.
+    pub retargets: bool,
+    pub genesis: Block,
+}
+
+/// The committed allocation file: genesis's outputs, and the time and nonce
+/// that make the block built from them meet its target.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Allocation {
+    pub genesis: CommittedGenesis,
+    #[serde(default)]
+    pub outputs: Vec<AllocatedOutput>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommittedGenesis {
+    pub time: u32,
+    pub nonce: u32,
+    pub hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AllocatedOutput {
+    pub value: u64,
+    pub destiny_pub_key: String,
+}
+
+impl Params {
+    pub fn of(network: Network) -> Result<Params> {
+        Self::from_allocation(network, network.constants().allocation)
+            .with_context(|| format!("the {network} network's genesis block is not valid"))
+    }
+
+    /// Derives from allocation text read at runtime rather than the copy
+    /// compiled in: how the genesis tool checks the files on disk.
+    pub fn from_allocation(network: Network, allocation: &str) -> Result<Params> {
+        let constants = network.constants();
+        let allocation: Allocation =
+            toml::from_str(allocation).context("the allocation could not be understood")?;
+
+        let mut genesis = genesis_block(network, &allocation);
+        genesis.nonce = allocation.genesis.nonce;
+        let hash = genesis.seal()?;
+
+        // Both, because each catches what the other cannot: an edited
+        // allocation whose stale nonce happens to meet an easy target still
+        // moves the hash, and a hand-edited hash still has to meet it.
+        if !Target::from_compact(genesis.n_bits)?.is_met_by(&hash) {
+            return Err(anyhow!(
+                "genesis {} does not meet its own target; regenerate its nonce",
+                display_hash(&hash)
+            ));
+        }
+        if display_hash(&hash) != allocation.genesis.hash {
+            return Err(anyhow!(
+                "genesis derives to {} but {} is committed; regenerate it",
+                display_hash(&hash),
+                allocation.genesis.hash
+            ));
+        }
+
+        Ok(Params {
+            network,
+            magic: constants.magic,
+            address_version: constants.address_version,
+            starting_n_bits: constants.starting_n_bits,
+            coinbase_maturity: constants.coinbase_maturity,
+            retargets: constants.retargets,
+            genesis,
+        })
+    }
+}
+
+/// Genesis as the allocation describes it, unmined: exactly one coinbase,
+/// whose outputs are the allocation. Its nonce is left for the caller.
+pub fn genesis_block(network: Network, allocation: &Allocation) -> Block {
+    let outputs = allocation
+        .outputs
+        .iter()
+        .map(|output| TxOut {
+            value: output.value,
+            destiny_pub_key: output.destiny_pub_key.clone(),
+        })
+        .collect();
+
+    let coinbase = Transaction::coinbase(format!("Avi Coin {network} genesis"), outputs);
+
+    Block::new(
+        1,
+        [0; 32],
+        allocation.genesis.time,
+        network.constants().starting_n_bits,
+        vec![coinbase],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::main(Network::Main)]
+    #[case::testnet(Network::Test)]
+    #[case::regtest(Network::Regtest)]
+    fn every_committed_genesis_derives_and_meets_its_own_target(#[case] network: Network) {
+        let params = Params::of(network).unwrap();
+        let hash = params.genesis.hash.expect("a derived genesis is sealed");
+
+        assert!(Target::from_compact(params.genesis.n_bits)
+            .unwrap()
+            .is_met_by(&hash));
+        assert_eq!([0; 32], params.genesis.previous_block_hash);
+    }
+
+    #[test]
+    fn every_network_has_its_own_genesis_hash() {
+        let hashes: std::collections::HashSet<_> = Network::ALL
+            .iter()
+            .map(|network| Params::of(*network).unwrap().genesis.hash.unwrap())
+            .collect();
+
+        assert_eq!(
+            Network::ALL.len(),
+            hashes.len(),
+            "chains that share a genesis can merge, whatever the magic bytes say"
+        );
+    }
+
+    #[test]
+    fn every_network_has_its_own_magic_bytes() {
+        let magics: std::collections::HashSet<_> =
+            Network::ALL.iter().map(|network| network.magic()).collect();
+
+        assert_eq!(Network::ALL.len(), magics.len());
+        assert_eq!(*b"AVI1", Network::Main.magic(), "ADR-0011's mainnet magic");
+        assert_eq!(*b"AVIT", Network::Test.magic(), "ADR-0011's test magic");
+    }
+
+    #[rstest]
+    #[case::main(Network::Main, 0x17)]
+    #[case::testnet(Network::Test, 0x41)]
+    #[case::regtest(Network::Regtest, 0x41)]
+    fn only_mainnet_addresses_carry_the_mainnet_version(
+        #[case] network: Network,
+        #[case] version: u8,
+    ) {
+        assert_eq!(version, Params::of(network).unwrap().address_version);
+    }
+
+    #[test]
+    fn genesis_is_one_coinbase_and_mainnet_has_no_premine() {
+        let genesis = Params::of(Network::Main).unwrap().genesis;
+
+        assert_eq!(1, genesis.transactions.len());
+        assert!(genesis.transactions[0].is_coinbase());
+        assert!(
+            genesis.transactions[0].outputs.is_empty(),
+            "every mainnet coin comes from a block reward"
+        );
+    }
+
+    #[test]
+    fn the_test_allocation_becomes_the_genesis_coinbases_outputs() {
+        let allocation: Allocation = toml::from_str(TEST.allocation).unwrap();
+        let genesis = Params::of(Network::Test).unwrap().genesis;
+
+        assert!(
+            !allocation.outputs.is_empty(),
+            "the test network funds its keys"
+        );
+        assert_eq!(
+            allocation
+                .outputs
+                .iter()
+                .map(|output| (output.value, output.destiny_pub_key.as_str()))
+                .collect::<Vec<_>>(),
+            genesis.transactions[0]
+                .outputs
+                .iter()
+                .map(|output| (output.value, output.destiny_pub_key.as_str()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn an_edited_allocation_with_a_stale_nonce_fails_loudly() {
+        let edited = TEST
+            .allocation
+            .replacen("value = 100000000000", "value = 100000000001", 1);
+        assert_ne!(TEST.allocation, edited, "the fixture must actually change");
+
+        let error = Params::from_allocation(Network::Test, &edited)
+            .expect_err("changing an allocation without regenerating genesis must not start");
+
+        assert!(
+            format!("{error:#}").contains("regenerate"),
+            "got: {error:#}"
+        );
+    }
+
+    #[rstest]
+    #[case::genesis(0, 50 * ATOMS_PER_AVI)]
+    #[case::last_before_halving(HALVING_INTERVAL - 1, 50 * ATOMS_PER_AVI)]
+    #[case::first_halving(HALVING_INTERVAL, 25 * ATOMS_PER_AVI)]
+    #[case::second_halving(2 * HALVING_INTERVAL, 1_250_000_000)]
+    #[case::last_atom(32 * HALVING_INTERVAL, 1)]
+    #[case::thirty_third_halving(33 * HALVING_INTERVAL, 0)]
+    #[case::past_sixty_four_halvings(u32::MAX, 0)]
+    fn the_subsidy_halves_every_interval_until_nothing_is_left(
+        #[case] height: u32,
+        #[case] expected: u64,
+    ) {
+        assert_eq!(expected, subsidy(height));
+    }
+
+    #[test]
+    fn a_garbled_allocation_is_an_error_rather_than_an_empty_one() {
+        Params::from_allocation(Network::Test, "outputs = 7").expect_err("not an allocation");
+    }
+}
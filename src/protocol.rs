@@ -1,23 +1,77 @@
+use crate::block::{Block, BlockHeader};
+use crate::blockchain::{check_body, PrematureHeader, MAX_FUTURE_DRIFT, MAX_HEADERS};
+use crate::compact::PartialBlock;
+use crate::download::{connect_arrived, request_blocks, Connected};
+use crate::mempool::Admission;
+use crate::messages::addr::{Addr, TimedAddress, MAX_ADDR};
+use crate::messages::block_txn::BlockTxn;
+use crate::messages::compact_block::CompactBlock;
+use crate::messages::get_addr::GetAddr;
+use crate::messages::get_block_txn::GetBlockTxn;
+use crate::messages::get_data::GetData;
+use crate::messages::get_headers::GetHeaders;
+use crate::messages::headers::Headers;
+use crate::messages::inv::Inv;
+use crate::messages::inventory::Inventory;
 use crate::messages::message::MessageReceived::{
-    PingMessage, PongMessage, VerackMessage, VersionMessage,
+    AddrMessage, BlockMessage, BlockTxnMessage, CompactBlockMessage, GetAddrMessage,
+    GetBlockTxnMessage, GetDataMessage, GetHeadersMessage, HeadersMessage, InvMessage,
+    NotFoundMessage, PingMessage, PongMessage, TxMessage, UnknownMessage, VerackMessage,
+    VersionMessage,
 };
-use crate::messages::message::{Message, MessageReceived};
+use crate::messages::message::{BadChecksum, Message, MessageReceived};
+use crate::messages::not_found::NotFound;
 use crate::messages::ping::Ping;
 use crate::messages::pong::Pong;
 use crate::messages::verack::Verack;
-use crate::messages::version::Version;
+use crate::messages::version::{negotiate, Version};
+use crate::misbehavior::{Misbehavior, BAN_DURATION, BAN_SCORE};
 use crate::node::{
-    record, Handshake, HandshakeEvent, Origin, PeerId, Refused, SharedNode, OUTBOUND_QUEUE,
+    record, ClockSkew, HandshakeEvent, HeaderSync, Origin, PeerId, Pings, Refused, SharedNode,
+    CLOCK_WARNING_OFFSET,
 };
+use crate::outbound::{queue, Outbound, Queue, Sent, OUTBOUND_BUDGET};
+use crate::params::Magic;
+use crate::transaction::Transaction;
+use crate::util::{display_hash, unix_time};
 use anyhow::{anyhow, Result};
+use rand::RngExt;
+use std::collections::HashSet;
+use std::fmt;
 use std::io::{ErrorKind, Read, Write};
 use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
-use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
 const PING_INTERVAL: Duration = Duration::from_secs(11);
+/// A peer that has left a ping unanswered this long is taken for dead. Long
+/// enough for a pong queued behind a full outbound budget to arrive.
+const PING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The mean wait between a writer's transaction announcements. Each wait is
+/// drawn afresh, up to twice this, so a peer cannot tell from when it hears
+/// of a transaction whether we were the first to.
+const TRICKLE_INTERVAL: Duration = Duration::from_secs(2);
+/// The most transactions one trickled `inv` names; the rest wait for the
+/// next. Far under `MAX_INVENTORY`, so a burst is spread over several.
+const MAX_TRICKLE_BATCH: usize = 1_000;
+
+/// How a writer spaces what it sends of its own accord.
+#[derive(Clone, Copy, Debug)]
+struct Pacing {
+    ping_interval: Duration,
+    ping_timeout: Duration,
+    trickle_interval: Duration,
+}
+
+const PACING: Pacing = Pacing {
+    ping_interval: PING_INTERVAL,
+    ping_timeout: PING_TIMEOUT,
+    trickle_interval: TRICKLE_INTERVAL,
+};
 
 /// A peer that has not accepted a byte in this long is not slow, it is gone.
 /// Without it `write_all` blocks forever on a socket whose peer stopped
@@ -29,17 +83,37 @@ const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
 /// against a deadline it cannot see.
 const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
 
+/// How long a dial may take to connect. The dialer tries addresses one after
+/// another, so one that swallows the SYN must not hold up the rest.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub fn connect(addr: SocketAddr, node: SharedNode) -> Result<()> {
-    let stream = TcpStream::connect(addr)?;
+    let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
     spawn_connection(stream, node, Origin::Dialled);
 
     Ok(())
 }
 
+/// Dials `addr` as a feeler: a handshake to learn whether a node is there,
+/// then a hang-up. Only a dial that fails outright is an error; how the
+/// handshake went is recorded in the address book.
+pub fn feel(addr: SocketAddr, node: SharedNode) -> Result<()> {
+    let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    spawn_connection(stream, node, Origin::Feeler);
+
+    Ok(())
+}
+
 pub fn listen(listener: TcpListener, node: SharedNode) -> Result<()> {
     for stream in listener.incoming() {
         match stream {
-            Ok(stream) => spawn_connection(stream, Arc::clone(&node), Origin::Accepted),
+            Ok(stream) => match stream.peer_addr() {
+                // Hung up on before a thread or a slot is spent on them.
+                Ok(peer) if is_banned(&node, peer) => {
+                    record(&node, format!("Refusing a connection with {peer}: banned"))
+                }
+                _ => spawn_connection(stream, Arc::clone(&node), Origin::Accepted),
+            },
             Err(e) => record(&node, format!("Could not accept a connection: {e}")),
         }
     }
@@ -47,6 +121,48 @@ pub fn listen(listener: TcpListener, node: SharedNode) -> Result<()> {
     Ok(())
 }
 
+/// Charges a peer other than the one whose message is at hand, for what
+/// only came to light later. At the ban score its address is banned and it
+/// is dropped from the table, which ends its connection.
+fn charge(node: &SharedNode, id: PeerId, misbehavior: Misbehavior, what: impl fmt::Display) {
+    let charged = {
+        let mut locked = node.lock().expect("node lock poisoned");
+        let Some(score) = locked.peers.misbehaved(id, misbehavior.weight()) else {
+            return;
+        };
+        let address = if score >= BAN_SCORE {
+            let handle = locked.peers.remove(id);
+            locked.downloads.release(id);
+            handle.map(|handle| {
+                locked.bans.ban(handle.address.ip(), Instant::now());
+                handle.address
+            })
+        } else {
+            locked.peers.get(id).map(|peer| peer.address)
+        };
+        address.map(|address| (address, score))
+    };
+
+    if let Some((address, score)) = charged {
+        let outcome = if score >= BAN_SCORE {
+            format!("; banned for {BAN_DURATION:?}")
+        } else {
+            String::new()
+        };
+        record(
+            node,
+            format!("{address} {what}: {misbehavior}, misbehavior score {score}{outcome}"),
+        );
+    }
+}
+
+fn is_banned(node: &SharedNode, peer: SocketAddr) -> bool {
+    node.lock()
+        .expect("node lock poisoned")
+        .bans
+        .is_banned(peer.ip(), Instant::now())
+}
+
 // Registration lives here, not in the two call sites that dial and accept.
 fn spawn_connection(stream: TcpStream, node: SharedNode, origin: Origin) {
     thread::spawn(move || {
@@ -61,7 +177,7 @@ fn spawn_connection(stream: TcpStream, node: SharedNode, origin: Origin) {
             }
         };
 
-        let (outbound, queued) = mpsc::sync_channel(OUTBOUND_QUEUE);
+        let (outbound, queued) = queue(OUTBOUND_BUDGET);
 
         let registered = match Registered::open(&node, peer, origin, outbound) {
             Ok(registered) => registered,
@@ -74,9 +190,20 @@ fn spawn_connection(stream: TcpStream, node: SharedNode, origin: Origin) {
             }
         };
 
+        let shared = Arc::clone(&registered.shared);
         if let Err(e) = handle_connection(stream, registered, queued, HANDSHAKE_TIMEOUT) {
             record(&node, format!("Connection with {peer} ended: {e:#}"));
         }
+
+        // A feeler that never completed the handshake found nothing worth
+        // dialling there.
+        if origin == Origin::Feeler && !shared.ready.load(Ordering::Acquire) {
+            node.lock()
+                .expect("node lock poisoned")
+                .addresses
+                .failed(peer);
+            record(&node, format!("Feeler to {peer} found no node there"));
+        }
     });
 }
 
@@ -84,6 +211,34 @@ struct Registered {
     node: SharedNode,
     id: PeerId,
     address: SocketAddr,
+    origin: Origin,
+    magic: Magic,
+    shared: Arc<Shared>,
+}
+
+/// What a connection's reader shares with its writer.
+#[derive(Debug, Default)]
+struct Shared {
+    /// Set once, when the handshake completes; the writer pings only from
+    /// then on.
+    ready: AtomicBool,
+    /// What the writer has pinged, for the reader to match pongs against.
+    pings: Mutex<Pings>,
+}
+
+/// What became of a compact block, decided under the node lock and acted on
+/// after it.
+enum CompactArrival {
+    /// Its parent is unknown; it only tells us there are headers to fetch.
+    Orphan,
+    /// Not the next block to connect, so only its header was taken.
+    Scheduled,
+    /// Its header fails its checks.
+    Invalid(anyhow::Error),
+    /// Every transaction was in the mempool.
+    Complete(PartialBlock),
+    /// Claimed from its sender, who is asked for these indexes.
+    Missing(Vec<u32>),
 }
 
 impl Registered {
@@ -91,576 +246,2997 @@ impl Registered {
         node: &SharedNode,
         peer: SocketAddr,
         origin: Origin,
-        outbound: SyncSender<Vec<u8>>,
+        outbound: Outbound,
     ) -> Result<Registered, Refused> {
-        let id = node
-            .lock()
-            .expect("node lock poisoned")
-            .peers
-            .register(peer, origin, outbound)?;
+        let (registered, evicted) = {
+            let mut locked = node.lock().expect("node lock poisoned");
+            let (id, evicted) = match locked.peers.register(peer, origin, outbound.clone()) {
+                Ok(id) => (id, None),
+                // A full side makes room by its worst-behaved peer, if any
+                // has misbehaved at all.
+                Err(Refused::InboundAtCapacity | Refused::OutboundAtCapacity) => {
+                    let Some((evicted_id, evicted)) = locked.peers.evict_worst(origin) else {
+                        return Err(locked.peers.register(peer, origin, outbound).unwrap_err());
+                    };
+                    locked.downloads.release(evicted_id);
+                    (
+                        locked.peers.register(peer, origin, outbound)?,
+                        Some(evicted),
+                    )
+                }
+                Err(refusal) => return Err(refusal),
+            };
+
+            let registered = Registered {
+                node: Arc::clone(node),
+                id,
+                address: peer,
+                origin,
+                magic: locked.params.magic,
+                shared: Arc::default(),
+            };
+            (registered, evicted)
+        };
 
-        Ok(Registered {
-            node: Arc::clone(node),
-            id,
-            address: peer,
-        })
+        if let Some(evicted) = evicted {
+            registered.record(format!(
+                "Evicting {} to make room for {peer}: misbehavior score {}",
+                evicted.address, evicted.score
+            ));
+        }
+        Ok(registered)
     }
 
     fn record(&self, entry: impl Into<String>) {
         record(&self.node, entry);
     }
 
+    /// Queues `message` for the peer. One whose command their protocol lacks
+    /// is not sent, and is no error: nothing of ours waits on it.
     fn deliver(&self, message: Vec<u8>) -> Result<()> {
-        let reached = self
+        let sent = self
             .node
             .lock()
             .expect("node lock poisoned")
             .peers
             .send_to(self.id, message);
 
-        if reached {
-            Ok(())
-        } else {
-            Err(anyhow!("peer cannot keep up with its own replies"))
+        match sent {
+            Sent::Queued | Sent::Skipped => Ok(()),
+            Sent::Failed => Err(anyhow!("peer cannot keep up with its own replies")),
         }
     }
 
-    fn advance_handshake(&self, event: HandshakeEvent) -> Result<Handshake> {
-        self.node
+    /// Whether `event` came in the handshake's order. One that did not is
+    /// charged as a handshake violation and otherwise ignored.
+    fn advance_handshake(&self, event: HandshakeEvent) -> Result<bool> {
+        let advanced = self
+            .node
             .lock()
             .expect("node lock poisoned")
             .peers
-            .advance_handshake(self.id, event)
+            .advance_handshake(self.id, event);
+
+        match advanced {
+            Ok(handshake) => {
+                if handshake.is_ready() {
+                    self.shared.ready.store(true, Ordering::Release);
+                }
+                Ok(true)
+            }
+            Err(e) => {
+                self.misbehaved(Misbehavior::HandshakeViolation, format!("sent {e}"))?;
+                Ok(false)
+            }
+        }
     }
 
-    fn is_ready(&self) -> bool {
+    /// Charges them for `misbehavior`, which `what` describes. Short of
+    /// `BAN_SCORE` it is only recorded; at it their address is banned and the
+    /// connection ends.
+    fn misbehaved(&self, misbehavior: Misbehavior, what: impl fmt::Display) -> Result<()> {
+        let score = {
+            let mut node = self.node.lock().expect("node lock poisoned");
+            let Some(score) = node.peers.misbehaved(self.id, misbehavior.weight()) else {
+                return Err(anyhow!("{} is no longer connected", self.address));
+            };
+            if score >= BAN_SCORE {
+                node.bans.ban(self.address.ip(), Instant::now());
+            }
+            score
+        };
+
+        if score >= BAN_SCORE {
+            return Err(anyhow!(
+                "{} {what}: {misbehavior}, misbehavior score {score}; banned for {BAN_DURATION:?}",
+                self.address
+            ));
+        }
+        self.record(format!(
+            "{} {what}: {misbehavior}, misbehavior score {score}",
+            self.address
+        ));
+        Ok(())
+    }
+
+    fn set_header_sync(&self, state: HeaderSync) {
         self.node
             .lock()
             .expect("node lock poisoned")
             .peers
-            .handshake_of(self.id)
-            .is_some_and(Handshake::is_ready)
-    }
-}
-
-impl Drop for Registered {
-    fn drop(&mut self) {
-        // Recovering the guard rather than unwrapping: this runs while a panic
-        // may already be unwinding, and panicking again would abort.
-        let mut node = self.node.lock().unwrap_or_else(|held| held.into_inner());
-        node.peers.remove(self.id);
+            .set_header_sync(self.id, state);
     }
-}
 
-struct ShutdownOnDrop(TcpStream);
+    /// Asks for whatever follows our best header. Marked before it is sent,
+    /// so the answer can never arrive to find it unasked for.
+    fn request_headers(&self) -> Result<()> {
+        let locator = self
+            .node
+            .lock()
+            .expect("node lock poisoned")
+            .chain
+            .locator();
+        self.set_header_sync(HeaderSync::Requested);
 
-impl Drop for ShutdownOnDrop {
-    fn drop(&mut self) {
-        // The reader's own timeout is the handshake's, so on an established
-        // connection a shutdown is the only thing that wakes it promptly.
-        let _ = self.0.shutdown(Shutdown::Both);
+        self.deliver(Message::new(self.magic, GetHeaders::new(locator))?.get_raw_format()?)
     }
-}
-
-fn handle_connection(
-    stream: TcpStream,
-    registered: Registered,
-    queued: Receiver<Vec<u8>>,
-    handshake_timeout: Duration,
-) -> Result<()> {
-    let write_half = ShutdownOnDrop(stream.try_clone()?);
-    write_half.0.set_write_timeout(Some(WRITE_TIMEOUT))?;
-    stream.set_read_timeout(Some(handshake_timeout))?;
 
-    let (host_address, peers, nonce) = {
-        let node = registered.node.lock().expect("node lock poisoned");
-        (node.config.host_address, node.peers.len(), node.nonce)
-    };
-    registered.record(format!(
-        "{host_address} is handling a connection from {} ({peers} peers)",
-        registered.address
-    ));
+    /// Validates an answer to our `getheaders`, indexing every header in it.
+    /// Any invalid header is an error, which ends the connection: a peer
+    /// that sends one is on another chain or lying, and either way useless.
+    /// Headers we did not ask for are only charged for, and ignored.
+    fn accept_headers(&self, headers: Vec<BlockHeader>) -> Result<()> {
+        let asked = self
+            .node
+            .lock()
+            .expect("node lock poisoned")
+            .peers
+            .header_sync_of(self.id);
+        let outdated = match asked {
+            Some(HeaderSync::Requested) => false,
+            Some(HeaderSync::Outdated) => true,
+            _ => {
+                return self.misbehaved(
+                    Misbehavior::UnsolicitedData,
+                    "sent headers we did not ask for",
+                )
+            }
+        };
 
-    let ours = Message::new(Version::new(nonce, host_address))?.get_raw_format()?;
-    let writer = thread::spawn(move || write_loop(&write_half.0, queued, PING_INTERVAL, ours));
+        if headers
+            .windows(2)
+            .any(|pair| pair[1].previous_block_hash != pair[0].hash())
+        {
+            return self.misbehaved(
+                Misbehavior::InvalidHeader,
+                "sent headers that do not form a chain",
+            );
+        }
+        let full = headers.len() == MAX_HEADERS;
+
+        // Those before an invalid one are kept: each passed on its own.
+        let (before, best, height, refused) = {
+            let mut node = self.node.lock().expect("node lock poisoned");
+            let node = &mut *node;
+            let before = node.chain.best_header_hash();
+            let now = unix_time();
+
+            let last = headers.last().map(BlockHeader::hash);
+            let refused = headers
+                .into_iter()
+                .try_for_each(|header| {
+                    node.chain
+                        .accept_header(header, &node.params, now)
+                        .map(drop)
+                })
+                .err();
+
+            if let Some(height) = last.and_then(|last| node.chain.height_of(&last)) {
+                node.peers.saw_height(self.id, height);
+            }
+            request_blocks(node, Instant::now())?;
+
+            (
+                before,
+                node.chain.best_header_hash(),
+                node.chain.best_header_height(),
+                refused,
+            )
+        };
 
-    let read_result = read_loop(stream, &registered, handshake_timeout);
+        if best != before {
+            self.record(format!(
+                "Best header {} at height {height} from {}",
+                display_hash(&best),
+                self.address
+            ));
+        }
 
-    // Before the join, not after: the table holds this peer's only sender, and
-    // while it does the writer never sees the disconnect that ends it.
-    drop(registered);
+        if let Some(e) = refused {
+            return self.refused_header(e, "a header");
+        }
 
-    match writer.join() {
-        Ok(write_result) => read_result.and(write_result),
-        Err(_) => Err(anyhow!("writer thread panicked")),
+        // A full batch means there may be more; anything less, that they have
+        // told us all they knew when we asked.
+        if full || outdated {
+            self.request_headers()
+        } else {
+            self.set_header_sync(HeaderSync::CaughtUp);
+            Ok(())
+        }
     }
-}
-
-fn write_loop<W: Write>(
-    mut writer: W,
-    queued: Receiver<Vec<u8>>,
-    ping_interval: Duration,
-    opening: Vec<u8>,
-) -> Result<()> {
-    // Ahead of the queue, not in it, so nothing we enqueue can precede it.
-    writer.write_all(&opening)?;
 
-    let mut next_ping = Instant::now();
+    /// Notes what they have, and asks for what we lack: a block by its
+    /// headers, a transaction by itself.
+    fn accept_inventory(&self, inventory: Vec<Inventory>) -> Result<()> {
+        let (new_block, wanted, sync) = {
+            let mut node = self.node.lock().expect("node lock poisoned");
+            node.peers.mark_known(self.id, &inventory);
+
+            let new_block = inventory.iter().any(|item| match item {
+                Inventory::Block(hash) => node.chain.height_of(hash).is_none(),
+                Inventory::Tx(_) => false,
+            });
+            let wanted: Vec<Inventory> = inventory
+                .iter()
+                .copied()
+                .filter(|item| match item {
+                    Inventory::Tx(txid) => !node.mempool.contains(txid),
+                    Inventory::Block(_) => false,
+                })
+                .collect();
+            (new_block, wanted, node.peers.header_sync_of(self.id))
+        };
 
-    loop {
-        if Instant::now() >= next_ping {
-            writer.write_all(&Message::new(Ping::new())?.get_raw_format()?)?;
-            next_ping = Instant::now() + ping_interval;
+        if !wanted.is_empty() {
+            let request = GetData { inventory: wanted };
+            self.deliver(Message::new(self.magic, request)?.get_raw_format()?)?;
+        }
+        if !new_block {
+            return Ok(());
         }
 
-        match queued.recv_timeout(next_ping.saturating_duration_since(Instant::now())) {
-            Ok(bytes) => writer.write_all(&bytes)?,
-            Err(RecvTimeoutError::Timeout) => {}
-            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        // One request at a time: a second answer would arrive unasked for.
+        match sync {
+            Some(HeaderSync::Requested) | Some(HeaderSync::Outdated) => {
+                self.set_header_sync(HeaderSync::Outdated);
+                Ok(())
+            }
+            _ => self.request_headers(),
         }
     }
-}
 
-fn read_loop<R: Read>(
-    mut reader: R,
-    registered: &Registered,
-    handshake_timeout: Duration,
-) -> Result<()> {
-    let mut buffer = [0u8; 4096];
-    let mut recv_buffer: Vec<u8> = Vec::new();
-    let handshake_by = Instant::now() + handshake_timeout;
-    let mut ready = false;
+    /// Offers a transaction to the mempool and tells every other ready peer
+    /// of it if it was taken. One that could never be valid is charged for;
+    /// one that lost a race to another spend, spends what we do not hold, or
+    /// spends it too soon, is only ignored.
+    fn accept_transaction(&self, transaction: Transaction) -> Result<()> {
+        let txid = transaction.get_tx_id();
+
+        let admission = {
+            let mut node = self.node.lock().expect("node lock poisoned");
+            let node = &mut *node;
+            node.peers.mark_known(self.id, &[Inventory::Tx(txid)]);
+
+            let admission = node.mempool.admit(
+                transaction,
+                node.chain.utxos(),
+                node.params.coinbase_maturity,
+            );
+            if let Ok(Admission::Accepted) = admission {
+                node.peers
+                    .announce(node.params.magic, &[Inventory::Tx(txid)])?;
+            }
+            admission
+        };
 
-    loop {
-        match reader.read(&mut buffer) {
-            Ok(0) => {
-                registered.record(format!("Connection with {} closed", registered.address));
-                return Ok(());
+        match admission {
+            Ok(Admission::Accepted) => self.record(format!(
+                "Accepted transaction {} from {}",
+                display_hash(&txid),
+                self.address
+            )),
+            Ok(Admission::Conflicts(holder)) => self.record(format!(
+                "Ignoring transaction {} from {}: it spends what {} already does",
+                display_hash(&txid),
+                self.address,
+                display_hash(&holder)
+            )),
+            Ok(Admission::MissingInputs) => self.record(format!(
+                "Ignoring transaction {} from {}: it spends outputs we do not hold",
+                display_hash(&txid),
+                self.address
+            )),
+            Ok(Admission::Immature) => self.record(format!(
+                "Ignoring transaction {} from {}: it spends a coinbase not yet mature",
+                display_hash(&txid),
+                self.address
+            )),
+            Ok(Admission::Full) => self.record(format!(
+                "Ignoring transaction {} from {}: the mempool is full",
+                display_hash(&txid),
+                self.address
+            )),
+            Ok(Admission::Known) => {}
+            Err(e) => {
+                return self.misbehaved(
+                    Misbehavior::InvalidTransaction,
+                    format!("sent invalid transaction {}: {e:#}", display_hash(&txid)),
+                )
             }
-            Ok(n) => process_incoming_bytes(registered, &mut recv_buffer, &buffer[..n])?,
-            Err(e) if e.kind() == ErrorKind::Interrupted => {}
-            Err(e) if expired(&e) => {}
-            Err(e) => return Err(e.into()),
         }
 
-        // Absolute, not per-read: a peer dribbling legal traffic would reset a
-        // per-read deadline forever. The latch keeps a settled peer off the lock.
-        if !ready {
-            ready = registered.is_ready();
+        Ok(())
+    }
 
-            if !ready && Instant::now() >= handshake_by {
-                return Err(anyhow!(
-                    "no handshake from {} within {handshake_timeout:?}",
-                    registered.address
-                ));
+    /// Answers a `getdata` with everything asked for that we have, and a
+    /// `notfound` for the rest. Each reply is looked up, framed and queued
+    /// before the next, so serving holds one object beyond what the peer's
+    /// queue does, and an item named twice is served once. Asking for more
+    /// than the queue holds is charged for, and the rest goes unanswered.
+    fn serve(&self, inventory: Vec<Inventory>) -> Result<()> {
+        let mut asked = HashSet::new();
+        let mut missing = Vec::new();
+
+        for item in inventory {
+            if !asked.insert(item) {
+                continue;
             }
-        }
-    }
-}
 
-fn expired(e: &std::io::Error) -> bool {
-    // A read timeout is WouldBlock on Unix and TimedOut on Windows.
-    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
-}
+            // Cloned under the lock, framed after it: serialising a block is
+            // not work to make every other peer wait on.
+            let reply = match item {
+                Inventory::Block(hash) => {
+                    let block = self
+                        .node
+                        .lock()
+                        .expect("node lock poisoned")
+                        .chain
+                        .block(&hash)
+                        .cloned();
+                    block
+                        .map(|block| Message::new(self.magic, block)?.get_raw_format())
+                        .transpose()?
+                }
+                Inventory::Tx(txid) => {
+                    let transaction = self
+                        .node
+                        .lock()
+                        .expect("node lock poisoned")
+                        .mempool
+                        .get(&txid)
+                        .cloned();
+                    transaction
+                        .map(|transaction| Message::new(self.magic, transaction)?.get_raw_format())
+                        .transpose()?
+                }
+            };
+            let Some(reply) = reply else {
+                missing.push(item);
+                continue;
+            };
+
+            if !self.reply(item, reply)? {
+                self.misbehaved(
+                    Misbehavior::ExcessiveRequest,
+                    format!("asked for more than its {OUTBOUND_BUDGET} byte queue holds"),
+                )?;
+                break;
+            }
+        }
 
-fn process_incoming_bytes(
-    registered: &Registered,
-    recv_buffer: &mut Vec<u8>,
-    buffer: &[u8],
-) -> Result<()> {
-    recv_buffer.extend(buffer);
-    while let (Some(message), bytes_consumed) = MessageReceived::try_parse_message(recv_buffer)? {
-        recv_buffer.drain(0..bytes_consumed);
+        if !missing.is_empty() {
+            let answer = NotFound { inventory: missing };
+            self.deliver(Message::new(self.magic, answer)?.get_raw_format()?)?;
+        }
 
-        handle_messages(registered, message)?
+        Ok(())
     }
-    Ok(())
-}
 
-fn handle_messages(registered: &Registered, message: MessageReceived) -> Result<()> {
-    match message {
-        VersionMessage(version) => {
-            let peer = version.payload;
-            registered.advance_handshake(HandshakeEvent::Version)?;
-            registered.record(format!(
-                "{} speaks protocol {} and listens on {}",
-                registered.address, peer.protocol_version, peer.listen_address
-            ));
-            registered.deliver(Message::new(Verack)?.get_raw_format()?)?;
-        }
-        VerackMessage => {
-            registered.advance_handshake(HandshakeEvent::Verack)?;
-            registered.record(format!("Handshake with {} complete", registered.address));
+    /// Queues the reply to `item` if it fits beside what the peer already
+    /// has waiting, and notes they now know it, if it was sent. Checked and
+    /// queued under one lock, so nothing else can take the room in between.
+    fn reply(&self, item: Inventory, reply: Vec<u8>) -> Result<bool> {
+        let mut node = self.node.lock().expect("node lock poisoned");
+        if !node.peers.has_room(self.id, reply.len()) {
+            return Ok(false);
         }
-        PingMessage(ping) => {
-            registered.record(format!("Ping received {ping:?}"));
-            let pong = Pong::new(ping.payload)?;
-            registered.deliver(Message::new(pong)?.get_raw_format()?)?;
+        match node.peers.send_to(self.id, reply) {
+            Sent::Queued => node.peers.mark_known(self.id, &[item]),
+            Sent::Skipped => {}
+            Sent::Failed => return Err(anyhow!("peer cannot keep up with its own replies")),
         }
-        PongMessage(pong) => registered.record(format!("Pong received {pong:?}")),
+        Ok(true)
     }
-    Ok(())
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::config::Config;
-    use crate::node::Node;
 
-    const NEVER: Duration = Duration::from_secs(3600);
+    /// Takes back whichever blocks we were waiting on them for, for the
+    /// scheduler to ask of someone else.
+    fn accept_not_found(&self, inventory: Vec<Inventory>) {
+        let cancelled = {
+            let mut node = self.node.lock().expect("node lock poisoned");
+            inventory
+                .iter()
+                .filter(|item| match item {
+                    Inventory::Block(hash) => node.downloads.cancel(hash, self.id),
+                    Inventory::Tx(_) => false,
+                })
+                .count()
+        };
 
-    fn framed<P: crate::messages::message::Payload>(payload: P) -> Vec<u8> {
-        Message::new(payload).unwrap().get_raw_format().unwrap()
+        self.record(format!(
+            "{} does not have {} of what was asked of them; {cancelled} blocks to ask elsewhere",
+            self.address,
+            inventory.len()
+        ));
     }
 
-    fn framed_ping() -> (Vec<u8>, u64) {
-        let ping = Ping::new();
-        let nonce = ping.nonce;
-        (framed(ping), nonce)
-    }
+    /// Checks a block we asked this peer for and lets it into the window,
+    /// connecting whatever it completes. The body is checked now, not when it
+    /// connects, so a bad one is blamed on its sender and no one else.
+    fn accept_block(&self, mut block: Block) -> Result<()> {
+        let hash = block.seal()?;
+
+        let connected = {
+            let mut node = self.node.lock().expect("node lock poisoned");
+            let node = &mut *node;
+            node.peers.mark_known(self.id, &[Inventory::Block(hash)]);
+
+            if node.downloads.requested_from(&hash) != Some(self.id) {
+                Err((
+                    Misbehavior::UnsolicitedData,
+                    format!("sent block {} we did not ask for", display_hash(&hash)),
+                ))
+            } else {
+                match check_body(&block, &hash) {
+                    Err(e) => Err((
+                        Misbehavior::InvalidBlock,
+                        format!("sent a block that fails its checks ({e:#})"),
+                    )),
+                    Ok(()) => {
+                        node.downloads.deliver(hash, block, self.id);
+                        let connected = connect_arrived(node);
+                        if connected.tip.is_some() {
+                            node.peers
+                                .announce_block(node.params.magic, node.chain.tip())?;
+                        }
+                        request_blocks(node, Instant::now())?;
+                        Ok(connected)
+                    }
+                }
+            }
+        };
 
-    fn framed_version() -> Vec<u8> {
-        framed(Version::new(7, "127.0.0.1:5000".parse().unwrap()))
+        let refused = match connected {
+            Err((misbehavior, what)) => return self.misbehaved(misbehavior, what),
+            Ok(Connected { tip, refused }) => {
+                if let Some((count, tip, height)) = tip {
+                    self.record(format!(
+                        "Connected {count} blocks; tip {} at height {height}",
+                        display_hash(&tip)
+                    ));
+                }
+                refused
+            }
+        };
+
+        // Checked on arrival, a body can still fail against the chain below
+        // it, which may have arrived after it, from someone else.
+        match refused {
+            Some((from, e)) if from == self.id => self.misbehaved(
+                Misbehavior::InvalidBlock,
+                format!("sent a block that does not connect ({e:#})"),
+            ),
+            Some((from, e)) => {
+                charge(
+                    &self.node,
+                    from,
+                    Misbehavior::InvalidBlock,
+                    format!("sent a block that does not connect ({e:#})"),
+                );
+                Ok(())
+            }
+            None => Ok(()),
+        }
     }
 
-    /// What a peer sends to be counted: its version, then a verack for ours.
-    fn identify(registered: &Registered) {
-        let mut recv_buffer = Vec::new();
-        let both = [framed_version(), framed(Verack)].concat();
+    /// Charges for a header that fails its checks. One that may be our fault
+    /// rather than theirs, on a parent we do not know or timestamped past our
+    /// clock, is only logged: a ban for it would cut a node whose clock runs
+    /// slow off from every honest peer.
+    fn refused_header(&self, error: anyhow::Error, what: &str) -> Result<()> {
+        if let Some(premature) = error.downcast_ref::<PrematureHeader>() {
+            self.record(format!(
+                "Ignoring {what} from {}: {premature}",
+                self.address
+            ));
+            return Ok(());
+        }
 
-        process_incoming_bytes(registered, &mut recv_buffer, &both)
-            .expect("a well-formed handshake should be accepted");
+        self.misbehaved(
+            Misbehavior::InvalidHeader,
+            format!("sent {what} that fails its checks ({error:#})"),
+        )
     }
 
-    fn parse_all(bytes: &[u8]) -> Vec<MessageReceived> {
-        let mut rest = bytes;
-        let mut messages = Vec::new();
+    /// Takes a block announced as a compact block. If it is the next to
+    /// connect it is rebuilt from the mempool, and its sender asked for
+    /// whatever the mempool lacks; any other is only a header, and its body is
+    /// left to the scheduler. One whose parent we do not know is taken as an
+    /// announcement, and the headers leading to it are asked for.
+    fn accept_compact_block(&self, compact: CompactBlock) -> Result<()> {
+        let hash = compact.header.hash();
+
+        let arrival = {
+            let mut node = self.node.lock().expect("node lock poisoned");
+            let node = &mut *node;
+            node.peers.mark_known(self.id, &[Inventory::Block(hash)]);
+
+            if node
+                .chain
+                .height_of(&compact.header.previous_block_hash)
+                .is_none()
+            {
+                CompactArrival::Orphan
+            } else if let Err(e) =
+                node.chain
+                    .accept_header(compact.header, &node.params, unix_time())
+            {
+                CompactArrival::Invalid(e)
+            } else {
+                let height = node
+                    .chain
+                    .height_of(&hash)
+                    .expect("an accepted header is indexed");
+                node.peers.saw_height(self.id, height);
+
+                let now = Instant::now();
+                let next = node.chain.missing_bodies(1).first().map(|(next, _)| *next);
+                if next != Some(hash) || !node.downloads.claim(hash, self.id, now) {
+                    request_blocks(node, now)?;
+                    CompactArrival::Scheduled
+                } else {
+                    let partial = PartialBlock::new(&compact, &node.mempool);
+                    let missing = partial.missing();
+                    if missing.is_empty() {
+                        CompactArrival::Complete(partial)
+                    } else {
+                        node.downloads.await_transactions(partial);
+                        CompactArrival::Missing(missing)
+                    }
+                }
+            }
+        };
 
-        while let (Some(message), consumed) = MessageReceived::try_parse_message(rest).unwrap() {
-            messages.push(message);
-            rest = &rest[consumed..];
+        match arrival {
+            CompactArrival::Orphan => self.accept_inventory(vec![Inventory::Block(hash)]),
+            CompactArrival::Scheduled => Ok(()),
+            CompactArrival::Invalid(e) => self.refused_header(e, "a compact block's header"),
+            CompactArrival::Complete(partial) => self.rebuilt(hash, partial.into_block()),
+            CompactArrival::Missing(indexes) => {
+                self.record(format!(
+                    "Asking {} for {} transactions of block {} our mempool lacks",
+                    self.address,
+                    indexes.len(),
+                    display_hash(&hash)
+                ));
+                let request = GetBlockTxn {
+                    block_hash: hash,
+                    indexes,
+                };
+                self.deliver(Message::new(self.magic, request)?.get_raw_format()?)
+            }
         }
-
-        assert!(rest.is_empty(), "{} trailing bytes", rest.len());
-        messages
     }
 
-    #[test]
-    fn the_opening_message_precedes_the_first_ping_which_does_not_wait_for_the_interval() {
-        let (outbound, queued) = mpsc::sync_channel(OUTBOUND_QUEUE);
-        drop(outbound);
+    /// Completes a compact block with the transactions we asked its sender
+    /// for.
+    fn accept_block_txn(&self, answer: BlockTxn) -> Result<()> {
+        let hash = answer.block_hash;
+        let partial = self
+            .node
+            .lock()
+            .expect("node lock poisoned")
+            .downloads
+            .take_partial(&hash, self.id);
+        let Some(mut partial) = partial else {
+            return self.misbehaved(
+                Misbehavior::UnsolicitedData,
+                format!(
+                    "sent transactions of block {} we did not ask for",
+                    display_hash(&hash)
+                ),
+            );
+        };
+
+        let rebuilt = partial
+            .fill(answer.transactions)
+            .and_then(|()| partial.into_block());
+        self.rebuilt(hash, rebuilt)
+    }
+
+    /// Takes a block rebuilt from a compact one like any block we asked for.
+    /// One that could not be rebuilt is asked for whole, of the same peer: it
+    /// is still in flight from them, so the block they send is one we asked
+    /// for.
+    fn rebuilt(&self, hash: [u8; 32], rebuilt: Result<Block>) -> Result<()> {
+        match rebuilt {
+            Ok(block) => self.accept_block(block),
+            Err(e) => {
+                self.record(format!(
+                    "Could not rebuild a compact block ({e:#}); asking {} for all of it",
+                    self.address
+                ));
+                let request = GetData {
+                    inventory: vec![Inventory::Block(hash)],
+                };
+                self.deliver(Message::new(self.magic, request)?.get_raw_format()?)
+            }
+        }
+    }
+
+    /// Answers a `getblocktxn` with the transactions asked for, or a
+    /// `notfound` for a block we have not connected. An index past the end of
+    /// the block is an error: a peer that had seen our compact block could not
+    /// have asked for it.
+    fn serve_block_txn(&self, request: GetBlockTxn) -> Result<()> {
+        let hash = request.block_hash;
+        let transactions = {
+            let node = self.node.lock().expect("node lock poisoned");
+            node.chain
+                .block(&hash)
+                .map(|block| {
+                    request
+                        .indexes
+                        .iter()
+                        .map(|index| {
+                            block
+                                .transactions
+                                .get(*index as usize)
+                                .cloned()
+                                .ok_or_else(|| {
+                                    anyhow!(
+                                    "{} asked for transaction {index} of block {}, which holds {}",
+                                    self.address,
+                                    display_hash(&hash),
+                                    block.transactions.len()
+                                )
+                                })
+                        })
+                        .collect::<Result<Vec<Transaction>>>()
+                })
+                .transpose()?
+        };
+
+        match transactions {
+            Some(transactions) => {
+                let answer = BlockTxn {
+                    block_hash: hash,
+                    transactions,
+                };
+                self.deliver(Message::new(self.magic, answer)?.get_raw_format()?)
+            }
+            None => {
+                let answer = NotFound {
+                    inventory: vec![Inventory::Block(hash)],
+                };
+                self.deliver(Message::new(self.magic, answer)?.get_raw_format()?)
+            }
+        }
+    }
+
+    /// A `version` carrying our own nonce was sent by this node: we have
+    /// dialled ourselves, through an address that does not look like it.
+    /// Neither that address nor the one it says it listens on is dialled again.
+    fn refuse_ourselves(&self, version: &Version) -> Result<()> {
+        let mut node = self.node.lock().expect("node lock poisoned");
+        if version.nonce != node.nonce {
+            return Ok(());
+        }
+
+        if self.origin != Origin::Accepted {
+            node.addresses.mark_ourselves(self.address);
+        }
+        node.addresses.mark_ourselves(version.listen_address);
+        Err(anyhow!(
+            "{} is this node: its version carries our nonce",
+            self.address
+        ))
+    }
+
+    /// Two connections to one node, one each way, when each dialled the
+    /// other. Both ends must drop the same one without talking it over, so the
+    /// one kept is the one dialled by whichever node has the larger nonce.
+    /// Two the same way round leave nothing to tell them apart but age, and
+    /// the older is kept. A nonce alone is anyone's to repeat, so only one
+    /// arriving from, or listening on, an address the other connection has
+    /// counts; without that, both stay.
+    fn keep_one_connection(&self, version: &Version) -> Result<()> {
+        // A feeler is about to hang up anyway; the other connection is the
+        // one worth keeping.
+        if self.origin == Origin::Feeler {
+            return Ok(());
+        }
+
+        let dropped = {
+            let mut node = self.node.lock().expect("node lock poisoned");
+            let Some((other, other_origin)) =
+                node.peers
+                    .identify(self.id, version.nonce, version.listen_address)
+            else {
+                return Ok(());
+            };
+
+            let keep_ours = node.nonce > version.nonce;
+            let keep_this =
+                self.origin != other_origin && (self.origin == Origin::Dialled) == keep_ours;
+            if !keep_this {
+                return Err(anyhow!(
+                    "{} is a node we are already connected to, and the other connection stays",
+                    self.address
+                ));
+            }
+
+            node.downloads.release(other);
+            node.peers.remove(other).map(|peer| peer.address)
+        };
+
+        if let Some(address) = dropped {
+            self.record(format!(
+                "Dropping {address}: the same node is connected as {}, and that connection stays",
+                self.address
+            ));
+        }
+        Ok(())
+    }
+
+    /// Matches a pong to the ping it answers, and keeps the round trip. One
+    /// answering no ping of ours is charged for.
+    fn take_pong(&self, nonce: u64) -> Result<()> {
+        let answered = self
+            .shared
+            .pings
+            .lock()
+            .expect("pings lock poisoned")
+            .answered(nonce, Instant::now());
+        let Some(latency) = answered else {
+            return self.misbehaved(
+                Misbehavior::UnknownPong,
+                format!("answered a ping we never sent, {nonce:#x}"),
+            );
+        };
+
+        self.node
+            .lock()
+            .expect("node lock poisoned")
+            .peers
+            .set_latency(self.id, latency);
+        self.record(format!(
+            "Pong received from {} in {latency:?}",
+            self.address
+        ));
+        Ok(())
+    }
+
+    /// What a peer's `version` says of it. Where one that dialled us listens
+    /// is somewhere others could dial too, so it goes in the address book.
+    /// Its clock joins the network's, and if ours has strayed from that by
+    /// enough to split us off, the log says so before blocks start failing.
+    fn describe(&self, version: &Version) {
+        let now = unix_time();
+        let (skew, adjusted) = {
+            let mut node = self.node.lock().expect("node lock poisoned");
+            node.peers.describe(self.id, version, now);
+            if self.origin == Origin::Accepted {
+                node.addresses.learn(version.listen_address, now, now);
+            }
+            (node.peers.reckon_clock(), node.peers.adjusted_time(now))
+        };
+
+        match skew {
+            Some(ClockSkew::Strayed(offset)) => self.record(format!(
+                "WARNING: our clock reads {now} but our peers' median reads {adjusted}, \
+                 {}s {}; at {MAX_FUTURE_DRIFT}s {}. Check this machine's time",
+                offset.abs(),
+                if offset > 0 {
+                    "behind them"
+                } else {
+                    "ahead of them"
+                },
+                if offset > 0 {
+                    "we refuse every block they mine"
+                } else {
+                    "they refuse every block we mine"
+                },
+            )),
+            Some(ClockSkew::Recovered(offset)) => self.record(format!(
+                "Our clock is back within {CLOCK_WARNING_OFFSET}s of our peers' median, \
+                 {offset}s from it"
+            )),
+            None => {}
+        }
+    }
+
+    /// Tells a peer that is behind us where our chain ends, for it to ask us
+    /// for what it lacks.
+    fn announce_tip(&self) -> Result<()> {
+        let tip = {
+            let mut node = self.node.lock().expect("node lock poisoned");
+            let tip = Inventory::Block(node.chain.tip_hash());
+            node.peers.mark_known(self.id, &[tip]);
+            tip
+        };
+
+        self.deliver(
+            Message::new(
+                self.magic,
+                Inv {
+                    inventory: vec![tip],
+                },
+            )?
+            .get_raw_format()?,
+        )
+    }
+
+    /// Whether their `version` claimed a chain at least as high as our best
+    /// header. One that is behind has nothing to sync us to, and is asked for
+    /// headers only once it announces a block we have not heard of.
+    fn may_be_ahead(&self) -> bool {
+        let node = self.node.lock().expect("node lock poisoned");
+        node.peers
+            .start_height_of(self.id)
+            .is_some_and(|height| height >= node.chain.best_header_height())
+    }
+
+    /// What both sides will speak, from the version their `version` says they
+    /// speak. One older than any we talk to ends the connection.
+    fn negotiate(&self, theirs: u32) -> Result<u32> {
+        let spoken = negotiate(theirs).map_err(|e| anyhow!("{} {e}", self.address))?;
+        self.node
+            .lock()
+            .expect("node lock poisoned")
+            .peers
+            .set_protocol_version(self.id, spoken);
+
+        Ok(spoken)
+    }
+
+    /// Counts a message under a command we do not know. It is otherwise
+    /// ignored: it may be one a newer node added. Each command is logged the
+    /// first time only, or a peer repeating one would fill the log.
+    fn skip_unknown(&self, command: &str) {
+        let skipped = self
+            .node
+            .lock()
+            .expect("node lock poisoned")
+            .peers
+            .skipped_unknown(self.id, command);
+
+        if let Some((skipped, true)) = skipped {
+            self.record(format!(
+                "Skipping unknown command {command:?} from {}, {skipped} so far; \
+                 more of it are only counted",
+                self.address
+            ));
+        }
+    }
+
+    /// A dial that completed its handshake: the address is a good one, and
+    /// the peer is asked for the others it knows.
+    fn met(&self) -> Result<()> {
+        if self.origin != Origin::Dialled {
+            return Ok(());
+        }
+
+        {
+            let mut node = self.node.lock().expect("node lock poisoned");
+            node.addresses.connected(self.address, unix_time());
+            node.configured.connected(self.address);
+        }
+        self.deliver(Message::new(self.magic, GetAddr)?.get_raw_format()?)
+    }
+
+    /// A feeler's handshake completed: a node answers there, and the reader
+    /// ends the connection.
+    fn felt(&self) {
+        self.node
+            .lock()
+            .expect("node lock poisoned")
+            .addresses
+            .connected(self.address, unix_time());
+        self.record(format!(
+            "Feeler to {} found a node; hanging up",
+            self.address
+        ));
+    }
+
+    fn serve_addresses(&self) -> Result<()> {
+        let addresses = self
+            .node
+            .lock()
+            .expect("node lock poisoned")
+            .addresses
+            .sample(MAX_ADDR);
+
+        self.deliver(Message::new(self.magic, Addr { addresses })?.get_raw_format()?)
+    }
+
+    fn accept_addresses(&self, addresses: Vec<TimedAddress>) {
+        let now = unix_time();
+        let learned = {
+            let mut node = self.node.lock().expect("node lock poisoned");
+            addresses
+                .iter()
+                .filter(|timed| node.addresses.learn(timed.address, timed.last_seen, now))
+                .count()
+        };
+
+        if learned > 0 {
+            self.record(format!("Learned {learned} addresses from {}", self.address));
+        }
+    }
+
+    /// The latch, not the table: the handshake only ever moves forward, so
+    /// this never needs the lock.
+    fn is_ready(&self) -> bool {
+        self.shared.ready.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        // Recovering the guard rather than unwrapping: this runs while a panic
+        // may already be unwinding, and panicking again would abort.
+        let mut node = self.node.lock().unwrap_or_else(|held| held.into_inner());
+        node.peers.remove(self.id);
+        // Left for the scheduler's next tick to hand to someone else.
+        node.downloads.release(self.id);
+    }
+}
+
+struct ShutdownOnDrop(TcpStream);
+
+impl Drop for ShutdownOnDrop {
+    fn drop(&mut self) {
+        // The reader's own timeout is the handshake's, so on an established
+        // connection a shutdown is the only thing that wakes it promptly.
+        let _ = self.0.shutdown(Shutdown::Both);
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    registered: Registered,
+    queued: Queue,
+    handshake_timeout: Duration,
+) -> Result<()> {
+    let write_half = ShutdownOnDrop(stream.try_clone()?);
+    write_half.0.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    stream.set_read_timeout(Some(handshake_timeout))?;
+
+    let (host_address, peers, nonce, best_height) = {
+        let node = registered.node.lock().expect("node lock poisoned");
+        (
+            node.config.host_address,
+            node.peers.len(),
+            node.nonce,
+            node.chain.height(),
+        )
+    };
+    registered.record(format!(
+        "{host_address} is handling a connection from {} ({peers} peers)",
+        registered.address
+    ));
+
+    let magic = registered.magic;
+    let ours =
+        Message::new(magic, Version::new(nonce, host_address, best_height))?.get_raw_format()?;
+    let shared = Arc::clone(&registered.shared);
+    let writer =
+        thread::spawn(move || write_loop(&write_half.0, queued, magic, PACING, ours, &shared));
+
+    let read_result = read_loop(stream, &registered, handshake_timeout);
+
+    // Before the join, not after: the table holds this peer's only sender, and
+    // while it does the writer never sees the disconnect that ends it.
+    drop(registered);
+
+    match writer.join() {
+        Ok(write_result) => read_result.and(write_result),
+        Err(_) => Err(anyhow!("writer thread panicked")),
+    }
+}
+
+/// Until `ready` is set, writes only what is queued: during the handshake
+/// that is our verack, and the `getheaders` or tip announcement that follows
+/// its completion is what wakes the writer to start pinging. A ping left
+/// unanswered for `ping_timeout` ends the connection. Once ready, it also
+/// announces the transactions left for it, in batches at random intervals.
+fn write_loop<W: Write>(
+    mut writer: W,
+    queued: Queue,
+    magic: Magic,
+    pacing: Pacing,
+    opening: Vec<u8>,
+    shared: &Shared,
+) -> Result<()> {
+    // Ahead of the queue, not in it, so nothing we enqueue can precede it.
+    writer.write_all(&opening)?;
+
+    let mut next_ping = Instant::now();
+    let mut next_trickle = Instant::now() + trickle_wait(pacing.trickle_interval);
+    let mut pinging = false;
+
+    loop {
+        if !shared.ready.load(Ordering::Acquire) {
+            match queued.recv() {
+                Ok(bytes) => writer.write_all(&bytes)?,
+                Err(_) => return Ok(()),
+            }
+            continue;
+        }
+
+        // The handshake can complete while our verack still waits in the
+        // queue, and a ping ahead of it is one the peer must charge us for.
+        if !pinging {
+            while let Ok(bytes) = queued.try_recv() {
+                writer.write_all(&bytes)?;
+            }
+            pinging = true;
+        }
+
+        if Instant::now() >= next_ping {
+            let now = Instant::now();
+            let ping = Ping::new();
+            {
+                let mut pings = shared.pings.lock().expect("pings lock poisoned");
+                if let Some(waited) = pings.overdue(now, pacing.ping_timeout) {
+                    return Err(anyhow!("a ping has gone unanswered for {waited:?}"));
+                }
+                pings.sent(ping.nonce, now);
+            }
+            writer.write_all(&Message::new(magic, ping)?.get_raw_format()?)?;
+            next_ping = now + pacing.ping_interval;
+        }
+
+        if Instant::now() >= next_trickle {
+            let due = queued.announcements(MAX_TRICKLE_BATCH);
+            if !due.is_empty() {
+                let inventory = due.into_iter().map(Inventory::Tx).collect();
+                writer.write_all(&Message::new(magic, Inv { inventory })?.get_raw_format()?)?;
+            }
+            next_trickle = Instant::now() + trickle_wait(pacing.trickle_interval);
+        }
+
+        let wake = next_ping.min(next_trickle);
+        match queued.recv_timeout(wake.saturating_duration_since(Instant::now())) {
+            Ok(bytes) => writer.write_all(&bytes)?,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+/// Anywhere from nothing to twice `mean`.
+fn trickle_wait(mean: Duration) -> Duration {
+    mean.mul_f64(2.0 * rand::rng().random::<f64>())
+}
+
+fn read_loop<R: Read>(
+    mut reader: R,
+    registered: &Registered,
+    handshake_timeout: Duration,
+) -> Result<()> {
+    let mut buffer = [0u8; 4096];
+    let mut recv_buffer: Vec<u8> = Vec::new();
+    let handshake_by = Instant::now() + handshake_timeout;
+
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => {
+                registered.record(format!("Connection with {} closed", registered.address));
+                return Ok(());
+            }
+            Ok(n) => {
+                process_incoming_bytes(registered, &mut recv_buffer, &buffer[..n])?;
+                if registered.origin == Origin::Feeler && registered.is_ready() {
+                    return Ok(());
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) if expired(&e) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        // Absolute, not per-read: a peer dribbling legal traffic would reset a
+        // per-read deadline forever.
+        if !registered.is_ready() && Instant::now() >= handshake_by {
+            return Err(anyhow!(
+                "no handshake from {} within {handshake_timeout:?}",
+                registered.address
+            ));
+        }
+    }
+}
+
+fn expired(e: &std::io::Error) -> bool {
+    // A read timeout is WouldBlock on Unix and TimedOut on Windows.
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+fn process_incoming_bytes(
+    registered: &Registered,
+    recv_buffer: &mut Vec<u8>,
+    buffer: &[u8],
+) -> Result<()> {
+    recv_buffer.extend(buffer);
+    loop {
+        match MessageReceived::try_parse_message(registered.magic, recv_buffer) {
+            Ok((Some(message), bytes_consumed)) => {
+                recv_buffer.drain(0..bytes_consumed);
+
+                handle_messages(registered, message)?
+            }
+            Ok((None, _)) => return Ok(()),
+            // The one parse failure that leaves the stream in step: its
+            // header said how long it was, so it is skipped and charged for.
+            Err(e) => {
+                let Some(bad) = e.downcast_ref::<BadChecksum>() else {
+                    return Err(e);
+                };
+                recv_buffer.drain(0..bad.frame_length);
+                registered.misbehaved(
+                    Misbehavior::BadChecksum,
+                    "sent a frame that does not match its checksum",
+                )?;
+            }
+        }
+    }
+}
+
+fn handle_messages(registered: &Registered, message: MessageReceived) -> Result<()> {
+    // Until both sides have identified themselves there is nothing to talk
+    // about: a peer that tries anyway is not speaking this protocol, and is
+    // charged for it and hung up on.
+    let handshake = matches!(message, VersionMessage(_) | VerackMessage);
+    if !handshake && !registered.is_ready() {
+        let what = format!(
+            "sent {} before completing the handshake",
+            message.command_name()
+        );
+        registered.misbehaved(Misbehavior::HandshakeViolation, &what)?;
+        return Err(anyhow!("{} {what}", registered.address));
+    }
+
+    match message {
+        VersionMessage(version) => {
+            let peer = version.payload;
+            if !registered.advance_handshake(HandshakeEvent::Version)? {
+                return Ok(());
+            }
+            registered.refuse_ourselves(&peer)?;
+            registered.keep_one_connection(&peer)?;
+            let spoken = registered.negotiate(peer.protocol_version)?;
+            registered.record(format!(
+                "{} runs {:?} at height {}, speaks protocol {}, negotiated to {spoken}, \
+                 and listens on {}",
+                registered.address,
+                peer.user_agent,
+                peer.best_height,
+                peer.protocol_version,
+                peer.listen_address
+            ));
+            registered.describe(&peer);
+            registered.deliver(Message::new(registered.magic, Verack)?.get_raw_format()?)?;
+        }
+        VerackMessage => {
+            if !registered.advance_handshake(HandshakeEvent::Verack)? {
+                return Ok(());
+            }
+            registered.record(format!("Handshake with {} complete", registered.address));
+            if registered.origin == Origin::Feeler {
+                registered.felt();
+                return Ok(());
+            }
+            if registered.may_be_ahead() {
+                registered.request_headers()?;
+            } else {
+                registered.announce_tip()?;
+            }
+            registered.met()?;
+        }
+        PingMessage(ping) => {
+            registered.record(format!("Ping received {ping:?}"));
+            let pong = Pong::new(ping.payload)?;
+            registered.deliver(Message::new(registered.magic, pong)?.get_raw_format()?)?;
+        }
+        PongMessage(pong) => registered.take_pong(pong.payload.nonce)?,
+        GetHeadersMessage(request) => {
+            let headers = registered
+                .node
+                .lock()
+                .expect("node lock poisoned")
+                .chain
+                .headers_after(&request.payload.locator, &request.payload.stop);
+            registered
+                .deliver(Message::new(registered.magic, Headers { headers })?.get_raw_format()?)?;
+        }
+        HeadersMessage(answer) => registered.accept_headers(answer.payload.headers)?,
+        InvMessage(announcement) => registered.accept_inventory(announcement.payload.inventory)?,
+        GetDataMessage(request) => registered.serve(request.payload.inventory)?,
+        NotFoundMessage(answer) => registered.accept_not_found(answer.payload.inventory),
+        TxMessage(transaction) => registered.accept_transaction(transaction.payload)?,
+        BlockMessage(block) => registered.accept_block(block.payload)?,
+        CompactBlockMessage(compact) => registered.accept_compact_block(compact.payload)?,
+        GetBlockTxnMessage(request) => registered.serve_block_txn(request.payload)?,
+        BlockTxnMessage(answer) => registered.accept_block_txn(answer.payload)?,
+        GetAddrMessage => registered.serve_addresses()?,
+        AddrMessage(answer) => registered.accept_addresses(answer.payload.addresses),
+        UnknownMessage(command) => registered.skip_unknown(&command),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::messages::message::TEST_MAGIC;
+    use crate::messages::version::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+    use crate::node::{Handshake, Node};
+    use crate::outbound::{Priority, Sent};
+    use crate::params::{Network, Params};
+    use rstest::rstest;
+    use std::sync::mpsc;
+
+    const NEVER: Duration = Duration::from_secs(3600);
+    /// For a writer whose handshake is not what is under test.
+    static READY: Shared = Shared {
+        ready: AtomicBool::new(true),
+        pings: Mutex::new(Pings::new()),
+    };
+
+    /// Pings as given, and no transaction announcements.
+    fn pacing(ping_interval: Duration, ping_timeout: Duration) -> Pacing {
+        Pacing {
+            ping_interval,
+            ping_timeout,
+            trickle_interval: NEVER,
+        }
+    }
+
+    fn framed<P: crate::messages::message::Payload>(payload: P) -> Vec<u8> {
+        Message::new(TEST_MAGIC, payload)
+            .unwrap()
+            .get_raw_format()
+            .unwrap()
+    }
+
+    fn framed_ping() -> (Vec<u8>, u64) {
+        let ping = Ping::new();
+        let nonce = ping.nonce;
+        (framed(ping), nonce)
+    }
+
+    fn framed_version() -> Vec<u8> {
+        framed(Version::new(7, "127.0.0.1:5000".parse().unwrap(), 0))
+    }
+
+    /// What a peer sends to be counted: its version, then a verack for ours.
+    fn identify(registered: &Registered) {
+        let mut recv_buffer = Vec::new();
+        let both = [framed_version(), framed(Verack)].concat();
+
+        process_incoming_bytes(registered, &mut recv_buffer, &both)
+            .expect("a well-formed handshake should be accepted");
+    }
+
+    fn parse_all(bytes: &[u8]) -> Vec<MessageReceived> {
+        let mut rest = bytes;
+        let mut messages = Vec::new();
+
+        while let (Some(message), consumed) =
+            MessageReceived::try_parse_message(TEST_MAGIC, rest).unwrap()
+        {
+            messages.push(message);
+            rest = &rest[consumed..];
+        }
+
+        assert!(rest.is_empty(), "{} trailing bytes", rest.len());
+        messages
+    }
+
+    #[test]
+    fn the_opening_message_precedes_the_first_ping_which_does_not_wait_for_the_interval() {
+        let (outbound, queued) = queue(OUTBOUND_BUDGET);
+        drop(outbound);
+
+        let mut output = Vec::new();
+        write_loop(
+            &mut output,
+            queued,
+            TEST_MAGIC,
+            pacing(NEVER, NEVER),
+            framed_version(),
+            &READY,
+        )
+        .unwrap();
+
+        assert!(
+            matches!(
+                parse_all(&output).as_slice(),
+                [VersionMessage(_), PingMessage(_)]
+            ),
+            "a new connection identifies itself first, then pings at once rather than after an hour"
+        );
+    }
+
+    #[test]
+    fn nothing_but_what_is_queued_follows_the_opening_until_the_handshake_completes() {
+        let (outbound, queued) = queue(OUTBOUND_BUDGET);
+        outbound.send(framed(Verack), Priority::Essential);
+        drop(outbound);
+
+        let mut output = Vec::new();
+        let unready = Shared::default();
+        write_loop(
+            &mut output,
+            queued,
+            TEST_MAGIC,
+            pacing(NEVER, NEVER),
+            framed_version(),
+            &unready,
+        )
+        .unwrap();
+
+        assert!(
+            matches!(
+                parse_all(&output).as_slice(),
+                [VersionMessage(_), VerackMessage]
+            ),
+            "no ping goes to a peer that has not identified itself"
+        );
+    }
+
+    #[test]
+    fn the_first_ping_follows_the_message_that_wakes_a_ready_writer() {
+        let (outbound, queued) = queue(OUTBOUND_BUDGET);
+        let ready = Arc::new(Shared::default());
+
+        let completes = {
+            let ready = Arc::clone(&ready);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                ready.ready.store(true, Ordering::Release);
+                outbound.send(framed(Verack), Priority::Essential);
+            })
+        };
+
+        let mut output = Vec::new();
+        write_loop(
+            &mut output,
+            queued,
+            TEST_MAGIC,
+            pacing(NEVER, NEVER),
+            Vec::new(),
+            &ready,
+        )
+        .unwrap();
+        completes.join().unwrap();
+
+        assert!(matches!(
+            parse_all(&output).as_slice(),
+            [VerackMessage, PingMessage(_)]
+        ));
+    }
+
+    #[test]
+    fn what_was_queued_before_the_handshake_completed_precedes_the_first_ping() {
+        let (outbound, queued) = queue(OUTBOUND_BUDGET);
+        outbound.send(framed(Verack), Priority::Essential);
+        drop(outbound);
+
+        let mut output = Vec::new();
+        write_loop(
+            &mut output,
+            queued,
+            TEST_MAGIC,
+            pacing(NEVER, NEVER),
+            Vec::new(),
+            &READY,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            parse_all(&output).as_slice(),
+            [VerackMessage, PingMessage(_)]
+        ));
+    }
+
+    #[test]
+    fn a_message_enqueued_from_another_thread_is_written_to_the_peer() {
+        let (outbound, queued) = queue(OUTBOUND_BUDGET);
+        let ping = Ping::new();
+        let nonce = ping.nonce;
+        let pong = framed(Pong::new(ping).unwrap());
+
+        let sender = thread::spawn(move || {
+            // The writer must already be parked in recv_timeout, or this proves
+            // only that a drained queue is written, not that a live writer wakes.
+            thread::sleep(Duration::from_millis(50));
+            outbound.send(pong, Priority::Essential);
+        });
+
+        let mut output = Vec::new();
+        write_loop(
+            &mut output,
+            queued,
+            TEST_MAGIC,
+            pacing(NEVER, NEVER),
+            Vec::new(),
+            &READY,
+        )
+        .unwrap();
+        sender.join().unwrap();
+
+        match parse_all(&output).as_slice() {
+            [PingMessage(_), PongMessage(pong)] => assert_eq!(nonce, pong.payload.nonce),
+            other => panic!("expected the opening ping then the enqueued pong, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn a_stalled_write_ends_the_connection_rather_than_blocking_forever() {
+        /// Takes the opening ping, then behaves like a socket whose write
+        /// timeout has expired — so the failure under test is the *queued*
+        /// message, not the ping.
+        #[derive(Default)]
+        struct AcceptsThenStalls {
+            taken: usize,
+        }
+
+        impl Write for AcceptsThenStalls {
+            fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+                if self.taken == 0 {
+                    self.taken += 1;
+                    return Ok(buffer.len());
+                }
+                Err(std::io::Error::from(ErrorKind::WouldBlock))
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (outbound, queued) = queue(OUTBOUND_BUDGET);
+        outbound.send(b"backlog".to_vec(), Priority::Essential);
+        drop(outbound);
+
+        // Dropping the peer cannot end this connection on its own: mpsc hands
+        // the writer every buffered message before it ever reports
+        // Disconnected, so the writer must give up on the socket itself.
+        write_loop(
+            AcceptsThenStalls::default(),
+            queued,
+            TEST_MAGIC,
+            pacing(NEVER, NEVER),
+            Vec::new(),
+            &READY,
+        )
+        .expect_err("a write that cannot proceed must end the connection");
+    }
+
+    #[test]
+    fn a_connection_bounds_how_long_a_write_may_block() {
+        let (_peer, accepted, peer_addr) = a_connected_pair();
+        let observer = accepted.try_clone().unwrap();
+
+        thread::spawn(move || handle_alone(accepted, peer_addr, a_node()));
+
+        eventually(
+            || observer.write_timeout().unwrap().is_some(),
+            "the write half never had its blocking bounded",
+        );
+        assert_eq!(Some(WRITE_TIMEOUT), observer.write_timeout().unwrap());
+    }
+
+    #[test]
+    fn a_connection_bounds_how_long_it_waits_to_be_told_who_it_is_talking_to() {
+        let (_peer, accepted, peer_addr) = a_connected_pair();
+        let observer = accepted.try_clone().unwrap();
+
+        thread::spawn(move || handle_alone(accepted, peer_addr, a_node()));
+
+        eventually(
+            || observer.read_timeout().unwrap().is_some(),
+            "the read half never had its waiting bounded",
+        );
+        assert_eq!(Some(HANDSHAKE_TIMEOUT), observer.read_timeout().unwrap());
+    }
+
+    #[test]
+    fn a_peer_that_never_identifies_itself_gives_its_slot_back() {
+        let (_peer, accepted, peer_addr) = a_connected_pair();
+        let node = a_node();
+        let watched = Arc::clone(&node);
+
+        thread::spawn(move || {
+            handle_alone_for(accepted, peer_addr, node, Duration::from_millis(50))
+        });
+
+        eventually(
+            || watched.lock().unwrap().peers.len() == 1,
+            "the connection never registered a peer",
+        );
+        // The slot and its recv_buffer are what the deadline is for;
+        // ending the connection without freeing them would miss the point.
+        eventually(
+            || watched.lock().unwrap().peers.is_empty(),
+            "a peer that never identified itself kept its slot",
+        );
+    }
+
+    #[test]
+    fn pings_recur_at_the_configured_interval() {
+        let interval = Duration::from_millis(20);
+        let run_for = Duration::from_millis(300);
+
+        let (outbound, queued) = queue(OUTBOUND_BUDGET);
+        let holder = thread::spawn(move || {
+            thread::sleep(run_for);
+            drop(outbound);
+        });
 
         let mut output = Vec::new();
-        write_loop(&mut output, queued, NEVER, framed_version()).unwrap();
+        write_loop(
+            &mut output,
+            queued,
+            TEST_MAGIC,
+            pacing(interval, NEVER),
+            Vec::new(),
+            &READY,
+        )
+        .unwrap();
+        holder.join().unwrap();
+
+        let pings = parse_all(&output).len();
+        let expected = run_for.as_millis() / interval.as_millis();
+
+        assert!(
+            pings >= 5,
+            "{pings} pings in {run_for:?} at a {interval:?} interval; \
+             a timer that only fires when something else wakes it would send ~1, not ~{expected}"
+        );
+        assert!(
+            pings <= 40,
+            "{pings} pings is a busy loop, not a {interval:?} interval"
+        );
+    }
+
+    #[test]
+    fn transactions_left_for_the_writer_are_announced_in_capped_batches() {
+        let (outbound, queued) = queue(OUTBOUND_BUDGET);
+        for i in 0..=MAX_TRICKLE_BATCH as u32 {
+            let mut txid = [0; 32];
+            txid[..4].copy_from_slice(&i.to_le_bytes());
+            assert_eq!(Sent::Queued, outbound.trickle(txid));
+        }
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            drop(outbound);
+        });
+
+        let mut output = Vec::new();
+        let quick = Pacing {
+            trickle_interval: Duration::from_millis(10),
+            ..pacing(NEVER, NEVER)
+        };
+        write_loop(&mut output, queued, TEST_MAGIC, quick, Vec::new(), &READY).unwrap();
+        holder.join().unwrap();
+
+        let batches: Vec<usize> = parse_all(&output)
+            .into_iter()
+            .filter_map(|message| match message {
+                InvMessage(inv) => Some(inv.payload.inventory.len()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![MAX_TRICKLE_BATCH, 1], batches);
+    }
+
+    #[test]
+    fn a_ping_left_unanswered_past_the_timeout_ends_the_connection() {
+        let (_outbound, queued) = queue(OUTBOUND_BUDGET);
+        let shared = Shared {
+            ready: AtomicBool::new(true),
+            ..Shared::default()
+        };
+
+        let mut output = Vec::new();
+        let error = write_loop(
+            &mut output,
+            queued,
+            TEST_MAGIC,
+            pacing(Duration::from_millis(10), Duration::from_millis(50)),
+            Vec::new(),
+            &shared,
+        )
+        .expect_err("a peer that never answers is dead");
+
+        assert!(
+            format!("{error:#}").contains("unanswered"),
+            "got: {error:#}"
+        );
+    }
+
+    #[test]
+    fn a_pong_to_our_ping_records_the_round_trip() {
+        let (registered, _queued) = a_ready_peer();
+        registered
+            .shared
+            .pings
+            .lock()
+            .unwrap()
+            .sent(0xFEED, Instant::now());
+
+        process_incoming_bytes(
+            &registered,
+            &mut Vec::new(),
+            &framed(Pong { nonce: 0xFEED }),
+        )
+        .unwrap();
+
+        let node = registered.node.lock().unwrap();
+        let peer = node.peers.get(registered.id).unwrap();
+        assert!(peer.latency.is_some());
+        assert_eq!(0, peer.score);
+    }
+
+    #[test]
+    fn a_pong_to_no_ping_of_ours_is_charged_for() {
+        let (registered, _queued) = a_ready_peer();
+
+        process_incoming_bytes(
+            &registered,
+            &mut Vec::new(),
+            &framed(Pong { nonce: 0xFEED }),
+        )
+        .expect("charged, not fatal");
+
+        let node = registered.node.lock().unwrap();
+        assert_eq!(
+            Some(Misbehavior::UnknownPong.weight()),
+            node.peers.score_of(registered.id)
+        );
+        assert_eq!(None, node.peers.get(registered.id).unwrap().latency);
+    }
+
+    #[test]
+    fn an_inbound_ping_is_answered_with_a_pong_on_the_outbound_channel() {
+        let (registered, queued) = a_ready_peer();
+        let mut recv_buffer = Vec::new();
+        let (ping, nonce) = framed_ping();
+
+        process_incoming_bytes(&registered, &mut recv_buffer, &ping).unwrap();
+
+        assert!(recv_buffer.is_empty(), "the ping should be fully consumed");
+
+        let reply = queued.try_recv().expect("a ping must be answered");
+        match parse_all(&reply).as_slice() {
+            [PongMessage(pong)] => assert_eq!(nonce, pong.payload.nonce),
+            other => panic!("expected a pong, got {other:?}"),
+        }
+        assert!(queued.try_recv().is_err(), "one ping, one pong");
+    }
+
+    #[test]
+    fn an_oversized_header_fails_the_connection_rather_than_being_awaited() {
+        let (registered, queued) = a_registered_peer();
+        let mut recv_buffer = Vec::new();
+        let header = crate::messages::message::header_claiming(u32::MAX);
+
+        let error = process_incoming_bytes(&registered, &mut recv_buffer, &header)
+            .expect_err("a header claiming 4 GB must fail the connection, not be waited on");
+
+        assert!(format!("{error:#}").contains("too large"), "got: {error:#}");
+        assert!(
+            queued.try_recv().is_err(),
+            "nothing should be queued in reply to a header that was refused"
+        );
+    }
+
+    #[test]
+    fn an_interrupted_read_does_not_end_the_connection() {
+        struct InterruptsOnce {
+            ping: Vec<u8>,
+            interrupted: bool,
+        }
+
+        impl Read for InterruptsOnce {
+            fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+                if !self.interrupted {
+                    self.interrupted = true;
+                    return Err(std::io::Error::from(ErrorKind::Interrupted));
+                }
+                let taken = self.ping.len();
+                buffer[..taken].copy_from_slice(&self.ping);
+                self.ping.clear();
+                Ok(taken)
+            }
+        }
+
+        let (registered, queued) = a_ready_peer();
+        let (ping, nonce) = framed_ping();
+        let reader = InterruptsOnce {
+            ping,
+            interrupted: false,
+        };
+
+        read_loop(reader, &registered, NEVER)
+            .expect("a signal-interrupted read must be retried, not fail the connection");
+
+        let reply = queued.try_recv().expect("the ping after the interrupt");
+        match parse_all(&reply).as_slice() {
+            [PongMessage(pong)] => assert_eq!(nonce, pong.payload.nonce),
+            other => panic!("expected a pong, got {other:?}"),
+        }
+    }
+
+    fn next_message(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> MessageReceived {
+        let mut chunk = [0u8; 512];
+
+        loop {
+            if let (Some(message), consumed) =
+                MessageReceived::try_parse_message(TEST_MAGIC, buffer)
+                    .expect("peer sent an unparseable message")
+            {
+                buffer.drain(0..consumed);
+                return message;
+            }
+
+            let read = stream.read(&mut chunk).expect("peer went quiet");
+            assert_ne!(0, read, "peer closed before sending the expected message");
+            buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    /// The next message that is a reply to something, rather than the timer's.
+    fn next_reply(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> MessageReceived {
+        loop {
+            match next_message(stream, buffer) {
+                PingMessage(_) => continue,
+                reply => return reply,
+            }
+        }
+    }
+
+    fn a_connected_pair() -> (TcpStream, TcpStream, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let peer = TcpStream::connect(address).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let (accepted, peer_addr) = listener.accept().unwrap();
+
+        (peer, accepted, peer_addr)
+    }
+
+    fn eventually(mut settled: impl FnMut() -> bool, what: &str) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while Instant::now() < deadline {
+            if settled() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        panic!("{what} within 5s");
+    }
+
+    /// What spawn_connection does, minus the registry, for tests about the
+    /// thread pair rather than about the peer table.
+    fn handle_alone(stream: TcpStream, peer_addr: SocketAddr, node: SharedNode) -> Result<()> {
+        handle_alone_for(stream, peer_addr, node, HANDSHAKE_TIMEOUT)
+    }
+
+    fn handle_alone_for(
+        stream: TcpStream,
+        peer_addr: SocketAddr,
+        node: SharedNode,
+        handshake_timeout: Duration,
+    ) -> Result<()> {
+        let (outbound, queued) = queue(OUTBOUND_BUDGET);
+        let registered = Registered::open(&node, peer_addr, Origin::Accepted, outbound)
+            .expect("an empty table should accept a peer");
+        handle_connection(stream, registered, queued, handshake_timeout)
+    }
+
+    /// A peer in a node's table, plus the queue its writer would drain.
+    fn score_of(registered: &Registered) -> Option<u32> {
+        registered
+            .node
+            .lock()
+            .unwrap()
+            .peers
+            .score_of(registered.id)
+    }
+
+    fn a_registered_peer() -> (Registered, Queue) {
+        a_peer_of(&a_node())
+    }
+
+    fn a_ready_peer() -> (Registered, Queue) {
+        a_syncing_peer(&a_node())
+    }
+
+    fn a_peer_of(node: &SharedNode) -> (Registered, Queue) {
+        a_peer_from(node, Origin::Accepted)
+    }
+
+    fn a_peer_from(node: &SharedNode, origin: Origin) -> (Registered, Queue) {
+        let (outbound, queued) = queue(OUTBOUND_BUDGET);
+        let registered =
+            Registered::open(node, "127.0.0.1:5000".parse().unwrap(), origin, outbound)
+                .expect("an empty table should accept a peer");
+
+        (registered, queued)
+    }
+
+    fn a_node() -> SharedNode {
+        a_node_on(Network::Main)
+    }
+
+    fn a_node_on(network: Network) -> SharedNode {
+        Node::shared(
+            Config {
+                network,
+                host_address: "127.0.0.1:34352".parse().unwrap(),
+                addresses_to_connect: Vec::new(),
+                data_dir: "data".into(),
+                max_inbound: crate::node::MAX_INBOUND,
+                max_outbound: crate::node::MAX_OUTBOUND,
+            },
+            Params::of(network).unwrap(),
+        )
+    }
+
+    /// `count` regtest headers on genesis, a second apart: a chain some peer
+    /// might hold and we do not.
+    fn regtest_headers(count: usize) -> Vec<BlockHeader> {
+        let params = Params::of(Network::Regtest).unwrap();
+        let target = crate::difficulty::Target::from_compact(params.starting_n_bits).unwrap();
+        let mut parent = params.genesis.header().unwrap();
+
+        (0..count)
+            .map(|_| {
+                let mut header = BlockHeader {
+                    previous_block_hash: parent.hash(),
+                    time: parent.time + 1,
+                    nonce: 0,
+                    ..parent
+                };
+                while !target.is_met_by(&header.hash()) {
+                    header.nonce += 1;
+                }
+                parent = header;
+                header
+            })
+            .collect()
+    }
+
+    /// A regtest peer that has been asked for headers, with the request drained.
+    fn a_syncing_peer(node: &SharedNode) -> (Registered, Queue) {
+        let (registered, queued) = a_peer_of(node);
+        let magic = registered.magic;
+        // A nonce of its own, or a second such peer is the first one again.
+        let version = Version::new(7 + registered.id, "127.0.0.1:5000".parse().unwrap(), 0);
+        let both = [
+            Message::new(magic, version)
+                .unwrap()
+                .get_raw_format()
+                .unwrap(),
+            Message::new(magic, Verack)
+                .unwrap()
+                .get_raw_format()
+                .unwrap(),
+        ]
+        .concat();
+        process_incoming_bytes(&registered, &mut Vec::new(), &both).unwrap();
+        while queued.try_recv().is_ok() {}
+        (registered, queued)
+    }
+
+    fn answer(registered: &Registered, headers: Vec<BlockHeader>) -> Result<()> {
+        let framed = Message::new(registered.magic, Headers { headers })
+            .unwrap()
+            .get_raw_format()
+            .unwrap();
+        process_incoming_bytes(registered, &mut Vec::new(), &framed)
+    }
+
+    fn parse_on(magic: Magic, bytes: &[u8]) -> MessageReceived {
+        match MessageReceived::try_parse_message(magic, bytes).unwrap() {
+            (Some(message), consumed) if consumed == bytes.len() => message,
+            other => panic!("expected exactly one message, got {other:?}"),
+        }
+    }
+
+    #[rstest]
+    #[case::behind(2, "inv")]
+    #[case::level(3, "getheaders")]
+    #[case::ahead(4, "getheaders")]
+    fn a_peer_is_asked_for_headers_only_if_its_version_claims_it_is_not_behind(
+        #[case] claimed: u32,
+        #[case] first: &str,
+    ) {
+        let node = a_node_on(Network::Regtest);
+        crate::mining::generate(
+            &node,
+            3,
+            "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+        )
+        .unwrap();
+        let (registered, queued) = a_peer_of(&node);
+        let magic = registered.magic;
+        let version = Version::new(7, "127.0.0.1:5000".parse().unwrap(), claimed);
+        let both = [
+            Message::new(magic, version)
+                .unwrap()
+                .get_raw_format()
+                .unwrap(),
+            Message::new(magic, Verack)
+                .unwrap()
+                .get_raw_format()
+                .unwrap(),
+        ]
+        .concat();
+
+        process_incoming_bytes(&registered, &mut Vec::new(), &both).unwrap();
+
+        queued.try_recv().expect("our verack");
+        let after = parse_on(magic, &queued.try_recv().unwrap());
+        assert_eq!(first, after.command_name());
+        if let InvMessage(inv) = after {
+            let tip = node.lock().unwrap().chain.tip_hash();
+            assert_eq!(vec![Inventory::Block(tip)], inv.payload.inventory);
+        }
+    }
+
+    #[test]
+    fn a_version_records_what_the_peer_says_of_itself() {
+        let (registered, _queued) = a_registered_peer();
+        let version = Version {
+            services: crate::messages::version::Services::FULL_NODE,
+            user_agent: "/other:9.9/".to_string(),
+            timestamp: 1_700_000_000,
+            ..Version::new(7, "127.0.0.1:5000".parse().unwrap(), 42)
+        };
+
+        process_incoming_bytes(&registered, &mut Vec::new(), &framed(version)).unwrap();
+
+        let node = registered.node.lock().unwrap();
+        let peer = node.peers.get(registered.id).unwrap();
+        assert_eq!(crate::messages::version::Services::FULL_NODE, peer.services);
+        assert_eq!("/other:9.9/", peer.user_agent);
+        assert_eq!(42, peer.start_height);
+        assert_eq!(1_700_000_000, peer.timestamp);
+        assert_eq!(0, peer.best_height, "a claim, not a header shown");
+    }
+
+    #[test]
+    fn a_version_records_how_far_the_peer_clock_runs_from_ours() {
+        let (registered, _queued) = a_registered_peer();
+        let version = Version {
+            timestamp: unix_time() + 1_000,
+            ..Version::new(7, "127.0.0.1:5000".parse().unwrap(), 0)
+        };
+
+        process_incoming_bytes(&registered, &mut Vec::new(), &framed(version)).unwrap();
+
+        let offset = registered
+            .node
+            .lock()
+            .unwrap()
+            .peers
+            .get(registered.id)
+            .unwrap()
+            .clock_offset;
+        assert!(
+            offset.is_some_and(|offset| (999..=1_000).contains(&offset)),
+            "got {offset:?}"
+        );
+    }
+
+    #[test]
+    fn a_getheaders_is_answered_with_what_follows_the_locator() {
+        let node = a_node_on(Network::Regtest);
+        crate::mining::generate(
+            &node,
+            3,
+            "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+        )
+        .unwrap();
+        let (registered, queued) = a_syncing_peer(&node);
+        let genesis = node.lock().unwrap().params.genesis.hash.unwrap();
+        let request = Message::new(registered.magic, GetHeaders::new(vec![genesis]))
+            .unwrap()
+            .get_raw_format()
+            .unwrap();
+
+        process_incoming_bytes(&registered, &mut Vec::new(), &request).unwrap();
+
+        match parse_on(registered.magic, &queued.try_recv().unwrap()) {
+            HeadersMessage(answer) => {
+                assert_eq!(3, answer.payload.headers.len());
+                assert_eq!(
+                    node.lock().unwrap().chain.tip_hash(),
+                    answer.payload.headers[2].hash()
+                );
+            }
+            other => panic!("expected headers, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn a_short_answer_moves_our_best_header_and_leaves_the_peer_caught_up() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, queued) = a_syncing_peer(&node);
+        let headers = regtest_headers(3);
+
+        answer(&registered, headers.clone()).unwrap();
+
+        let locked = node.lock().unwrap();
+        assert_eq!(3, locked.chain.best_header_height());
+        assert_eq!(headers[2].hash(), locked.chain.best_header_hash());
+        assert_eq!(
+            Some(HeaderSync::CaughtUp),
+            locked.peers.header_sync_of(registered.id)
+        );
+        match parse_on(registered.magic, &queued.try_recv().unwrap()) {
+            GetDataMessage(request) => assert_eq!(
+                headers
+                    .iter()
+                    .map(|header| Inventory::Block(header.hash()))
+                    .collect::<Vec<_>>(),
+                request.payload.inventory,
+                "no more headers to ask for, only the bodies behind them"
+            ),
+            other => panic!("expected a getdata, got {other:?}"),
+        }
+        assert!(queued.try_recv().is_err());
+        assert!(locked
+            .log
+            .recent()
+            .any(|entry| entry.starts_with("Best header") && entry.contains("height 3")));
+    }
+
+    #[test]
+    fn a_full_answer_is_followed_by_a_request_for_more() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, queued) = a_syncing_peer(&node);
+        let headers = regtest_headers(MAX_HEADERS);
+
+        answer(&registered, headers.clone()).unwrap();
+
+        // Bodies are asked for first, but the next batch must follow them.
+        let request = queued
+            .try_iter()
+            .find_map(|bytes| match parse_on(registered.magic, &bytes) {
+                GetHeadersMessage(request) => Some(request),
+                _ => None,
+            })
+            .expect("another getheaders");
+        assert_eq!(headers[MAX_HEADERS - 1].hash(), request.payload.locator[0]);
+    }
+
+    /// `count` blocks mined on another regtest node, for a fresh one to sync.
+    fn mined_elsewhere(count: u32) -> Vec<Block> {
+        let source = a_node_on(Network::Regtest);
+        let hashes = crate::mining::generate(
+            &source,
+            count,
+            "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+        )
+        .unwrap();
+
+        let locked = source.lock().unwrap();
+        hashes
+            .iter()
+            .map(|hash| locked.chain.block(hash).unwrap().clone())
+            .collect()
+    }
+
+    fn send_block(registered: &Registered, block: &Block) -> Result<()> {
+        let framed = Message::new(registered.magic, block.clone())
+            .unwrap()
+            .get_raw_format()
+            .unwrap();
+        process_incoming_bytes(registered, &mut Vec::new(), &framed)
+    }
+
+    /// A fresh regtest node whose peer has shown it `blocks`' headers, with
+    /// the requests that followed drained.
+    fn syncing_blocks(blocks: &[Block]) -> (SharedNode, Registered, Queue) {
+        let node = a_node_on(Network::Regtest);
+        let (registered, queued) = a_syncing_peer(&node);
+        answer(
+            &registered,
+            blocks.iter().map(|block| block.header().unwrap()).collect(),
+        )
+        .unwrap();
+        while queued.try_recv().is_ok() {}
+
+        (node, registered, queued)
+    }
+
+    #[test]
+    fn a_block_that_fails_to_connect_after_arriving_early_is_charged_to_its_sender() {
+        let node = a_node_on(Network::Regtest);
+        let (sender, _sender_queue) = a_syncing_peer(&node);
+        let (bystander, _bystander_queue) = a_syncing_peer(&node);
+        let first = mined_elsewhere(1).remove(0);
+        let coinbase = Transaction::coinbase(
+            "height 2".to_string(),
+            vec![crate::transaction::TxOut {
+                value: 1,
+                destiny_pub_key: "miner".to_string(),
+            }],
+        );
+        let mut nowhere = spending(0, 5);
+        nowhere.inputs[0].previous_output.tx_id = [9; 32];
+        let mut invalid = Block::new(
+            1,
+            first.hash.unwrap(),
+            first.time + 1,
+            first.n_bits,
+            vec![coinbase, nowhere],
+        );
+        invalid.mine().unwrap();
+        let hashes = [first.hash.unwrap(), invalid.hash.unwrap()];
+        answer(
+            &sender,
+            vec![first.header().unwrap(), invalid.header().unwrap()],
+        )
+        .unwrap();
+        {
+            let mut locked = node.lock().unwrap();
+            locked.downloads.release(sender.id);
+            locked.downloads.release(bystander.id);
+            assert!(locked.downloads.claim(hashes[1], sender.id, Instant::now()));
+            assert!(locked
+                .downloads
+                .claim(hashes[0], bystander.id, Instant::now()));
+        }
+
+        send_block(&sender, &invalid).expect("its parent is not here yet");
+        send_block(&bystander, &first).expect("its block is sound");
+
+        let locked = node.lock().unwrap();
+        assert_eq!(hashes[0], locked.chain.tip_hash());
+        assert_eq!(Some(0), locked.peers.score_of(bystander.id));
+        assert_eq!(None, locked.peers.score_of(sender.id), "banned and dropped");
+        assert!(
+            locked.chain.missing_bodies(1).is_empty(),
+            "the invalid block is not asked for again"
+        );
+        assert_eq!(hashes[0], locked.chain.best_header_hash());
+    }
+
+    #[test]
+    fn blocks_arriving_out_of_order_are_connected_in_order() {
+        let blocks = mined_elsewhere(3);
+        let (node, registered, _queued) = syncing_blocks(&blocks);
+
+        send_block(&registered, &blocks[2]).unwrap();
+        send_block(&registered, &blocks[1]).unwrap();
+        assert_eq!(
+            0,
+            node.lock().unwrap().chain.height(),
+            "block 1 is still missing"
+        );
+
+        send_block(&registered, &blocks[0]).unwrap();
+
+        let locked = node.lock().unwrap();
+        assert_eq!(3, locked.chain.height());
+        assert_eq!(blocks[2].hash.unwrap(), locked.chain.tip_hash());
+        assert!(locked
+            .log
+            .recent()
+            .any(|entry| entry.starts_with("Connected 3 blocks")));
+    }
+
+    #[test]
+    fn a_block_nobody_asked_for_is_ignored() {
+        let blocks = mined_elsewhere(1);
+        let node = a_node_on(Network::Regtest);
+        let (registered, _queued) = a_syncing_peer(&node);
+
+        send_block(&registered, &blocks[0]).expect("ignored, not fatal");
+
+        let locked = node.lock().unwrap();
+        assert_eq!(0, locked.chain.height());
+        assert_eq!(
+            Some(Misbehavior::UnsolicitedData.weight()),
+            locked.peers.score_of(registered.id),
+            "but charged for"
+        );
+    }
+
+    #[test]
+    fn a_requested_block_whose_body_is_invalid_ends_the_connection_and_bans_its_sender() {
+        let params = Params::of(Network::Regtest).unwrap();
+        let greedy = crate::transaction::Transaction::coinbase(
+            "height 1".to_string(),
+            vec![crate::transaction::TxOut {
+                value: crate::params::subsidy(1) + 1,
+                destiny_pub_key: "miner".to_string(),
+            }],
+        );
+        let mut block = Block::new(
+            1,
+            params.genesis.hash.unwrap(),
+            params.genesis.time + 1,
+            params.starting_n_bits,
+            vec![greedy],
+        );
+        assert!(block.mine().unwrap());
+        let (node, registered, _queued) = syncing_blocks(std::slice::from_ref(&block));
+
+        let error = send_block(&registered, &block).expect_err("more than the subsidy");
 
+        assert!(format!("{error:#}").contains("subsidy"), "got: {error:#}");
+        let mut locked = node.lock().unwrap();
+        assert_eq!(0, locked.chain.height());
         assert!(
-            matches!(
-                parse_all(&output).as_slice(),
-                [VersionMessage(_), PingMessage(_)]
-            ),
-            "a new connection identifies itself first, then pings at once rather than after an hour"
+            locked
+                .bans
+                .is_banned(registered.address.ip(), Instant::now()),
+            "and its sender is banned"
         );
     }
 
     #[test]
-    fn a_message_enqueued_from_another_thread_is_written_to_the_peer() {
-        let (outbound, queued) = mpsc::sync_channel(OUTBOUND_QUEUE);
-        let ping = Ping::new();
-        let nonce = ping.nonce;
-        let pong = framed(Pong::new(ping).unwrap());
+    fn a_disconnecting_peer_gives_its_requests_back() {
+        let blocks = mined_elsewhere(2);
+        let (node, registered, _queued) = syncing_blocks(&blocks);
+        let hash = blocks[0].hash.unwrap();
+        assert_eq!(
+            Some(registered.id),
+            node.lock().unwrap().downloads.requested_from(&hash)
+        );
 
-        let sender = thread::spawn(move || {
-            // The writer must already be parked in recv_timeout, or this proves
-            // only that a drained queue is written, not that a live writer wakes.
-            thread::sleep(Duration::from_millis(50));
-            outbound.send(pong).unwrap();
-        });
+        drop(registered);
 
-        let mut output = Vec::new();
-        write_loop(&mut output, queued, NEVER, Vec::new()).unwrap();
-        sender.join().unwrap();
+        assert_eq!(None, node.lock().unwrap().downloads.requested_from(&hash));
+    }
 
-        match parse_all(&output).as_slice() {
-            [PingMessage(_), PongMessage(pong)] => assert_eq!(nonce, pong.payload.nonce),
-            other => panic!("expected the opening ping then the enqueued pong, got {other:?}"),
+    #[test]
+    fn a_getdata_for_a_connected_block_is_answered_with_it() {
+        let node = a_node_on(Network::Regtest);
+        let hashes = crate::mining::generate(
+            &node,
+            2,
+            "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+        )
+        .unwrap();
+        let (registered, queued) = a_syncing_peer(&node);
+        let request = GetData {
+            inventory: vec![Inventory::Block(hashes[1]), Inventory::Block([5; 32])],
+        };
+        let framed = Message::new(registered.magic, request)
+            .unwrap()
+            .get_raw_format()
+            .unwrap();
+
+        process_incoming_bytes(&registered, &mut Vec::new(), &framed).unwrap();
+
+        match parse_on(registered.magic, &queued.try_recv().unwrap()) {
+            BlockMessage(mut block) => assert_eq!(hashes[1], block.payload.seal().unwrap()),
+            other => panic!("expected a block, got {other:?}"),
+        }
+        match parse_on(registered.magic, &queued.try_recv().unwrap()) {
+            NotFoundMessage(answer) => {
+                assert_eq!(vec![Inventory::Block([5; 32])], answer.payload.inventory)
+            }
+            other => panic!("expected a notfound, got {other:?}"),
         }
     }
 
     #[test]
-    fn a_stalled_write_ends_the_connection_rather_than_blocking_forever() {
-        /// Takes the opening ping, then behaves like a socket whose write
-        /// timeout has expired — so the failure under test is the *queued*
-        /// message, not the ping.
-        #[derive(Default)]
-        struct AcceptsThenStalls {
-            taken: usize,
+    fn a_block_named_twice_in_one_getdata_is_served_once() {
+        let node = a_node_on(Network::Regtest);
+        let hashes = crate::mining::generate(
+            &node,
+            1,
+            "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+        )
+        .unwrap();
+        let (registered, queued) = a_syncing_peer(&node);
+
+        send(
+            &registered,
+            GetData {
+                inventory: vec![Inventory::Block(hashes[0]); 3],
+            },
+        );
+
+        assert!(matches!(
+            parse_on(registered.magic, &queued.try_recv().unwrap()),
+            BlockMessage(_)
+        ));
+        assert!(queued.try_recv().is_err(), "one copy, and no notfound");
+    }
+
+    #[test]
+    fn a_getdata_for_more_than_the_queue_holds_is_charged_for_and_left_unanswered() {
+        let node = a_node_on(Network::Regtest);
+        let hashes = crate::mining::generate(
+            &node,
+            2,
+            "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+        )
+        .unwrap();
+        let (registered, queued) = a_syncing_peer(&node);
+        let unread = vec![0; OUTBOUND_BUDGET];
+        assert_eq!(
+            Sent::Queued,
+            node.lock().unwrap().peers.send_to(registered.id, unread)
+        );
+
+        send(
+            &registered,
+            GetData {
+                inventory: hashes.iter().copied().map(Inventory::Block).collect(),
+            },
+        );
+
+        assert_eq!(
+            Some(Misbehavior::ExcessiveRequest.weight()),
+            score_of(&registered)
+        );
+        assert_eq!(OUTBOUND_BUDGET, queued.try_recv().unwrap().len());
+        assert!(queued.try_recv().is_err(), "nothing queued past the budget");
+    }
+
+    fn send<P: crate::messages::message::Payload>(registered: &Registered, payload: P) {
+        let framed = Message::new(registered.magic, payload)
+            .unwrap()
+            .get_raw_format()
+            .unwrap();
+        process_incoming_bytes(registered, &mut Vec::new(), &framed).unwrap();
+    }
+
+    /// A transaction spending output `v_out` of regtest's genesis coinbase,
+    /// which allocates three.
+    fn spending(v_out: u32, value: u64) -> Transaction {
+        let genesis = Params::of(Network::Regtest).unwrap().genesis;
+        let tx_id = genesis.transactions[0].get_tx_id();
+        Transaction {
+            version: 1,
+            inputs: vec![crate::transaction::TxIn {
+                previous_output: crate::transaction::Outpoint { tx_id, v_out },
+                signature: "signed".to_string(),
+                sequence: u32::MAX,
+            }],
+            outputs: vec![crate::transaction::TxOut {
+                value,
+                destiny_pub_key: "someone".to_string(),
+            }],
+            lock_time: 0,
         }
+    }
 
-        impl Write for AcceptsThenStalls {
-            fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
-                if self.taken == 0 {
-                    self.taken += 1;
-                    return Ok(buffer.len());
-                }
-                Err(std::io::Error::from(ErrorKind::WouldBlock))
-            }
-            fn flush(&mut self) -> std::io::Result<()> {
-                Ok(())
-            }
+    fn pool(node: &SharedNode, transaction: Transaction) {
+        let mut locked = node.lock().unwrap();
+        let locked = &mut *locked;
+        let admission = locked.mempool.admit(
+            transaction,
+            locked.chain.utxos(),
+            locked.params.coinbase_maturity,
+        );
+        assert_eq!(Admission::Accepted, admission.unwrap());
+    }
+
+    #[test]
+    fn a_getdata_for_a_pending_transaction_is_answered_from_the_mempool() {
+        let node = a_node_on(Network::Regtest);
+        let pending = spending(0, 5);
+        let txid = pending.get_tx_id();
+        pool(&node, pending.clone());
+        let (registered, queued) = a_syncing_peer(&node);
+
+        send(
+            &registered,
+            GetData {
+                inventory: vec![Inventory::Tx(txid)],
+            },
+        );
+
+        match parse_on(registered.magic, &queued.try_recv().unwrap()) {
+            TxMessage(answer) => assert_eq!(txid, answer.payload.get_tx_id()),
+            other => panic!("expected a tx, got {other:?}"),
         }
+    }
 
-        let (outbound, queued) = mpsc::sync_channel(OUTBOUND_QUEUE);
-        outbound.try_send(b"backlog".to_vec()).unwrap();
-        drop(outbound);
+    #[test]
+    fn an_announced_transaction_we_lack_is_asked_for() {
+        let node = a_node_on(Network::Regtest);
+        let held = spending(0, 5);
+        pool(&node, held.clone());
+        let (registered, queued) = a_syncing_peer(&node);
 
-        // Dropping the peer cannot end this connection on its own: mpsc hands
-        // the writer every buffered message before it ever reports
-        // Disconnected, so the writer must give up on the socket itself.
-        write_loop(AcceptsThenStalls::default(), queued, NEVER, Vec::new())
-            .expect_err("a write that cannot proceed must end the connection");
+        send(
+            &registered,
+            Inv {
+                inventory: vec![Inventory::Tx(held.get_tx_id()), Inventory::Tx([7; 32])],
+            },
+        );
+
+        match parse_on(registered.magic, &queued.try_recv().unwrap()) {
+            GetDataMessage(request) => {
+                assert_eq!(vec![Inventory::Tx([7; 32])], request.payload.inventory)
+            }
+            other => panic!("expected a getdata, got {other:?}"),
+        }
     }
 
     #[test]
-    fn a_connection_bounds_how_long_a_write_may_block() {
-        let (_peer, accepted, peer_addr) = a_connected_pair();
-        let observer = accepted.try_clone().unwrap();
+    fn a_valid_transaction_is_pooled_and_announced_to_every_peer_but_its_sender() {
+        let node = a_node_on(Network::Regtest);
+        let (sender, to_sender) = a_syncing_peer(&node);
+        let (_other, to_other) = a_syncing_peer(&node);
+        let transaction = spending(0, 5);
+        let txid = transaction.get_tx_id();
+
+        send(&sender, transaction);
+
+        assert!(node.lock().unwrap().mempool.contains(&txid));
+        assert_eq!(vec![txid], to_other.announcements(10));
+        assert!(to_sender.announcements(10).is_empty(), "they sent it to us");
+    }
 
-        thread::spawn(move || handle_alone(accepted, peer_addr, a_node()));
+    #[test]
+    fn a_transaction_that_can_never_be_valid_ends_the_connection_and_bans_its_sender() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, _queued) = a_syncing_peer(&node);
+        let minted = Transaction::coinbase("free money".to_string(), spending(0, 5).outputs);
+        let framed = Message::new(registered.magic, minted)
+            .unwrap()
+            .get_raw_format()
+            .unwrap();
 
-        eventually(
-            || observer.write_timeout().unwrap().is_some(),
-            "the write half never had its blocking bounded",
+        let error = process_incoming_bytes(&registered, &mut Vec::new(), &framed)
+            .expect_err("a coinbase outside a block");
+
+        assert!(
+            format!("{error:#}").contains("invalid transaction"),
+            "got: {error:#}"
         );
-        assert_eq!(Some(WRITE_TIMEOUT), observer.write_timeout().unwrap());
+        let mut locked = node.lock().unwrap();
+        assert_eq!(0, locked.mempool.len());
+        assert!(locked
+            .bans
+            .is_banned(registered.address.ip(), Instant::now()));
     }
 
     #[test]
-    fn a_connection_bounds_how_long_it_waits_to_be_told_who_it_is_talking_to() {
-        let (_peer, accepted, peer_addr) = a_connected_pair();
-        let observer = accepted.try_clone().unwrap();
+    fn a_transaction_spending_what_we_do_not_hold_is_ignored_not_charged() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, queued) = a_syncing_peer(&node);
 
-        thread::spawn(move || handle_alone(accepted, peer_addr, a_node()));
+        send(&registered, spending(3, 5));
 
-        eventually(
-            || observer.read_timeout().unwrap().is_some(),
-            "the read half never had its waiting bounded",
-        );
-        assert_eq!(Some(HANDSHAKE_TIMEOUT), observer.read_timeout().unwrap());
+        assert_eq!(0, node.lock().unwrap().mempool.len());
+        assert_eq!(Some(0), score_of(&registered));
+        assert!(queued.announcements(10).is_empty());
     }
 
     #[test]
-    fn a_peer_that_never_identifies_itself_gives_its_slot_back() {
-        let (_peer, accepted, peer_addr) = a_connected_pair();
-        let node = a_node();
-        let watched = Arc::clone(&node);
+    fn a_second_spend_of_a_pooled_output_is_ignored_not_fatal() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, _queued) = a_syncing_peer(&node);
+        send(&registered, spending(0, 5));
+
+        send(&registered, spending(0, 4));
+
+        let locked = node.lock().unwrap();
+        assert_eq!(1, locked.mempool.len());
+        assert!(locked
+            .log
+            .recent()
+            .any(|entry| entry.starts_with("Ignoring transaction")));
+    }
 
-        thread::spawn(move || {
-            handle_alone_for(accepted, peer_addr, node, Duration::from_millis(50))
-        });
+    #[test]
+    fn an_announced_block_we_have_not_heard_of_is_asked_for_by_its_headers() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, queued) = a_syncing_peer(&node);
+        answer(&registered, Vec::new()).unwrap();
+        let genesis = node.lock().unwrap().chain.tip_hash();
 
-        eventually(
-            || watched.lock().unwrap().peers.len() == 1,
-            "the connection never registered a peer",
+        send(
+            &registered,
+            Inv {
+                inventory: vec![Inventory::Block(genesis)],
+            },
         );
-        // The slot and its 32 MiB recv_buffer are what the deadline is for;
-        // ending the connection without freeing them would miss the point.
-        eventually(
-            || watched.lock().unwrap().peers.is_empty(),
-            "a peer that never identified itself kept its slot",
+        assert!(queued.try_recv().is_err(), "we have that one");
+
+        send(
+            &registered,
+            Inv {
+                inventory: vec![Inventory::Block([9; 32])],
+            },
         );
+        assert!(matches!(
+            parse_on(registered.magic, &queued.try_recv().unwrap()),
+            GetHeadersMessage(_)
+        ));
     }
 
     #[test]
-    fn pings_recur_at_the_configured_interval() {
-        let interval = Duration::from_millis(20);
-        let run_for = Duration::from_millis(300);
+    fn a_block_announced_while_headers_are_on_their_way_is_asked_for_after_them() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, queued) = a_syncing_peer(&node);
 
-        let (outbound, queued) = mpsc::sync_channel::<Vec<u8>>(OUTBOUND_QUEUE);
-        let holder = thread::spawn(move || {
-            thread::sleep(run_for);
-            drop(outbound);
-        });
+        send(
+            &registered,
+            Inv {
+                inventory: vec![Inventory::Block([9; 32])],
+            },
+        );
+        assert!(
+            queued.try_recv().is_err(),
+            "a second request would be answered unasked for"
+        );
 
-        let mut output = Vec::new();
-        write_loop(&mut output, queued, interval, Vec::new()).unwrap();
-        holder.join().unwrap();
+        answer(&registered, Vec::new()).unwrap();
+        assert!(matches!(
+            parse_on(registered.magic, &queued.try_recv().unwrap()),
+            GetHeadersMessage(_)
+        ));
+    }
 
-        let pings = parse_all(&output).len();
-        let expected = run_for.as_millis() / interval.as_millis();
+    #[test]
+    fn a_connected_block_is_announced_to_every_peer_but_its_sender() {
+        let blocks = mined_elsewhere(1);
+        let (node, registered, queued) = syncing_blocks(&blocks);
+        let (_other, to_other) = a_syncing_peer(&node);
 
+        send_block(&registered, &blocks[0]).unwrap();
+
+        match parse_on(registered.magic, &to_other.try_recv().unwrap()) {
+            CompactBlockMessage(announcement) => {
+                assert_eq!(blocks[0].hash.unwrap(), announcement.payload.header.hash())
+            }
+            other => panic!("expected a cmpctblock, got {other:?}"),
+        }
         assert!(
-            pings >= 5,
-            "{pings} pings in {run_for:?} at a {interval:?} interval; \
-             a timer that only fires when something else wakes it would send ~1, not ~{expected}"
+            !std::iter::from_fn(|| queued.try_recv().ok()).any(|bytes| matches!(
+                parse_on(registered.magic, &bytes),
+                InvMessage(_) | CompactBlockMessage(_)
+            )),
+            "they sent it to us"
+        );
+    }
+
+    /// A block on regtest's genesis holding `transactions` after its coinbase.
+    fn mined_holding(transactions: Vec<Transaction>) -> Block {
+        let params = Params::of(Network::Regtest).unwrap();
+        let coinbase = Transaction::coinbase(
+            "height 1".to_string(),
+            vec![crate::transaction::TxOut {
+                value: 1,
+                destiny_pub_key: "miner".to_string(),
+            }],
+        );
+        let mut block = Block::new(
+            1,
+            params.genesis.hash.unwrap(),
+            params.genesis.time + 1,
+            params.starting_n_bits,
+            std::iter::once(coinbase).chain(transactions).collect(),
         );
+        assert!(block.mine().unwrap());
+        block
+    }
+
+    #[test]
+    fn a_compact_block_whose_transactions_are_pooled_connects_without_asking_for_anything() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, queued) = a_syncing_peer(&node);
+        let pending = spending(0, 5);
+        pool(&node, pending.clone());
+        let block = mined_holding(vec![pending]);
+
+        send(&registered, CompactBlock::new(&block, 7).unwrap());
+
+        let locked = node.lock().unwrap();
+        assert_eq!(block.hash.unwrap(), locked.chain.tip_hash());
+        assert_eq!(0, locked.mempool.len(), "confirmed");
         assert!(
-            pings <= 40,
-            "{pings} pings is a busy loop, not a {interval:?} interval"
+            !std::iter::from_fn(|| queued.try_recv().ok()).any(|bytes| matches!(
+                parse_on(registered.magic, &bytes),
+                GetBlockTxnMessage(_) | GetDataMessage(_)
+            )),
+            "nothing was missing"
         );
     }
 
     #[test]
-    fn an_inbound_ping_is_answered_with_a_pong_on_the_outbound_channel() {
-        let (registered, queued) = a_registered_peer();
-        let mut recv_buffer = Vec::new();
-        let (ping, nonce) = framed_ping();
+    fn what_the_mempool_lacks_is_asked_of_the_sender_and_completes_the_block() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, queued) = a_syncing_peer(&node);
+        let (pooled, unheard) = (spending(0, 5), spending(1, 5));
+        pool(&node, pooled.clone());
+        let block = mined_holding(vec![pooled, unheard.clone()]);
+        let hash = block.hash.unwrap();
+
+        send(&registered, CompactBlock::new(&block, 7).unwrap());
+
+        match parse_on(registered.magic, &queued.try_recv().unwrap()) {
+            GetBlockTxnMessage(request) => {
+                assert_eq!(hash, request.payload.block_hash);
+                assert_eq!(vec![2], request.payload.indexes);
+            }
+            other => panic!("expected a getblocktxn, got {other:?}"),
+        }
+        assert_eq!(0, node.lock().unwrap().chain.height(), "not yet");
 
-        process_incoming_bytes(&registered, &mut recv_buffer, &ping).unwrap();
+        send(
+            &registered,
+            BlockTxn {
+                block_hash: hash,
+                transactions: vec![unheard],
+            },
+        );
 
-        assert!(recv_buffer.is_empty(), "the ping should be fully consumed");
+        assert_eq!(hash, node.lock().unwrap().chain.tip_hash());
+    }
 
-        let reply = queued.try_recv().expect("a ping must be answered");
-        match parse_all(&reply).as_slice() {
-            [PongMessage(pong)] => assert_eq!(nonce, pong.payload.nonce),
-            other => panic!("expected a pong, got {other:?}"),
+    #[test]
+    fn a_compact_block_that_does_not_rebuild_is_asked_for_whole() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, queued) = a_syncing_peer(&node);
+        let block = mined_holding(vec![spending(0, 5)]);
+        let hash = block.hash.unwrap();
+        // A pooled transaction under the short id of the one in the block:
+        // the collision a real one would take 2^48 tries to find.
+        let impostor = spending(1, 5);
+        pool(&node, impostor.clone());
+        let mut compact = CompactBlock::new(&block, 7).unwrap();
+        compact.short_ids = vec![compact.short_id(&impostor.get_tx_id())];
+
+        send(&registered, compact);
+
+        match parse_on(registered.magic, &queued.try_recv().unwrap()) {
+            GetDataMessage(request) => {
+                assert_eq!(vec![Inventory::Block(hash)], request.payload.inventory)
+            }
+            other => panic!("expected a getdata, got {other:?}"),
         }
-        assert!(queued.try_recv().is_err(), "one ping, one pong");
+
+        send_block(&registered, &block).unwrap();
+
+        assert_eq!(hash, node.lock().unwrap().chain.tip_hash());
+        assert_eq!(Some(0), score_of(&registered), "the block was asked for");
     }
 
     #[test]
-    fn an_oversized_header_fails_the_connection_rather_than_being_awaited() {
-        let (registered, queued) = a_registered_peer();
-        let mut recv_buffer = Vec::new();
-        let header = crate::messages::message::header_claiming(u32::MAX);
+    fn a_compact_block_on_a_parent_we_do_not_know_is_asked_for_by_its_headers() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, queued) = a_syncing_peer(&node);
+        answer(&registered, Vec::new()).unwrap();
+        let mut orphan = mined_holding(Vec::new());
+        orphan.previous_block_hash = [9; 32];
+        orphan.mine().unwrap();
 
-        let error = process_incoming_bytes(&registered, &mut recv_buffer, &header)
-            .expect_err("a header claiming 4 GB must fail the connection, not be waited on");
+        send(&registered, CompactBlock::new(&orphan, 7).unwrap());
 
-        assert!(format!("{error:#}").contains("too large"), "got: {error:#}");
-        assert!(
-            queued.try_recv().is_err(),
-            "nothing should be queued in reply to a header that was refused"
+        assert!(matches!(
+            parse_on(registered.magic, &queued.try_recv().unwrap()),
+            GetHeadersMessage(_)
+        ));
+    }
+
+    #[test]
+    fn transactions_of_a_block_we_did_not_ask_about_are_ignored_and_charged_for() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, _queued) = a_syncing_peer(&node);
+
+        send(
+            &registered,
+            BlockTxn {
+                block_hash: [9; 32],
+                transactions: vec![spending(0, 5)],
+            },
+        );
+
+        assert_eq!(
+            Some(Misbehavior::UnsolicitedData.weight()),
+            score_of(&registered)
+        );
+    }
+
+    #[test]
+    fn a_getblocktxn_is_answered_from_the_connected_block() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, queued) = a_syncing_peer(&node);
+        let block = mined_holding(vec![spending(0, 5)]);
+        let hash = block.hash.unwrap();
+        {
+            let mut locked = node.lock().unwrap();
+            let locked = &mut *locked;
+            locked.chain.connect(block.clone(), &locked.params).unwrap();
+        }
+
+        send(
+            &registered,
+            GetBlockTxn {
+                block_hash: hash,
+                indexes: vec![1],
+            },
+        );
+        match parse_on(registered.magic, &queued.try_recv().unwrap()) {
+            BlockTxnMessage(answer) => assert_eq!(
+                vec![block.transactions[1].get_tx_id()],
+                answer
+                    .payload
+                    .transactions
+                    .iter()
+                    .map(Transaction::get_tx_id)
+                    .collect::<Vec<_>>()
+            ),
+            other => panic!("expected a blocktxn, got {other:?}"),
+        }
+
+        send(
+            &registered,
+            GetBlockTxn {
+                block_hash: [9; 32],
+                indexes: vec![1],
+            },
+        );
+        assert!(matches!(
+            parse_on(registered.magic, &queued.try_recv().unwrap()),
+            NotFoundMessage(_)
+        ));
+
+        let past_the_end = Message::new(
+            registered.magic,
+            GetBlockTxn {
+                block_hash: hash,
+                indexes: vec![2],
+            },
+        )
+        .unwrap()
+        .get_raw_format()
+        .unwrap();
+        let error = process_incoming_bytes(&registered, &mut Vec::new(), &past_the_end)
+            .expect_err("no such transaction");
+        assert!(format!("{error:#}").contains("holds 2"), "got: {error:#}");
+    }
+
+    #[test]
+    fn a_notfound_gives_the_block_back_to_be_asked_of_someone_else() {
+        let blocks = mined_elsewhere(2);
+        let (node, registered, _queued) = syncing_blocks(&blocks);
+        let hash = blocks[0].hash.unwrap();
+
+        send(
+            &registered,
+            NotFound {
+                inventory: vec![Inventory::Block(hash)],
+            },
+        );
+
+        assert_eq!(None, node.lock().unwrap().downloads.requested_from(&hash));
+        assert_eq!(
+            Some(registered.id),
+            node.lock()
+                .unwrap()
+                .downloads
+                .requested_from(&blocks[1].hash.unwrap()),
+            "only what they said they lack"
         );
     }
 
     #[test]
-    fn an_interrupted_read_does_not_end_the_connection() {
-        struct InterruptsOnce {
-            ping: Vec<u8>,
-            interrupted: bool,
-        }
+    fn headers_we_did_not_ask_for_are_ignored_and_charged_for() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, _queued) = a_syncing_peer(&node);
+        answer(&registered, Vec::new()).unwrap();
 
-        impl Read for InterruptsOnce {
-            fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
-                if !self.interrupted {
-                    self.interrupted = true;
-                    return Err(std::io::Error::from(ErrorKind::Interrupted));
-                }
-                let taken = self.ping.len();
-                buffer[..taken].copy_from_slice(&self.ping);
-                self.ping.clear();
-                Ok(taken)
-            }
-        }
+        answer(&registered, regtest_headers(1)).expect("ignored, not fatal");
 
-        let (registered, queued) = a_registered_peer();
-        let (ping, nonce) = framed_ping();
-        let reader = InterruptsOnce {
-            ping,
-            interrupted: false,
-        };
+        let locked = node.lock().unwrap();
+        assert_eq!(0, locked.chain.best_header_height());
+        assert_eq!(
+            Some(Misbehavior::UnsolicitedData.weight()),
+            locked.peers.score_of(registered.id)
+        );
+    }
 
-        read_loop(reader, &registered, NEVER)
-            .expect("a signal-interrupted read must be retried, not fail the connection");
+    #[test]
+    fn headers_that_do_not_form_a_chain_are_refused() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, _queued) = a_syncing_peer(&node);
+        let mut headers = regtest_headers(3);
+        headers.remove(1);
 
-        let reply = queued.try_recv().expect("the ping after the interrupt");
-        match parse_all(&reply).as_slice() {
-            [PongMessage(pong)] => assert_eq!(nonce, pong.payload.nonce),
-            other => panic!("expected a pong, got {other:?}"),
-        }
+        let error = answer(&registered, headers).expect_err("a gap");
+
+        assert!(format!("{error:#}").contains("chain"), "got: {error:#}");
+        assert_eq!(0, node.lock().unwrap().chain.best_header_height());
+        assert!(is_banned(&node, registered.address));
     }
 
-    fn next_message(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> MessageReceived {
-        let mut chunk = [0u8; 512];
+    #[test]
+    fn an_invalid_header_is_refused_whoever_sends_it() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, _queued) = a_syncing_peer(&node);
+        let mut headers = regtest_headers(1);
+        headers[0].n_bits = 0x2100ffff;
 
-        loop {
-            if let (Some(message), consumed) = MessageReceived::try_parse_message(buffer)
-                .expect("peer sent an unparseable message")
-            {
-                buffer.drain(0..consumed);
-                return message;
-            }
+        let error = answer(&registered, headers).expect_err("an easier target than the network's");
 
-            let read = stream.read(&mut chunk).expect("peer went quiet");
-            assert_ne!(0, read, "peer closed before sending the expected message");
-            buffer.extend_from_slice(&chunk[..read]);
-        }
+        assert!(
+            format!("{error:#}").contains("invalid header"),
+            "got: {error:#}"
+        );
+        assert_eq!(0, node.lock().unwrap().chain.best_header_height());
+        assert!(is_banned(&node, registered.address));
     }
 
-    /// The next message that is a reply to something, rather than the timer's.
-    fn next_reply(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> MessageReceived {
-        loop {
-            match next_message(stream, buffer) {
-                PingMessage(_) => continue,
-                reply => return reply,
-            }
+    #[test]
+    fn a_header_from_past_our_clock_is_logged_and_its_sender_kept() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, _queued) = a_syncing_peer(&node);
+        let target =
+            crate::difficulty::Target::from_compact(node.lock().unwrap().params.starting_n_bits)
+                .unwrap();
+        let mut headers = regtest_headers(1);
+        headers[0].time = unix_time() + MAX_FUTURE_DRIFT + 60 * 60;
+        while !target.is_met_by(&headers[0].hash()) {
+            headers[0].nonce += 1;
         }
+
+        answer(&registered, headers).expect("maybe our clock, not their header");
+
+        assert!(!is_banned(&node, registered.address));
+        let locked = node.lock().unwrap();
+        assert_eq!(0, locked.chain.best_header_height());
+        assert_eq!(Some(0), locked.peers.score_of(registered.id));
+        assert!(locked
+            .log
+            .recent()
+            .any(|entry| entry.contains("if our clock is wrong")));
     }
 
-    fn a_connected_pair() -> (TcpStream, TcpStream, SocketAddr) {
-        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
-        let address = listener.local_addr().unwrap();
+    #[test]
+    fn a_compact_block_from_past_our_clock_is_logged_and_its_sender_kept() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, _queued) = a_syncing_peer(&node);
+        let mut block = mined_holding(Vec::new());
+        block.time = unix_time() + MAX_FUTURE_DRIFT + 60 * 60;
+        block.mine().unwrap();
+
+        send(&registered, CompactBlock::new(&block, 7).unwrap());
+
+        assert_eq!(0, node.lock().unwrap().chain.best_header_height());
+        assert_eq!(Some(0), score_of(&registered));
+        assert!(!is_banned(&node, registered.address));
+    }
 
-        let peer = TcpStream::connect(address).unwrap();
-        peer.set_read_timeout(Some(Duration::from_secs(10)))
+    #[test]
+    fn a_compact_block_whose_header_fails_its_checks_ends_the_connection_and_bans_its_sender() {
+        let node = a_node_on(Network::Regtest);
+        let (registered, _queued) = a_syncing_peer(&node);
+        let mut block = mined_holding(Vec::new());
+        block.n_bits = 0x2100ffff;
+        block.mine().unwrap();
+
+        let message = Message::new(registered.magic, CompactBlock::new(&block, 7).unwrap())
+            .unwrap()
+            .get_raw_format()
             .unwrap();
-        let (accepted, peer_addr) = listener.accept().unwrap();
+        let error = process_incoming_bytes(&registered, &mut Vec::new(), &message)
+            .expect_err("an easier target than the network's");
 
-        (peer, accepted, peer_addr)
+        assert!(
+            format!("{error:#}").contains("invalid header"),
+            "got: {error:#}"
+        );
+        assert_eq!(0, node.lock().unwrap().chain.best_header_height());
+        assert!(is_banned(&node, registered.address));
     }
 
-    fn eventually(mut settled: impl FnMut() -> bool, what: &str) {
-        let deadline = Instant::now() + Duration::from_secs(5);
+    #[test]
+    fn a_peer_we_dialled_is_asked_for_addresses_once_it_is_ready() {
+        let node = a_node();
+        let (registered, queued) = a_peer_from(&node, Origin::Dialled);
 
-        while Instant::now() < deadline {
-            if settled() {
-                return;
-            }
-            thread::sleep(Duration::from_millis(10));
-        }
+        process_incoming_bytes(&registered, &mut Vec::new(), &framed_version()).unwrap();
+        process_incoming_bytes(&registered, &mut Vec::new(), &framed(Verack)).unwrap();
 
-        panic!("{what} within 5s");
+        let sent: Vec<String> = std::iter::from_fn(|| queued.try_recv().ok())
+            .flat_map(|bytes| parse_all(&bytes))
+            .map(|message| message.command_name().to_string())
+            .collect();
+        assert_eq!(vec!["verack", "getheaders", "getaddr"], sent);
+        assert_eq!(
+            0,
+            node.lock()
+                .unwrap()
+                .addresses
+                .get(&registered.address)
+                .expect("a dial that reached Ready is a good address")
+                .failures
+        );
     }
 
-    /// What spawn_connection does, minus the registry, for tests about the
-    /// thread pair rather than about the peer table.
-    fn handle_alone(stream: TcpStream, peer_addr: SocketAddr, node: SharedNode) -> Result<()> {
-        handle_alone_for(stream, peer_addr, node, HANDSHAKE_TIMEOUT)
+    #[test]
+    fn a_peer_that_dialled_us_is_not_asked_for_addresses_but_its_listen_address_is_learned() {
+        let node = a_node();
+        let (registered, queued) = a_peer_of(&node);
+
+        process_incoming_bytes(&registered, &mut Vec::new(), &framed_version()).unwrap();
+        process_incoming_bytes(&registered, &mut Vec::new(), &framed(Verack)).unwrap();
+
+        assert!(std::iter::from_fn(|| queued.try_recv().ok())
+            .flat_map(|bytes| parse_all(&bytes))
+            .all(|message| !matches!(message, GetAddrMessage)));
+        let node = node.lock().unwrap();
+        let listening: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        assert!(node.addresses.get(&listening).is_some());
+        assert!(node.peers.addresses().contains(&listening));
     }
 
-    fn handle_alone_for(
-        stream: TcpStream,
-        peer_addr: SocketAddr,
-        node: SharedNode,
-        handshake_timeout: Duration,
-    ) -> Result<()> {
-        let (outbound, queued) = mpsc::sync_channel(OUTBOUND_QUEUE);
-        let registered = Registered::open(&node, peer_addr, Origin::Accepted, outbound)
-            .expect("an empty table should accept a peer");
-        handle_connection(stream, registered, queued, handshake_timeout)
+    #[test]
+    fn a_getaddr_is_answered_with_the_addresses_we_know() {
+        let node = a_node();
+        let known: SocketAddr = "10.0.0.1:34352".parse().unwrap();
+        node.lock().unwrap().addresses.learn(known, 100, 100);
+        let (registered, queued) = a_syncing_peer(&node);
+
+        send(&registered, GetAddr);
+
+        match parse_on(registered.magic, &queued.try_recv().unwrap()) {
+            AddrMessage(answer) => assert!(answer
+                .payload
+                .addresses
+                .iter()
+                .any(|timed| timed.address == known)),
+            other => panic!("expected an addr, got {other:?}"),
+        }
     }
 
-    /// A peer in a node's table, plus the queue its writer would drain.
-    fn a_registered_peer() -> (Registered, Receiver<Vec<u8>>) {
+    #[test]
+    fn addresses_a_peer_tells_us_of_are_learned() {
         let node = a_node();
-        let (outbound, queued) = mpsc::sync_channel(OUTBOUND_QUEUE);
-        let registered = Registered::open(
-            &node,
-            "127.0.0.1:5000".parse().unwrap(),
-            Origin::Accepted,
-            outbound,
-        )
-        .expect("an empty table should accept a peer");
+        let (registered, _queued) = a_syncing_peer(&node);
+        let told: SocketAddr = "10.0.0.2:34352".parse().unwrap();
 
-        (registered, queued)
-    }
+        send(
+            &registered,
+            Addr {
+                addresses: vec![TimedAddress {
+                    last_seen: unix_time(),
+                    address: told,
+                }],
+            },
+        );
 
-    fn a_node() -> SharedNode {
-        Node::shared(Config {
-            host_address: "127.0.0.1:34352".parse().unwrap(),
-            addresses_to_connect: Vec::new(),
-        })
+        assert!(node.lock().unwrap().addresses.get(&told).is_some());
+        assert!(registered
+            .node
+            .lock()
+            .unwrap()
+            .log
+            .recent()
+            .any(|entry| entry.starts_with("Learned 1 addresses")));
     }
 
     #[test]
@@ -674,20 +3250,163 @@ mod tests {
             matches!(next_message(&mut peer, &mut buffer), VersionMessage(_)),
             "a connection should open by identifying itself"
         );
+        peer.write_all(&[framed_version(), framed(Verack)].concat())
+            .unwrap();
         assert!(
-            matches!(next_message(&mut peer, &mut buffer), PingMessage(_)),
-            "and then ping its peer"
+            matches!(next_message(&mut peer, &mut buffer), VerackMessage),
+            "nothing comes between its version and its verack"
         );
+        while !matches!(next_message(&mut peer, &mut buffer), PingMessage(_)) {}
 
         let (ping, nonce) = framed_ping();
         peer.write_all(&ping).unwrap();
 
-        match next_message(&mut peer, &mut buffer) {
-            PongMessage(pong) => assert_eq!(nonce, pong.payload.nonce),
-            other => panic!("expected a pong for our ping, got {other:?}"),
+        loop {
+            match next_message(&mut peer, &mut buffer) {
+                PongMessage(pong) => break assert_eq!(nonce, pong.payload.nonce),
+                PingMessage(_) | GetHeadersMessage(_) => continue,
+                other => panic!("expected a pong for our ping, got {other:?}"),
+            }
+        }
+    }
+
+    #[rstest]
+    #[case::ping(framed_ping().0)]
+    #[case::getheaders(framed(GetHeaders::new(Vec::new())))]
+    #[case::inv(framed(Inv { inventory: Vec::new() }))]
+    fn anything_but_the_handshake_before_it_completes_ends_the_connection_and_is_charged_for(
+        #[case] early: Vec<u8>,
+    ) {
+        let (registered, queued) = a_registered_peer();
+
+        let error = process_incoming_bytes(&registered, &mut Vec::new(), &early)
+            .expect_err("the first is enough");
+
+        assert!(
+            format!("{error:#}").contains("before completing the handshake"),
+            "got: {error:#}"
+        );
+        assert!(queued.try_recv().is_err(), "nothing is said in reply");
+        assert_eq!(
+            Some(Misbehavior::HandshakeViolation.weight()),
+            score_of(&registered)
+        );
+    }
+
+    #[test]
+    fn misbehavior_that_reaches_the_ban_score_ends_the_connection_and_bans_the_address() {
+        let (registered, _queued) = a_registered_peer();
+        process_incoming_bytes(&registered, &mut Vec::new(), &framed(Verack)).unwrap();
+
+        let error = process_incoming_bytes(&registered, &mut Vec::new(), &framed_ping().0)
+            .expect_err("two handshake violations reach the ban score");
+
+        assert!(format!("{error:#}").contains("banned"), "got: {error:#}");
+        assert!(registered
+            .node
+            .lock()
+            .unwrap()
+            .bans
+            .is_banned(registered.address.ip(), Instant::now()));
+    }
+
+    #[test]
+    fn a_frame_that_fails_its_checksum_is_skipped_and_what_follows_it_is_heard() {
+        let (registered, queued) = a_ready_peer();
+        while queued.try_recv().is_ok() {}
+        let (mut corrupted, _) = framed_ping();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xff;
+        let (ping, nonce) = framed_ping();
+
+        let mut recv_buffer = Vec::new();
+        process_incoming_bytes(&registered, &mut recv_buffer, &[corrupted, ping].concat())
+            .expect("a bad checksum alone is forgiven");
+
+        assert!(recv_buffer.is_empty());
+        assert_eq!(
+            Some(Misbehavior::BadChecksum.weight()),
+            score_of(&registered)
+        );
+        let pongs: Vec<u64> = std::iter::from_fn(|| queued.try_recv().ok())
+            .flat_map(|bytes| parse_all(&bytes))
+            .filter_map(|message| match message {
+                PongMessage(pong) => Some(pong.payload.nonce),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![nonce], pongs, "only the sound ping is answered");
+    }
+
+    #[test]
+    fn an_unknown_command_is_skipped_and_counted_and_what_follows_it_is_heard() {
+        let (registered, queued) = a_ready_peer();
+        while queued.try_recv().is_ok() {}
+        let (mut unknown, _) = framed_ping();
+        unknown[4..16].copy_from_slice(&crate::util::command_12("sendfuture"));
+        let (ping, nonce) = framed_ping();
+
+        let mut recv_buffer = Vec::new();
+        process_incoming_bytes(&registered, &mut recv_buffer, &[unknown, ping].concat())
+            .expect("a command from a newer node is no reason to hang up");
+
+        assert!(recv_buffer.is_empty());
+        assert_eq!(Some(0), score_of(&registered), "not charged for");
+        let node = registered.node.lock().unwrap();
+        assert_eq!(1, node.peers.unknown_messages_of(registered.id).unwrap());
+        drop(node);
+        match parse_all(&queued.try_recv().unwrap()).as_slice() {
+            [PongMessage(pong)] => assert_eq!(nonce, pong.payload.nonce),
+            other => panic!("expected a pong, got {other:?}"),
         }
     }
 
+    #[test]
+    fn an_unknown_command_is_logged_once_however_often_it_is_repeated() {
+        let (registered, _queued) = a_ready_peer();
+        let (mut unknown, _) = framed_ping();
+        unknown[4..16].copy_from_slice(&crate::util::command_12("sendfuture"));
+
+        let repeated = [unknown.clone(), unknown.clone(), unknown].concat();
+        process_incoming_bytes(&registered, &mut Vec::new(), &repeated).unwrap();
+
+        let node = registered.node.lock().unwrap();
+        assert_eq!(3, node.peers.unknown_messages_of(registered.id).unwrap());
+        let logged = node
+            .log
+            .recent()
+            .filter(|entry| entry.starts_with("Skipping unknown command"))
+            .count();
+        assert_eq!(1, logged);
+    }
+
+    #[rstest]
+    #[case::older(MIN_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION)]
+    #[case::newer(PROTOCOL_VERSION + 1, PROTOCOL_VERSION)]
+    fn a_version_settles_the_protocol_both_sides_speak(#[case] theirs: u32, #[case] spoken: u32) {
+        let (registered, _queued) = a_registered_peer();
+        let mut version = Version::new(7, "127.0.0.1:5000".parse().unwrap(), 0);
+        version.protocol_version = theirs;
+
+        process_incoming_bytes(&registered, &mut Vec::new(), &framed(version)).unwrap();
+
+        let node = registered.node.lock().unwrap();
+        assert_eq!(Some(spoken), node.peers.protocol_version_of(registered.id));
+    }
+
+    #[test]
+    fn a_version_older_than_any_we_speak_ends_the_connection() {
+        let (registered, queued) = a_registered_peer();
+        let mut version = Version::new(7, "127.0.0.1:5000".parse().unwrap(), 0);
+        version.protocol_version = MIN_PROTOCOL_VERSION - 1;
+
+        let error = process_incoming_bytes(&registered, &mut Vec::new(), &framed(version))
+            .expect_err("nothing in common to speak");
+
+        assert!(format!("{error:#}").contains("older"), "got: {error:#}");
+        assert!(queued.try_recv().is_err(), "not answered with a verack");
+    }
+
     #[test]
     fn the_version_a_connection_opens_with_carries_the_nodes_nonce_and_listen_address() {
         let (mut peer, accepted, peer_addr) = a_connected_pair();
@@ -747,6 +3466,75 @@ mod tests {
         );
     }
 
+    #[test]
+    fn a_feeler_hangs_up_once_the_handshake_completes_and_marks_the_address_alive() {
+        let (mut peer, dialled, peer_addr) = a_connected_pair();
+        let node = a_node();
+        node.lock().unwrap().addresses.learn(peer_addr, 1, 1);
+        node.lock().unwrap().addresses.failed(peer_addr);
+        let watched = Arc::clone(&node);
+
+        spawn_connection(dialled, node, Origin::Feeler);
+
+        let mut buffer = Vec::new();
+        assert!(matches!(
+            next_message(&mut peer, &mut buffer),
+            VersionMessage(_)
+        ));
+        peer.write_all(&framed_version()).unwrap();
+        assert!(matches!(next_reply(&mut peer, &mut buffer), VerackMessage));
+        peer.write_all(&framed(Verack)).unwrap();
+
+        let mut rest = Vec::new();
+        peer.read_to_end(&mut rest)
+            .expect("the feeler hangs up rather than waiting on us");
+        assert!(
+            parse_all(&rest)
+                .iter()
+                .all(|message| matches!(message, PingMessage(_))),
+            "a feeler does not sync: got {:?}",
+            parse_all(&rest)
+        );
+        eventually(
+            || {
+                let node = watched.lock().unwrap();
+                node.peers.is_empty()
+                    && node
+                        .addresses
+                        .get(&peer_addr)
+                        .is_some_and(|entry| entry.failures == 0)
+            },
+            "a feeler that met a node did not mark its address alive",
+        );
+    }
+
+    #[test]
+    fn a_feeler_that_gets_no_handshake_counts_the_address_as_failed() {
+        let (mut peer, dialled, peer_addr) = a_connected_pair();
+        let node = a_node();
+        node.lock().unwrap().addresses.learn(peer_addr, 1, 1);
+        let watched = Arc::clone(&node);
+
+        spawn_connection(dialled, node, Origin::Feeler);
+        assert!(matches!(
+            next_message(&mut peer, &mut Vec::new()),
+            VersionMessage(_)
+        ));
+        drop(peer);
+
+        eventually(
+            || {
+                watched
+                    .lock()
+                    .unwrap()
+                    .addresses
+                    .get(&peer_addr)
+                    .is_some_and(|entry| entry.failures == 1)
+            },
+            "a feeler that met no node did not count the failure",
+        );
+    }
+
     #[test]
     fn a_version_is_answered_with_a_verack_and_only_their_verack_completes_it() {
         let (registered, queued) = a_registered_peer();
@@ -768,7 +3556,114 @@ mod tests {
         process_incoming_bytes(&registered, &mut recv_buffer, &framed(Verack)).unwrap();
 
         assert!(registered.is_ready());
-        assert!(queued.try_recv().is_err(), "a verack is not itself answered");
+        let reply = queued
+            .try_recv()
+            .expect("a ready peer is asked for headers");
+        assert!(
+            matches!(parse_all(&reply).as_slice(), [GetHeadersMessage(_)]),
+            "a verack is not itself answered, only followed by sync; got {:?}",
+            parse_all(&reply)
+        );
+    }
+
+    #[rstest]
+    #[case::dialled(Origin::Dialled)]
+    #[case::accepted(Origin::Accepted)]
+    fn a_version_carrying_our_own_nonce_ends_the_connection_and_the_address_is_not_dialled_again(
+        #[case] origin: Origin,
+    ) {
+        let node = a_node();
+        let (nonce, ours) = {
+            let locked = node.lock().unwrap();
+            (locked.nonce, locked.config.host_address)
+        };
+        let (registered, _queued) = a_peer_from(&node, origin);
+        node.lock()
+            .unwrap()
+            .addresses
+            .learn(registered.address, 1, 1);
+
+        let error = process_incoming_bytes(
+            &registered,
+            &mut Vec::new(),
+            &framed(Version::new(nonce, ours, 0)),
+        )
+        .expect_err("a connection to ourselves is no peer");
+
+        assert!(format!("{error:#}").contains("our nonce"), "got: {error:#}");
+        let mut locked = node.lock().unwrap();
+        assert!(!locked.addresses.learn(ours, 2, 2));
+        assert_eq!(
+            origin == Origin::Accepted,
+            locked.addresses.get(&registered.address).is_some(),
+            "only a dial says the address it went to is ours"
+        );
+    }
+
+    #[rstest]
+    #[case::ours_larger_keeps_our_dial(u64::MAX, Origin::Accepted)]
+    #[case::theirs_larger_keeps_their_dial(0, Origin::Dialled)]
+    fn of_two_connections_to_one_node_the_one_its_larger_nonce_dialled_is_kept(
+        #[case] ours: u64,
+        #[case] dropped: Origin,
+    ) {
+        let node = a_node();
+        node.lock().unwrap().nonce = ours;
+        let theirs = framed(Version::new(7, "127.0.0.1:5000".parse().unwrap(), 0));
+        let (first, _first) = a_peer_from(&node, dropped);
+        process_incoming_bytes(&first, &mut Vec::new(), &theirs).unwrap();
+        let kept_origin = match dropped {
+            Origin::Dialled => Origin::Accepted,
+            Origin::Accepted | Origin::Feeler => Origin::Dialled,
+        };
+        let (second, _second) = a_peer_from(&node, kept_origin);
+
+        process_incoming_bytes(&second, &mut Vec::new(), &theirs).unwrap();
+
+        let table = &node.lock().unwrap().peers;
+        assert_eq!(vec![second.id], table.ids());
+    }
+
+    #[test]
+    fn a_nonce_repeated_from_an_unrelated_address_does_not_displace_a_dialled_peer() {
+        let node = a_node();
+        node.lock().unwrap().nonce = 0;
+        let (dialled, _dialled) = a_peer_from(&node, Origin::Dialled);
+        let theirs = framed(Version::new(7, "127.0.0.1:5000".parse().unwrap(), 0));
+        process_incoming_bytes(&dialled, &mut Vec::new(), &theirs).unwrap();
+        let (outbound, _queued) = queue(OUTBOUND_BUDGET);
+        let impostor = Registered::open(
+            &node,
+            "127.0.0.2:40000".parse().unwrap(),
+            Origin::Accepted,
+            outbound,
+        )
+        .unwrap();
+
+        let copied = framed(Version::new(7, "127.0.0.2:6000".parse().unwrap(), 0));
+        process_incoming_bytes(&impostor, &mut Vec::new(), &copied).unwrap();
+
+        let mut ids = node.lock().unwrap().peers.ids();
+        ids.sort();
+        assert_eq!(vec![dialled.id, impostor.id], ids);
+    }
+
+    #[test]
+    fn a_second_connection_to_one_node_the_same_way_round_is_refused() {
+        let node = a_node();
+        let theirs = framed(Version::new(7, "127.0.0.1:5000".parse().unwrap(), 0));
+        let (first, _first) = a_peer_of(&node);
+        process_incoming_bytes(&first, &mut Vec::new(), &theirs).unwrap();
+        let (second, _second) = a_peer_of(&node);
+
+        let error = process_incoming_bytes(&second, &mut Vec::new(), &theirs)
+            .expect_err("the older connection stays");
+
+        assert!(
+            format!("{error:#}").contains("already connected"),
+            "got: {error:#}"
+        );
+        assert!(node.lock().unwrap().peers.handshake_of(first.id).is_some());
     }
 
     #[test]
@@ -776,24 +3671,32 @@ mod tests {
         let (registered, queued) = a_registered_peer();
 
         process_incoming_bytes(&registered, &mut Vec::new(), &framed(Verack))
-            .expect_err("a verack answers a version this peer never sent");
+            .expect("charged for, not fatal alone");
 
         assert!(!registered.is_ready());
         assert!(queued.try_recv().is_err(), "nothing is owed to a bad peer");
+        assert_eq!(
+            Some(Misbehavior::HandshakeViolation.weight()),
+            score_of(&registered)
+        );
     }
 
     #[test]
-    fn a_second_version_after_the_handshake_is_a_protocol_error() {
+    fn a_second_version_after_the_handshake_is_ignored_and_charged_for() {
         let (registered, queued) = a_registered_peer();
         identify(&registered);
         while queued.try_recv().is_ok() {}
 
         process_incoming_bytes(&registered, &mut Vec::new(), &framed_version())
-            .expect_err("a handshake happens once; a second version is not a second one");
+            .expect("charged for, not fatal alone");
 
         assert!(
             queued.try_recv().is_err(),
-            "a refused version must not be answered with another verack"
+            "a handshake happens once; a second version is not answered with another verack"
+        );
+        assert_eq!(
+            Some(Misbehavior::HandshakeViolation.weight()),
+            score_of(&registered)
         );
     }
 
@@ -820,41 +3723,52 @@ mod tests {
         let error = read_loop(SaysNothing(40), &registered, Duration::from_millis(50))
             .expect_err("a peer that never identifies itself must not hold a slot forever");
 
-        assert!(format!("{error:#}").contains("no handshake"), "got: {error:#}");
+        assert!(
+            format!("{error:#}").contains("no handshake"),
+            "got: {error:#}"
+        );
     }
 
     #[test]
     fn a_peer_that_talks_without_identifying_itself_still_loses_its_connection() {
-        /// Legal traffic, no handshake. Every read returns bytes, so the read
-        /// timeout never expires and only an absolute deadline ends this. It
-        /// runs out, so a node that lost the deadline fails rather than hangs.
+        /// Legal traffic, no handshake: one large message, a byte at a time.
+        /// Every read returns bytes, so the read timeout never expires and
+        /// only an absolute deadline ends this. It runs out, so a node that
+        /// lost the deadline fails rather than hangs.
         struct Chatters {
-            pong: Vec<u8>,
-            left: usize,
+            message: Vec<u8>,
+            at: usize,
         }
 
         impl Read for Chatters {
             fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
-                if self.left == 0 {
+                if self.at == self.message.len() {
                     return Ok(0);
                 }
-                self.left -= 1;
                 thread::sleep(Duration::from_millis(2));
-                buffer[..self.pong.len()].copy_from_slice(&self.pong);
-                Ok(self.pong.len())
+                buffer[0] = self.message[self.at];
+                self.at += 1;
+                Ok(1)
             }
         }
 
         let (registered, _queued) = a_registered_peer();
         let chatty = Chatters {
-            pong: framed(Pong::new(Ping::new()).unwrap()),
-            left: 100,
+            message: [
+                crate::messages::message::command_header_claiming("block", 1 << 19),
+                vec![0; 100],
+            ]
+            .concat(),
+            at: 0,
         };
 
         let error = read_loop(chatty, &registered, Duration::from_millis(50))
             .expect_err("the handshake deadline is absolute, not reset by every read");
 
-        assert!(format!("{error:#}").contains("no handshake"), "got: {error:#}");
+        assert!(
+            format!("{error:#}").contains("no handshake"),
+            "got: {error:#}"
+        );
     }
 
     #[test]
@@ -939,9 +3853,9 @@ mod tests {
         let watched = Arc::clone(&node);
 
         spawn_connection(accepted, node, Origin::Accepted);
-        peer.write_all(&framed_ping().0).unwrap();
+        peer.write_all(&framed_version()).unwrap();
 
-        for expected in ["is handling a connection from", "Ping received"] {
+        for expected in ["is handling a connection from", "speaks protocol"] {
             eventually(
                 || {
                     watched
@@ -961,8 +3875,8 @@ mod tests {
         let node = a_node();
         let mut held = Vec::new();
 
-        for index in 0..crate::node::MAX_PEERS {
-            let (outbound, queued) = mpsc::sync_channel(OUTBOUND_QUEUE);
+        for index in 0..crate::node::MAX_INBOUND {
+            let (outbound, queued) = queue(OUTBOUND_BUDGET);
             held.push(queued);
             let filler = format!("127.0.0.1:{}", 5000 + index).parse().unwrap();
             node.lock()
@@ -983,7 +3897,7 @@ mod tests {
             "a refused peer should be hung up on, not left connected in silence"
         );
         assert_eq!(
-            crate::node::MAX_PEERS,
+            crate::node::MAX_INBOUND,
             node.lock().unwrap().peers.len(),
             "a refused connection must not displace an established peer"
         );
@@ -29,7 +29,42 @@ pub struct TxOut {
     pub destiny_pub_key: String,
 }
 
+impl Outpoint {
+    /// Refers to no output at all: how a coinbase input says it spends nothing.
+    pub fn null() -> Self {
+        Outpoint {
+            tx_id: [0; 32],
+            v_out: u32::MAX,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.tx_id == [0; 32] && self.v_out == u32::MAX
+    }
+}
+
 impl Transaction {
+    /// A transaction minting `outputs` from nothing. `coinbase_data` rides in
+    /// the input's `signature` until ADR-0008's `coinbase_data` field exists;
+    /// it is covered by the txid either way, which is what keeps two
+    /// coinbases paying the same outputs from sharing one.
+    pub fn coinbase(coinbase_data: String, outputs: Vec<TxOut>) -> Self {
+        Transaction {
+            version: 1,
+            inputs: vec![TxIn {
+                previous_output: Outpoint::null(),
+                signature: coinbase_data,
+                sequence: u32::MAX,
+            }],
+            outputs,
+            lock_time: 0,
+        }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        matches!(self.inputs.as_slice(), [input] if input.previous_output.is_null())
+    }
+
     pub fn get_tx_id(&self) -> [u8; 32] {
         get_hash(self.get_raw_format().as_slice())
     }
@@ -61,13 +96,13 @@ impl Transaction {
     pub fn parse_raw(reader: &mut ByteReader) -> Result<Transaction> {
         let version = reader.read_u32()?;
         let input_count = reader.read_compact()?;
-        let mut inputs = Vec::with_capacity(input_count as usize);
+        let mut inputs = Vec::with_capacity(reader.capacity_for(input_count));
         for _ in 0..input_count {
             let tx_id = reader.read_array::<32>()?;
             let v_out = reader.read_u32()?;
             let signature_length = reader.read_compact()?;
 
-            let mut string_bytes = Vec::with_capacity(signature_length as usize);
+            let mut string_bytes = Vec::with_capacity(reader.capacity_for(signature_length));
             for _ in 0..signature_length {
                 string_bytes.push(reader.read_byte()?)
             }
@@ -84,12 +119,12 @@ impl Transaction {
         }
 
         let output_count = reader.read_compact()?;
-        let mut outputs = Vec::with_capacity(output_count as usize);
+        let mut outputs = Vec::with_capacity(reader.capacity_for(output_count));
         for _ in 0..output_count {
             let value = reader.read_u64()?;
             let pub_length = reader.read_compact()?;
 
-            let mut string_bytes = Vec::with_capacity(pub_length as usize);
+            let mut string_bytes = Vec::with_capacity(reader.capacity_for(pub_length));
             for _ in 0..pub_length {
                 string_bytes.push(reader.read_byte()?)
             }
@@ -118,6 +153,37 @@ impl Transaction {
 mod tests {
     use crate::transaction::{Outpoint, Transaction, TxIn, TxOut};
 
+    #[test]
+    fn a_coinbase_is_recognised_by_its_single_null_input() {
+        let coinbase = Transaction::coinbase("height 7".to_string(), Vec::new());
+
+        assert!(coinbase.is_coinbase());
+        assert!(coinbase.inputs[0].previous_output.is_null());
+    }
+
+    #[test]
+    fn spending_a_real_output_is_not_a_coinbase() {
+        let mut spend = Transaction::coinbase(String::new(), Vec::new());
+        spend.inputs[0].previous_output = Outpoint {
+            tx_id: [1; 32],
+            v_out: 0,
+        };
+
+        assert!(!spend.is_coinbase());
+    }
+
+    #[test]
+    fn coinbase_data_is_covered_by_the_txid() {
+        let first = Transaction::coinbase("height 1".to_string(), Vec::new());
+        let second = Transaction::coinbase("height 2".to_string(), Vec::new());
+
+        assert_ne!(
+            first.get_tx_id(),
+            second.get_tx_id(),
+            "two coinbases paying the same outputs must not share a txid"
+        );
+    }
+
     #[test]
     fn test_transaction_round_trip_conversion() {
         use crate::byte_reader::ByteReader;
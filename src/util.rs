@@ -1,10 +1,27 @@
 use anyhow::{anyhow, Context, Result};
 use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn get_hash(slice: &[u8]) -> [u8; 32] {
     Sha256::digest(Sha256::digest(slice)).into()
 }
 
+/// Hashes are little-endian internally and big-endian only on screen, so this
+/// is the one place the bytes are reversed for a human.
+pub fn display_hash(hash: &[u8; 32]) -> String {
+    let mut displayed = *hash;
+    displayed.reverse();
+    hex::encode(displayed)
+}
+
+/// Seconds since the epoch, as a header carries them. A clock set before 1970
+/// reads as zero rather than failing.
+pub fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as u32)
+}
+
 pub fn get_compact_int(number: u64) -> Vec<u8> {
     match number {
         ..=252 => (number as u8).to_le_bytes().to_vec(),
@@ -77,6 +94,18 @@ mod tests {
         assert_eq!(result.len(), 32);
     }
 
+    #[test]
+    fn a_displayed_hash_is_the_reverse_of_the_internal_one() {
+        let mut hash = [0u8; 32];
+        hash[0] = 0x6f;
+        hash[31] = 0x01;
+
+        let displayed = display_hash(&hash);
+
+        assert!(displayed.starts_with("01") && displayed.ends_with("6f"));
+        assert_eq!(64, displayed.len());
+    }
+
     #[test]
     fn get_hash_different_inputs_different_outputs() {
         let result1 = get_hash(b"hello");
@@ -0,0 +1,307 @@
+use crate::transaction::{Outpoint, Transaction, TxOut};
+use crate::util::display_hash;
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+
+/// An unspent output, with what spending it is checked against.
+#[derive(Clone, Debug)]
+pub struct Coin {
+    pub output: TxOut,
+    /// The height of the block that paid it.
+    pub height: u32,
+    pub is_coinbase: bool,
+}
+
+impl Coin {
+    /// Whether a block at `height` may spend it: a coinbase's outputs wait
+    /// `maturity` blocks first (ADR-0008), genesis's included.
+    pub fn is_mature_at(&self, height: u32, maturity: u32) -> bool {
+        !self.is_coinbase || height.saturating_sub(self.height) >= maturity
+    }
+}
+
+/// Every output of the connected chain not yet spent, by the outpoint that
+/// would spend it: what a transaction's inputs are checked against. In
+/// memory, and rebuilt from genesis each run, until ADR-0013's store.
+#[derive(Debug, Default)]
+pub struct UtxoSet {
+    coins: HashMap<([u8; 32], u32), Coin>,
+    /// The height of the last block applied.
+    height: u32,
+}
+
+impl UtxoSet {
+    pub fn get(&self, outpoint: &Outpoint) -> Option<&Coin> {
+        self.coins.get(&(outpoint.tx_id, outpoint.v_out))
+    }
+
+    /// What `transaction`'s inputs are worth, if every one is here to spend.
+    pub fn value_in(&self, transaction: &Transaction) -> Option<u64> {
+        transaction.inputs.iter().try_fold(0u64, |sum, input| {
+            Some(sum.saturating_add(self.get(&input.previous_output)?.output.value))
+        })
+    }
+
+    /// Whether every input of `transaction` here could be spent by the next
+    /// block, as far as `maturity` goes.
+    pub fn is_mature(&self, transaction: &Transaction, maturity: u32) -> bool {
+        transaction.inputs.iter().all(|input| {
+            self.get(&input.previous_output)
+                .is_none_or(|coin| coin.is_mature_at(self.height + 1, maturity))
+        })
+    }
+
+    /// Checks what a block's `transactions`, coinbase first, spend at
+    /// `height`: each input of the rest unspent, here or earlier in the
+    /// block, spent once, and past `maturity` if a coinbase paid it, and no
+    /// transaction paying out more than it spends. What they leave over, the
+    /// fees the coinbase may claim.
+    pub fn check_spends(
+        &self,
+        transactions: &[Transaction],
+        height: u32,
+        maturity: u32,
+    ) -> Result<u64> {
+        let mut spent = HashSet::new();
+        let mut fees = 0u64;
+        let mut created: HashMap<([u8; 32], u32), &TxOut> = HashMap::new();
+
+        for transaction in transactions.iter().skip(1) {
+            let txid = transaction.get_tx_id();
+            let mut value_in = 0u64;
+
+            for input in &transaction.inputs {
+                let spends = (input.previous_output.tx_id, input.previous_output.v_out);
+                if !spent.insert(spends) {
+                    return Err(anyhow!(
+                        "block spends {}:{} twice",
+                        display_hash(&spends.0),
+                        spends.1
+                    ));
+                }
+                let value = match (created.get(&spends), self.coins.get(&spends)) {
+                    (Some(output), _) => output.value,
+                    (None, Some(coin)) if coin.is_mature_at(height, maturity) => coin.output.value,
+                    (None, Some(coin)) => {
+                        return Err(anyhow!(
+                            "transaction {} spends coinbase output {}:{} from height {}, \
+                             not mature until {}",
+                            display_hash(&txid),
+                            display_hash(&spends.0),
+                            spends.1,
+                            coin.height,
+                            coin.height + maturity
+                        ))
+                    }
+                    (None, None) => {
+                        return Err(anyhow!(
+                            "transaction {} spends {}:{}, which is not there to spend",
+                            display_hash(&txid),
+                            display_hash(&spends.0),
+                            spends.1
+                        ))
+                    }
+                };
+                value_in = value_in.saturating_add(value);
+            }
+            fees = fees.saturating_add(check_value(transaction, value_in)?);
+
+            for (v_out, output) in transaction.outputs.iter().enumerate() {
+                created.insert((txid, v_out as u32), output);
+            }
+        }
+
+        Ok(fees)
+    }
+
+    /// Spends what the block at `height`'s `transactions` spend and adds
+    /// what they pay, in order. Only for transactions `check_spends` passed.
+    pub fn apply(&mut self, transactions: &[Transaction], height: u32) {
+        for transaction in transactions {
+            let is_coinbase = transaction.is_coinbase();
+            if !is_coinbase {
+                for input in &transaction.inputs {
+                    self.coins
+                        .remove(&(input.previous_output.tx_id, input.previous_output.v_out));
+                }
+            }
+
+            let txid = transaction.get_tx_id();
+            for (v_out, output) in transaction.outputs.iter().enumerate() {
+                let coin = Coin {
+                    output: output.clone(),
+                    height,
+                    is_coinbase,
+                };
+                self.coins.insert((txid, v_out as u32), coin);
+            }
+        }
+        self.height = height;
+    }
+}
+
+/// Refuses `transaction` if it pays out more than its inputs, worth
+/// `value_in`, hold. Whatever it leaves over is its fee, returned.
+pub fn check_value(transaction: &Transaction, value_in: u64) -> Result<u64> {
+    let value_out = transaction
+        .outputs
+        .iter()
+        .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+        .ok_or_else(|| anyhow!("a transaction's outputs overflow"))?;
+
+    if value_out > value_in {
+        return Err(anyhow!(
+            "transaction {} pays out {value_out}, more than the {value_in} it spends",
+            display_hash(&transaction.get_tx_id())
+        ));
+    }
+    Ok(value_in - value_out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TxIn;
+
+    fn paying(values: &[u64]) -> Vec<TxOut> {
+        values
+            .iter()
+            .map(|value| TxOut {
+                value: *value,
+                destiny_pub_key: "someone".to_string(),
+            })
+            .collect()
+    }
+
+    fn spending(spends: &[([u8; 32], u32)], values: &[u64]) -> Transaction {
+        Transaction {
+            version: 1,
+            inputs: spends
+                .iter()
+                .map(|(tx_id, v_out)| TxIn {
+                    previous_output: Outpoint {
+                        tx_id: *tx_id,
+                        v_out: *v_out,
+                    },
+                    signature: "signed".to_string(),
+                    sequence: u32::MAX,
+                })
+                .collect(),
+            outputs: paying(values),
+            lock_time: 0,
+        }
+    }
+
+    /// A set holding a coinbase's two outputs of 10, and that coinbase.
+    fn funded() -> (UtxoSet, Transaction) {
+        let coinbase = Transaction::coinbase("height 0".to_string(), paying(&[10, 10]));
+        let mut utxos = UtxoSet::default();
+        utxos.apply(std::slice::from_ref(&coinbase), 0);
+        (utxos, coinbase)
+    }
+
+    fn a_coinbase() -> Transaction {
+        Transaction::coinbase("height 1".to_string(), paying(&[50]))
+    }
+
+    #[test]
+    fn an_applied_block_spends_its_inputs_and_adds_its_outputs() {
+        let (mut utxos, funding) = funded();
+        let funding_id = funding.get_tx_id();
+        let spend = spending(&[(funding_id, 0)], &[4, 5]);
+
+        utxos.apply(&[a_coinbase(), spend.clone()], 1);
+
+        let spent = Outpoint {
+            tx_id: funding_id,
+            v_out: 0,
+        };
+        assert!(utxos.get(&spent).is_none());
+        assert_eq!(Some(10), utxos.value_in(&spending(&[(funding_id, 1)], &[])));
+        assert_eq!(
+            Some(9),
+            utxos.value_in(&spending(
+                &[(spend.get_tx_id(), 0), (spend.get_tx_id(), 1)],
+                &[]
+            ))
+        );
+    }
+
+    #[test]
+    fn an_input_that_is_not_there_has_no_value() {
+        let (utxos, funding) = funded();
+
+        assert_eq!(
+            None,
+            utxos.value_in(&spending(&[(funding.get_tx_id(), 2)], &[1]))
+        );
+    }
+
+    #[test]
+    fn a_block_may_spend_what_it_created_earlier_but_nothing_twice() {
+        let (utxos, funding) = funded();
+        let first = spending(&[(funding.get_tx_id(), 0)], &[10]);
+        let second = spending(&[(first.get_tx_id(), 0)], &[10]);
+
+        utxos
+            .check_spends(&[a_coinbase(), first.clone(), second], 1, 1)
+            .unwrap();
+
+        let again = spending(&[(funding.get_tx_id(), 0)], &[3]);
+        let error = utxos
+            .check_spends(&[a_coinbase(), first, again], 1, 1)
+            .expect_err("spent twice");
+        assert!(format!("{error:#}").contains("twice"), "got: {error:#}");
+    }
+
+    #[test]
+    fn what_a_blocks_transactions_leave_unspent_is_their_fees() {
+        let (utxos, funding) = funded();
+        let first = spending(&[(funding.get_tx_id(), 0)], &[7]);
+        let second = spending(&[(first.get_tx_id(), 0), (funding.get_tx_id(), 1)], &[15]);
+
+        let fees = utxos
+            .check_spends(&[a_coinbase(), first, second], 1, 1)
+            .unwrap();
+
+        assert_eq!(3 + 2, fees);
+    }
+
+    #[test]
+    fn paying_out_more_than_is_spent_is_refused() {
+        let (utxos, funding) = funded();
+        let greedy = spending(&[(funding.get_tx_id(), 0)], &[6, 5]);
+
+        let error = utxos
+            .check_spends(&[a_coinbase(), greedy], 1, 1)
+            .expect_err("11 out of 10");
+
+        assert!(format!("{error:#}").contains("more than"), "got: {error:#}");
+    }
+
+    #[test]
+    fn a_coinbase_output_is_spendable_only_once_mature() {
+        let (utxos, funding) = funded();
+        let spend = spending(&[(funding.get_tx_id(), 0)], &[10]);
+
+        let error = utxos
+            .check_spends(&[a_coinbase(), spend.clone()], 2, 3)
+            .expect_err("paid at 0, mature at 3");
+        assert!(format!("{error:#}").contains("mature"), "got: {error:#}");
+        assert!(!utxos.is_mature(&spend, 2), "the next block is at height 1");
+
+        utxos
+            .check_spends(&[a_coinbase(), spend.clone()], 3, 3)
+            .unwrap();
+        assert!(utxos.is_mature(&spend, 1));
+    }
+
+    #[test]
+    fn what_an_ordinary_transaction_pays_is_mature_at_once() {
+        let (mut utxos, funding) = funded();
+        let spend = spending(&[(funding.get_tx_id(), 0)], &[10]);
+        utxos.apply(&[a_coinbase(), spend.clone()], 1);
+
+        assert!(utxos.is_mature(&spending(&[(spend.get_tx_id(), 0)], &[10]), 100));
+    }
+}
@@ -8,34 +8,103 @@ file and `src/messages/` ever disagree, that is the suite working.
 
 import ipaddress
 import struct
+import time
 from dataclasses import dataclass
 from hashlib import sha256
 from typing import Optional, Tuple
 
-MAGIC = bytes([0xF9, 0xBE, 0xB4, 0xD9])
+# One per network (ADR-0011), so a node on one can recognise a frame meant for
+# another. Mainnet is the default because it is the node's.
+MAGICS = {"main": b"AVI1", "test": b"AVIT", "regtest": b"AVIR"}
+MAGIC = MAGICS["main"]
 HEADER_LENGTH = 24
 COMMAND_LENGTH = 12
-MAX_PAYLOAD_SIZE = 32 * 1024 * 1024
-PROTOCOL_VERSION = 1
+PROTOCOL_VERSION = 4
+MIN_PROTOCOL_VERSION = 3
+# The first version whose blocks are announced as `cmpctblock`.
+COMPACT_BLOCKS_VERSION = 4
+SERVICE_FULL_NODE = 1 << 0
+SERVICE_SERVES_BLOCKS = 1 << 1
+MAX_USER_AGENT_LENGTH = 256
+
+# What params/regtest.toml allocates in genesis: 1000 AVI to each of the keys
+# whose private keys are 1, 2 and 3.
+REGTEST_ALLOCATION = [
+    (100_000_000_000, "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798"),
+    (100_000_000_000, "02C6047F9441ED7D6D3045406E95C07CD85C778E4B8CEF3CA7ABAC09B95C709EE5"),
+    (100_000_000_000, "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9"),
+]
+
+BLOCK_HEADER_LENGTH = 80
+MAX_HEADERS = 2000
+MAX_LOCATOR_LENGTH = 101
+MAX_INVENTORY = 50_000
+MAX_ADDR = 1_000
+MAX_BLOCK_SIZE = 1_000_000
+# More than a block under MAX_BLOCK_SIZE can hold: its smallest transaction
+# is 10 bytes.
+MAX_BLOCK_TRANSACTIONS = MAX_BLOCK_SIZE // 10
+SHORT_ID_LENGTH = 6
+INVENTORY_TX = 1
+INVENTORY_BLOCK = 2
 
 # What each command's payload must weigh. A node that sends a different number
 # of bytes under one of these names has broken the format, so this is an
-# assertion about the node rather than a lookup table.
-PAYLOAD_SIZES = {"ping": 8, "pong": 8, "version": 30, "verack": 0}
+# assertion about the node rather than a lookup table. None is a variable size,
+# whose shape `parse` checks by decoding it instead.
+PAYLOAD_SIZES = {
+    "ping": 8,
+    "pong": 8,
+    "version": None,
+    "verack": 0,
+    "getheaders": None,
+    "headers": None,
+    "inv": None,
+    "getdata": None,
+    "notfound": None,
+    "tx": None,
+    "block": None,
+    "getaddr": 0,
+    "addr": None,
+    "cmpctblock": None,
+    "getblocktxn": None,
+    "blocktxn": None,
+}
+
+# The most each command's payload may weigh, checked on its header alone. A
+# compact-size count is at most 9 bytes; an inventory entry 36, an addr entry 22.
+MAX_PAYLOAD_SIZES = {
+    "ping": 8,
+    "pong": 8,
+    "version": 4 + 8 + 4 + 8 + 18 + 3 + MAX_USER_AGENT_LENGTH + 4,
+    "verack": 0,
+    "getheaders": 4 + 9 + 32 * MAX_LOCATOR_LENGTH + 32,
+    "headers": 9 + BLOCK_HEADER_LENGTH * MAX_HEADERS,
+    "inv": 9 + 36 * MAX_INVENTORY,
+    "getdata": 9 + 36 * MAX_INVENTORY,
+    "notfound": 9 + 36 * MAX_INVENTORY,
+    "tx": MAX_BLOCK_SIZE,
+    "block": MAX_BLOCK_SIZE,
+    "getaddr": 0,
+    "addr": 9 + 22 * MAX_ADDR,
+    "cmpctblock": MAX_BLOCK_SIZE,
+    "getblocktxn": 32 + 9 + 5 * MAX_BLOCK_TRANSACTIONS,
+    "blocktxn": MAX_BLOCK_SIZE,
+}
 
 
 def hash256(payload: bytes) -> bytes:
     return sha256(sha256(payload).digest()).digest()
 
 
-def frame(command: str, payload: bytes) -> bytes:
+def frame(command: str, payload: bytes, magic: bytes = MAGIC) -> bytes:
     name = command.encode("ascii")
     if len(name) > COMMAND_LENGTH:
         raise ValueError(f"command {command!r} exceeds {COMMAND_LENGTH} bytes")
 
     return b"".join(
         [
-            MAGIC,
+            magic,
             name.ljust(COMMAND_LENGTH, b"\0"),
             struct.pack("<I", len(payload)),
             hash256(payload)[:4],
@@ -53,16 +122,206 @@ def pong(nonce: int) -> bytes:
 
 
 def version(
-    nonce: int, listen_address: str, protocol_version: int = PROTOCOL_VERSION
+    nonce: int,
+    listen_address: str,
+    protocol_version: int = PROTOCOL_VERSION,
+    magic: bytes = MAGIC,
+    services: int = SERVICE_FULL_NODE | SERVICE_SERVES_BLOCKS,
+    timestamp: Optional[int] = None,
+    user_agent: str = "/functional-test:0/",
+    best_height: int = 0,
 ) -> bytes:
+    if timestamp is None:
+        timestamp = int(time.time())
+    agent = user_agent.encode("utf-8")
+
     return frame(
         "version",
-        struct.pack("<IQ", protocol_version, nonce) + pack_address(listen_address),
+        struct.pack("<IQIQ", protocol_version, services, timestamp, nonce)
+        + pack_address(listen_address)
+        + compact_size(len(agent))
+        + agent
+        + struct.pack("<I", best_height),
+        magic,
+    )
+
+
+def verack(magic: bytes = MAGIC) -> bytes:
+    return frame("verack", b"", magic)
+
+
+def getheaders(locator: list, stop: bytes = bytes(32), magic: bytes = MAGIC) -> bytes:
+    return frame(
+        "getheaders",
+        struct.pack("<I", PROTOCOL_VERSION)
+        + compact_size(len(locator))
+        + b"".join(locator)
+        + stop,
+        magic,
+    )
+
+
+def headers(raw_headers: list, magic: bytes = MAGIC) -> bytes:
+    return frame(
+        "headers", compact_size(len(raw_headers)) + b"".join(raw_headers), magic
+    )
+
+
+def inventory_payload(inventory: list) -> bytes:
+    """`inventory` is (type, hash) pairs."""
+    return compact_size(len(inventory)) + b"".join(
+        struct.pack("<I", kind) + hash for kind, hash in inventory
+    )
+
+
+def inv(inventory: list, magic: bytes = MAGIC) -> bytes:
+    return frame("inv", inventory_payload(inventory), magic)
+
+
+def getdata(inventory: list, magic: bytes = MAGIC) -> bytes:
+    return frame("getdata", inventory_payload(inventory), magic)
+
+
+def getaddr(magic: bytes = MAGIC) -> bytes:
+    return frame("getaddr", b"", magic)
+
+
+def addr(addresses: list, magic: bytes = MAGIC) -> bytes:
+    """`addresses` is (last seen, "host:port") pairs."""
+    return frame(
+        "addr",
+        compact_size(len(addresses))
+        + b"".join(
+            struct.pack("<I", last_seen) + pack_address(address)
+            for last_seen, address in addresses
+        ),
+        magic,
     )
 
 
-def verack() -> bytes:
-    return frame("verack", b"")
+def raw_transaction(spends: list, value: int, to: str) -> bytes:
+    """Version 1, spending each (txid, v_out) in `spends`, paying `value` to `to`.
+    Nothing checks a signature yet, so each input carries a placeholder."""
+    inputs = [(txid, v_out, b"signed") for txid, v_out in spends]
+    return _raw_transaction(inputs, [(value, to)])
+
+
+def raw_coinbase(coinbase_data: str, outputs: list) -> bytes:
+    """A coinbase carrying `coinbase_data` where a spend signs, paying each
+    (value, to) of `outputs`."""
+    return _raw_transaction([(bytes(32), 0xFFFFFFFF, coinbase_data.encode())], outputs)
+
+
+def _raw_transaction(inputs: list, outputs: list) -> bytes:
+    raw = struct.pack("<I", 1) + compact_size(len(inputs))
+    for txid, v_out, signature in inputs:
+        raw += txid + struct.pack("<I", v_out)
+        raw += compact_size(len(signature)) + signature + struct.pack("<I", 0xFFFFFFFF)
+    raw += compact_size(len(outputs))
+    for value, to in outputs:
+        raw += struct.pack("<Q", value) + compact_size(len(to)) + to.encode()
+    return raw + struct.pack("<I", 0)
+
+
+def regtest_allocation(v_out: int) -> Tuple[bytes, int]:
+    """(txid, v_out) of one of the three outputs regtest's genesis allocates:
+    the only coins a test can spend without mining any."""
+    genesis = raw_coinbase("Avi Coin regtest genesis", REGTEST_ALLOCATION)
+    return hash256(genesis), v_out
+
+
+def tx(raw: bytes, magic: bytes = MAGIC) -> bytes:
+    return frame("tx", raw, magic)
+
+
+def merkle_root(txids: list) -> bytes:
+    level = list(txids)
+    while len(level) > 1:
+        if len(level) % 2:
+            level.append(level[-1])
+        level = [hash256(level[i] + level[i + 1]) for i in range(0, len(level), 2)]
+    return level[0]
+
+
+def mined_header(previous: bytes, raw_transactions: list, n_bits: int) -> bytes:
+    """A version 1 header over `raw_transactions`, timed now, searched for a
+    nonce meeting `n_bits`. Only regtest's target is easy enough to search
+    here."""
+    exponent, mantissa = n_bits >> 24, n_bits & 0x7FFFFF
+    target = mantissa << (8 * (exponent - 3))
+    root = merkle_root([hash256(raw) for raw in raw_transactions])
+    prefix = struct.pack("<i", 1) + previous + root + struct.pack("<II", int(time.time()), n_bits)
+
+    for nonce in range(2**32):
+        raw_header = prefix + struct.pack("<I", nonce)
+        if int.from_bytes(header_hash(raw_header), "little") <= target:
+            return raw_header
+    raise AssertionError("no nonce meets the target")
+
+
+def short_id(block_hash: bytes, nonce: int, txid: bytes) -> bytes:
+    return hash256(block_hash + struct.pack("<Q", nonce) + txid)[:SHORT_ID_LENGTH]
+
+
+def cmpctblock(
+    raw_header: bytes, nonce: int, raw_transactions: list, magic: bytes = MAGIC
+) -> bytes:
+    """The coinbase, first of `raw_transactions`, sent whole; the rest by short id."""
+    block_hash = header_hash(raw_header)
+    coinbase, rest = raw_transactions[0], raw_transactions[1:]
+    return frame(
+        "cmpctblock",
+        raw_header
+        + struct.pack("<Q", nonce)
+        + compact_size(len(rest))
+        + b"".join(short_id(block_hash, nonce, hash256(raw)) for raw in rest)
+        + compact_size(1)
+        + compact_size(0)
+        + coinbase,
+        magic,
+    )
+
+
+def getblocktxn(block_hash: bytes, indexes: list, magic: bytes = MAGIC) -> bytes:
+    return frame(
+        "getblocktxn",
+        block_hash
+        + compact_size(len(indexes))
+        + b"".join(compact_size(index) for index in indexes),
+        magic,
+    )
+
+
+def blocktxn(block_hash: bytes, raw_transactions: list, magic: bytes = MAGIC) -> bytes:
+    return frame(
+        "blocktxn",
+        block_hash + compact_size(len(raw_transactions)) + b"".join(raw_transactions),
+        magic,
+    )
+
+
+def compact_size(number: int) -> bytes:
+    if number < 0xFD:
+        return bytes([number])
+    if number <= 0xFFFF:
+        return b"\xfd" + struct.pack("<H", number)
+    if number <= 0xFFFFFFFF:
+        return b"\xfe" + struct.pack("<I", number)
+    return b"\xff" + struct.pack("<Q", number)
+
+
+def read_compact_size(buffer: bytes) -> Tuple[int, int]:
+    """(value, bytes it took)."""
+    prefix = buffer[0]
+    if prefix < 0xFD:
+        return prefix, 1
+    width = {0xFD: 2, 0xFE: 4, 0xFF: 8}[prefix]
+    return int.from_bytes(buffer[1 : 1 + width], "little"), 1 + width
+
+
+def header_hash(raw_header: bytes) -> bytes:
+    """Internal byte order, as headers and locators carry it."""
+    return hash256(raw_header)
 
 
 def pack_address(address: str) -> bytes:
@@ -87,8 +346,12 @@ def unpack_address(packed: bytes) -> str:
 @dataclass(frozen=True)
 class Version:
     protocol_version: int
+    services: int
+    timestamp: int
     nonce: int
     listen_address: str
+    user_agent: str
+    best_height: int
 
 
 @dataclass(frozen=True)
@@ -103,16 +366,130 @@ class Frame:
 
     def as_version(self) -> Version:
         assert self.command == "version", f"a {self.command} is not a version"
-        protocol_version, nonce = struct.unpack("<IQ", self.payload[:12])
+        protocol_version, services, timestamp, nonce = struct.unpack(
+            "<IQIQ", self.payload[:24]
+        )
+        length, taken = read_compact_size(self.payload[42:])
+        agent = self.payload[42 + taken : 42 + taken + length]
 
         return Version(
             protocol_version=protocol_version,
+            services=services,
+            timestamp=timestamp,
             nonce=nonce,
-            listen_address=unpack_address(self.payload[12:]),
+            listen_address=unpack_address(self.payload[24:42]),
+            user_agent=agent.decode("utf-8"),
+            best_height=struct.unpack("<I", self.payload[-4:])[0],
         )
 
+    def as_getheaders(self) -> Tuple[list, bytes]:
+        """(locator, stop hash)."""
+        assert self.command == "getheaders", f"a {self.command} is not a getheaders"
+        count, taken = read_compact_size(self.payload[4:])
+        start = 4 + taken
+        locator = [
+            self.payload[start + 32 * i : start + 32 * (i + 1)] for i in range(count)
+        ]
+
+        return locator, self.payload[start + 32 * count :]
+
+    def as_inventory(self) -> list:
+        """(type, hash) pairs, from an inv, a getdata or a notfound."""
+        assert self.command in (
+            "inv",
+            "getdata",
+            "notfound",
+        ), f"a {self.command} carries no inventory"
+        count, taken = read_compact_size(self.payload)
+        entries = self.payload[taken:]
+
+        return [
+            (
+                struct.unpack("<I", entries[36 * i : 36 * i + 4])[0],
+                entries[36 * i + 4 : 36 * (i + 1)],
+            )
+            for i in range(count)
+        ]
+
+    def as_addresses(self) -> list:
+        """(last seen, "host:port") pairs, from an addr."""
+        assert self.command == "addr", f"a {self.command} carries no addresses"
+        count, taken = read_compact_size(self.payload)
+        entries = self.payload[taken:]
+
+        return [
+            (
+                struct.unpack("<I", entries[22 * i : 22 * i + 4])[0],
+                unpack_address(entries[22 * i + 4 : 22 * (i + 1)]),
+            )
+            for i in range(count)
+        ]
+
+    def as_compact_block(self) -> Tuple[bytes, int, list, list]:
+        """(raw header, nonce, short ids, (index, raw transaction) pairs)."""
+        assert self.command == "cmpctblock", f"a {self.command} is not a cmpctblock"
+        raw_header = self.payload[:BLOCK_HEADER_LENGTH]
+        (nonce,) = struct.unpack("<Q", self.payload[80:88])
+        count, taken = read_compact_size(self.payload[88:])
+        at = 88 + taken
+        short_ids = [
+            self.payload[at + SHORT_ID_LENGTH * i : at + SHORT_ID_LENGTH * (i + 1)]
+            for i in range(count)
+        ]
+        at += SHORT_ID_LENGTH * count
+
+        prefilled = []
+        count, taken = read_compact_size(self.payload[at:])
+        at += taken
+        for _ in range(count):
+            index, taken = read_compact_size(self.payload[at:])
+            at += taken
+            length = transaction_size(self.payload[at:])
+            prefilled.append((index, self.payload[at : at + length]))
+            at += length
+
+        return raw_header, nonce, short_ids, prefilled
+
+    def as_getblocktxn(self) -> Tuple[bytes, list]:
+        """(block hash, indexes)."""
+        assert self.command == "getblocktxn", f"a {self.command} is not a getblocktxn"
+        count, at = read_compact_size(self.payload[32:])
+        at += 32
+        indexes = []
+        for _ in range(count):
+            index, taken = read_compact_size(self.payload[at:])
+            indexes.append(index)
+            at += taken
+
+        return self.payload[:32], indexes
+
+    def as_blocktxn(self) -> Tuple[bytes, list]:
+        """(block hash, raw transactions)."""
+        assert self.command == "blocktxn", f"a {self.command} is not a blocktxn"
+        count, at = read_compact_size(self.payload[32:])
+        at += 32
+        transactions = []
+        for _ in range(count):
+            length = transaction_size(self.payload[at:])
+            transactions.append(self.payload[at : at + length])
+            at += length
+
+        return self.payload[:32], transactions
+
+    def as_headers(self) -> list:
+        """The raw 80-byte headers, oldest first."""
+        assert self.command == "headers", f"a {self.command} is not a headers"
+        count, taken = read_compact_size(self.payload)
+
+        body = self.payload[taken:]
+
+        return [
+            body[BLOCK_HEADER_LENGTH * i : BLOCK_HEADER_LENGTH * (i + 1)]
+            for i in range(count)
+        ]
+
 
-def parse(buffer: bytes) -> Tuple[Optional[Frame], int]:
+def parse(buffer: bytes, magic: bytes = MAGIC) -> Tuple[Optional[Frame], int]:
     """Returns (frame, bytes consumed), or (None, 0) if more bytes are needed.
 
     Every check here is an assertion about the node, not defensive coding: a
@@ -122,10 +499,13 @@ def parse(buffer: bytes) -> Tuple[Optional[Frame], int]:
     if len(buffer) < HEADER_LENGTH:
         return None, 0
 
-    assert buffer[:4] == MAGIC, f"frame is not on our network: {buffer[:4].hex()}"
+    assert buffer[:4] == magic, f"frame is not on our network: {buffer[:4].hex()}"
+
+    command = buffer[4 : 4 + COMMAND_LENGTH].rstrip(b"\0").decode("ascii")
+    assert command in PAYLOAD_SIZES, f"node emitted an unknown command {command!r}"
 
     size = struct.unpack("<I", buffer[16:20])[0]
-    assert size <= MAX_PAYLOAD_SIZE, f"node emitted a {size}-byte payload"
+    assert size <= MAX_PAYLOAD_SIZES[command], f"node emitted a {size}-byte {command}"
 
     if len(buffer) < HEADER_LENGTH + size:
         return None, 0
@@ -135,16 +515,100 @@ def parse(buffer: bytes) -> Tuple[Optional[Frame], int]:
         hash256(payload)[:4] == buffer[20:24]
     ), "checksum does not cover the payload it was sent with"
 
-    command = buffer[4 : 4 + COMMAND_LENGTH].rstrip(b"\0").decode("ascii")
-    assert command in PAYLOAD_SIZES, f"node emitted an unknown command {command!r}"
-    assert len(payload) == PAYLOAD_SIZES[command], (
-        f"a {command} should carry {PAYLOAD_SIZES[command]} bytes, "
-        f"got {len(payload)}"
+    expected = PAYLOAD_SIZES[command]
+    if expected is None:
+        expected = shaped_size(command, payload)
+    assert len(payload) == expected, (
+        f"a {command} should carry {expected} bytes, got {len(payload)}"
     )
 
     return Frame(command=command, payload=payload), HEADER_LENGTH + size
 
 
+def shaped_size(command: str, payload: bytes) -> int:
+    """What a variable-size payload must weigh, going by the count it leads with."""
+    if command == "version":
+        length, taken = read_compact_size(payload[42:])
+        assert length <= MAX_USER_AGENT_LENGTH, f"node sent a {length}-byte user agent"
+        return 42 + taken + length + 4
+
+    if command == "getheaders":
+        count, taken = read_compact_size(payload[4:])
+        assert count <= MAX_LOCATOR_LENGTH, f"node sent a {count}-hash locator"
+        return 4 + taken + 32 * count + 32
+
+    if command in ("inv", "getdata", "notfound"):
+        count, taken = read_compact_size(payload)
+        assert count <= MAX_INVENTORY, f"node sent {count} inventory entries"
+        return taken + 36 * count
+
+    if command == "addr":
+        count, taken = read_compact_size(payload)
+        assert count <= MAX_ADDR, f"node sent {count} addresses in one addr"
+        return taken + 22 * count
+
+    if command == "tx":
+        return transaction_size(payload)
+
+    if command == "cmpctblock":
+        short_ids, taken = read_compact_size(payload[88:])
+        at = 88 + taken + SHORT_ID_LENGTH * short_ids
+        prefilled, taken = read_compact_size(payload[at:])
+        at += taken
+        for _ in range(prefilled):
+            _, taken = read_compact_size(payload[at:])
+            at += taken
+            at += transaction_size(payload[at:])
+        return at
+
+    if command == "getblocktxn":
+        count, at = read_compact_size(payload[32:])
+        at += 32
+        for _ in range(count):
+            _, taken = read_compact_size(payload[at:])
+            at += taken
+        return at
+
+    if command == "blocktxn":
+        return 32 + transactions_size(payload[32:])
+
+    if command == "block":
+        return BLOCK_HEADER_LENGTH + transactions_size(payload[BLOCK_HEADER_LENGTH:])
+
+    count, taken = read_compact_size(payload)
+    assert count <= MAX_HEADERS, f"node sent {count} headers in one message"
+    return taken + BLOCK_HEADER_LENGTH * count
+
+
+def transaction_size(buffer: bytes) -> int:
+    """How many bytes the transaction `buffer` starts with takes."""
+    # One transaction is a list of them without the count.
+    return transactions_size(compact_size(1) + buffer) - 1
+
+
+def transactions_size(buffer: bytes) -> int:
+    """How many bytes a count of transactions and the transactions take."""
+    count, at = read_compact_size(buffer)
+
+    for _ in range(count):
+        at += 4  # version
+        inputs, taken = read_compact_size(buffer[at:])
+        at += taken
+        for _ in range(inputs):
+            at += 36  # outpoint
+            signature, taken = read_compact_size(buffer[at:])
+            at += taken + signature + 4  # and the sequence
+        outputs, taken = read_compact_size(buffer[at:])
+        at += taken
+        for _ in range(outputs):
+            at += 8  # value
+            key, taken = read_compact_size(buffer[at:])
+            at += taken + key
+        at += 4  # lock_time
+
+    return at
+
+
 def parse_all(buffer: bytes) -> list:
     frames = []
     rest = buffer
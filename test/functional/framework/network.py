@@ -4,6 +4,7 @@ import socket
 from typing import List, Optional
 
 from .node import Node, Sandbox
+from .messages import MAGIC
 from .p2p import Peer, address_of, free_port
 
 
@@ -14,21 +15,31 @@ class Network:
         self._peers: List[Peer] = []
 
     def node(self, *args: str, config: Optional[str] = None) -> Node:
-        started = Node(*args, sandbox=Sandbox(config))
+        return self.node_in(Sandbox(config), *args)
+
+    def restart(self, stopped: Node, *args: str) -> Node:
+        """A new process in `stopped`'s sandbox, so it finds what the old one
+        left in its data directory."""
+        stopped.halt()
+        self._nodes.remove(stopped)
+        return self.node_in(stopped.sandbox, *args)
+
+    def node_in(self, sandbox: Sandbox, *args: str) -> Node:
+        started = Node(*args, sandbox=sandbox)
         self._nodes.append(started)
         return started
 
-    def listener(self) -> socket.socket:
+    def listener(self, port: int = 0) -> socket.socket:
         """A socket a node can be pointed at, so we see it dial out."""
-        listening = free_port()
+        listening = free_port(port)
         self._listeners.append(listening)
         return listening
 
     def address(self) -> str:
         return address_of(self.listener())
 
-    def dial(self, address: str) -> Peer:
-        peer = Peer.dial(address)
+    def dial(self, address: str, magic: bytes = MAGIC) -> Peer:
+        peer = Peer.dial(address, magic)
         self._peers.append(peer)
         return peer
 
@@ -5,6 +5,7 @@ rebuilt and used.
 """
 
 import os
+import re
 import shutil
 import subprocess
 import tempfile
@@ -69,6 +70,7 @@ class Node:
         self.process = subprocess.Popen(
             [str(binary_path()), *args],
             cwd=self.sandbox.path,
+            stdin=subprocess.PIPE,
             stdout=subprocess.PIPE,
             stderr=subprocess.STDOUT,
             text=True,
@@ -105,10 +107,34 @@ class Node:
             f"the node said:\n" + "\n".join(self.said())
         )
 
+    def line_matching(self, pattern: str, patience: float = PATIENCE) -> str:
+        """`line_containing`, for a line whose parts are separated by a hash
+        or a count the test cannot know in advance."""
+        deadline = time.monotonic() + patience
+
+        while time.monotonic() < deadline:
+            for line in self.said():
+                if re.search(pattern, line):
+                    return line
+            if self.process.poll() is not None:
+                break
+            time.sleep(0.02)
+
+        raise AssertionError(
+            f"nothing matching {pattern!r} within {patience}s; "
+            f"the node said:\n" + "\n".join(self.said())
+        )
+
+    def tell(self, command: str) -> None:
+        """One line on the node's console. Answers arrive in its output."""
+        self.process.stdin.write(command + "\n")
+        self.process.stdin.flush()
+
     def listening_on(self) -> str:
         return self.line_containing("Listening on").rsplit(" ", 1)[1]
 
-    def stop(self) -> None:
+    def halt(self) -> None:
+        """End the process but keep its sandbox, for a node to restart in."""
         if self.process.poll() is None:
             self.process.terminate()
             try:
@@ -116,6 +142,9 @@ class Node:
             except subprocess.TimeoutExpired:
                 self.process.kill()
                 self.process.wait(timeout=5)
+
+    def stop(self) -> None:
+        self.halt()
         self.sandbox.cleanup()
 
 
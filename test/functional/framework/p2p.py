@@ -4,11 +4,12 @@ Every wait here is bounded. A hanging test is worse than a failing one, because
 it takes the suite with it rather than reporting one red case.
 """
 
+import random
 import socket
 import time
 from typing import List, Optional
 
-from .messages import Frame, parse, verack, version
+from .messages import MAGIC, PROTOCOL_VERSION, Frame, parse, verack, version
 
 # How long to wait for something that should happen. Every operation it guards
 # -- a process exec, a loopback connect, a ping already queued -- is sub-second
@@ -22,20 +23,23 @@ IMPATIENCE = 3.0
 
 
 class Peer:
-    def __init__(self, sock: socket.socket):
+    def __init__(self, sock: socket.socket, magic: bytes = MAGIC):
         self.socket = sock
+        self.magic = magic
         self.socket.settimeout(PATIENCE)
         self.buffer = b""
 
     @classmethod
-    def dial(cls, address: str) -> "Peer":
-        return cls(socket.create_connection(split_address(address), timeout=PATIENCE))
+    def dial(cls, address: str, magic: bytes = MAGIC) -> "Peer":
+        return cls(
+            socket.create_connection(split_address(address), timeout=PATIENCE), magic
+        )
 
     def send(self, payload: bytes) -> None:
         self.socket.sendall(payload)
 
     def _take_frame(self) -> Optional[Frame]:
-        parsed, consumed = parse(self.buffer)
+        parsed, consumed = parse(self.buffer, self.magic)
         if parsed is None:
             return None
         self.buffer = self.buffer[consumed:]
@@ -71,12 +75,33 @@ class Peer:
 
         raise AssertionError(f"the node sent no {command} within {PATIENCE}s")
 
-    def handshake(self) -> None:
-        """Become a peer: answer the node's version, and send our own."""
+    def handshake(
+        self,
+        nonce: Optional[int] = None,
+        best_height: int = 0,
+        protocol_version: int = PROTOCOL_VERSION,
+    ) -> None:
+        """Become a peer: answer the node's version, and send our own.
+
+        A fresh nonce unless told otherwise: two peers sharing one are one
+        node to the node, and it keeps only one of their connections. The node
+        asks for headers only if `best_height` is not behind its own, and
+        announces blocks compactly only if `protocol_version` speaks them.
+        """
         self.next_frame_of("version")
-        self.send(version(0x51DE, "127.0.0.1:5000"))
+        if nonce is None:
+            nonce = random.getrandbits(64)
+        self.send(
+            version(
+                nonce,
+                "127.0.0.1:5000",
+                protocol_version=protocol_version,
+                magic=self.magic,
+                best_height=best_height,
+            )
+        )
         self.next_frame_of("verack")
-        self.send(verack())
+        self.send(verack(self.magic))
 
     def frames_within(self, window: float = IMPATIENCE) -> List[Frame]:
         """Everything the node says within `window`.
@@ -153,11 +178,12 @@ def split_address(address: str):
     return host, int(port)
 
 
-def free_port() -> socket.socket:
-    """A listening socket on an ephemeral port, kept open so nothing races us."""
+def free_port(port: int = 0) -> socket.socket:
+    """A listening socket on an ephemeral port, kept open so nothing races us.
+    Given a `port`, on that one instead, to come back up where one was closed."""
     listener = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
     listener.setsockopt(socket.SOL_SOCKET, socket.SO_REUSEADDR, 1)
-    listener.bind(("127.0.0.1", 0))
+    listener.bind(("127.0.0.1", port))
     listener.listen(8)
     return listener
 
@@ -178,7 +204,7 @@ def accept_within(listener: socket.socket, patience: float) -> Optional[socket.s
     return accepted
 
 
-def expect_dialled(listener: socket.socket) -> Peer:
+def expect_dialled(listener: socket.socket, magic: bytes = MAGIC) -> Peer:
     accepted = accept_within(listener, PATIENCE)
     assert accepted is not None, f"the node never dialled us within {PATIENCE}s"
-    return Peer(accepted)
+    return Peer(accepted, magic)
@@ -0,0 +1,67 @@
+"""Address discovery: a node asks the peers it dials for the addresses they
+know, dials what it learns, answers the same question, and remembers the
+addresses across a restart."""
+
+import time
+
+from framework.messages import MAGICS, addr, getaddr
+from framework.p2p import PATIENCE, address_of, expect_dialled
+
+REGTEST = MAGICS["regtest"]
+ADDRESS_BOOK = "data/regtest/peers.txt"
+
+
+def regtest_node(net, *args):
+    return net.node("--network", "regtest", "--host-address", "127.0.0.1:0", *args)
+
+
+def dialled_and_ready(listening):
+    peer = expect_dialled(listening, REGTEST)
+    peer.handshake()
+    return peer
+
+
+def test_a_node_asks_a_peer_it_dialled_for_addresses_and_dials_what_it_learns(net):
+    listening = net.listener()
+    told_of = net.listener()
+    regtest_node(net, "--addresses-to-connect", address_of(listening))
+
+    peer = net.track(dialled_and_ready(listening))
+    peer.next_frame_of("getaddr")
+    peer.send(addr([(int(time.time()), address_of(told_of))], magic=REGTEST))
+
+    net.track(dialled_and_ready(told_of))
+
+
+def test_a_getaddr_is_answered_with_the_addresses_the_node_knows(net):
+    listening = net.listener()
+    node = regtest_node(net, "--addresses-to-connect", address_of(listening))
+    net.track(dialled_and_ready(listening)).next_frame_of("getaddr")
+
+    asking = net.dial(node.listening_on(), REGTEST)
+    asking.handshake()
+    asking.send(getaddr(REGTEST))
+
+    known = [address for _, address in asking.next_frame_of("addr").as_addresses()]
+    assert address_of(listening) in known
+
+
+def test_a_restarted_node_dials_the_addresses_it_knew_without_being_told_them(net):
+    listening = net.listener()
+    node = regtest_node(net, "--addresses-to-connect", address_of(listening))
+    net.track(dialled_and_ready(listening)).next_frame_of("getaddr")
+
+    book = node.sandbox.path / ADDRESS_BOOK
+    deadline = time.monotonic() + PATIENCE
+    while time.monotonic() < deadline and not (
+        book.exists() and address_of(listening) in book.read_text()
+    ):
+        time.sleep(0.05)
+    assert address_of(listening) in book.read_text()
+
+    restarted = net.restart(
+        node, "--network", "regtest", "--host-address", "127.0.0.1:0"
+    )
+
+    restarted.line_containing("Loaded 1 addresses")
+    net.track(dialled_and_ready(listening))
@@ -0,0 +1,108 @@
+"""Block download: once a node knows the best headers, it fetches their bodies
+from every peer that has them, and gives up on a peer that sits on a request."""
+
+import re
+import time
+
+from framework.messages import MAGICS, frame, getdata, getheaders, header_hash, headers
+from framework.p2p import PATIENCE, address_of, expect_dialled
+
+KEY_ONE = "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798"
+REGTEST = MAGICS["regtest"]
+AHEAD = 1_000_000
+INVENTORY_BLOCK = 2
+# How long the node waits on a requested block before it blames the peer.
+BLOCK_TIMEOUT = 10.0
+CONNECTED_ALL = r"^Connected \d+ blocks; tip \S+ at height 60$"
+
+
+def regtest_node(net, *args):
+    return net.node("--network", "regtest", "--host-address", "127.0.0.1:0", *args)
+
+
+def mined(net, blocks):
+    node = regtest_node(net)
+    node.tell(f"generate {blocks} to {KEY_ONE}")
+    node.line_containing(f"Generated {blocks} blocks")
+    return node
+
+
+def chain_of(net, node) -> list:
+    """(header, block) for every block after genesis, asked for as a peer."""
+    peer = net.dial(node.listening_on(), REGTEST)
+    # Claiming to be ahead, so the node asks us for headers: its locator ends
+    # at genesis.
+    peer.handshake(best_height=AHEAD)
+    genesis = peer.next_frame_of("getheaders").as_getheaders()[0][-1]
+
+    peer.send(getheaders([genesis], magic=REGTEST))
+    raw_headers = peer.next_frame_of("headers").as_headers()
+    wanted = [(INVENTORY_BLOCK, header_hash(header)) for header in raw_headers]
+    peer.send(getdata(wanted, magic=REGTEST))
+
+    return [(header, peer.next_frame_of("block").payload) for header in raw_headers]
+
+
+def test_a_fresh_node_downloads_every_block_a_peer_has(net):
+    source = mined(net, 40)
+
+    fresh = regtest_node(net, "--addresses-to-connect", source.listening_on())
+
+    fresh.line_matching(r"^Connected \d+ blocks; tip \S+ at height 40$")
+
+
+def test_a_getdata_is_answered_with_the_blocks_asked_for(net):
+    source = mined(net, 3)
+
+    chain = chain_of(net, source)
+
+    assert len(chain) == 3
+    for header, block in chain:
+        # A block message is the header followed by the transactions.
+        assert block[:80] == header
+
+
+def test_a_peer_sitting_on_its_requests_is_dropped_and_others_finish_the_job(net):
+    chain = chain_of(net, mined(net, 60))
+    blocks = {header_hash(header): block for header, block in chain}
+    announced = headers([header for header, _ in chain], magic=REGTEST)
+
+    stalling = net.listener()
+    serving = net.listener()
+    fresh = regtest_node(
+        net,
+        "--addresses-to-connect",
+        address_of(stalling),
+        "--addresses-to-connect",
+        address_of(serving),
+    )
+
+    # The staller announces first, so the first requests are certainly its.
+    staller = net.track(expect_dialled(stalling, REGTEST))
+    staller.handshake(best_height=len(chain))
+    staller.next_frame_of("getheaders")
+    staller.send(announced)
+    withheld = staller.next_frame_of("getdata").as_inventory()
+    assert withheld
+
+    server = net.track(expect_dialled(serving, REGTEST))
+    server.handshake(best_height=len(chain))
+    server.next_frame_of("getheaders")
+    server.send(announced)
+
+    asked = set()
+    deadline = time.monotonic() + BLOCK_TIMEOUT + PATIENCE
+    while time.monotonic() < deadline and not any(
+        re.search(CONNECTED_ALL, line) for line in fresh.said()
+    ):
+        for received in server.frames_within(0.2):
+            if received.command != "getdata":
+                continue
+            for _, wanted in received.as_inventory():
+                asked.add(wanted)
+                server.send(frame("block", blocks[wanted], REGTEST))
+
+    fresh.line_matching(CONNECTED_ALL)
+    assert "not delivered within" in fresh.line_containing("Dropping")
+    # Everything the staller sat on came from the server in the end.
+    assert {wanted for _, wanted in withheld} <= asked
@@ -0,0 +1,116 @@
+"""Compact block relay: a block is announced as its header and short
+transaction ids, rebuilt from the mempool, and only what the mempool lacks is
+fetched with `getblocktxn`."""
+
+from framework.messages import (
+    INVENTORY_BLOCK,
+    MAGICS,
+    blocktxn,
+    cmpctblock,
+    getblocktxn,
+    header_hash,
+    headers,
+    mined_header,
+    raw_transaction,
+    regtest_allocation,
+    tx,
+)
+
+KEY_ONE = "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798"
+REGTEST = MAGICS["regtest"]
+REGTEST_N_BITS = 0x207FFFFF
+CONNECTED_ONE = "Connected 1 blocks; tip "
+
+
+def regtest_node(net, *args):
+    return net.node("--network", "regtest", "--host-address", "127.0.0.1:0", *args)
+
+
+def a_caught_up_peer(net, node):
+    """A peer past the handshake, whose first getheaders is answered, and the
+    genesis hash that getheaders ended its locator with."""
+    peer = net.dial(node.listening_on(), REGTEST)
+    peer.handshake()
+    genesis = peer.next_frame_of("getheaders").as_getheaders()[0][-1]
+    peer.send(headers([], magic=REGTEST))
+    return peer, genesis
+
+
+def a_block_on(genesis: bytes, spends: list) -> tuple:
+    """(raw header, raw transactions): a coinbase and one spend of each
+    (txid, v_out) of `spends`, mined on genesis."""
+    coinbase = raw_transaction([(bytes(32), 0xFFFFFFFF)], 1, KEY_ONE)
+    transactions = [coinbase] + [
+        raw_transaction([spent], 5, KEY_ONE) for spent in spends
+    ]
+    return mined_header(genesis, transactions, REGTEST_N_BITS), transactions
+
+
+def test_a_new_block_is_announced_compactly(net):
+    node = regtest_node(net)
+    peer, _ = a_caught_up_peer(net, node)
+
+    node.tell(f"generate 1 to {KEY_ONE}")
+    tip = node.line_containing("Generated block").split("Generated block ")[1].split()[0]
+
+    raw_header, _, short_ids, prefilled = peer.next_frame_of("cmpctblock").as_compact_block()
+    assert header_hash(raw_header)[::-1].hex() == tip
+    assert short_ids == []
+    assert [index for index, _ in prefilled] == [0], "the coinbase, sent whole"
+
+
+def test_a_compact_block_of_pooled_transactions_connects_without_a_round_trip(net):
+    node = regtest_node(net)
+    peer, genesis = a_caught_up_peer(net, node)
+    raw_header, transactions = a_block_on(genesis, [regtest_allocation(0)])
+    peer.send(tx(transactions[1], magic=REGTEST))
+    node.line_containing("Accepted transaction")
+
+    peer.send(cmpctblock(raw_header, 7, transactions, magic=REGTEST))
+
+    assert header_hash(raw_header)[::-1].hex() in node.line_containing(CONNECTED_ONE)
+    assert "getblocktxn" not in [frame.command for frame in peer.frames_within()]
+
+
+def test_what_the_mempool_lacks_is_fetched_with_getblocktxn(net):
+    node = regtest_node(net)
+    peer, genesis = a_caught_up_peer(net, node)
+    raw_header, transactions = a_block_on(genesis, [regtest_allocation(0), regtest_allocation(1)])
+    block_hash = header_hash(raw_header)
+    peer.send(tx(transactions[1], magic=REGTEST))
+    node.line_containing("Accepted transaction")
+
+    peer.send(cmpctblock(raw_header, 7, transactions, magic=REGTEST))
+
+    assert peer.next_frame_of("getblocktxn").as_getblocktxn() == (block_hash, [2])
+    peer.send(blocktxn(block_hash, [transactions[2]], magic=REGTEST))
+    assert block_hash[::-1].hex() in node.line_containing(CONNECTED_ONE)
+
+
+def test_a_compact_block_that_cannot_be_rebuilt_is_fetched_whole(net):
+    node = regtest_node(net)
+    peer, genesis = a_caught_up_peer(net, node)
+    raw_header, transactions = a_block_on(genesis, [regtest_allocation(0)])
+    block_hash = header_hash(raw_header)
+
+    # A blocktxn with the wrong transaction rebuilds a block that does not
+    # match its header.
+    peer.send(cmpctblock(raw_header, 7, transactions, magic=REGTEST))
+    peer.next_frame_of("getblocktxn")
+    impostor = raw_transaction([regtest_allocation(1)], 5, KEY_ONE)
+    peer.send(blocktxn(block_hash, [impostor], magic=REGTEST))
+
+    assert peer.next_frame_of("getdata").as_inventory() == [(INVENTORY_BLOCK, block_hash)]
+    node.line_containing("Could not rebuild a compact block")
+
+
+def test_a_getblocktxn_is_answered_with_the_transactions_asked_for(net):
+    node = regtest_node(net)
+    peer, _ = a_caught_up_peer(net, node)
+    node.tell(f"generate 1 to {KEY_ONE}")
+    raw_header, _, _, prefilled = peer.next_frame_of("cmpctblock").as_compact_block()
+    block_hash = header_hash(raw_header)
+
+    peer.send(getblocktxn(block_hash, [0], magic=REGTEST))
+
+    assert peer.next_frame_of("blocktxn").as_blocktxn() == (block_hash, [prefilled[0][1]])
@@ -1,16 +1,41 @@
-from framework.messages import ping, pong
-from framework.p2p import address_of, expect_dialled
+from framework.messages import ping, pong, verack
+from framework.p2p import address_of, expect_dialled, free_port, split_address
 
 
-def test_a_node_pings_whoever_dials_it(net):
+def test_a_node_pings_a_peer_once_the_handshake_completes(net):
     node = net.node("--host-address", "127.0.0.1:0")
+    peer = net.dial(node.listening_on())
+
+    peer.handshake()
+
+    assert peer.next_frame_of("ping").command == "ping"
+
+
+def test_a_node_says_nothing_but_its_version_to_a_peer_that_has_not_answered(net):
+    node = net.node("--host-address", "127.0.0.1:0")
+    peer = net.dial(node.listening_on())
+
+    assert [frame.command for frame in peer.frames_within()] == ["version"]
+
+
+def test_a_ping_before_the_handshake_goes_unanswered_and_ends_the_connection(net):
+    node = net.node("--host-address", "127.0.0.1:0")
+    address = node.listening_on()
+    peer = net.dial(address)
 
-    assert net.dial(node.listening_on()).next_frame_of("ping").command == "ping"
+    peer.send(ping(7))
+    assert "before completing the handshake" in node.line_containing("misbehavior")
+    assert [frame.command for frame in peer.frames_within()] == ["version"]
+    peer.expect_closed()
+
+    again = net.dial(address)
+    assert [frame.command for frame in again.frames_within()] == ["version"], "charged, not banned"
 
 
 def test_a_node_answers_a_ping_with_a_pong_carrying_the_same_nonce(net):
     node = net.node("--host-address", "127.0.0.1:0")
     peer = net.dial(node.listening_on())
+    peer.handshake()
 
     peer.send(ping(0x0123456789ABCDEF))
 
@@ -38,14 +63,31 @@ def test_a_node_dials_every_address_it_was_given(net):
 def test_a_pong_is_accepted_and_does_not_provoke_another_pong(net):
     node = net.node("--host-address", "127.0.0.1:0")
     peer = net.dial(node.listening_on())
+    peer.handshake()
 
-    opening = peer.next_frame_of("ping")
-    peer.send(pong(opening.nonce))
+    # Its getheaders and first ping, in whichever order the writer woke.
+    opening = {frame.command: frame for frame in (peer.next_frame(), peer.next_frame())}
+    assert set(opening) == {"getheaders", "ping"}
+    peer.send(pong(opening["ping"].nonce))
 
-    node.line_containing("Pong received")
+    line = node.line_containing("Pong received")
+    assert " in " in line, "the round trip is measured"
     peer.expect_silence()
 
 
+def test_a_pong_answering_no_ping_is_charged_and_the_peer_kept(net):
+    node = net.node("--host-address", "127.0.0.1:0")
+    peer = net.dial(node.listening_on())
+    peer.handshake()
+    first_ping = peer.next_frame_of("ping").nonce
+
+    peer.send(pong(first_ping ^ 1))
+
+    assert "unknown pong" in node.line_containing("misbehavior score 10")
+    peer.send(ping(0xFEEDFACE))
+    assert 0xFEEDFACE in peer.pongs_within()
+
+
 def test_two_real_nodes_hand_shake_and_complete_a_ping_pong_round_trip(net):
     listener = net.node("--host-address", "127.0.0.1:0")
 
@@ -63,3 +105,71 @@ def test_two_real_nodes_hand_shake_and_complete_a_ping_pong_round_trip(net):
     for node in (listener, dialler):
         node.line_containing("Handshake with")
         node.line_containing("Pong received")
+
+
+def test_a_configured_peer_that_is_not_up_yet_is_dialled_once_it_is(net):
+    closed = free_port()
+    address = address_of(closed)
+    closed.close()
+
+    node = net.node("--host-address", "127.0.0.1:0", "--addresses-to-connect", address)
+    node.line_containing(f"Could not connect to {address}")
+
+    node.tell("connections")
+    node.line_matching(rf"{address} after \d+ attempts; next in \d+s")
+
+    listening = net.listener(split_address(address)[1])
+    net.track(expect_dialled(listening)).handshake()
+
+
+def test_a_configured_peer_that_disconnects_is_dialled_again(net):
+    listening = net.listener()
+    net.node(
+        "--host-address",
+        "127.0.0.1:0",
+        "--addresses-to-connect",
+        address_of(listening),
+    )
+
+    first = expect_dialled(listening)
+    first.handshake()
+    first.close()
+
+    net.track(expect_dialled(listening)).handshake()
+
+
+def test_peers_dialling_in_past_the_inbound_cap_do_not_stop_the_node_dialling_out(
+    net,
+):
+    closed = free_port()
+    address = address_of(closed)
+    closed.close()
+    node = net.node(
+        "--host-address",
+        "127.0.0.1:0",
+        "--max-inbound",
+        "1",
+        "--addresses-to-connect",
+        address,
+    )
+
+    net.dial(node.listening_on()).handshake()
+    net.dial(node.listening_on()).expect_closed()
+    assert "InboundAtCapacity" in node.line_containing("Refusing a connection")
+
+    listening = net.listener(split_address(address)[1])
+    net.track(expect_dialled(listening)).handshake()
+
+
+def test_a_full_inbound_side_makes_room_by_evicting_a_peer_that_misbehaved(net):
+    node = net.node("--host-address", "127.0.0.1:0", "--max-inbound", "1")
+    misbehaving = net.dial(node.listening_on())
+    misbehaving.handshake()
+    misbehaving.send(verack())
+    node.line_containing("misbehavior score")
+
+    newcomer = net.dial(node.listening_on())
+    newcomer.handshake()
+
+    misbehaving.expect_closed()
+    node.line_containing("Evicting")
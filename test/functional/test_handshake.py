@@ -1,6 +1,19 @@
 """A connection is not a peer until both sides have said who they are."""
 
-from framework.messages import PROTOCOL_VERSION, frame, ping, verack, version
+import random
+import time
+
+from framework.messages import (
+    MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+    SERVICE_SERVES_BLOCKS,
+    addr,
+    frame,
+    ping,
+    verack,
+    version,
+)
+from framework.p2p import IMPATIENCE, address_of, expect_dialled, free_port
 
 
 def test_a_node_opens_a_connection_by_identifying_itself(net):
@@ -10,6 +23,10 @@ def test_a_node_opens_a_connection_by_identifying_itself(net):
     said = net.dial(address).next_frame().as_version()
 
     assert said.protocol_version == PROTOCOL_VERSION
+    assert said.services & SERVICE_SERVES_BLOCKS
+    assert said.user_agent.startswith("/avicoin:")
+    assert said.best_height == 0
+    assert abs(said.timestamp - time.time()) < 60
     assert said.listen_address == address, (
         "a peer re-dials the address we advertise, so it must be the bound one "
         "and not the 127.0.0.1:0 that was asked for"
@@ -39,6 +56,29 @@ def test_a_version_is_answered_with_a_verack(net):
     assert peer.next_frame_of("verack").payload == b""
 
 
+def test_a_peer_speaking_a_newer_protocol_is_spoken_to_in_ours(net):
+    node = net.node("--host-address", "127.0.0.1:0")
+    peer = net.dial(node.listening_on())
+    peer.next_frame_of("version")
+
+    newer = PROTOCOL_VERSION + 1
+    peer.send(version(7, "127.0.0.1:5000", protocol_version=newer))
+
+    assert peer.next_frame_of("verack").payload == b""
+    node.line_containing(f"speaks protocol {newer}, negotiated to {PROTOCOL_VERSION}")
+
+
+def test_a_peer_speaking_an_older_protocol_is_dropped(net):
+    node = net.node("--host-address", "127.0.0.1:0")
+    peer = net.dial(node.listening_on())
+    peer.next_frame_of("version")
+
+    peer.send(version(7, "127.0.0.1:5000", protocol_version=MIN_PROTOCOL_VERSION - 1))
+
+    peer.expect_closed()
+    node.line_containing("older than the oldest we talk to")
+
+
 def test_a_peer_that_completes_the_handshake_keeps_talking(net):
     node = net.node("--host-address", "127.0.0.1:0")
     peer = net.dial(node.listening_on())
@@ -49,28 +89,33 @@ def test_a_peer_that_completes_the_handshake_keeps_talking(net):
     assert peer.pongs_within() == [0xFEEDFACE]
 
 
-def test_a_peer_sending_a_verack_it_was_never_owed_is_dropped(net):
+def test_a_verack_that_was_never_owed_is_charged_for_and_a_second_gets_the_peer_banned(
+    net,
+):
     node = net.node("--host-address", "127.0.0.1:0")
     address = node.listening_on()
 
     villain = net.dial(address)
     villain.next_frame_of("version")
     villain.send(verack())
+    assert "verack before any version" in node.line_containing("misbehavior score 50")
+    villain.send(verack())
     villain.expect_closed()
 
-    assert net.dial(address).next_frame().command == "version"
+    net.dial(address).expect_closed()
 
 
-def test_a_second_version_after_the_handshake_is_refused(net):
+def test_a_second_version_after_the_handshake_is_ignored_and_charged_for(net):
     node = net.node("--host-address", "127.0.0.1:0")
-    address = node.listening_on()
 
-    villain = net.dial(address)
+    villain = net.dial(node.listening_on())
     villain.handshake()
     villain.send(version(0x1122334455667788, "127.0.0.1:5000"))
-    villain.expect_closed()
+    node.line_containing("version after the handshake")
 
-    assert net.dial(address).next_frame().command == "version"
+    assert "verack" not in [frame.command for frame in villain.frames_within()]
+    villain.send(ping(0xFEEDFACE))
+    assert villain.pongs_within() == [0xFEEDFACE]
 
 
 def test_a_verack_carrying_a_payload_is_not_a_verack(net):
@@ -85,3 +130,104 @@ def test_a_verack_carrying_a_payload_is_not_a_verack(net):
     villain.expect_closed()
 
     assert net.dial(address).next_frame().command == "version"
+
+
+def test_a_node_that_dials_itself_drops_the_connection_and_never_dials_it_again(net):
+    reserved = free_port()
+    own = address_of(reserved)
+    reserved.close()
+    listening = net.listener()
+    # Listening everywhere, so the address it dials does not look like its own.
+    node = net.node(
+        "--host-address",
+        own.replace("127.0.0.1", "0.0.0.0"),
+        "--addresses-to-connect",
+        own,
+        "--addresses-to-connect",
+        address_of(listening),
+    )
+    node.line_containing("is this node")
+
+    # Told of its own address by someone else, it still does not dial it.
+    peer = net.track(expect_dialled(listening))
+    peer.handshake()
+    peer.next_frame_of("getaddr")
+    peer.send(addr([(int(time.time()), own)]))
+    time.sleep(IMPATIENCE)
+
+    # One for each end of the first connection, and none after.
+    assert len([line for line in node.said() if "is this node" in line]) == 2
+
+
+
+def both_ways(net, theirs):
+    """A connection each way between the node and a peer with nonce `theirs`:
+    (the one the node dialled, the one the peer dialled). The second has only
+    sent its version, which is when the node finds it has two."""
+    listening = net.listener()
+    node = net.node(
+        "--host-address", "127.0.0.1:0", "--addresses-to-connect", address_of(listening)
+    )
+    dialled = net.track(expect_dialled(listening))
+    dialled.handshake(nonce=theirs)
+
+    dialling = net.dial(node.listening_on())
+    dialling.next_frame_of("version")
+    dialling.send(version(theirs, "127.0.0.1:5000"))
+    return dialled, dialling
+
+
+def test_of_two_connections_to_a_node_with_a_smaller_nonce_ours_stays(net):
+    dialled, dialling = both_ways(net, 0)
+
+    dialling.expect_closed()
+    dialled.send(ping(0xFEEDFACE))
+    assert 0xFEEDFACE in dialled.pongs_within()
+
+
+def test_of_two_connections_to_a_node_with_a_larger_nonce_theirs_stays(net):
+    dialled, dialling = both_ways(net, 2**64 - 1)
+
+    dialled.expect_closed()
+    dialling.next_frame_of("verack")
+    dialling.send(verack())
+    dialling.send(ping(0xFEEDFACE))
+    assert 0xFEEDFACE in dialling.pongs_within()
+
+
+def versions_from_clocks(net, node, offsets):
+    """Peers that each send a version stamped `offset` seconds from now."""
+    peers = []
+    for offset in offsets:
+        peer = net.dial(node.listening_on())
+        peer.next_frame_of("version")
+        peer.send(
+            version(
+                random.getrandbits(64),
+                "127.0.0.1:5000",
+                timestamp=int(time.time()) + offset,
+            )
+        )
+        peer.next_frame_of("verack")
+        peers.append(peer)
+    return peers
+
+
+def test_a_clock_far_from_the_network_median_is_warned_of(net):
+    """ADR-0009: past its future limit a drifting clock splits the node off
+    silently, so the log must say so first."""
+    node = net.node("--host-address", "127.0.0.1:0")
+
+    versions_from_clocks(net, node, [-200, -210, -190, -205, 0])
+
+    warning = node.line_containing("WARNING: our clock")
+    assert "ahead of them" in warning, warning
+
+
+def test_one_peer_with_a_wild_clock_is_outvoted(net):
+    node = net.node("--host-address", "127.0.0.1:0")
+
+    versions_from_clocks(net, node, [0, 1, -1, 2, 86_400])
+    versions_from_clocks(net, node, [0])
+
+    assert not any("WARNING" in line for line in node.said())
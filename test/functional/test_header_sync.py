@@ -0,0 +1,133 @@
+"""Headers-first sync: a node learns the chain's headers from every peer it
+completes a handshake with, and follows whichever chain has the most work."""
+
+import struct
+
+from framework.messages import (
+    INVENTORY_BLOCK,
+    MAGICS,
+    getheaders,
+    header_hash,
+    headers,
+)
+
+# Private keys 1 and 2's public keys. Two miners paying different keys build
+# different chains, even in the same second.
+KEY_ONE = "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798"
+KEY_TWO = "02C6047F9441ED7D6D3045406E95C07CD85C778E4B8CEF3CA7ABAC09B95C709EE5"
+REGTEST = MAGICS["regtest"]
+AHEAD = 1_000_000
+
+
+def regtest_node(net, *args):
+    return net.node("--network", "regtest", "--host-address", "127.0.0.1:0", *args)
+
+
+def mined(net, blocks, to=KEY_ONE):
+    node = regtest_node(net)
+    node.tell(f"generate {blocks} to {to}")
+    node.line_containing(f"Generated {blocks} blocks")
+    return node
+
+
+def tip_of(node) -> str:
+    """The tip a `generate` answer named, as displayed."""
+    return node.line_containing("; tip ").split("; tip ")[1].split()[0]
+
+
+def best_chain_of(net, node) -> list:
+    """Every header after genesis on the node's best chain, asked for as a peer."""
+    peer = net.dial(node.listening_on(), REGTEST)
+    # Claiming to be ahead, so the node asks us for headers: its locator ends
+    # at genesis.
+    peer.handshake(best_height=AHEAD)
+    genesis = peer.next_frame_of("getheaders").as_getheaders()[0][-1]
+
+    peer.send(getheaders([genesis], magic=REGTEST))
+    return peer.next_frame_of("headers").as_headers()
+
+
+def test_a_peer_is_asked_for_headers_once_the_handshake_completes(net):
+    node = regtest_node(net)
+    peer = net.dial(node.listening_on(), REGTEST)
+
+    peer.handshake()
+    locator, stop = peer.next_frame_of("getheaders").as_getheaders()
+
+    # A fresh node knows only genesis, so that is all it can offer to share.
+    assert len(locator) == 1
+    assert stop == bytes(32)
+
+
+def test_a_peer_behind_the_node_is_told_its_tip_rather_than_asked_for_headers(net):
+    node = mined(net, 3)
+    peer = net.dial(node.listening_on(), REGTEST)
+
+    peer.handshake(best_height=1)
+    announced = peer.next_frame_of("inv").as_inventory()
+
+    assert [(kind, hash[::-1].hex()) for kind, hash in announced] == [
+        (INVENTORY_BLOCK, tip_of(node))
+    ]
+    assert "getheaders" not in [frame.command for frame in peer.frames_within()]
+
+
+def test_a_getheaders_is_answered_with_the_chain_after_the_locator(net):
+    node = mined(net, 4)
+
+    answer = best_chain_of(net, node)
+
+    assert len(answer) == 4
+    for parent, child in zip(answer, answer[1:]):
+        assert child[4:36] == header_hash(parent)
+    assert header_hash(answer[-1])[::-1].hex() == tip_of(node)
+
+
+def test_a_fresh_node_catches_up_on_a_peers_headers(net):
+    source = mined(net, 5)
+
+    fresh = regtest_node(net, "--addresses-to-connect", source.listening_on())
+
+    assert "at height 5" in fresh.line_containing("Best header")
+
+
+def test_the_chain_with_the_most_work_wins_whichever_peer_answers_first(net):
+    short = mined(net, 2, to=KEY_ONE)
+    long = mined(net, 6, to=KEY_TWO)
+
+    fresh = regtest_node(
+        net,
+        "--addresses-to-connect",
+        short.listening_on(),
+        "--addresses-to-connect",
+        long.listening_on(),
+    )
+    fresh.line_containing("at height 6")
+    fresh.line_containing(f"Handshake with {short.listening_on()}")
+
+    # Asked rather than read from the log: two peers answer on two threads, so
+    # the order their lines are printed in is not the order they were judged.
+    best = best_chain_of(net, fresh)
+    assert len(best) == 6
+    assert header_hash(best[-1])[::-1].hex() == tip_of(long)
+
+
+def test_a_peer_answering_with_an_invalid_header_is_dropped(net):
+    node = regtest_node(net)
+    peer = net.dial(node.listening_on(), REGTEST)
+    # Claiming to be ahead, so the node asks us for headers: its locator ends
+    # at genesis.
+    peer.handshake(best_height=AHEAD)
+    genesis = peer.next_frame_of("getheaders").as_getheaders()[0][-1]
+
+    # On genesis, but claiming a target easier than regtest's own.
+    forged = (
+        struct.pack("<i", 1)
+        + genesis
+        + bytes(32)
+        + struct.pack("<III", 1785369601, 0x2100FFFF, 0)
+    )
+    peer.send(headers([forged], magic=REGTEST))
+
+    peer.expect_closed()
+    assert "n_bits" in node.line_containing("Connection with")
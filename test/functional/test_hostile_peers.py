@@ -1,4 +1,5 @@
-"""A bad peer loses its connection. It never takes the node down.
+"""A bad peer loses its connection, or is charged until it is banned. It never
+takes the node down.
 
 Each case ends by dialling again: proving the connection died is only half the
 guarantee, and the half that matters least.
@@ -6,7 +7,7 @@ guarantee, and the half that matters least.
 
 import struct
 
-from framework.messages import HEADER_LENGTH, MAX_PAYLOAD_SIZE, frame, ping
+from framework.messages import HEADER_LENGTH, MAX_BLOCK_SIZE, frame, ping
 
 
 def test_a_peer_speaking_another_networks_magic_bytes_is_dropped(net):
@@ -35,41 +36,76 @@ def test_a_peer_claiming_a_four_gigabyte_payload_is_dropped(net):
     assert net.dial(address).next_frame().command == "version"
 
 
-def test_a_payload_at_the_limit_is_not_refused_for_being_too_large(net):
-    """The cap is 32 MiB inclusive; a claim exactly at it is legal, merely
-    incomplete. The node should wait for bytes that never come, not hang up."""
+def test_a_ping_claiming_more_than_its_eight_bytes_is_dropped(net):
+    """Each command has its own cap, so a ping may not make the node buffer
+    what only a block could fill."""
     node = net.node("--host-address", "127.0.0.1:0")
+    address = node.listening_on()
 
     header = bytearray(ping(1)[:HEADER_LENGTH])
-    header[16:20] = struct.pack("<I", MAX_PAYLOAD_SIZE)
+    header[16:20] = struct.pack("<I", 9)
+    villain = net.dial(address)
+    villain.send(bytes(header))
+    villain.expect_closed()
+
+    assert net.dial(address).next_frame().command == "version"
+
+
+def test_a_payload_at_the_limit_is_not_refused_for_being_too_large(net):
+    """A block's cap is MAX_BLOCK_SIZE inclusive; a claim exactly at it is
+    legal, merely incomplete. The node should wait for bytes that never come,
+    not hang up."""
+    node = net.node("--host-address", "127.0.0.1:0")
+
+    header = bytearray(frame("block", b"")[:HEADER_LENGTH])
+    header[16:20] = struct.pack("<I", MAX_BLOCK_SIZE)
     peer = net.dial(node.listening_on())
     peer.send(bytes(header))
 
     assert peer.next_frame().command == "version", "the connection should still be live"
 
 
-def test_a_peer_whose_payload_does_not_match_its_checksum_is_dropped(net):
+def corrupted(message: bytes) -> bytes:
+    garbled = bytearray(message)
+    garbled[-1] ^= 0xFF
+    return bytes(garbled)
+
+
+def test_a_frame_whose_payload_does_not_match_its_checksum_is_skipped(net):
     node = net.node("--host-address", "127.0.0.1:0")
-    address = node.listening_on()
 
-    corrupted = bytearray(ping(1))
-    corrupted[-1] ^= 0xFF
-    villain = net.dial(address)
-    villain.send(bytes(corrupted))
-    villain.expect_closed()
+    peer = net.dial(node.listening_on())
+    peer.handshake()
+    peer.send(corrupted(ping(1)) + ping(2))
 
-    assert net.dial(address).next_frame().command == "version"
+    assert peer.pongs_within() == [2]
+    node.line_containing("bad checksum")
 
 
-def test_a_peer_sending_an_unknown_command_is_dropped(net):
+def test_a_peer_that_keeps_failing_checksums_is_banned(net):
     node = net.node("--host-address", "127.0.0.1:0")
     address = node.listening_on()
 
     villain = net.dial(address)
-    villain.send(frame("notacommand", struct.pack("<Q", 7)))
+    villain.handshake()
+    villain.send(corrupted(ping(1)) * 5)
     villain.expect_closed()
 
-    assert net.dial(address).next_frame().command == "version"
+    net.dial(address).expect_closed()
+    node.line_containing(": banned")
+
+
+def test_an_unknown_command_is_skipped_and_the_peer_kept(net):
+    """A command we do not know may be one a newer node added; so long as it
+    is well framed, it costs the peer nothing."""
+    node = net.node("--host-address", "127.0.0.1:0")
+
+    peer = net.dial(node.listening_on())
+    peer.handshake()
+    peer.send(frame("notacommand", struct.pack("<Q", 7)) + ping(3))
+
+    assert peer.pongs_within() == [3]
+    node.line_containing('Skipping unknown command "notacommand"')
 
 
 def test_a_peer_that_vanishes_mid_message_does_not_take_the_node_down(net):
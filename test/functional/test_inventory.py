@@ -0,0 +1,143 @@
+"""Inventory relay: a node announces the transactions it pools in batches, and
+the blocks it connects to peers that predate compact blocks, with `inv`, asks
+for what it is told of, and says `notfound` for what it cannot serve."""
+
+from framework.messages import (
+    COMPACT_BLOCKS_VERSION,
+    INVENTORY_BLOCK,
+    INVENTORY_TX,
+    MAGICS,
+    getdata,
+    hash256,
+    headers,
+    inv,
+    raw_transaction,
+    regtest_allocation,
+    tx,
+)
+
+KEY_ONE = "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798"
+REGTEST = MAGICS["regtest"]
+CONNECTED_THREE = r"^Connected \d+ blocks; tip \S+ at height 3$"
+
+
+def regtest_node(net, *args):
+    return net.node("--network", "regtest", "--host-address", "127.0.0.1:0", *args)
+
+
+def a_caught_up_peer(net, node, **handshake):
+    """A peer past the handshake, whose first getheaders is answered."""
+    peer = net.dial(node.listening_on(), REGTEST)
+    peer.handshake(**handshake)
+    peer.next_frame_of("getheaders")
+    peer.send(headers([], magic=REGTEST))
+    return peer
+
+
+def test_a_block_generated_after_the_handshake_reaches_the_peer(net):
+    miner = regtest_node(net)
+    follower = regtest_node(net, "--addresses-to-connect", miner.listening_on())
+    follower.line_containing("Handshake with")
+    miner.line_containing("Handshake with")
+
+    miner.tell(f"generate 3 to {KEY_ONE}")
+
+    follower.line_matching(CONNECTED_THREE)
+
+
+def test_a_new_block_is_announced_to_a_ready_peer_that_predates_compact_blocks(net):
+    node = regtest_node(net)
+    peer = a_caught_up_peer(net, node, protocol_version=COMPACT_BLOCKS_VERSION - 1)
+
+    node.tell(f"generate 1 to {KEY_ONE}")
+    tip = node.line_containing("; tip ").split("; tip ")[1].split()[0]
+
+    announced = peer.next_frame_of("inv").as_inventory()
+    assert announced == [(INVENTORY_BLOCK, bytes.fromhex(tip)[::-1])]
+
+
+def test_an_announced_block_the_node_lacks_is_asked_for_by_its_headers(net):
+    node = regtest_node(net)
+    peer = a_caught_up_peer(net, node)
+
+    peer.send(inv([(INVENTORY_BLOCK, bytes([9] * 32))], magic=REGTEST))
+
+    peer.next_frame_of("getheaders")
+
+
+def test_what_the_node_cannot_serve_is_answered_with_notfound(net):
+    node = regtest_node(net)
+    peer = a_caught_up_peer(net, node)
+    unknown = [(INVENTORY_BLOCK, bytes([9] * 32)), (INVENTORY_TX, bytes([8] * 32))]
+
+    peer.send(getdata(unknown, magic=REGTEST))
+
+    assert peer.next_frame_of("notfound").as_inventory() == unknown
+
+
+def test_a_transaction_is_pooled_and_announced_to_the_other_ready_peers(net):
+    node = regtest_node(net)
+    sender = a_caught_up_peer(net, node)
+    other = a_caught_up_peer(net, node)
+    raw = raw_transaction([regtest_allocation(0)], 5, KEY_ONE)
+
+    sender.send(tx(raw, magic=REGTEST))
+
+    announced = other.next_frame_of("inv").as_inventory()
+    assert announced == [(INVENTORY_TX, hash256(raw))]
+    node.line_containing("Accepted transaction")
+
+
+def test_transactions_are_announced_in_batches_not_one_inv_each(net):
+    node = regtest_node(net)
+    sender = a_caught_up_peer(net, node)
+    other = a_caught_up_peer(net, node)
+    raws = [raw_transaction([regtest_allocation(i)], 5, KEY_ONE) for i in range(3)]
+
+    # In one write, so all three are pooled well inside one trickle interval.
+    sender.send(b"".join(tx(raw, magic=REGTEST) for raw in raws))
+
+    batches = []
+    entries = []
+    while len(entries) < len(raws):
+        batches.append(other.next_frame_of("inv").as_inventory())
+        entries += batches[-1]
+    assert sorted(entries) == sorted((INVENTORY_TX, hash256(raw)) for raw in raws)
+    assert len(batches) < len(raws), "at least two shared an inv"
+
+
+def test_a_transaction_crosses_nodes_by_announcement(net):
+    first = regtest_node(net)
+    second = regtest_node(net, "--addresses-to-connect", first.listening_on())
+    second.line_containing("Handshake with")
+    first.line_containing("Handshake with")
+    raw = raw_transaction([regtest_allocation(0)], 5, KEY_ONE)
+    txid = hash256(raw)[::-1].hex()
+
+    a_caught_up_peer(net, first).send(tx(raw, magic=REGTEST))
+
+    assert txid in second.line_containing("Accepted transaction")
+
+
+def test_a_coinbase_outside_a_block_ends_the_connection(net):
+    node = regtest_node(net)
+    peer = a_caught_up_peer(net, node)
+
+    minted = raw_transaction([(bytes(32), 0xFFFFFFFF)], 5, KEY_ONE)
+
+    peer.send(tx(minted, magic=REGTEST))
+
+    peer.expect_closed()
+    assert "invalid transaction" in node.line_containing("ended")
+
+
+def test_a_transaction_spending_coins_that_do_not_exist_is_neither_pooled_nor_relayed(net):
+    node = regtest_node(net)
+    sender = a_caught_up_peer(net, node)
+    other = a_caught_up_peer(net, node)
+    made_up = raw_transaction([(bytes([1] * 32), 0)], 5, KEY_ONE)
+
+    sender.send(tx(made_up, magic=REGTEST))
+
+    assert hash256(made_up)[::-1].hex() in node.line_containing("outputs we do not hold")
+    assert "inv" not in [frame.command for frame in other.frames_within()]
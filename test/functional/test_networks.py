@@ -0,0 +1,50 @@
+"""Each network is its own: its own magic bytes on the wire, and its own
+genesis block underneath them (ADR-0007, ADR-0011)."""
+
+from framework.messages import MAGICS, ping
+
+
+def test_a_node_is_on_mainnet_unless_told_otherwise(net):
+    node = net.node("--host-address", "127.0.0.1:0")
+
+    assert "main network" in node.line_containing("genesis")
+    assert net.dial(node.listening_on(), MAGICS["main"]).next_frame().command == "version"
+
+
+def test_a_test_node_speaks_the_test_networks_magic_bytes(net):
+    node = net.node("--network", "test", "--host-address", "127.0.0.1:0")
+
+    peer = net.dial(node.listening_on(), MAGICS["test"])
+
+    assert peer.next_frame().command == "version"
+
+
+def test_a_test_node_drops_a_peer_speaking_mainnet(net):
+    node = net.node("--network", "test", "--host-address", "127.0.0.1:0")
+    address = node.listening_on()
+
+    mainnet = net.dial(address, MAGICS["main"])
+    mainnet.send(ping(1))
+    mainnet.expect_closed()
+
+    assert net.dial(address, MAGICS["test"]).next_frame().command == "version"
+
+
+def test_the_network_can_be_chosen_in_the_config_file(net):
+    node = net.node("--host-address", "127.0.0.1:0", config='network = "regtest"\n')
+
+    assert "regtest network" in node.line_containing("genesis")
+    assert net.dial(node.listening_on(), MAGICS["regtest"]).next_frame().command == "version"
+
+
+def test_every_network_derives_a_different_genesis(net):
+    genesis = {
+        name: net.node("--network", name, "--host-address", "127.0.0.1:0")
+        .line_containing("genesis")
+        .rsplit(" ", 1)[1]
+        for name in MAGICS
+    }
+
+    assert len(set(genesis.values())) == len(MAGICS), (
+        f"networks sharing a genesis can merge their chains: {genesis}"
+    )
@@ -0,0 +1,48 @@
+"""Regtest produces blocks on command, so a scenario can be scripted block by
+block against the real binary rather than waiting on a miner."""
+
+# Private key 1's public key: well known, and funded on test and regtest.
+KEY_ONE = "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798"
+
+
+def regtest_node(net):
+    return net.node("--network", "regtest", "--host-address", "127.0.0.1:0")
+
+
+def test_generate_mines_the_requested_blocks_on_the_tip(net):
+    node = regtest_node(net)
+
+    node.tell(f"generate 3 to {KEY_ONE}")
+
+    assert "at height 3" in node.line_containing("Generated 3 blocks")
+    for height in (1, 2, 3):
+        assert "Generated block" in node.line_containing(f"at height {height}")
+
+
+def test_generating_twice_continues_from_the_tip(net):
+    node = regtest_node(net)
+
+    node.tell(f"generate 2 to {KEY_ONE}")
+    node.line_containing("Generated 2 blocks")
+    node.tell(f"generate 101 to {KEY_ONE}")
+
+    assert "at height 103" in node.line_containing("Generated 101 blocks")
+
+
+def test_generate_is_refused_off_regtest(net):
+    node = net.node("--network", "test", "--host-address", "127.0.0.1:0")
+
+    node.tell(f"generate 1 to {KEY_ONE}")
+
+    assert "regtest" in node.line_containing("failed")
+    assert not any("Generated block" in line for line in node.said())
+
+
+def test_a_malformed_command_is_reported_and_the_console_keeps_listening(net):
+    node = regtest_node(net)
+
+    node.tell("generate lots to nobody")
+    node.line_containing("failed")
+    node.tell(f"generate 1 to {KEY_ONE}")
+
+    node.line_containing("at height 1")